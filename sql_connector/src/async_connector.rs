@@ -0,0 +1,513 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{self, TcpStream};
+use crate::async_row_stream::AsyncRowStream;
+use crate::async_tds_stream::AsyncTdsStream;
+use crate::connection_settings::ConnectionSettings;
+use crate::session::{self, Session};
+use crate::tds_message::{TdsMessage, PreLoginResponse, TransactionRequest};
+use crate::tds_token::{LoginAck, ServerMessage};
+use crate::query_result::QueryResult;
+use crate::sql_value::SqlValue;
+use crate::tls::Encryption;
+use crate::transaction::IsolationLevel;
+use crate::error::{Error, Result};
+
+/**
+ * Connector for tokio
+ *
+ * Works like the blocking Connector and shares its message building and token parsing,
+ * only the reads and writes are async. Needs the `tokio` feature.
+ */
+pub struct AsyncConnector {
+    session: Session,
+    stream: Option<AsyncTdsStream>,
+    // an AsyncRowStream was dropped before the end of its response
+    unread_response: bool
+}
+
+impl AsyncConnector {
+    pub fn new(settings: ConnectionSettings) -> AsyncConnector {
+        AsyncConnector {
+            session: Session::new(settings),
+            stream: None,
+            unread_response: false
+        }
+    }
+
+    pub fn settings(&self) -> &ConnectionSettings {
+        self.session.settings()
+    }
+
+    /**
+     * Tries each of the server's addresses in turn, giving each one the connect timeout.
+     *
+     * Looking up a named instance's port is a blocking UDP exchange, so that part runs on
+     * tokio's blocking pool.
+     */
+    pub async fn connect(&mut self) -> Result<bool> {
+        let settings: ConnectionSettings = self.settings().clone();
+        let port: u16 = match settings.instance() {
+            Some(_) => tokio::task::spawn_blocking(move || session::server_port(&settings)).await
+                .map_err(|e| Error::io("Failed to look up the instance port", io::Error::other(e)))??,
+            None => settings.port()
+        };
+
+        let server: &str = self.settings().server();
+        let addresses: Vec<SocketAddr> = net::lookup_host((server, port)).await
+            .map_err(|e| Error::io("Failed to resolve the server address", e))?
+            .collect();
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("No address found for {}", server));
+        for address in addresses {
+            let stream = match timeout(self.settings().connect_timeout(), TcpStream::connect(address)).await {
+                Some(stream) => stream,
+                None => Err(io::ErrorKind::TimedOut.into())
+            };
+
+            match stream {
+                Ok(stream) => {
+                    self.stream = Some(AsyncTdsStream::new(stream));
+                    return Ok(true);
+                },
+                Err(err) => last_error = err
+            }
+        }
+
+        Err(Error::io("Failed to connect", last_error))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    pub fn encryption(&self) -> Encryption {
+        self.session.encryption()
+    }
+
+    /**
+     * PRELOGIN exchange, and the TLS handshake if the client and server settle on encryption.
+     */
+    pub async fn authenticate(&mut self) -> Result<bool> {
+        let login_timeout: Option<Duration> = self.settings().login_timeout();
+
+        match timeout(login_timeout, self.prelogin()).await {
+            Some(result) => result.map(|_| true),
+            None => Err(self.login_timed_out())
+        }
+    }
+
+    async fn prelogin(&mut self) -> Result<()> {
+        if !self.is_connected() {
+            return Err(Error::NotConnected);
+        }
+        if self.settings().mars() {
+            return Err(Error::Config(String::from("MARS is only supported by the blocking Connector")));
+        }
+
+        let mut message: TdsMessage = self.session.prelogin_message();
+        let response: TdsMessage = self.send_message(&mut message).await?;
+
+        if self.session.read_prelogin(response.body())? != Encryption::None {
+            let (client_config, server_name) = self.session.tls_parameters()?;
+
+            let stream: &mut AsyncTdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+            stream.start_tls(client_config, server_name).await?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Sends LOGIN7, doing the PRELOGIN exchange first if it hasn't happened yet. The login
+     * timeout covers all of it, if it runs out the connection is closed.
+     */
+    pub async fn login(&mut self) -> Result<bool> {
+        let login_timeout: Option<Duration> = self.settings().login_timeout();
+
+        match timeout(login_timeout, self.send_login()).await {
+            Some(result) => result.map(|_| true),
+            None => Err(self.login_timed_out())
+        }
+    }
+
+    async fn send_login(&mut self) -> Result<()> {
+        if self.session.prelogin_response().is_none() {
+            self.prelogin().await?;
+        }
+
+        let mut message: TdsMessage = self.session.login_message()?;
+        self.write_message(&mut message).await?;
+
+        // with login only encryption the server answers LOGIN7 in plain text
+        if self.encryption() == Encryption::LoginOnly {
+            self.stream.as_mut().ok_or(Error::NotConnected)?.stop_tls();
+        }
+
+        let response: TdsMessage = self.read_message().await?;
+        self.session.read_login_response(response.body())
+    }
+
+    fn login_timed_out(&mut self) -> Error {
+        self.stream = None;
+        Error::Timeout(format!("Login didn't finish within {:?}", self.settings().login_timeout().unwrap_or_default()))
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.session.is_authenticated()
+    }
+
+    /**
+     * Has the server reset the session before the next request runs.
+     */
+    pub fn reset_session(&mut self, skip_transaction: bool) {
+        self.session.reset_on_next_request(skip_transaction);
+    }
+
+    pub fn prelogin_response(&self) -> Option<&PreLoginResponse> {
+        self.session.prelogin_response()
+    }
+
+    pub fn login_ack(&self) -> Option<&LoginAck> {
+        self.session.login_ack()
+    }
+
+    pub fn current_database(&self) -> Option<&str> {
+        self.session.current_database()
+    }
+
+    pub fn packet_size(&self) -> u32 {
+        self.session.packet_size()
+    }
+
+    pub fn messages(&self) -> &[ServerMessage] {
+        self.session.messages()
+    }
+
+    pub async fn execute(&mut self, sql: &str) -> Result<u64> {
+        Ok(self.query(sql).await?.total_rows_affected())
+    }
+
+    pub async fn query(&mut self, sql: &str) -> Result<QueryResult> {
+        let mut message: TdsMessage = self.session.sql_batch_message(sql)?;
+
+        let response: TdsMessage = self.send_command(&mut message).await?;
+        self.session.read_query_result(response.body())
+    }
+
+    pub async fn execute_params(&mut self, sql: &str, params: &[SqlValue]) -> Result<u64> {
+        Ok(self.query_params(sql, params).await?.total_rows_affected())
+    }
+
+    pub async fn query_params(&mut self, sql: &str, params: &[SqlValue]) -> Result<QueryResult> {
+        let mut message: TdsMessage = self.session.sp_executesql_message(sql, params)?;
+
+        let response: TdsMessage = self.send_command(&mut message).await?;
+        self.session.read_query_result(response.body())
+    }
+
+    /**
+     * Runs a batch and hands its rows over as they arrive, like Connector::query_stream.
+     * The connection is busy until the AsyncRowStream is dropped.
+     */
+    pub async fn query_stream(&mut self, sql: &str) -> Result<AsyncRowStream<'_>> {
+        let mut message: TdsMessage = self.session.sql_batch_message(sql)?;
+        AsyncRowStream::start(self, &mut message).await
+    }
+
+    /**
+     * query_stream with parameters, see query_params.
+     */
+    pub async fn query_params_stream(&mut self, sql: &str, params: &[SqlValue]) -> Result<AsyncRowStream<'_>> {
+        let mut message: TdsMessage = self.session.sp_executesql_message(sql, params)?;
+        AsyncRowStream::start(self, &mut message).await
+    }
+
+    /**
+     * Sends a request whose response an AsyncRowStream reads with read_response_packet.
+     */
+    pub(crate) async fn start_stream(&mut self, message: &mut TdsMessage) -> Result<()> {
+        let command_timeout: Option<Duration> = self.settings().command_timeout();
+
+        match timeout(command_timeout, self.write_message(message)).await {
+            Some(result) => result,
+            None => Err(self.cancel_timed_out(command_timeout.unwrap_or_default()).await)
+        }
+    }
+
+    /**
+     * The next packet of a streamed response, cancelling the command if it doesn't arrive
+     * within the command timeout.
+     */
+    pub(crate) async fn read_response_packet(&mut self) -> Result<TdsMessage> {
+        let command_timeout: Option<Duration> = self.settings().command_timeout();
+        let stream: &mut AsyncTdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+
+        match timeout(command_timeout, TdsMessage::read_async_packet(stream)).await {
+            Some(result) => result,
+            None => Err(self.cancel_timed_out(command_timeout.unwrap_or_default()).await)
+        }
+    }
+
+    /**
+     * For an AsyncRowStream dropped part way: the rest of its response is read before the
+     * next request goes out.
+     */
+    pub(crate) fn discard_response_later(&mut self) {
+        self.unread_response = true;
+    }
+
+    pub(crate) fn close(&mut self) {
+        self.stream = None;
+        self.unread_response = false;
+    }
+
+    pub(crate) fn session_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    /**
+     * Transactions work like Connector's, but there's no guard: dropping can't wait on the
+     * rollback, so commit or roll back explicitly.
+     */
+    pub async fn begin_transaction(&mut self, isolation: IsolationLevel) -> Result<()> {
+        self.transaction_request(TransactionRequest::Begin(isolation)).await
+    }
+
+    pub async fn commit(&mut self) -> Result<()> {
+        self.transaction_request(TransactionRequest::Commit).await
+    }
+
+    pub async fn rollback(&mut self) -> Result<()> {
+        self.transaction_request(TransactionRequest::Rollback(None)).await
+    }
+
+    pub async fn savepoint(&mut self, name: &str) -> Result<()> {
+        self.transaction_request(TransactionRequest::Save(String::from(name))).await
+    }
+
+    pub async fn rollback_to(&mut self, name: &str) -> Result<()> {
+        self.transaction_request(TransactionRequest::Rollback(Some(String::from(name)))).await
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.session.in_transaction()
+    }
+
+    async fn transaction_request(&mut self, request: TransactionRequest) -> Result<()> {
+        let mut message: TdsMessage = self.session.transaction_message(&request)?;
+
+        let response: TdsMessage = self.send_command(&mut message).await?;
+        self.session.read_query_result(response.body()).map(|_| ())
+    }
+
+    /**
+     * send_message with the command timeout, cancelling with ATTENTION when it runs out.
+     */
+    async fn send_command(&mut self, message: &mut TdsMessage) -> Result<TdsMessage> {
+        let command_timeout: Option<Duration> = self.settings().command_timeout();
+
+        if let Some(result) = timeout(command_timeout, self.send_message(message)).await {
+            return result;
+        }
+
+        Err(self.cancel_timed_out(command_timeout.unwrap_or_default()).await)
+    }
+
+    /**
+     * Cancels a command that ran out of time with ATTENTION. The timeout drops the read or
+     * write wherever it had got to, so if that was part way through a packet, or the
+     * cancel fails too, the connection is closed.
+     */
+    async fn cancel_timed_out(&mut self, command_timeout: Duration) -> Error {
+        if !self.stream.as_ref().is_some_and(|stream| stream.at_packet_boundary()) {
+            self.close();
+            return Error::Timeout(format!("The command didn't finish within {:?}, the connection was closed as it stopped part way through a packet", command_timeout));
+        }
+
+        match timeout(Some(session::ATTENTION_TIMEOUT), self.send_attention()).await {
+            Some(Ok(())) => Error::Timeout(format!("The command didn't finish within {:?} and was cancelled", command_timeout)),
+            _ => {
+                self.close();
+                Error::Timeout(format!("The command didn't finish within {:?}, the connection was closed as cancelling it failed", command_timeout))
+            }
+        }
+    }
+
+    /**
+     * Sends ATTENTION and reads until the server acknowledges it.
+     */
+    async fn send_attention(&mut self) -> Result<()> {
+        let mut message: TdsMessage = self.session.attention_message();
+        self.write_message(&mut message).await?;
+
+        while !session::is_attention_ack(self.read_message().await?.body()) {}
+
+        Ok(())
+    }
+
+    async fn send_message(&mut self, message: &mut TdsMessage) -> Result<TdsMessage> {
+        self.write_message(message).await?;
+        self.read_message().await
+    }
+
+    async fn write_message(&mut self, message: &mut TdsMessage) -> Result<()> {
+        let packets: Vec<Vec<u8>> = message.to_packets(self.session.packet_size());
+
+        let stream: &mut AsyncTdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+
+        if self.unread_response {
+            while !TdsMessage::read_async_packet(stream).await?.header().is_end_of_message() {}
+            self.unread_response = false;
+        }
+
+        for packet in packets {
+            stream.write_all(&packet).await.map_err(|e| Error::io("Failed to write to stream", e))?;
+        }
+        stream.flush().await.map_err(|e| Error::io("Failed to write to stream", e))
+    }
+
+    async fn read_message(&mut self) -> Result<TdsMessage> {
+        let stream: &mut AsyncTdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+
+        TdsMessage::from_async_stream(stream).await
+    }
+}
+
+/**
+ * None if `future` didn't finish in time, no timeout waits forever.
+ */
+async fn timeout<F: Future>(duration: Option<Duration>, future: F) -> Option<F::Output> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, future).await.ok(),
+        None => Some(future.await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{fake_server, fake_tls_server, prelogin_response, login_ack_response, query_response, attention_ack_response, transaction_response};
+    use crate::tls::{TlsSettings, EncryptMode};
+
+    fn fake_connector(port: &str, tls: TlsSettings) -> AsyncConnector {
+        let settings: ConnectionSettings = ConnectionSettings::builder()
+            .server("localhost")
+            .port(port.parse().unwrap())
+            .user("sa")
+            .password("pass")
+            .database("sample")
+            .tls(tls)
+            .build()
+            .unwrap();
+        AsyncConnector::new(settings)
+    }
+
+    #[tokio::test]
+    async fn test_asyncconnector_query_returns_rows() {
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), query_response()]);
+        let mut con: AsyncConnector = fake_connector(&port, TlsSettings::new());
+
+        con.connect().await.unwrap();
+        assert!(con.login().await.unwrap());
+        assert_eq!(con.packet_size(), 8000);
+
+        let result = con.query("SELECT id, name FROM people").await.unwrap();
+        assert_eq!(result.rows().len(), 2);
+        assert_eq!(result.rows()[0].get(0), Some(&SqlValue::Int(1)));
+        assert_eq!(result.total_rows_affected(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1].header().message_type(), 0x10);
+        assert_eq!(received[2].header().message_type(), 0x01);
+    }
+
+    #[tokio::test]
+    async fn test_asyncconnector_query_times_out_and_sends_attention() {
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), Vec::new(), attention_ack_response(), query_response()];
+        let (port, server) = fake_server(responses);
+        let settings: ConnectionSettings = fake_connector(&port, TlsSettings::new()).settings().to_builder()
+            .command_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let mut con: AsyncConnector = AsyncConnector::new(settings);
+
+        con.connect().await.unwrap();
+        con.login().await.unwrap();
+
+        assert!(matches!(con.query("WAITFOR DELAY '01:00'").await, Err(Error::Timeout(_))));
+        assert_eq!(con.query("SELECT id, name FROM people").await.unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[3].header().message_type(), 0x06);
+    }
+
+    #[tokio::test]
+    async fn test_asyncconnector_query_timing_out_mid_packet_closes_connection() {
+        // only the start of the response arrives, then the server stalls
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), query_response()[..12].to_vec(), Vec::new()];
+        let (port, server) = fake_server(responses);
+        let settings: ConnectionSettings = fake_connector(&port, TlsSettings::new()).settings().to_builder()
+            .command_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let mut con: AsyncConnector = AsyncConnector::new(settings);
+
+        con.connect().await.unwrap();
+        con.login().await.unwrap();
+
+        assert!(matches!(con.query("SELECT id, name FROM people").await, Err(Error::Timeout(_))));
+        assert!(!con.is_connected());
+
+        // no ATTENTION was sent, the server just sees the connection close
+        assert!(server.join().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_asyncconnector_transaction_tracks_descriptor() {
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), transaction_response(0x08, 3), transaction_response(0x0a, 3)];
+        let (port, server) = fake_server(responses);
+        let mut con: AsyncConnector = fake_connector(&port, TlsSettings::new());
+
+        con.connect().await.unwrap();
+        con.login().await.unwrap();
+
+        con.begin_transaction(IsolationLevel::ReadCommitted).await.unwrap();
+        assert!(con.in_transaction());
+        con.rollback().await.unwrap();
+        assert!(!con.in_transaction());
+
+        let received = server.join().unwrap();
+        assert_eq!(&received[3].body()[10..18], &3u64.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_asyncconnector_query_fails_when_not_logged_in() {
+        let mut con: AsyncConnector = fake_connector("1433", TlsSettings::new());
+
+        assert!(matches!(con.query("SELECT 1").await, Err(Error::NotLoggedIn)));
+        assert!(matches!(con.authenticate().await, Err(Error::NotConnected)));
+    }
+
+    #[tokio::test]
+    async fn test_asyncconnector_login_encrypts_everything() {
+        let (port, _, server) = fake_tls_server(0x01, true, vec![login_ack_response(), query_response()]);
+        let mut tls: TlsSettings = TlsSettings::new();
+        tls.encrypt = EncryptMode::On;
+        tls.trust_server_certificate = true;
+        let mut con: AsyncConnector = fake_connector(&port, tls);
+
+        con.connect().await.unwrap();
+        con.login().await.unwrap();
+
+        assert_eq!(con.encryption(), Encryption::Full);
+        assert!(con.stream.as_ref().unwrap().is_encrypted());
+        assert_eq!(con.query("SELECT id, name FROM people").await.unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[2].header().message_type(), 0x01);
+    }
+}
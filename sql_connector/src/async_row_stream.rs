@@ -0,0 +1,342 @@
+use std::io::{self, Read};
+use crate::async_connector::AsyncConnector;
+use crate::tds_message::TdsMessage;
+use crate::tds_token::{TokenStream, TdsToken, Column, Row, DoneStatus};
+use crate::error::{Error, Result};
+
+/**
+ * RowStream for AsyncConnector
+ *
+ * The response is read a packet at a time as rows are asked for and the tokens are parsed
+ * out of the packets that have arrived, so only the packets holding the current row are in
+ * memory. A token that runs on past them is parsed again once more packets are in. Unlike
+ * RowStream's, values are always read whole.
+ *
+ * Dropping the stream can't wait for the rest of the response, so the AsyncConnector reads
+ * and throws it away before its next request instead. Dropping a next_row future part way
+ * through a packet loses track of where the packets are, and the connection is closed.
+ */
+pub struct AsyncRowStream<'a> {
+    connector: &'a mut AsyncConnector,
+    tokens: TokenStream<PacketBuffer>,
+    columns: Vec<Column>,
+    next_columns: Option<Vec<Column>>,
+    in_rows: bool,
+    finished: bool,
+    reading: bool,
+    rows_affected: Vec<u64>,
+    return_status: Option<i32>
+}
+impl<'a> AsyncRowStream<'a> {
+    /**
+     * Sends `message` and reads up to the first result set's columns.
+     */
+    pub(crate) async fn start(connector: &'a mut AsyncConnector, message: &mut TdsMessage) -> Result<AsyncRowStream<'a>> {
+        connector.start_stream(message).await?;
+
+        let mut rows: AsyncRowStream = AsyncRowStream {
+            connector,
+            tokens: TokenStream::new(PacketBuffer::new()),
+            columns: Vec::new(),
+            next_columns: None,
+            in_rows: false,
+            finished: false,
+            reading: false,
+            rows_affected: Vec::new(),
+            return_status: None
+        };
+
+        rows.next_result_set().await?;
+        Ok(rows)
+    }
+
+    /**
+     * Columns of the current result set, empty once there are no more.
+     */
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    /**
+     * The next row of the current result set, None when it has run out.
+     */
+    pub async fn next_row(&mut self) -> Result<Option<Row>> {
+        if !self.in_rows {
+            return Ok(None);
+        }
+
+        match self.advance().await? {
+            Some(Event::Row(row)) => return Ok(Some(row)),
+            Some(Event::Columns(columns)) => self.next_columns = Some(columns),
+            Some(Event::Done) | None => ()
+        }
+
+        self.in_rows = false;
+        Ok(None)
+    }
+
+    /**
+     * Moves to the next result set, skipping whatever is left of this one. False when
+     * there isn't another.
+     */
+    pub async fn next_result_set(&mut self) -> Result<bool> {
+        while self.next_row().await?.is_some() {}
+
+        loop {
+            if let Some(columns) = self.next_columns.take() {
+                self.columns = columns;
+                self.in_rows = true;
+                return Ok(true);
+            }
+
+            match self.advance().await? {
+                Some(Event::Columns(columns)) => self.next_columns = Some(columns),
+                Some(Event::Row(_)) => return Err(Error::Protocol(String::from("Row received without a result set"))),
+                Some(Event::Done) => (),
+                None => {
+                    self.columns.clear();
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    /**
+     * Row counts from the DONE tokens read so far. Only complete once the stream is at the end.
+     */
+    pub fn rows_affected(&self) -> &[u64] {
+        &self.rows_affected
+    }
+
+    pub fn return_status(&self) -> Option<i32> {
+        self.return_status
+    }
+
+    /**
+     * Reads tokens until one a caller cares about, like RowStream::advance. None once the
+     * whole response has been read.
+     */
+    async fn advance(&mut self) -> Result<Option<Event>> {
+        loop {
+            let Some(token) = self.next_token().await? else {
+                self.finished = true;
+                return Ok(None);
+            };
+
+            match token {
+                TdsToken::ColMetadata(columns) if !columns.is_empty() => return Ok(Some(Event::Columns(columns))),
+                TdsToken::Row(row) => return Ok(Some(Event::Row(row))),
+                TdsToken::Done(done) | TdsToken::DoneProc(done) | TdsToken::DoneInProc(done) => {
+                    if let Some(count) = done.rows_affected() {
+                        self.rows_affected.push(count);
+                    }
+                    if done.has_status(DoneStatus::Attention) {
+                        return Err(Error::Cancelled);
+                    }
+                    return Ok(Some(Event::Done));
+                },
+                TdsToken::ReturnStatus(status) => self.return_status = Some(status),
+                TdsToken::EnvChange(change) => self.connector.session_mut().apply_env_change(change),
+                TdsToken::Info(info) => self.connector.session_mut().add_message(info),
+                TdsToken::Error(message) => return Err(Error::Server(message)),
+                _ => ()
+            }
+        }
+    }
+
+    /**
+     * Parses the next token out of the packets read so far, reading more whenever it runs
+     * past them. The packets wanted at least double each time so a big row isn't parsed over
+     * and over.
+     */
+    async fn next_token(&mut self) -> Result<Option<TdsToken>> {
+        if self.reading {
+            return Err(self.abort(Error::Protocol(String::from("A read was dropped part way through a packet"))));
+        }
+
+        loop {
+            if self.finished {
+                return Ok(None);
+            }
+
+            self.tokens.reader_mut().discard_read();
+            match self.tokens.next_token() {
+                Err(error) if needs_packet(&error) => {
+                    let buffer: &mut PacketBuffer = self.tokens.reader_mut();
+                    buffer.rewind();
+                    let wanted: usize = buffer.data.len().max(1) * 2;
+
+                    while !self.tokens.reader_mut().last_packet && self.tokens.reader_mut().data.len() < wanted {
+                        self.read_packet().await?;
+                    }
+                },
+                Err(error) => return Err(self.abort(error)),
+                Ok(token) => return Ok(token)
+            }
+        }
+    }
+
+    async fn read_packet(&mut self) -> Result<()> {
+        self.reading = true;
+        let packet: Result<TdsMessage> = self.connector.read_response_packet().await;
+        self.reading = false;
+
+        match packet {
+            Ok(packet) => {
+                self.tokens.reader_mut().add_packet(&packet);
+                Ok(())
+            },
+            // the command was cancelled, and the rest of the response read with it
+            Err(error @ Error::Timeout(_)) => {
+                self.finished = true;
+                Err(error)
+            },
+            Err(error) => Err(self.abort(error))
+        }
+    }
+
+    /**
+     * Where the response can't be followed any more the connection is closed.
+     */
+    fn abort(&mut self, error: Error) -> Error {
+        self.finished = true;
+        self.connector.close();
+        error
+    }
+}
+
+impl Drop for AsyncRowStream<'_> {
+    fn drop(&mut self) {
+        if self.reading {
+            self.connector.close();
+        } else if !self.finished && !self.tokens.reader_mut().last_packet {
+            self.connector.discard_response_later();
+        }
+    }
+}
+
+enum Event {
+    Columns(Vec<Column>),
+    Row(Row),
+    Done
+}
+
+/**
+ * The packet bodies read so far from the token being parsed on. Running out before the
+ * last packet is a WouldBlock error, so the token can be parsed again with more.
+ */
+struct PacketBuffer {
+    data: Vec<u8>,
+    position: usize,
+    last_packet: bool
+}
+impl PacketBuffer {
+    fn new() -> PacketBuffer {
+        PacketBuffer {
+            data: Vec::new(),
+            position: 0,
+            last_packet: false
+        }
+    }
+
+    fn add_packet(&mut self, packet: &TdsMessage) {
+        self.data.extend_from_slice(packet.body());
+        self.last_packet = packet.header().is_end_of_message();
+    }
+
+    /**
+     * Drops the tokens already parsed, leaving the start of the next one at the front.
+     */
+    fn discard_read(&mut self) {
+        self.data.drain(..self.position);
+        self.position = 0;
+    }
+
+    fn rewind(&mut self) {
+        self.position = 0;
+    }
+}
+impl Read for PacketBuffer {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let available: &[u8] = &self.data[self.position..];
+        if available.is_empty() && !buffer.is_empty() && !self.last_packet {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "The token carries on in the next packet"));
+        }
+
+        let length: usize = buffer.len().min(available.len());
+        buffer[..length].copy_from_slice(&available[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+fn needs_packet(error: &Error) -> bool {
+    matches!(error, Error::Io { source, .. } if source.kind() == io::ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_settings::ConnectionSettings;
+    use crate::sql_value::SqlValue;
+    use crate::test_server::{fake_server, split_response, prelogin_response, login_ack_response, query_response};
+
+    async fn logged_in_connector(port: &str) -> AsyncConnector {
+        let settings: ConnectionSettings = ConnectionSettings::builder()
+            .server("127.0.0.1")
+            .port(port.parse().unwrap())
+            .user("sa")
+            .password("pass")
+            .build()
+            .unwrap();
+        let mut con: AsyncConnector = AsyncConnector::new(settings);
+
+        con.connect().await.unwrap();
+        con.login().await.unwrap();
+        con
+    }
+
+    #[tokio::test]
+    async fn test_asyncrowstream_reads_result_sets_across_packets() {
+        let body: Vec<u8> = [&query_response()[8..], &query_response()[8..]].concat();
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), split_response(&body, 3), query_response()]);
+        let mut con: AsyncConnector = logged_in_connector(&port).await;
+
+        {
+            let mut rows: AsyncRowStream = con.query_stream("SELECT id, name FROM people; SELECT id, name FROM people").await.unwrap();
+
+            assert_eq!(rows.column_index("name"), Some(1));
+            assert_eq!(rows.next_row().await.unwrap().unwrap().get(1), Some(&SqlValue::String(String::from("ab"))));
+            assert_eq!(rows.next_row().await.unwrap().unwrap().get(0), Some(&SqlValue::Int(2)));
+            assert!(rows.next_row().await.unwrap().is_none());
+
+            assert!(rows.next_result_set().await.unwrap());
+            assert!(rows.next_row().await.unwrap().is_some());
+            assert!(!rows.next_result_set().await.unwrap());
+            assert!(rows.columns().is_empty());
+            assert_eq!(rows.rows_affected(), &[2, 2]);
+        }
+
+        assert_eq!(con.query("SELECT id, name FROM people").await.unwrap().rows().len(), 2);
+        let _ = server.join();
+    }
+
+    #[tokio::test]
+    async fn test_asyncrowstream_dropped_early_is_read_before_next_request() {
+        let body: Vec<u8> = [&query_response()[8..], &query_response()[8..]].concat();
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), split_response(&body, 16), query_response()]);
+        let mut con: AsyncConnector = logged_in_connector(&port).await;
+
+        let mut rows: AsyncRowStream = con.query_stream("SELECT id, name FROM people; SELECT id, name FROM people").await.unwrap();
+        assert!(rows.next_row().await.unwrap().is_some());
+        drop(rows);
+
+        assert!(con.is_connected());
+        assert_eq!(con.query("SELECT id, name FROM people").await.unwrap().rows().len(), 2);
+        let _ = server.join();
+    }
+}
@@ -0,0 +1,240 @@
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use rustls::{ClientConfig, ClientConnection};
+use rustls_pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use crate::tds_message::{TdsMessage, ClientMessageType, DEFAULT_PACKET_SIZE};
+use crate::tds_stream::PacketProgress;
+use crate::error::{Error, Result};
+
+/**
+ * TdsStream for tokio
+ *
+ * Same idea as the blocking one: plain TCP, or rustls on top of it with the handshake
+ * wrapped in PRELOGIN packets. rustls is driven by hand rather than through an async TLS
+ * crate because of that wrapping.
+ *
+ * A read or write future dropped part way, e.g. by a timeout, can leave the stream in the
+ * middle of a packet, so the packets going each way are followed.
+ */
+pub struct AsyncTdsStream {
+    socket: TcpStream,
+    tls: Option<ClientConnection>,
+    incoming: PacketProgress,
+    outgoing: PacketProgress
+}
+impl AsyncTdsStream {
+    pub fn new(socket: TcpStream) -> AsyncTdsStream {
+        AsyncTdsStream {
+            socket,
+            tls: None,
+            incoming: PacketProgress::new(),
+            outgoing: PacketProgress::new()
+        }
+    }
+
+    /**
+     * False when only part of a packet has been read, or written.
+     */
+    pub fn at_packet_boundary(&self) -> bool {
+        self.incoming.at_boundary() && self.outgoing.at_boundary()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    pub fn into_socket(self) -> TcpStream {
+        self.socket
+    }
+
+    pub async fn start_tls(&mut self, config: Arc<ClientConfig>, server_name: ServerName<'static>) -> Result<()> {
+        let mut connection = ClientConnection::new(config, server_name)
+            .map_err(|e| Error::Tls(format!("Failed to start TLS: {}", e)))?;
+
+        while connection.is_handshaking() {
+            if connection.wants_write() {
+                self.write_handshake(&mut connection).await?;
+            } else {
+                let packet = TdsMessage::from_async_stream(&mut self.socket).await?;
+                let mut records: &[u8] = packet.body();
+
+                while !records.is_empty() {
+                    connection.read_tls(&mut records).map_err(|e| Error::Tls(format!("TLS handshake failed: {}", e)))?;
+                    connection.process_new_packets().map_err(|e| Error::Tls(format!("TLS handshake failed: {}", e)))?;
+                }
+            }
+        }
+
+        // our last flight (e.g. Finished) is still part of the handshake so still gets wrapped
+        if connection.wants_write() {
+            self.write_handshake(&mut connection).await?;
+        }
+
+        self.tls = Some(connection);
+        Ok(())
+    }
+
+    /**
+     * For login only encryption: TLS is dropped as soon as LOGIN7 has been sent.
+     */
+    pub fn stop_tls(&mut self) {
+        self.tls = None;
+    }
+
+    async fn write_handshake(&mut self, connection: &mut ClientConnection) -> Result<()> {
+        let mut records: Vec<u8> = Vec::new();
+        while connection.wants_write() {
+            connection.write_tls(&mut records).map_err(|e| Error::Tls(format!("TLS handshake failed: {}", e)))?;
+        }
+
+        let mut message = TdsMessage::new();
+        message.set_body(ClientMessageType::PreLogin, records);
+
+        for packet in message.to_packets(DEFAULT_PACKET_SIZE) {
+            self.socket.write_all(&packet).await.map_err(|e| Error::io("Failed to write to stream", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AsyncRead for AsyncTdsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled: usize = buffer.filled().len();
+
+        ready!(this.poll_read_plaintext(cx, buffer))?;
+        this.incoming.consume(&buffer.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncTdsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let written: usize = ready!(this.poll_write_plaintext(cx, buffer))?;
+        this.outgoing.consume(&buffer[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(connection) = &mut this.tls {
+            ready!(poll_write_records(connection, &mut this.socket, cx))?;
+        }
+
+        Pin::new(&mut this.socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(connection) = &mut this.tls {
+            connection.send_close_notify();
+            ready!(poll_write_records(connection, &mut this.socket, cx))?;
+        }
+
+        Pin::new(&mut this.socket).poll_shutdown(cx)
+    }
+}
+
+impl AsyncTdsStream {
+    fn poll_read_plaintext(&mut self, cx: &mut Context<'_>, buffer: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let connection: &mut ClientConnection = match &mut self.tls {
+            Some(connection) => connection,
+            None => return Pin::new(&mut self.socket).poll_read(cx, buffer)
+        };
+
+        loop {
+            match connection.reader().read(buffer.initialize_unfilled()) {
+                Ok(read) => {
+                    buffer.advance(read);
+                    return Poll::Ready(Ok(()));
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Poll::Ready(Err(e))
+            }
+
+            // nothing decrypted yet, wait for more records
+            let mut records: [u8; 4096] = [0; 4096];
+            let mut records_buffer: ReadBuf = ReadBuf::new(&mut records);
+            ready!(Pin::new(&mut self.socket).poll_read(cx, &mut records_buffer))?;
+
+            let mut records: &[u8] = records_buffer.filled();
+            if records.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+
+            while !records.is_empty() {
+                connection.read_tls(&mut records)?;
+                connection.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+    }
+
+    /**
+     * Whatever rustls takes is sent, the records it can't write yet go out before the next write.
+     */
+    fn poll_write_plaintext(&mut self, cx: &mut Context<'_>, buffer: &[u8]) -> Poll<io::Result<usize>> {
+        let connection: &mut ClientConnection = match &mut self.tls {
+            Some(connection) => connection,
+            None => return Pin::new(&mut self.socket).poll_write(cx, buffer)
+        };
+
+        // send what's already encrypted before taking any more
+        ready!(poll_write_records(connection, &mut self.socket, cx))?;
+
+        let written: usize = connection.writer().write(buffer)?;
+        if let Poll::Ready(Err(e)) = poll_write_records(connection, &mut self.socket, cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(written))
+    }
+}
+
+/**
+ * Writes out whatever rustls has encrypted, Pending if the socket can't take it all yet.
+ */
+fn poll_write_records(connection: &mut ClientConnection, socket: &mut TcpStream, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    while connection.wants_write() {
+        let mut writer: SocketWriter = SocketWriter { socket, cx };
+
+        match connection.write_tls(&mut writer) {
+            Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e))
+        }
+    }
+
+    Poll::Ready(Ok(()))
+}
+
+/**
+ * rustls writes records to a std::io::Write, this turns Pending into WouldBlock.
+ */
+struct SocketWriter<'a, 'b> {
+    socket: &'a mut TcpStream,
+    cx: &'a mut Context<'b>
+}
+impl Write for SocketWriter<'_, '_> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.socket).poll_write(self.cx, buffer) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use crate::error::{Error, Result};
+
+pub const BROWSER_PORT: u16 = 1434;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// CLNT_UCAST_INST asks about one instance, SVR_RESP is the answer
+const CLNT_UCAST_INST: u8 = 0x04;
+const SVR_RESP: u8 = 0x05;
+
+/**
+ * SQL Server Browser
+ *
+ * Named instances usually listen on a dynamic port. The browser service on UDP 1434 knows
+ * which, and answers a CLNT_UCAST_INST request with a list of key;value pairs like
+ * `ServerName;DB;InstanceName;SQLEXPRESS;IsClustered;No;Version;16.0.1000.6;tcp;52314;;`.
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/mc-sqlr/1ea6e25f-bff9-4364-ba21-5dc449a601b7
+ */
+pub fn instance_port(server: &str, instance: &str, timeout: Duration) -> Result<u16> {
+    query_browser(server, BROWSER_PORT, instance, timeout)
+}
+
+fn query_browser(server: &str, port: u16, instance: &str, timeout: Duration) -> Result<u16> {
+    // resolved as a pair so IPv6 literals like ::1 don't need brackets
+    let address = (server, port).to_socket_addrs()
+        .map_err(|e| Error::io("Failed to resolve SQL Server Browser address", e))?
+        .next()
+        .ok_or(Error::Config(format!("No address found for {}", server)))?;
+
+    let local: &str = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket: UdpSocket = UdpSocket::bind(local).map_err(|e| Error::io("Failed to open UDP socket", e))?;
+    socket.set_read_timeout(Some(timeout)).map_err(|e| Error::io("Failed to set UDP timeout", e))?;
+
+    let mut request: Vec<u8> = vec![CLNT_UCAST_INST];
+    request.extend_from_slice(instance.as_bytes());
+    request.push(0x00);
+
+    socket.send_to(&request, address).map_err(|e| Error::io("Failed to send to SQL Server Browser", e))?;
+
+    let mut response: [u8; 1024] = [0; 1024];
+    let (length, _) = socket.recv_from(&mut response)
+        .map_err(|e| Error::io(&format!("No answer from SQL Server Browser about instance '{}'", instance), e))?;
+
+    parse_response(&response[..length], instance)
+}
+
+fn parse_response(response: &[u8], instance: &str) -> Result<u16> {
+    if response.len() < 3 || response[0] != SVR_RESP {
+        return Err(Error::Protocol(String::from("Invalid SQL Server Browser response")));
+    }
+
+    let size: usize = u16::from_le_bytes([response[1], response[2]]) as usize;
+    let data = response.get(3..3 + size)
+        .ok_or(Error::Protocol(String::from("Truncated SQL Server Browser response")))?;
+    let data: String = String::from_utf8_lossy(data).into_owned();
+
+    let fields: Vec<&str> = data.split(';').collect();
+    fields.chunks(2)
+        .find(|pair| pair[0].eq_ignore_ascii_case("tcp"))
+        .and_then(|pair| pair.get(1))
+        .and_then(|port| port.parse::<u16>().ok())
+        .ok_or(Error::Config(format!("Instance '{}' is not listening on TCP", instance)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn browser_response(data: &str) -> Vec<u8> {
+        let mut response: Vec<u8> = vec![SVR_RESP];
+        response.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        response.extend_from_slice(data.as_bytes());
+        response
+    }
+
+    #[test]
+    fn test_browser_parse_response_finds_tcp_port() {
+        let response = browser_response("ServerName;DB;InstanceName;SQLEXPRESS;IsClustered;No;Version;16.0.1000.6;tcp;52314;;");
+
+        assert_eq!(parse_response(&response, "SQLEXPRESS").unwrap(), 52314);
+    }
+
+    #[test]
+    fn test_browser_parse_response_rejects_missing_tcp() {
+        let response = browser_response("ServerName;DB;InstanceName;SQLEXPRESS;IsClustered;No;np;\\\\DB\\pipe\\sql\\query;;");
+
+        assert!(matches!(parse_response(&response, "SQLEXPRESS"), Err(Error::Config(_))));
+        assert!(matches!(parse_response(&[0x01, 0x00], "SQLEXPRESS"), Err(Error::Protocol(_))));
+    }
+
+    fn answer_browser_request(server: UdpSocket) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut request: [u8; 64] = [0; 64];
+            let (length, client) = server.recv_from(&mut request).unwrap();
+            server.send_to(&browser_response("ServerName;DB;InstanceName;SQLEXPRESS;tcp;49172;;"), client).unwrap();
+            request[..length].to_vec()
+        })
+    }
+
+    #[test]
+    fn test_browser_query_browser_asks_for_instance() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let handle = answer_browser_request(server);
+
+        let port = query_browser("127.0.0.1", port, "SQLEXPRESS", Duration::from_secs(5)).unwrap();
+
+        assert_eq!(port, 49172);
+        assert_eq!(handle.join().unwrap(), b"\x04SQLEXPRESS\x00".to_vec());
+    }
+
+    #[test]
+    fn test_browser_query_browser_accepts_ipv6_literal() {
+        // nothing to test against on a host without IPv6
+        let Ok(server) = UdpSocket::bind("[::1]:0") else {
+            return;
+        };
+        let port = server.local_addr().unwrap().port();
+        let handle = answer_browser_request(server);
+
+        assert_eq!(query_browser("::1", port, "SQLEXPRESS", Duration::from_secs(5)).unwrap(), 49172);
+        let _ = handle.join();
+    }
+}
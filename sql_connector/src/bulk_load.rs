@@ -0,0 +1,344 @@
+use crate::sql_value::SqlValue;
+use crate::tds_message::b_varchar;
+use crate::tds_token::Column;
+use crate::tds_type::{DataType, TypeInfo, Collation};
+use crate::error::{Error, Result};
+
+const COLMETADATA_TOKEN: u8 = 0x81;
+const ROW_TOKEN: u8 = 0xd1;
+const DONE_TOKEN: u8 = 0xfd;
+
+/**
+ * How Connector::bulk_insert loads the rows. Apart from batch_size these are the hints in
+ * INSERT BULK's WITH clause, all off by default like they are for SQL Server's own bulk
+ * loads, so constraints aren't checked, triggers don't fire and NULLs get the column default.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkLoadOptions {
+    /** TABLOCK: one table lock for the load rather than a lock per row. */
+    pub table_lock: bool,
+    pub check_constraints: bool,
+    pub fire_triggers: bool,
+    /** KEEP_NULLS: NULLs are loaded as NULL even where the column has a default. */
+    pub keep_nulls: bool,
+    /** Rows per INSERT BULK, each batch committing on its own. None loads everything in one. */
+    pub batch_size: Option<usize>
+}
+impl Default for BulkLoadOptions {
+    fn default() -> BulkLoadOptions {
+        BulkLoadOptions::new()
+    }
+}
+impl BulkLoadOptions {
+    pub fn new() -> BulkLoadOptions {
+        BulkLoadOptions {
+            table_lock: false,
+            check_constraints: false,
+            fire_triggers: false,
+            keep_nulls: false,
+            batch_size: None
+        }
+    }
+
+    fn hints(&self) -> Vec<&'static str> {
+        let hints: [(bool, &'static str); 4] = [
+            (self.table_lock, "TABLOCK"),
+            (self.check_constraints, "CHECK_CONSTRAINTS"),
+            (self.fire_triggers, "FIRE_TRIGGERS"),
+            (self.keep_nulls, "KEEP_NULLS")
+        ];
+
+        hints.into_iter().filter(|(enabled, _)| *enabled).map(|(_, hint)| hint).collect()
+    }
+}
+
+/**
+ * Bulk load (BCP)
+ *
+ * The client announces the load with an INSERT BULK statement naming the columns and their
+ * types, then sends the rows as a BULK_LOAD message: a COLMETADATA token describing the
+ * columns, a ROW token per row and a DONE token to end it. The server answers with a DONE
+ * holding the number of rows copied.
+ *
+ * The columns are described the way the server described them to us, so every value is
+ * sent in its column's own type.
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/88176081-df75-4b81-8d80-4ff8d8b4f0e2
+ */
+pub(crate) struct BulkLoad {
+    // quoted, see quote_table_name
+    table: String,
+    declarations: Vec<String>,
+    columns: Vec<Column>
+}
+impl BulkLoad {
+    /**
+     * `columns` is the COLMETADATA of a SELECT of the destination columns.
+     */
+    pub fn new(table: &str, columns: Vec<Column>) -> Result<BulkLoad> {
+        let table: String = quote_table_name(table)?;
+        if columns.is_empty() {
+            return Err(Error::Config(String::from("A bulk load needs at least one column")));
+        }
+
+        let declarations: Vec<String> = columns.iter().map(|column| column.type_info.declaration()).collect();
+        let mut sent: Vec<Column> = Vec::with_capacity(columns.len());
+
+        for column in columns {
+            let mut column: Column = column;
+
+            match column.type_info.data_type {
+                DataType::Udt | DataType::SqlVariant => {
+                    return Err(Error::Config(format!(
+                        "Column {} is {}, which can't be bulk loaded",
+                        column.name,
+                        column.type_info.declaration()
+                    )));
+                },
+                // xml goes as nvarchar(max) and the server converts it
+                DataType::Xml => {
+                    let mut type_info: TypeInfo = TypeInfo::new(DataType::NVarChar);
+                    type_info.length = 0xffff;
+                    type_info.collation = Some(Collation::new(0, 0));
+                    column.type_info = type_info;
+                },
+                _ => ()
+            }
+
+            sent.push(column);
+        }
+
+        Ok(BulkLoad {
+            table,
+            declarations,
+            columns: sent
+        })
+    }
+
+    /**
+     * INSERT BULK [schema].[table] ([a] int, [b] nvarchar(50)) WITH (TABLOCK, ...)
+     */
+    pub fn statement(&self, options: &BulkLoadOptions) -> String {
+        let columns: Vec<String> = self.columns.iter()
+            .zip(&self.declarations)
+            .map(|(column, declaration)| format!("{} {}", quote_name(&column.name), declaration))
+            .collect();
+
+        let mut statement: String = format!("INSERT BULK {} ({})", self.table, columns.join(", "));
+
+        let hints: Vec<&str> = options.hints();
+        if !hints.is_empty() {
+            statement.push_str(&format!(" WITH ({})", hints.join(", ")));
+        }
+
+        statement
+    }
+
+    /**
+     * COLMETADATA that starts the BULK_LOAD message.
+     */
+    pub fn column_metadata(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![COLMETADATA_TOKEN];
+        bytes.extend_from_slice(&(self.columns.len() as u16).to_le_bytes());
+
+        for column in &self.columns {
+            bytes.extend_from_slice(&column.user_type.to_le_bytes());
+            bytes.extend_from_slice(&column.flags.to_le_bytes());
+            bytes.extend_from_slice(&column.type_info.to_bytes());
+            bytes.extend_from_slice(&b_varchar(&column.name));
+        }
+
+        bytes
+    }
+
+    /**
+     * One ROW token, the values in column order.
+     */
+    pub fn row(&self, values: &[SqlValue]) -> Result<Vec<u8>> {
+        if values.len() != self.columns.len() {
+            return Err(Error::Config(format!("Row has {} values but {} columns are being loaded", values.len(), self.columns.len())));
+        }
+
+        let mut bytes: Vec<u8> = vec![ROW_TOKEN];
+        for (column, value) in self.columns.iter().zip(values) {
+            let data: Vec<u8> = value.encode_as(&column.type_info)
+                .map_err(|e| Error::Config(format!("Column {}: {}", column.name, e)))?;
+            bytes.extend_from_slice(&data);
+        }
+
+        Ok(bytes)
+    }
+
+    /**
+     * DONE that ends the rows. The status and count are left at 0, the server counts the rows itself.
+     */
+    pub fn done() -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![DONE_TOKEN];
+        bytes.extend_from_slice(&[0u8; 2 + 2 + 8]);
+        bytes
+    }
+}
+
+/**
+ * [name], with any ] doubled.
+ */
+pub(crate) fn quote_name(name: &str) -> String {
+    format!("[{}]", name.replace(']', "]]"))
+}
+
+/**
+ * A table name of up to three parts (database.schema.table) with each part quoted, so it
+ * can go into a statement as it is. Parts already in brackets are unquoted first, so
+ * `[dbo].[odd.name]` is two parts and quotes to itself.
+ */
+pub(crate) fn quote_table_name(table: &str) -> Result<String> {
+    let invalid = || Error::Config(format!("'{}' isn't a valid table name", table));
+    let mut parts: Vec<String> = Vec::new();
+    let mut chars = table.chars().peekable();
+
+    loop {
+        let mut part: String = String::new();
+
+        if chars.peek() == Some(&'[') {
+            chars.next();
+            loop {
+                match chars.next().ok_or_else(invalid)? {
+                    ']' if chars.peek() == Some(&']') => {
+                        chars.next();
+                        part.push(']');
+                    },
+                    ']' => break,
+                    c => part.push(c)
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != '.') {
+                part.push(c);
+            }
+        }
+
+        if part.is_empty() {
+            return Err(invalid());
+        }
+        parts.push(quote_name(&part));
+
+        match chars.next() {
+            None => break,
+            Some('.') => (),
+            Some(_) => return Err(invalid())
+        }
+    }
+
+    if parts.len() > 3 {
+        return Err(invalid());
+    }
+
+    Ok(parts.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_value::Decimal;
+
+    fn column(name: &str, type_info: TypeInfo) -> Column {
+        Column {
+            name: String::from(name),
+            user_type: 0,
+            flags: 0x0009,
+            type_info
+        }
+    }
+
+    fn int_column(name: &str) -> Column {
+        let mut type_info: TypeInfo = TypeInfo::new(DataType::IntN);
+        type_info.length = 4;
+        column(name, type_info)
+    }
+
+    fn nvarchar_column(name: &str, length: u32) -> Column {
+        let mut type_info: TypeInfo = TypeInfo::new(DataType::NVarChar);
+        type_info.length = length;
+        type_info.collation = Some(Collation::new(0x00d00409, 0x34));
+        column(name, type_info)
+    }
+
+    #[test]
+    fn test_bulkload_statement_declares_columns_and_hints() {
+        let mut decimal: TypeInfo = TypeInfo::new(DataType::DecimalN);
+        decimal.length = 9;
+        decimal.precision = 18;
+        decimal.scale = 2;
+        let columns: Vec<Column> = vec![int_column("id"), nvarchar_column("odd]name", 100), column("price", decimal), column("doc", TypeInfo::new(DataType::Xml))];
+        let bulk_load: BulkLoad = BulkLoad::new("dbo.items", columns).unwrap();
+
+        let mut options: BulkLoadOptions = BulkLoadOptions::new();
+        assert_eq!(bulk_load.statement(&options), "INSERT BULK [dbo].[items] ([id] int, [odd]]name] nvarchar(50), [price] decimal(18, 2), [doc] xml)");
+
+        options.table_lock = true;
+        options.keep_nulls = true;
+        assert!(bulk_load.statement(&options).ends_with(" WITH (TABLOCK, KEEP_NULLS)"));
+    }
+
+    #[test]
+    fn test_quote_table_name_quotes_each_part() {
+        assert_eq!(quote_table_name("people").unwrap(), "[people]");
+        assert_eq!(quote_table_name("shop.dbo.items").unwrap(), "[shop].[dbo].[items]");
+        assert_eq!(quote_table_name("[dbo].[odd.na]]me]").unwrap(), "[dbo].[odd.na]]me]");
+        assert_eq!(quote_table_name("people; DROP TABLE people").unwrap(), "[people; DROP TABLE people]");
+
+        for bad in ["", "dbo.", ".items", "[dbo", "[dbo]x", "a.b.c.d"] {
+            assert!(matches!(quote_table_name(bad), Err(Error::Config(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_bulkload_column_metadata_round_trips_type_info() {
+        let bulk_load: BulkLoad = BulkLoad::new("t", vec![int_column("id"), nvarchar_column("name", 100)]).unwrap();
+
+        let metadata: Vec<u8> = bulk_load.column_metadata();
+
+        let mut expected: Vec<u8> = vec![0x81, 0x02, 0x00, 0, 0, 0, 0, 0x09, 0x00, 0x26, 0x04, 0x02];
+        expected.extend_from_slice(&crate::tds_message::ucs2_bytes("id"));
+        expected.extend_from_slice(&[0, 0, 0, 0, 0x09, 0x00, 0xe7, 0x64, 0x00, 0x09, 0x04, 0xd0, 0x00, 0x34, 0x04]);
+        expected.extend_from_slice(&crate::tds_message::ucs2_bytes("name"));
+        assert_eq!(metadata, expected);
+    }
+
+    #[test]
+    fn test_bulkload_row_converts_values_to_column_types() {
+        let mut small: TypeInfo = TypeInfo::new(DataType::IntN);
+        small.length = 2;
+        let mut decimal: TypeInfo = TypeInfo::new(DataType::DecimalN);
+        decimal.length = 5;
+        decimal.precision = 5;
+        decimal.scale = 2;
+        let columns: Vec<Column> = vec![column("a", small), column("b", decimal), nvarchar_column("c", 100)];
+        let bulk_load: BulkLoad = BulkLoad::new("t", columns).unwrap();
+
+        let row: Vec<u8> = bulk_load.row(&[SqlValue::Int(7), SqlValue::Decimal(Decimal::new(15, 1)), SqlValue::Null]).unwrap();
+
+        // 1.5 at scale 2 is 150
+        assert_eq!(row, vec![0xd1, 0x02, 0x07, 0x00, 0x05, 0x01, 0x96, 0x00, 0x00, 0x00, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_bulkload_row_rejects_bad_values() {
+        let mut small: TypeInfo = TypeInfo::new(DataType::IntN);
+        small.length = 1;
+        let bulk_load: BulkLoad = BulkLoad::new("t", vec![column("a", small), nvarchar_column("b", 4)]).unwrap();
+
+        assert!(matches!(bulk_load.row(&[SqlValue::Int(1)]), Err(Error::Config(_))));
+        assert!(matches!(bulk_load.row(&[SqlValue::Int(256), SqlValue::Null]), Err(Error::Config(_))));
+        assert!(matches!(bulk_load.row(&[SqlValue::String(String::from("x")), SqlValue::Null]), Err(Error::Config(_))));
+        assert!(matches!(bulk_load.row(&[SqlValue::Int(1), SqlValue::String(String::from("abc"))]), Err(Error::Config(_))));
+        assert!(bulk_load.row(&[SqlValue::TinyInt(255), SqlValue::String(String::from("ab"))]).is_ok());
+    }
+
+    #[test]
+    fn test_bulkload_new_rejects_sql_variant() {
+        let result = BulkLoad::new("t", vec![column("v", TypeInfo::new(DataType::SqlVariant))]);
+
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+}
@@ -1,200 +1,1459 @@
 use std::collections::HashMap;
-use config::Config;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use config::{Config, Environment, FileFormat};
 use std::fs;
+use crate::tls::{TlsSettings, EncryptMode};
+use crate::connection_string::{ConnectionString, quote_value};
+use crate::secret::Secret;
+use crate::tds_message::{DEFAULT_PACKET_SIZE, MIN_PACKET_SIZE, MAX_PACKET_SIZE};
+use crate::error::{Error, Result};
 
+pub const DEFAULT_PORT: u16 = 1433;
+
+// the environment variable that points at a config file, and the file name we look for
+pub const CONFIG_ENV: &str = "SQL_API_CONFIG";
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+const CONFIG_DIR_NAME: &str = "sql_api";
+
+// SQL_API_<KEY> overrides a key from the file, SQL_API_PROFILE picks the profile
+pub const ENV_PREFIX: &str = "SQL_API";
+pub const PROFILE_ENV: &str = "SQL_API_PROFILE";
+
+/**
+ * Every key the config file (and SQL_API_<KEY>) understands.
+ */
+pub const SETTING_KEYS: [&str; 18] = [
+    "server", "port", "instance", "user", "password", "password_file", "password_command",
+    "database", "application_name",
+    "connect_timeout", "login_timeout", "command_timeout", "packet_size", "mars",
+    "encrypt", "trust_server_certificate", "ca_file", "verify_hostname"
+];
+
+// any one of these gives the password
+const PASSWORD_KEYS: [&str; 3] = ["password", "password_file", "password_command"];
+
+// LOGIN7 limits for the variable length fields, in characters
+const MAX_NAME_LENGTH: usize = 128;
+const MAX_INSTANCE_LENGTH: usize = 32;
+
+/**
+ * Where and how to connect.
+ *
+ * Build these with ConnectionSettings::builder(), from a connection string or from the
+ * config file. Every route ends in ConnectionSettingsBuilder::build, so the values are
+ * always validated.
+ */
+#[derive(Debug, Clone)]
 pub struct ConnectionSettings {
     server: String,
-    port: String,
+    port: Option<u16>,
+    instance: Option<String>,
     user: String,
-    password: String,
-    from_file: bool,
+    password: PasswordSource,
+    database: Option<String>,
+    application_name: Option<String>,
+    connect_timeout: Option<Duration>,
+    login_timeout: Option<Duration>,
+    command_timeout: Option<Duration>,
+    packet_size: u32,
+    mars: bool,
+    tls: TlsSettings,
+    source: Option<ConfigSource>,
+    origins: HashMap<String, SettingSource>,
+}
+
+/**
+ * Where a setting's value came from. Later ones win:
+ *
+ * 1. Default - nothing set it
+ * 2. File - the config file
+ * 3. Environment - a SQL_API_<KEY> variable
+ * 4. Code - the builder, a connection string or an update
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingSource {
+    Default,
+    File(PathBuf),
+    Environment(String),
+    Code
+}
+
+/**
+ * The password itself, or where to get it when it's needed. Files and commands are read
+ * again on every login, so a rotated password is picked up without restarting.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordSource {
+    Value(Secret),
+    File(PathBuf),
+    Command(String)
+}
+impl PasswordSource {
+    pub fn resolve(&self) -> Result<Secret> {
+        match self {
+            PasswordSource::Value(secret) => Ok(secret.clone()),
+            PasswordSource::File(path) => Secret::from_file(path),
+            PasswordSource::Command(command) => Secret::from_command(command)
+        }
+    }
+}
+
+/**
+ * The file and profile some settings were loaded from, so changes go back to the same place.
+ * No profile means the `[connection_settings]` table.
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct ConfigSource {
+    path: PathBuf,
+    profile: Option<String>
 }
 
 impl ConnectionSettings {
-    pub fn new(server: &str, port: &str, user: &str, pass: &str) -> ConnectionSettings {
+    pub fn new(server: &str, port: u16, user: &str, pass: &str) -> ConnectionSettings {
         ConnectionSettings {
             server: String::from(server),
-            port: String::from(port),
+            port: Some(port),
+            instance: None,
             user: String::from(user),
-            password: String::from(pass),
-            from_file: false
+            password: PasswordSource::Value(Secret::new(pass)),
+            database: None,
+            application_name: None,
+            connect_timeout: None,
+            login_timeout: None,
+            command_timeout: None,
+            packet_size: DEFAULT_PACKET_SIZE,
+            mars: false,
+            tls: TlsSettings::new(),
+            source: None,
+            origins: ["server", "port", "user", "password"].iter()
+                .map(|key| (String::from(*key), SettingSource::Code))
+                .collect()
         }
     }
 
-    pub fn from_file() -> ConnectionSettings {
-        let settings: HashMap<String, String> = Config::builder()
-            .add_source(config::File::with_name("config"))
-            .build()
-            .unwrap()
-            .try_deserialize::<HashMap<String, HashMap<String, String>>>()
-            .unwrap()["connection_settings"].clone();
+    pub fn builder() -> ConnectionSettingsBuilder {
+        ConnectionSettingsBuilder::new()
+    }
+
+    /**
+     * A builder starting from these settings, for making a changed copy.
+     */
+    pub fn to_builder(&self) -> ConnectionSettingsBuilder {
+        let mut settings: ConnectionSettings = self.clone();
+        settings.source = None;
 
-        return ConnectionSettings {
-            server: settings["server"].to_string(),
-            port: settings["port"].to_string(),
-            user: settings["user"].to_string(),
-            password: settings["password"].to_string(),
-            from_file: true
+        ConnectionSettingsBuilder {
+            settings
         }
     }
 
-    pub fn get(&self, field: &str) -> &str {
-        match self.get_result(field) {
-            Ok(value) => value,
-            Err(err) => {
-                eprintln!("Error in getting field {field}: {err}");
-                ""
-            }
-        }        
+    /**
+     * The default profile from the first config file find_config_file turns up, with any
+     * SQL_API_<KEY> environment variables on top.
+     */
+    pub fn from_file() -> Result<ConnectionSettings> {
+        ConnectionSettings::load(None)
     }
 
-    fn get_result(&self, field_string: &str) -> Result<&str, String> {
-        match field_string {
-            "server" => Ok(&self.server),
-            "user" => Ok(&self.user),
-            "password" => Ok(&self.password),
-            "port" => Ok(&self.port),
-            _ => Err(format!("invalid field name to get '{}'", field_string))
+    /**
+     * A named profile (SQL_API_PROFILE, or the default one, for None) from the first config
+     * file find_config_file turns up, with any SQL_API_<KEY> environment variables on top.
+     *
+     * If there's no config file at all the environment can supply everything, as long as it
+     * has at least SQL_API_SERVER.
+     */
+    pub fn load(profile: Option<&str>) -> Result<ConnectionSettings> {
+        let environment: HashMap<String, String> = environment_settings(None)?;
+        let profile: Option<String> = profile.map(String::from).or_else(|| env::var(PROFILE_ENV).ok());
+
+        match ConnectionSettings::find_config_file() {
+            Ok(path) => ConnectionSettings::layered(Some(&path), profile.as_deref(), environment),
+            Err(_) if env::var(CONFIG_ENV).is_err() && profile.is_none() && environment.contains_key("server") => {
+                ConnectionSettings::layered(None, None, environment)
+            },
+            Err(e) => Err(e)
         }
     }
 
-    pub fn update(&mut self, field_string: &str, value: &str) -> Result<(), String>{
-        match field_string {
-            "server" => {
-                self.server = String::from(value);
-            },
-            "user" => {
-                self.user = String::from(value);
+    /**
+     * Settings from SQL_API_<KEY> environment variables alone.
+     */
+    pub fn from_env() -> Result<ConnectionSettings> {
+        ConnectionSettings::layered(None, None, environment_settings(None)?)
+    }
+
+    /**
+     * Reads settings from a TOML file. A file can hold a single `[connection_settings]` table
+     * or several `[profiles.<name>]` tables:
+     *
+     * ```toml
+     * default_profile = "dev"
+     *
+     * [profiles.dev]
+     * server = "localhost"
+     * ...
+     *
+     * [profiles.prod]
+     * server = "db.example.com"
+     * ...
+     * ```
+     *
+     * With no profile asked for, `default_profile` picks one, and without that it's the
+     * `[connection_settings]` table.
+     *
+     * Only the file is read here, environment variables are left to load.
+     */
+    pub fn from_path(path: &Path, profile: Option<&str>) -> Result<ConnectionSettings> {
+        ConnectionSettings::layered(Some(path), profile, HashMap::new())
+    }
+
+    /**
+     * The file's table (if there is a file) with the environment values over the top,
+     * remembering where each value came from.
+     */
+    fn layered(path: Option<&Path>, profile: Option<&str>, environment: HashMap<String, String>) -> Result<ConnectionSettings> {
+        let mut values: HashMap<String, String> = HashMap::new();
+        let mut origins: HashMap<String, SettingSource> = HashMap::new();
+        let mut source: Option<ConfigSource> = None;
+
+        if let Some(path) = path {
+            let (table, config_source) = ConnectionSettings::read_table(path, profile)?;
+            for (key, value) in table {
+                origins.insert(key.clone(), SettingSource::File(path.to_path_buf()));
+                values.insert(key, value);
             }
-            "password" => {
-                self.password = String::from(value);
+            source = Some(config_source);
+        }
+
+        // a password from the environment, in whichever form, replaces the file's
+        if environment.keys().any(|key| PASSWORD_KEYS.contains(&key.as_str())) {
+            for key in PASSWORD_KEYS {
+                values.remove(key);
+                origins.remove(key);
             }
-            "port" => {
-                self.port = String::from(value);
+        }
+
+        for (key, value) in environment {
+            origins.insert(key.clone(), SettingSource::Environment(format!("{}_{}", ENV_PREFIX, key.to_uppercase())));
+            values.insert(key, value);
+        }
+
+        let mut settings: ConnectionSettings = ConnectionSettings::builder_from_map(&values)?.build()?;
+        settings.source = source;
+        settings.origins = origins;
+
+        Ok(settings)
+    }
+
+    fn read_table(path: &Path, profile: Option<&str>) -> Result<(HashMap<String, String>, ConfigSource)> {
+        let config: Config = Config::builder()
+            .add_source(config::File::from(path).format(FileFormat::Toml))
+            .build()
+            .map_err(|e| Error::Config(format!("Failed to read config file {}: {}", path.display(), e)))?;
+
+        let profile: Option<String> = match profile {
+            Some(profile) => Some(String::from(profile)),
+            None => config.get_string("default_profile").ok()
+        };
+
+        let table: String = match &profile {
+            Some(profile) => {
+                if profile.is_empty() || !profile.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                    return Err(Error::Config(format!("Invalid profile name '{}'", profile)));
+                }
+                format!("profiles.{}", profile)
+            },
+            None => String::from("connection_settings")
+        };
+
+        let settings: HashMap<String, String> = config.get(&table)
+            .map_err(|_| Error::Config(format!("{} has no [{}] table", path.display(), table)))?;
+
+        Ok((settings, ConfigSource {
+            path: path.to_path_buf(),
+            profile
+        }))
+    }
+
+    /**
+     * The first config file that exists out of
+     *
+     * - the file SQL_API_CONFIG points at
+     * - config.toml in the current directory
+     * - sql_api/config.toml in the user's config directory ($XDG_CONFIG_HOME, ~/.config, or
+     *   %APPDATA% on Windows)
+     * - sql_api/config.toml in each of $XDG_CONFIG_DIRS (/etc/xdg by default)
+     */
+    pub fn find_config_file() -> Result<PathBuf> {
+        if let Ok(path) = env::var(CONFIG_ENV) {
+            let path: PathBuf = PathBuf::from(path);
+            if !path.is_file() {
+                return Err(Error::Config(format!("{} points at {}, which doesn't exist", CONFIG_ENV, path.display())));
             }
-            _ => ()
+            return Ok(path);
+        }
+
+        let candidates: Vec<PathBuf> = config_file_candidates(|name| env::var(name).ok());
+
+        candidates.iter()
+            .find(|path| path.is_file())
+            .cloned()
+            .ok_or_else(|| {
+                let searched: Vec<String> = candidates.iter().map(|path| path.display().to_string()).collect();
+                Error::Config(format!("No config file found, looked in {}", searched.join(", ")))
+            })
+    }
+
+    /**
+     * server, user and one of password, password_file or password_command have to be set,
+     * everything else is optional and keeps its default when it's missing.
+     */
+    fn builder_from_map(settings: &HashMap<String, String>) -> Result<ConnectionSettingsBuilder> {
+        let field = |name: &str| -> Result<&str> {
+            settings.get(name)
+                .map(|value| value.as_str())
+                .ok_or(Error::Config(format!("'{}' isn't set in the config file or {}_{}", name, ENV_PREFIX, name.to_uppercase())))
+        };
+
+        let mut builder: ConnectionSettingsBuilder = ConnectionSettings::builder()
+            .server(field("server")?)
+            .user(field("user")?)
+            .tls(ConnectionSettings::tls_from_map(settings)?);
+
+        let passwords: Vec<&str> = PASSWORD_KEYS.into_iter().filter(|key| settings.contains_key(*key)).collect();
+        builder = match passwords.as_slice() {
+            ["password"] => builder.password(field("password")?),
+            ["password_file"] => builder.password_file(Path::new(field("password_file")?)),
+            ["password_command"] => builder.password_command(field("password_command")?),
+            [] => return Err(Error::Config(format!("No password set, use password, password_file or password_command (or {}_PASSWORD)", ENV_PREFIX))),
+            _ => return Err(Error::Config(format!("Only one of {} can be set", passwords.join(", "))))
+        };
+
+        if let Some(port) = settings.get("port") {
+            builder = builder.port(parse_port(port)?);
+        }
+        if let Some(instance) = settings.get("instance") {
+            builder = builder.instance(instance);
+        }
+        if let Some(database) = settings.get("database") {
+            builder = builder.database(database);
+        }
+        if let Some(application_name) = settings.get("application_name") {
+            builder = builder.application_name(application_name);
+        }
+        if let Some(timeout) = settings.get("connect_timeout") {
+            builder = builder.connect_timeout(parse_seconds("connect_timeout", timeout)?);
+        }
+        if let Some(timeout) = settings.get("login_timeout") {
+            builder = builder.login_timeout(parse_seconds("login_timeout", timeout)?);
+        }
+        if let Some(timeout) = settings.get("command_timeout") {
+            builder = builder.command_timeout(parse_seconds("command_timeout", timeout)?);
+        }
+        if let Some(packet_size) = settings.get("packet_size") {
+            builder = builder.packet_size(parse_packet_size(packet_size)?);
+        }
+        if let Some(mars) = settings.get("mars") {
+            builder = builder.mars(parse_bool("mars", mars)?);
+        }
+
+        Ok(builder)
+    }
+
+    /**
+     * The file these settings were loaded from, if they came from one.
+     */
+    pub fn config_path(&self) -> Option<&Path> {
+        self.source.as_ref().map(|source| source.path.as_path())
+    }
+
+    /**
+     * The profile these settings were loaded from, None for `[connection_settings]` or
+     * settings that didn't come from a file.
+     */
+    pub fn profile(&self) -> Option<&str> {
+        self.source.as_ref().and_then(|source| source.profile.as_deref())
+    }
+
+    /**
+     * Where the value for one of the SETTING_KEYS came from, None for anything else.
+     */
+    pub fn source_of(&self, key: &str) -> Option<SettingSource> {
+        if !SETTING_KEYS.contains(&key) {
+            return None;
+        }
+
+        Some(self.origins.get(key).cloned().unwrap_or(SettingSource::Default))
+    }
+
+    /**
+     * Reads an ADO.NET (`Server=tcp:host,1433;User Id=sa;Password=...`) or ODBC
+     * (`DRIVER={...};SERVER=host,1433;UID=sa;PWD=...`) connection string. Keywords we don't
+     * know are an error rather than being quietly ignored, except DRIVER which only means
+     * something to the ODBC driver manager.
+     */
+    pub fn from_connection_string(connection_string: &str) -> Result<ConnectionSettings> {
+        let parsed: ConnectionString = ConnectionString::parse(connection_string)?;
+        let mut builder: ConnectionSettingsBuilder = ConnectionSettings::builder();
+
+        for (keyword, value) in parsed.pairs() {
+            builder = match keyword.as_str() {
+                "server" | "data source" | "address" | "addr" | "network address" => {
+                    let (server, instance, port) = ConnectionSettings::parse_server(value)?;
+                    builder = builder.server(&server);
+                    if let Some(instance) = instance {
+                        builder = builder.instance(&instance);
+                    }
+                    match port {
+                        Some(port) => builder.port(port),
+                        None => builder
+                    }
+                },
+                "database" | "initial catalog" => builder.database(value),
+                "user id" | "uid" | "user" => builder.user(value),
+                "password" | "pwd" => builder.password(value),
+                "encrypt" => builder.encrypt(EncryptMode::from_value(value)?),
+                "trustservercertificate" | "trust server certificate" => {
+                    builder.trust_server_certificate(parse_bool(keyword, value)?)
+                },
+                "application name" | "app" => builder.application_name(value),
+                "connect timeout" | "connection timeout" | "timeout" => builder.connect_timeout(parse_seconds(keyword, value)?),
+                "login timeout" => builder.login_timeout(parse_seconds(keyword, value)?),
+                "command timeout" => builder.command_timeout(parse_seconds(keyword, value)?),
+                "packet size" => builder.packet_size(parse_packet_size(value)?),
+                "multipleactiveresultsets" | "multiple active result sets" | "mars_connection" => builder.mars(parse_bool(keyword, value)?),
+                "driver" => builder,
+                _ => return Err(Error::Config(format!("Unsupported connection string keyword '{}'", keyword)))
+            };
+        }
+
+        builder.build()
+    }
+
+    /**
+     * Splits `[tcp:]host[\instance][,port]` from a Server keyword. "." and "(local)" mean
+     * this machine.
+     */
+    fn parse_server(value: &str) -> Result<(String, Option<String>, Option<u16>)> {
+        let value: &str = match value.split_once(':') {
+            Some((protocol, rest)) if protocol.eq_ignore_ascii_case("tcp") => rest,
+            Some((protocol, _)) if ["np", "lpc", "admin"].contains(&protocol.to_lowercase().as_str()) => {
+                return Err(Error::Config(format!("Unsupported protocol '{}' in server '{}', only tcp is supported", protocol, value)));
+            },
+            _ => value
+        };
+
+        let (host, port) = match value.split_once(',') {
+            Some((host, port)) => (host.trim(), Some(parse_port(port.trim())?)),
+            None => (value.trim(), None)
+        };
+
+        let (host, instance) = match host.split_once('\\') {
+            Some((host, instance)) => (host, Some(String::from(instance))),
+            None => (host, None)
+        };
+
+        let host: &str = match host {
+            "." | "(local)" => "localhost",
+            host => host
         };
 
-        if self.from_file {
-            self.save_config()?;
+        Ok((String::from(host), instance, port))
+    }
+
+    /**
+     * ADO.NET style connection string for these settings, with the password masked so it
+     * can go in logs.
+     */
+    pub fn to_connection_string(&self) -> String {
+        let mut server: String = format!("tcp:{}", self.server);
+        if let Some(instance) = &self.instance {
+            server = format!("{}\\{}", server, instance);
+        }
+        if self.instance.is_none() || self.port.is_some() {
+            server = format!("{},{}", server, self.port());
+        }
+
+        let mut parts: Vec<String> = vec![format!("Server={}", quote_value(&server))];
+
+        if let Some(database) = &self.database {
+            parts.push(format!("Database={}", quote_value(database)));
+        }
+        parts.push(format!("User Id={}", quote_value(&self.user)));
+        parts.push(String::from("Password=********"));
+        parts.push(format!("Encrypt={}", self.tls.encrypt.value()));
+
+        if self.tls.trust_server_certificate {
+            parts.push(String::from("TrustServerCertificate=true"));
+        }
+        if let Some(application_name) = &self.application_name {
+            parts.push(format!("Application Name={}", quote_value(application_name)));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            parts.push(format!("Connect Timeout={}", timeout.as_secs()));
+        }
+        if let Some(timeout) = self.login_timeout {
+            parts.push(format!("Login Timeout={}", timeout.as_secs()));
+        }
+        if let Some(timeout) = self.command_timeout {
+            parts.push(format!("Command Timeout={}", timeout.as_secs()));
+        }
+        if self.packet_size != DEFAULT_PACKET_SIZE {
+            parts.push(format!("Packet Size={}", self.packet_size));
+        }
+        if self.mars {
+            parts.push(String::from("MultipleActiveResultSets=true"));
+        }
+
+        parts.join(";")
+    }
+
+    /**
+     * TLS keys are optional in the file, anything missing keeps its default.
+     */
+    fn tls_from_map(settings: &HashMap<String, String>) -> Result<TlsSettings> {
+        let mut tls = TlsSettings::new();
+
+        if let Some(encrypt) = settings.get("encrypt") {
+            tls.encrypt = EncryptMode::from_value(encrypt)?;
+        }
+        if let Some(trust) = settings.get("trust_server_certificate") {
+            tls.trust_server_certificate = parse_bool("trust_server_certificate", trust)?;
+        }
+        if let Some(ca_file) = settings.get("ca_file") {
+            tls.ca_file = Some(PathBuf::from(ca_file));
+        }
+        if let Some(verify) = settings.get("verify_hostname") {
+            tls.verify_hostname = parse_bool("verify_hostname", verify)?;
+        }
+
+        Ok(tls)
+    }
+
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /**
+     * The port to connect to, 1433 unless one has been set.
+     */
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    /**
+     * The port if one was given. With a named instance and no port the SQL Server Browser
+     * is asked for it instead.
+     */
+    pub fn explicit_port(&self) -> Option<u16> {
+        self.port
+    }
+
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /**
+     * The password, read from the file or command if that's where it comes from.
+     */
+    pub fn password(&self) -> Result<Secret> {
+        self.password.resolve()
+    }
+
+    pub fn password_source(&self) -> &PasswordSource {
+        &self.password
+    }
+
+    pub fn database(&self) -> Option<&str> {
+        self.database.as_deref()
+    }
+
+    pub fn application_name(&self) -> Option<&str> {
+        self.application_name.as_deref()
+    }
+
+    /**
+     * How long to wait for the TCP connection to each of the server's addresses.
+     */
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /**
+     * How long PRELOGIN, the TLS handshake and LOGIN7 can take altogether.
+     */
+    pub fn login_timeout(&self) -> Option<Duration> {
+        self.login_timeout
+    }
+
+    /**
+     * How long to wait for a query's response before cancelling it.
+     */
+    pub fn command_timeout(&self) -> Option<Duration> {
+        self.command_timeout
+    }
+
+    /**
+     * Packet size to ask for in LOGIN7, the server can still pick a different one.
+     */
+    pub fn packet_size(&self) -> u32 {
+        self.packet_size
+    }
+
+    /**
+     * Whether to ask the server for MARS, so Connector::open_session can run several
+     * requests at once over the one connection.
+     */
+    pub fn mars(&self) -> bool {
+        self.mars
+    }
+
+    pub fn tls(&self) -> &TlsSettings {
+        &self.tls
+    }
+
+    /**
+     * Changes some of the settings, e.g. `settings.update(|b| b.server("db2").port(1434))`.
+     * The result is validated like a fresh build, and written back to the config file (and
+     * profile) if that's where the settings came from.
+     */
+    pub fn update<F>(&mut self, change: F) -> Result<()>
+    where F: FnOnce(ConnectionSettingsBuilder) -> ConnectionSettingsBuilder {
+        let mut updated: ConnectionSettings = change(self.to_builder()).build()?;
+        updated.source = self.source.clone();
+        *self = updated;
+
+        if let Some(source) = &self.source {
+            self.save_config(source, false)?;
         }
 
         Ok(())
     }
 
-    fn save_config(&self) -> Result<(), String> {
-        //rebuild the serializable data structure. 
-        let mut settings_map = HashMap::new();
-        settings_map.insert("server", &self.server);
-        settings_map.insert("port", &self.port);
-        settings_map.insert("user", &self.user);
-        settings_map.insert("password", &self.password);
+    /**
+     * update never writes a plain text password to the config file. This does, for when
+     * that really is what's wanted.
+     */
+    pub fn save_password(&self) -> Result<()> {
+        let source: &ConfigSource = self.source.as_ref()
+            .ok_or(Error::Config(String::from("These settings weren't loaded from a config file")))?;
 
-        let mut config_data = HashMap::new();
-        config_data.insert("connection_settings", settings_map);
+        self.save_config(source, true)
+    }
+
+    /**
+     * Writes the settings back into their table, leaving the rest of the file (other
+     * profiles, default_profile) as it was. A plain password is only written when
+     * `include_password` is set, otherwise whatever the file had is kept.
+     */
+    fn save_config(&self, source: &ConfigSource, include_password: bool) -> Result<()> {
+        //rebuild the serializable data structure.
+        let mut settings_map: HashMap<&str, String> = HashMap::new();
+        settings_map.insert("server", self.server.clone());
+        settings_map.insert("user", self.user.clone());
+
+        match &self.password {
+            PasswordSource::Value(secret) if include_password => {
+                settings_map.insert("password", String::from(secret.expose()));
+            },
+            PasswordSource::Value(_) => (),
+            PasswordSource::File(path) => {
+                settings_map.insert("password_file", path.display().to_string());
+            },
+            PasswordSource::Command(command) => {
+                settings_map.insert("password_command", command.clone());
+            }
+        }
+
+        if let Some(port) = self.port {
+            settings_map.insert("port", port.to_string());
+        }
+        if let Some(instance) = &self.instance {
+            settings_map.insert("instance", instance.clone());
+        }
+        if let Some(database) = &self.database {
+            settings_map.insert("database", database.clone());
+        }
+        if let Some(application_name) = &self.application_name {
+            settings_map.insert("application_name", application_name.clone());
+        }
+        if let Some(timeout) = self.connect_timeout {
+            settings_map.insert("connect_timeout", timeout.as_secs().to_string());
+        }
+        if let Some(timeout) = self.login_timeout {
+            settings_map.insert("login_timeout", timeout.as_secs().to_string());
+        }
+        if let Some(timeout) = self.command_timeout {
+            settings_map.insert("command_timeout", timeout.as_secs().to_string());
+        }
+        if self.packet_size != DEFAULT_PACKET_SIZE {
+            settings_map.insert("packet_size", self.packet_size.to_string());
+        }
+        if self.mars {
+            settings_map.insert("mars", self.mars.to_string());
+        }
+
+        //only write the TLS keys that have been changed from the defaults
+        let defaults = TlsSettings::new();
+
+        if self.tls.encrypt != defaults.encrypt {
+            settings_map.insert("encrypt", String::from(self.tls.encrypt.value()));
+        }
+        if self.tls.trust_server_certificate != defaults.trust_server_certificate {
+            settings_map.insert("trust_server_certificate", self.tls.trust_server_certificate.to_string());
+        }
+        if let Some(ca_file) = &self.tls.ca_file {
+            settings_map.insert("ca_file", ca_file.display().to_string());
+        }
+        if self.tls.verify_hostname != defaults.verify_hostname {
+            settings_map.insert("verify_hostname", self.tls.verify_hostname.to_string());
+        }
+
+        let mut config_data: toml::Table = match fs::read_to_string(&source.path) {
+            Ok(contents) => contents.parse::<toml::Table>()
+                .map_err(|e| Error::Config(format!("Failed to parse {}: {}", source.path.display(), e)))?,
+            Err(_) => toml::Table::new()
+        };
+
+        let existing: Option<&toml::Table> = match &source.profile {
+            Some(profile) => config_data.get("profiles").and_then(|profiles| profiles.get(profile)),
+            None => config_data.get("connection_settings")
+        }.and_then(|table| table.as_table());
+
+        let keep_password: bool = !include_password && matches!(self.password, PasswordSource::Value(_));
+        // unless code set the password, the file keeps its own, even when the password in
+        // use came from the environment in its place
+        let password_from_code: bool = PASSWORD_KEYS.iter().any(|key| matches!(self.origins.get(*key), Some(SettingSource::Code)));
+        let keep_file_password: bool = !include_password && !password_from_code;
+
+        let mut table: toml::Table = toml::Table::new();
+        for key in SETTING_KEYS {
+            // values from the environment stay out of the file, whatever was there is kept
+            let from_environment: bool = matches!(self.origins.get(key), Some(SettingSource::Environment(_)));
+            let password_key: bool = PASSWORD_KEYS.contains(&key);
+
+            if from_environment || (key == "password" && keep_password) || (password_key && keep_file_password) {
+                if let Some(value) = existing.and_then(|existing| existing.get(key)) {
+                    table.insert(String::from(key), value.clone());
+                }
+            } else if let Some(value) = settings_map.remove(key) {
+                table.insert(String::from(key), toml::Value::String(value));
+            }
+        }
+
+        match &source.profile {
+            Some(profile) => {
+                let profiles = config_data.entry("profiles")
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+                let profiles: &mut toml::Table = profiles.as_table_mut()
+                    .ok_or(Error::Config(format!("'profiles' in {} is not a table", source.path.display())))?;
+                profiles.insert(profile.clone(), toml::Value::Table(table));
+            },
+            None => {
+                config_data.insert(String::from("connection_settings"), toml::Value::Table(table));
+            }
+        }
 
         let serialized_config = toml::to_string(&config_data)
-            .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+            .map_err(|e| Error::Config(format!("Failed to serialize settings: {}", e)))?;
 
-        fs::write("config.toml", serialized_config)
-            .map_err(|e| format!("Failed to write to file: {}", e))?;
+        fs::write(&source.path, serialized_config)
+            .map_err(|e| Error::io("Failed to write to file", e))?;
 
         Ok(())
     }
 }
 
+impl fmt::Display for ConnectionSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_connection_string())
+    }
+}
+
+/**
+ * Builds ConnectionSettings, checking the values when build is called.
+ *
+ * Only the server is required, the port defaults to 1433 and the rest are optional.
+ */
+#[derive(Clone)]
+pub struct ConnectionSettingsBuilder {
+    settings: ConnectionSettings
+}
+impl Default for ConnectionSettingsBuilder {
+    fn default() -> ConnectionSettingsBuilder {
+        ConnectionSettingsBuilder::new()
+    }
+}
+impl ConnectionSettingsBuilder {
+    pub fn new() -> ConnectionSettingsBuilder {
+        let mut settings: ConnectionSettings = ConnectionSettings::new("", DEFAULT_PORT, "", "");
+        settings.port = None;
+        settings.origins.clear();
+
+        ConnectionSettingsBuilder {
+            settings
+        }
+    }
+
+    fn mark(&mut self, keys: &[&str]) {
+        for key in keys {
+            self.settings.origins.insert(String::from(*key), SettingSource::Code);
+        }
+    }
+
+    pub fn server(mut self, server: &str) -> ConnectionSettingsBuilder {
+        self.settings.server = String::from(server);
+        self.mark(&["server"]);
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> ConnectionSettingsBuilder {
+        self.settings.port = Some(port);
+        self.mark(&["port"]);
+        self
+    }
+
+    pub fn instance(mut self, instance: &str) -> ConnectionSettingsBuilder {
+        self.settings.instance = Some(String::from(instance));
+        self.mark(&["instance"]);
+        self
+    }
+
+    pub fn user(mut self, user: &str) -> ConnectionSettingsBuilder {
+        self.settings.user = String::from(user);
+        self.mark(&["user"]);
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> ConnectionSettingsBuilder {
+        self.settings.password = PasswordSource::Value(Secret::new(password));
+        self.mark(&["password"]);
+        self
+    }
+
+    /**
+     * Read the password from the first line of a file at each login.
+     */
+    pub fn password_file(mut self, path: &Path) -> ConnectionSettingsBuilder {
+        self.settings.password = PasswordSource::File(path.to_path_buf());
+        self.mark(&["password_file"]);
+        self
+    }
+
+    /**
+     * Run a command at each login and use the first line it prints as the password.
+     */
+    pub fn password_command(mut self, command: &str) -> ConnectionSettingsBuilder {
+        self.settings.password = PasswordSource::Command(String::from(command));
+        self.mark(&["password_command"]);
+        self
+    }
+
+    pub fn database(mut self, database: &str) -> ConnectionSettingsBuilder {
+        self.settings.database = Some(String::from(database));
+        self.mark(&["database"]);
+        self
+    }
+
+    pub fn application_name(mut self, application_name: &str) -> ConnectionSettingsBuilder {
+        self.settings.application_name = Some(String::from(application_name));
+        self.mark(&["application_name"]);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> ConnectionSettingsBuilder {
+        self.settings.connect_timeout = Some(timeout);
+        self.mark(&["connect_timeout"]);
+        self
+    }
+
+    pub fn login_timeout(mut self, timeout: Duration) -> ConnectionSettingsBuilder {
+        self.settings.login_timeout = Some(timeout);
+        self.mark(&["login_timeout"]);
+        self
+    }
+
+    pub fn command_timeout(mut self, timeout: Duration) -> ConnectionSettingsBuilder {
+        self.settings.command_timeout = Some(timeout);
+        self.mark(&["command_timeout"]);
+        self
+    }
+
+    pub fn packet_size(mut self, packet_size: u32) -> ConnectionSettingsBuilder {
+        self.settings.packet_size = packet_size;
+        self.mark(&["packet_size"]);
+        self
+    }
+
+    pub fn mars(mut self, mars: bool) -> ConnectionSettingsBuilder {
+        self.settings.mars = mars;
+        self.mark(&["mars"]);
+        self
+    }
+
+    pub fn encrypt(mut self, encrypt: EncryptMode) -> ConnectionSettingsBuilder {
+        self.settings.tls.encrypt = encrypt;
+        self.mark(&["encrypt"]);
+        self
+    }
+
+    pub fn trust_server_certificate(mut self, trust: bool) -> ConnectionSettingsBuilder {
+        self.settings.tls.trust_server_certificate = trust;
+        self.mark(&["trust_server_certificate"]);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsSettings) -> ConnectionSettingsBuilder {
+        self.settings.tls = tls;
+        self.mark(&["encrypt", "trust_server_certificate", "ca_file", "verify_hostname"]);
+        self
+    }
+
+    pub fn build(self) -> Result<ConnectionSettings> {
+        let settings: ConnectionSettings = self.settings;
+
+        if settings.server.trim().is_empty() {
+            return Err(Error::Config(String::from("A server is required")));
+        }
+        if settings.password == PasswordSource::Command(String::new()) {
+            return Err(Error::Config(String::from("The password command is empty")));
+        }
+        if settings.port == Some(0) {
+            return Err(Error::Config(String::from("Invalid port 0")));
+        }
+        if let Some(instance) = &settings.instance {
+            if instance.is_empty() || instance.len() > MAX_INSTANCE_LENGTH {
+                return Err(Error::Config(format!("Invalid instance name '{}', it must be 1 to {} characters", instance, MAX_INSTANCE_LENGTH)));
+            }
+        }
+        for (name, value) in [("user", Some(&settings.user)), ("database", settings.database.as_ref()), ("application name", settings.application_name.as_ref())] {
+            if value.is_some_and(|value| value.chars().count() > MAX_NAME_LENGTH) {
+                return Err(Error::Config(format!("The {} can't be longer than {} characters", name, MAX_NAME_LENGTH)));
+            }
+        }
+        if !(MIN_PACKET_SIZE..=MAX_PACKET_SIZE).contains(&settings.packet_size) {
+            return Err(Error::Config(format!("Invalid packet size {}, it must be between {} and {}", settings.packet_size, MIN_PACKET_SIZE, MAX_PACKET_SIZE)));
+        }
+        for (name, timeout) in [("connect", settings.connect_timeout), ("login", settings.login_timeout), ("command", settings.command_timeout)] {
+            if timeout == Some(Duration::ZERO) {
+                return Err(Error::Config(format!("The {} timeout must be more than zero, leave it unset for no timeout", name)));
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+/**
+ * SQL_API_<KEY> variables for the SETTING_KEYS, e.g. SQL_API_PASSWORD or
+ * SQL_API_CONNECT_TIMEOUT, read through the config crate. `vars` replaces the real
+ * environment, for the tests.
+ */
+fn environment_settings(vars: Option<config::Map<String, String>>) -> Result<HashMap<String, String>> {
+    let config: Config = Config::builder()
+        .add_source(Environment::with_prefix(ENV_PREFIX).prefix_separator("_").source(vars))
+        .build()
+        .map_err(|e| Error::Config(format!("Failed to read environment variables: {}", e)))?;
+
+    Ok(SETTING_KEYS.iter()
+        .filter_map(|key| config.get_string(key).ok().map(|value| (String::from(*key), value)))
+        .collect())
+}
+
+/**
+ * Where find_config_file looks after SQL_API_CONFIG, in order. `var` reads an environment
+ * variable, it's passed in so the tests don't have to change the real environment.
+ */
+fn config_file_candidates<F>(var: F) -> Vec<PathBuf>
+where F: Fn(&str) -> Option<String> {
+    let mut candidates: Vec<PathBuf> = vec![PathBuf::from(CONFIG_FILE_NAME)];
+
+    let user_dir: Option<PathBuf> = var("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| var("APPDATA").map(PathBuf::from));
+    if let Some(dir) = user_dir {
+        candidates.push(dir.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME));
+    }
+
+    let system_dirs: String = var("XDG_CONFIG_DIRS").filter(|dirs| !dirs.is_empty()).unwrap_or(String::from("/etc/xdg"));
+    for dir in system_dirs.split(':').filter(|dir| !dir.is_empty()) {
+        candidates.push(Path::new(dir).join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME));
+    }
+
+    candidates
+}
+
+fn parse_bool(keyword: &str, value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" => Ok(true),
+        "false" | "no" => Ok(false),
+        _ => Err(Error::Config(format!("Invalid value '{}' for '{}', expected true or false", value, keyword)))
+    }
+}
+
+fn parse_port(value: &str) -> Result<u16> {
+    value.trim().parse::<u16>()
+        .map_err(|_| Error::Config(format!("Invalid port '{}'", value)))
+}
+
+fn parse_packet_size(value: &str) -> Result<u32> {
+    value.trim().parse::<u32>()
+        .map_err(|_| Error::Config(format!("Invalid packet size '{}'", value)))
+}
+
+/**
+ * Timeouts are given in whole seconds in both the config file and connection strings.
+ */
+fn parse_seconds(keyword: &str, value: &str) -> Result<Duration> {
+    value.trim().parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|_| Error::Config(format!("Invalid value '{}' for '{}', expected a number of seconds", value, keyword)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_connectionsettings_get_gets_field() {
-        let settings: ConnectionSettings = ConnectionSettings {
-            server: String::from("localhost"),
-            port: String::from("1433"),
-            user: String::from("sa"),
-            password: String::from("SomeTestPass123!"),
-            from_file: false
-
-        };
-
-        let server: &str = settings.get("server");
-        let port: &str = settings.get("port");
-        let user: &str = settings.get("user");
-        let password: &str = settings.get("password");
+    fn test_connectionsettings_accessors_return_typed_values() {
+        let settings: ConnectionSettings = ConnectionSettings::new("localhost", 1433, "sa", "SomeTestPass123!");
 
-        assert_eq!(server, String::from("localhost"));
-        assert_eq!(port, "1433");
-        assert_eq!(user, String::from("sa"));
-        assert_eq!(password, String::from("SomeTestPass123!"));
+        assert_eq!(settings.server(), "localhost");
+        assert_eq!(settings.port(), 1433);
+        assert_eq!(settings.user(), "sa");
+        assert_eq!(settings.password().unwrap().expose(), "SomeTestPass123!");
+        assert_eq!(settings.database(), None);
+        assert_eq!(settings.packet_size(), DEFAULT_PACKET_SIZE);
     }
 
     #[test]
     fn test_connectionsettings_update_updates_field_value() {
-        let mut settings: ConnectionSettings = ConnectionSettings {
-            server: String::from("localhost"),
-            port: String::from("1433"),
-            user: String::from("sa"),
-            password: String::from("SomeTestPass123!"),
-            from_file: false
-        };
+        let mut settings: ConnectionSettings = ConnectionSettings::new("localhost", 1433, "sa", "SomeTestPass123!");
 
-        let new_server_value: &str = "https://localhost";
-        let new_port_value: &str = "8080";
-        let new_user_value: &str = "user";
-        let new_pass_value: &str = "password";
+        settings.update(|builder| builder
+            .server("https://localhost")
+            .port(8080)
+            .user("user")
+            .password("password")
+            .database("sales")
+        ).unwrap();
 
-        let _ = settings.update("server", new_server_value);
-        let _ = settings.update("port", new_port_value);
-        let _ = settings.update("user", new_user_value);
-        let _ = settings.update("password", new_pass_value);
+        assert_eq!(settings.server(), "https://localhost");
+        assert_eq!(settings.port(), 8080);
+        assert_eq!(settings.user(), "user");
+        assert_eq!(settings.password().unwrap().expose(), "password");
+        assert_eq!(settings.database(), Some("sales"));
+    }
+
+    #[test]
+    fn test_connectionsettings_update_rejects_bad_values() {
+        let mut settings: ConnectionSettings = ConnectionSettings::new("localhost", 1433, "sa", "pass");
 
-        assert_eq!(settings.server, new_server_value);
-        assert_eq!(settings.port, new_port_value);
-        assert_eq!(settings.user, new_user_value);
-        assert_eq!(settings.password, new_pass_value);
+        let result = settings.update(|builder| builder.server("db2").packet_size(100));
+
+        assert!(matches!(result, Err(Error::Config(_))));
+        assert_eq!(settings.server(), "localhost");
     }
 
     #[test]
     fn test_connectionsettings_new_creates_instance() {
         let settings = ConnectionSettings::new(
             "localhost",
-            "1433",
+            1433,
             "sa",
             "SomePassword123!",
         );
 
         assert_eq!(settings.server, "localhost");
-        assert_eq!(settings.port, "1433");
+        assert_eq!(settings.port, Some(1433));
         assert_eq!(settings.user, "sa");
-        assert_eq!(settings.password, "SomePassword123!");
+        assert_eq!(settings.password, PasswordSource::Value(Secret::new("SomePassword123!")));
+        assert_eq!(settings.tls, TlsSettings::new());
+    }
+
+    #[test]
+    fn test_connectionsettingsbuilder_build_sets_every_field() {
+        let settings: ConnectionSettings = ConnectionSettings::builder()
+            .server("db")
+            .instance("SQLEXPRESS")
+            .user("app")
+            .password("pass")
+            .database("sales")
+            .application_name("reports")
+            .connect_timeout(Duration::from_secs(15))
+            .login_timeout(Duration::from_secs(20))
+            .command_timeout(Duration::from_secs(30))
+            .packet_size(8192)
+            .mars(true)
+            .encrypt(EncryptMode::On)
+            .build()
+            .unwrap();
+
+        assert_eq!(settings.server(), "db");
+        assert_eq!(settings.port(), DEFAULT_PORT);
+        assert_eq!(settings.explicit_port(), None);
+        assert_eq!(settings.instance(), Some("SQLEXPRESS"));
+        assert_eq!(settings.application_name(), Some("reports"));
+        assert_eq!(settings.connect_timeout(), Some(Duration::from_secs(15)));
+        assert_eq!(settings.login_timeout(), Some(Duration::from_secs(20)));
+        assert_eq!(settings.command_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(settings.packet_size(), 8192);
+        assert!(settings.mars());
+        assert_eq!(settings.tls().encrypt, EncryptMode::On);
+    }
+
+    #[test]
+    fn test_connectionsettingsbuilder_build_validates() {
+        let builder = || ConnectionSettings::builder().server("db");
+
+        assert!(matches!(ConnectionSettings::builder().build(), Err(Error::Config(_))));
+        assert!(matches!(builder().port(0).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().instance("").build(), Err(Error::Config(_))));
+        assert!(matches!(builder().database(&"x".repeat(129)).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().packet_size(40000).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().connect_timeout(Duration::ZERO).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().login_timeout(Duration::ZERO).build(), Err(Error::Config(_))));
+        assert!(builder().build().is_ok());
+    }
+
+    #[test]
+    fn test_connectionsettings_tls_from_map_reads_optional_keys() {
+        let mut map: HashMap<String, String> = HashMap::new();
+        map.insert(String::from("encrypt"), String::from("on"));
+        map.insert(String::from("trust_server_certificate"), String::from("true"));
+        map.insert(String::from("ca_file"), String::from("ca.pem"));
+        map.insert(String::from("verify_hostname"), String::from("false"));
+
+        let tls = ConnectionSettings::tls_from_map(&map).unwrap();
+
+        assert_eq!(tls.encrypt, EncryptMode::On);
+        assert!(tls.trust_server_certificate);
+        assert_eq!(tls.ca_file, Some(PathBuf::from("ca.pem")));
+        assert!(!tls.verify_hostname);
+        assert_eq!(ConnectionSettings::tls_from_map(&HashMap::new()).unwrap(), TlsSettings::new());
+    }
+
+    #[test]
+    fn test_connectionsettings_tls_from_map_rejects_unknown_encrypt() {
+        let mut map: HashMap<String, String> = HashMap::new();
+        map.insert(String::from("encrypt"), String::from("sometimes"));
+
+        let result = ConnectionSettings::tls_from_map(&map);
+
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_connectionsettings_builder_from_map_parses_typed_values() {
+        let mut map: HashMap<String, String> = HashMap::new();
+        for (key, value) in [("server", "db"), ("user", "sa"), ("password", "pass"), ("port", "14330"), ("login_timeout", "20"), ("command_timeout", "30"), ("packet_size", "8192")] {
+            map.insert(String::from(key), String::from(value));
+        }
+
+        let settings: ConnectionSettings = ConnectionSettings::builder_from_map(&map).unwrap().build().unwrap();
+
+        assert_eq!(settings.port(), 14330);
+        assert_eq!(settings.login_timeout(), Some(Duration::from_secs(20)));
+        assert_eq!(settings.command_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(settings.packet_size(), 8192);
+
+        map.insert(String::from("port"), String::from("not a port"));
+        assert!(matches!(ConnectionSettings::builder_from_map(&map), Err(Error::Config(_))));
     }
 
     #[test]
     fn test_connectionsettings_fromfile_creates_instance() {
         //Update to use temp file at some point
 
-        let settings: ConnectionSettings = ConnectionSettings::from_file();
+        let settings: ConnectionSettings = ConnectionSettings::from_file().unwrap();
 
-        assert_eq!(settings.server, "localhost");
-        assert_eq!(settings.port, "1433");
-        assert_eq!(settings.user, "sa");
-        assert_eq!(settings.password, "SomeTestPass123!");
+        assert_eq!(settings.server(), "localhost");
+        assert_eq!(settings.port(), 1433);
+        assert_eq!(settings.user(), "sa");
+        assert_eq!(settings.password().unwrap().expose(), "SomeTestPass123!");
+    }
+
+    fn temp_config(name: &str, contents: &str) -> PathBuf {
+        let path: PathBuf = env::temp_dir().join(format!("sql_connector_{}_{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
     }
 
+    const PROFILES: &str = r#"
+default_profile = "dev"
+
+[profiles.dev]
+server = "localhost"
+user = "sa"
+password = "dev-pass"
+
+[profiles.prod]
+server = "db.example.com"
+port = "14330"
+user = "app"
+password = "prod-pass"
+"#;
+
     #[test]
     fn test_connectionsettings_update_updates_file() {
-        //Update to use temp file at some point
-        
-        let mut settings: ConnectionSettings = ConnectionSettings::from_file();
+        let path: PathBuf = temp_config("update", "[connection_settings]\nserver = \"localhost\"\nport = \"1433\"\nuser = \"sa\"\npassword = \"pass\"\n");
+
+        let mut settings: ConnectionSettings = ConnectionSettings::from_path(&path, None).unwrap();
         let update_value = "https://localhost";
 
-        let _ = settings.update("server", &update_value);
-        assert_eq!(settings.server, update_value);
+        settings.update(|builder| builder.server(update_value)).unwrap();
+        assert_eq!(settings.server(), update_value);
+        assert_eq!(settings.config_path(), Some(path.as_path()));
+
+        let settings2: ConnectionSettings = ConnectionSettings::from_path(&path, None).unwrap();
+        assert_eq!(settings2.server(), update_value);
+        assert_eq!(settings2.port(), 1433);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connectionsettings_from_path_selects_profiles() {
+        let path: PathBuf = temp_config("profiles", PROFILES);
+
+        let default: ConnectionSettings = ConnectionSettings::from_path(&path, None).unwrap();
+        assert_eq!(default.profile(), Some("dev"));
+        assert_eq!(default.password().unwrap().expose(), "dev-pass");
+
+        let prod: ConnectionSettings = ConnectionSettings::from_path(&path, Some("prod")).unwrap();
+        assert_eq!(prod.profile(), Some("prod"));
+        assert_eq!(prod.server(), "db.example.com");
+        assert_eq!(prod.port(), 14330);
+
+        assert!(matches!(ConnectionSettings::from_path(&path, Some("staging")), Err(Error::Config(_))));
+        assert!(matches!(ConnectionSettings::from_path(&path, Some("dev.x")), Err(Error::Config(_))));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connectionsettings_update_keeps_other_profiles() {
+        let path: PathBuf = temp_config("profile_update", PROFILES);
+
+        let mut prod: ConnectionSettings = ConnectionSettings::from_path(&path, Some("prod")).unwrap();
+        prod.update(|builder| builder.database("sales")).unwrap();
+
+        assert_eq!(ConnectionSettings::from_path(&path, Some("prod")).unwrap().database(), Some("sales"));
+        assert_eq!(ConnectionSettings::from_path(&path, None).unwrap().server(), "localhost");
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connectionsettings_update_does_not_save_password() {
+        let path: PathBuf = temp_config("no_password", "[connection_settings]\nserver = \"localhost\"\nuser = \"sa\"\npassword = \"pass\"\n");
+
+        let mut settings: ConnectionSettings = ConnectionSettings::from_path(&path, None).unwrap();
+        settings.update(|builder| builder.password("new-pass")).unwrap();
+        assert_eq!(settings.password().unwrap().expose(), "new-pass");
+        assert_eq!(ConnectionSettings::from_path(&path, None).unwrap().password().unwrap().expose(), "pass");
+
+        settings.save_password().unwrap();
+        assert_eq!(ConnectionSettings::from_path(&path, None).unwrap().password().unwrap().expose(), "new-pass");
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connectionsettings_password_file_is_read_at_login() {
+        let secret: PathBuf = env::temp_dir().join(format!("sql_connector_password_{}", std::process::id()));
+        fs::write(&secret, "first-pass\n").unwrap();
+        let path: PathBuf = temp_config("password_file", &format!(
+            "[connection_settings]\nserver = \"localhost\"\nuser = \"sa\"\npassword_file = {:?}\n",
+            secret.display().to_string()
+        ));
+
+        let settings: ConnectionSettings = ConnectionSettings::from_path(&path, None).unwrap();
+        assert_eq!(settings.password_source(), &PasswordSource::File(secret.clone()));
+        assert_eq!(settings.password().unwrap().expose(), "first-pass");
+
+        // a rotated password is picked up without reloading the settings
+        fs::write(&secret, "second-pass\n").unwrap();
+        assert_eq!(settings.password().unwrap().expose(), "second-pass");
+        assert!(!format!("{:?}", settings).contains("second-pass"));
+
+        let _ = fs::remove_file(&secret);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connectionsettings_builder_from_map_needs_one_password() {
+        let mut map: HashMap<String, String> = HashMap::from([
+            (String::from("server"), String::from("localhost")),
+            (String::from("user"), String::from("sa"))
+        ]);
+        assert!(matches!(ConnectionSettings::builder_from_map(&map), Err(Error::Config(_))));
+
+        map.insert(String::from("password_command"), String::from("echo pass"));
+        assert_eq!(
+            ConnectionSettings::builder_from_map(&map).unwrap().build().unwrap().password_source(),
+            &PasswordSource::Command(String::from("echo pass"))
+        );
+
+        map.insert(String::from("password"), String::from("pass"));
+        assert!(matches!(ConnectionSettings::builder_from_map(&map), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_connectionsettings_debug_hides_password() {
+        let settings: ConnectionSettings = ConnectionSettings::new("localhost", 1433, "sa", "hunter2");
+
+        assert!(!format!("{:?}", settings).contains("hunter2"));
+    }
+
+    fn environment(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        let vars: config::Map<String, String> = vars.iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect();
+        environment_settings(Some(vars)).unwrap()
+    }
+
+    #[test]
+    fn test_environment_settings_reads_prefixed_keys() {
+        let settings = environment(&[("SQL_API_SERVER", "env-db"), ("SQL_API_CONNECT_TIMEOUT", "9"), ("SQL_API_PROFILE", "prod"), ("HOME", "/home/me")]);
+
+        assert_eq!(settings.len(), 2);
+        assert_eq!(settings.get("server").map(|value| value.as_str()), Some("env-db"));
+        assert_eq!(settings.get("connect_timeout").map(|value| value.as_str()), Some("9"));
+    }
+
+    #[test]
+    fn test_connectionsettings_layered_environment_overrides_file() {
+        let path: PathBuf = temp_config("layered", PROFILES);
+
+        let mut settings: ConnectionSettings = ConnectionSettings::layered(
+            Some(&path),
+            Some("prod"),
+            environment(&[("SQL_API_PASSWORD", "env-pass"), ("SQL_API_DATABASE", "sales")])
+        ).unwrap();
+
+        assert_eq!(settings.server(), "db.example.com");
+        assert_eq!(settings.password().unwrap().expose(), "env-pass");
+        assert_eq!(settings.database(), Some("sales"));
+        assert_eq!(settings.source_of("server"), Some(SettingSource::File(path.clone())));
+        assert_eq!(settings.source_of("password"), Some(SettingSource::Environment(String::from("SQL_API_PASSWORD"))));
+        assert_eq!(settings.source_of("packet_size"), Some(SettingSource::Default));
+        assert_eq!(settings.source_of("nope"), None);
+
+        settings.update(|builder| builder.application_name("reports")).unwrap();
+        assert_eq!(settings.source_of("application_name"), Some(SettingSource::Code));
+
+        // the environment's password and database don't end up in the file
+        let saved: ConnectionSettings = ConnectionSettings::from_path(&path, Some("prod")).unwrap();
+        assert_eq!(saved.password().unwrap().expose(), "prod-pass");
+        assert_eq!(saved.database(), None);
+        assert_eq!(saved.application_name(), Some("reports"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connectionsettings_update_keeps_file_password_under_environment_password() {
+        let path: PathBuf = temp_config("env_password", "[connection_settings]\nserver = \"localhost\"\nuser = \"sa\"\npassword_file = \"/run/secrets/sql\"\n");
+
+        let mut settings: ConnectionSettings = ConnectionSettings::layered(
+            Some(&path),
+            None,
+            environment(&[("SQL_API_PASSWORD", "env-pass")])
+        ).unwrap();
+        settings.update(|builder| builder.database("sales")).unwrap();
+
+        let saved: ConnectionSettings = ConnectionSettings::from_path(&path, None).unwrap();
+        assert_eq!(saved.password_source(), &PasswordSource::File(PathBuf::from("/run/secrets/sql")));
+        assert_eq!(saved.database(), Some("sales"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connectionsettings_layered_needs_a_server() {
+        let settings = ConnectionSettings::layered(None, None, environment(&[("SQL_API_SERVER", "db"), ("SQL_API_USER", "sa"), ("SQL_API_PASSWORD", "pass")])).unwrap();
+        assert_eq!(settings.source_of("server"), Some(SettingSource::Environment(String::from("SQL_API_SERVER"))));
+        assert_eq!(settings.config_path(), None);
+
+        let result = ConnectionSettings::layered(None, None, environment(&[("SQL_API_USER", "sa")]));
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_config_file_candidates_follow_xdg() {
+        let vars: HashMap<&str, &str> = HashMap::from([("HOME", "/home/me"), ("XDG_CONFIG_DIRS", "/etc/a:/etc/b")]);
+        let candidates = config_file_candidates(|name| vars.get(name).map(|value| value.to_string()));
+
+        assert_eq!(candidates, vec![
+            PathBuf::from("config.toml"),
+            PathBuf::from("/home/me/.config/sql_api/config.toml"),
+            PathBuf::from("/etc/a/sql_api/config.toml"),
+            PathBuf::from("/etc/b/sql_api/config.toml")
+        ]);
+
+        let candidates = config_file_candidates(|name| (name == "XDG_CONFIG_HOME").then(|| String::from("/xdg")));
+        assert_eq!(candidates[1], PathBuf::from("/xdg/sql_api/config.toml"));
+        assert_eq!(candidates[2], PathBuf::from("/etc/xdg/sql_api/config.toml"));
+    }
+
+    #[test]
+    fn test_connectionsettings_from_connection_string_reads_ado_net() {
+        let settings = ConnectionSettings::from_connection_string(
+            "Server=tcp:db.example.com,14330;Initial Catalog=sales;User Id=app;Password='p;ss';Encrypt=true;TrustServerCertificate=yes;Application Name=reports;Connect Timeout=15;MultipleActiveResultSets=true"
+        ).unwrap();
+
+        assert_eq!(settings.server(), "db.example.com");
+        assert_eq!(settings.port(), 14330);
+        assert_eq!(settings.database(), Some("sales"));
+        assert_eq!(settings.user(), "app");
+        assert_eq!(settings.password().unwrap().expose(), "p;ss");
+        assert_eq!(settings.application_name(), Some("reports"));
+        assert_eq!(settings.connect_timeout(), Some(Duration::from_secs(15)));
+        assert!(settings.mars());
+        assert_eq!(settings.tls().encrypt, EncryptMode::On);
+        assert!(settings.tls().trust_server_certificate);
+    }
+
+    #[test]
+    fn test_connectionsettings_from_connection_string_reads_odbc() {
+        let settings = ConnectionSettings::from_connection_string(
+            "DRIVER={ODBC Driver 18 for SQL Server};SERVER=(local)\\SQLEXPRESS;DATABASE=master;UID=sa;PWD={a}}b}"
+        ).unwrap();
+
+        assert_eq!(settings.server(), "localhost");
+        assert_eq!(settings.instance(), Some("SQLEXPRESS"));
+        assert_eq!(settings.explicit_port(), None);
+        assert_eq!(settings.database(), Some("master"));
+        assert_eq!(settings.user(), "sa");
+        assert_eq!(settings.password().unwrap().expose(), "a}b");
+    }
+
+    #[test]
+    fn test_connectionsettings_from_connection_string_rejects_bad_values() {
+        for connection_string in ["Server=db,abc", "Server=np:db", "Server=db;Encrypt=sometimes", "Server=db;Connect Timeout=soon", "Server=db;Packet Size=1", "Server=db;Pooling=false", "User Id=sa"] {
+            let result = ConnectionSettings::from_connection_string(connection_string);
+            assert!(matches!(result, Err(Error::Config(_))), "{}", connection_string);
+        }
+    }
+
+    #[test]
+    fn test_connectionsettings_to_connection_string_masks_password() {
+        let settings = ConnectionSettings::from_connection_string("Server=db;Database=sales;User Id=sa;Password=secret;Application Name='a;b'").unwrap();
+        let rendered: String = settings.to_string();
 
-        let mut settings2: ConnectionSettings = ConnectionSettings::from_file();
-        assert_eq!(settings2.server, update_value);
+        assert_eq!(rendered, "Server=tcp:db,1433;Database=sales;User Id=sa;Password=********;Encrypt=off;Application Name=\"a;b\"");
+        assert!(!rendered.contains("secret"));
 
-        let _ = settings2.update("server", "localhost");
+        let reparsed = ConnectionSettings::from_connection_string(&rendered).unwrap();
+        assert_eq!(reparsed.application_name(), Some("a;b"));
+        assert_eq!(reparsed.server(), "db");
     }
 }
@@ -0,0 +1,291 @@
+use std::fmt;
+use crate::error::{Error, Result};
+
+// keywords whose values are kept out of Debug output
+const PASSWORD_KEYWORDS: [&str; 2] = ["password", "pwd"];
+
+/**
+ * Connection strings
+ *
+ * Handles both the ADO.NET style (`Server=tcp:host,1433;User Id=sa;Password=...`) and the
+ * ODBC style (`DRIVER={ODBC Driver 18 for SQL Server};SERVER=host;UID=sa;PWD=...`). The two
+ * only differ in how values are quoted:
+ *
+ * - ADO.NET values can be wrapped in single or double quotes, with the quote doubled to
+ *   escape it ('It''s'), and `==` in a keyword is a literal '='
+ * - ODBC values can be wrapped in braces, with '}}' for a literal '}'
+ *
+ * Keywords are case insensitive and if one appears twice the last value wins.
+ *
+ * https://learn.microsoft.com/en-us/dotnet/framework/data/adonet/connection-string-syntax
+ */
+#[derive(Clone, PartialEq, Default)]
+pub struct ConnectionString {
+    pairs: Vec<(String, String)>
+}
+impl ConnectionString {
+    pub fn new() -> ConnectionString {
+        ConnectionString {
+            pairs: Vec::new()
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<ConnectionString> {
+        let mut connection_string: ConnectionString = ConnectionString::new();
+        let mut parser: Parser = Parser::new(input);
+
+        while let Some(keyword) = parser.keyword()? {
+            let value: String = parser.value(&keyword)?;
+            connection_string.set(&keyword, &value);
+        }
+
+        Ok(connection_string)
+    }
+
+    /**
+     * Sets a keyword, replacing any earlier value for it.
+     */
+    pub fn set(&mut self, keyword: &str, value: &str) {
+        let keyword: String = normalise_keyword(keyword);
+        self.pairs.retain(|(existing, _)| *existing != keyword);
+        self.pairs.push((keyword, String::from(value)));
+    }
+
+    pub fn get(&self, keyword: &str) -> Option<&str> {
+        let keyword: String = normalise_keyword(keyword);
+        self.pairs.iter()
+            .find(|(existing, _)| *existing == keyword)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /**
+     * Keywords (lower cased, single spaced) and values in the order they were set.
+     */
+    pub fn pairs(&self) -> &[(String, String)] {
+        &self.pairs
+    }
+}
+
+impl fmt::Debug for ConnectionString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pairs: Vec<(&str, &str)> = self.pairs.iter()
+            .map(|(keyword, value)| {
+                let value: &str = if PASSWORD_KEYWORDS.contains(&keyword.as_str()) { "********" } else { value };
+                (keyword.as_str(), value)
+            })
+            .collect();
+
+        f.debug_struct("ConnectionString").field("pairs", &pairs).finish()
+    }
+}
+
+/**
+ * Quotes a value if it would otherwise be read back differently, i.e. it has a ';', a quote
+ * or leading / trailing whitespace.
+ */
+pub fn quote_value(value: &str) -> String {
+    let needs_quotes: bool = value.contains([';', '\'', '"'])
+        || value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace);
+
+    if !needs_quotes {
+        return String::from(value);
+    }
+
+    if value.contains('"') && !value.contains('\'') {
+        format!("'{}'", value)
+    } else {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }
+}
+
+fn normalise_keyword(keyword: &str) -> String {
+    keyword.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize
+}
+impl Parser {
+    fn new(input: &str) -> Parser {
+        Parser {
+            chars: input.chars().collect(),
+            position: 0
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    /**
+     * Reads up to and including the '=' after a keyword, skipping empty entries (";;").
+     * None at the end of the string.
+     */
+    fn keyword(&mut self) -> Result<Option<String>> {
+        while self.peek().is_some_and(|c| c == ';' || c.is_whitespace()) {
+            self.position += 1;
+        }
+
+        if self.peek().is_none() {
+            return Ok(None);
+        }
+
+        let mut keyword: String = String::new();
+        loop {
+            match self.peek() {
+                Some('=') if self.chars.get(self.position + 1) == Some(&'=') => {
+                    keyword.push('=');
+                    self.position += 2;
+                },
+                Some('=') => {
+                    self.position += 1;
+                    break;
+                },
+                Some(';') | None => {
+                    return Err(Error::Config(format!("Expected '=' after keyword '{}' in connection string", keyword.trim())));
+                },
+                Some(c) => {
+                    keyword.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+
+        let keyword: &str = keyword.trim();
+        if keyword.is_empty() {
+            return Err(Error::Config(String::from("Missing keyword before '=' in connection string")));
+        }
+
+        Ok(Some(String::from(keyword)))
+    }
+
+    fn value(&mut self, keyword: &str) -> Result<String> {
+        self.skip_whitespace();
+
+        let value: String = match self.peek() {
+            Some(quote @ ('\'' | '"')) => self.quoted(keyword, quote)?,
+            Some('{') => self.quoted(keyword, '}')?,
+            _ => {
+                let start: usize = self.position;
+                while self.peek().is_some_and(|c| c != ';') {
+                    self.position += 1;
+                }
+                return Ok(self.chars[start..self.position].iter().collect::<String>().trim().to_string());
+            }
+        };
+
+        // nothing but whitespace is allowed between the closing quote and the next ';'
+        self.skip_whitespace();
+        match self.peek() {
+            Some(';') | None => Ok(value),
+            Some(c) => Err(Error::Config(format!("Unexpected '{}' after the quoted value of '{}' in connection string", c, keyword)))
+        }
+    }
+
+    /**
+     * A value between an opening quote (or brace) and `close`, where a doubled `close` is a
+     * literal one.
+     */
+    fn quoted(&mut self, keyword: &str, close: char) -> Result<String> {
+        let mut value: String = String::new();
+        self.position += 1;
+
+        loop {
+            match self.peek() {
+                Some(c) if c == close && self.chars.get(self.position + 1) == Some(&close) => {
+                    value.push(close);
+                    self.position += 2;
+                },
+                Some(c) if c == close => {
+                    self.position += 1;
+                    break;
+                },
+                Some(c) => {
+                    value.push(c);
+                    self.position += 1;
+                },
+                None => return Err(Error::Config(format!("Unterminated quoted value for '{}' in connection string", keyword)))
+            }
+        }
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connectionstring_debug_hides_passwords() {
+        let parsed = ConnectionString::parse("Server=db;Password=secret-1;PWD=secret-2;User Id=sa").unwrap();
+
+        let debug: String = format!("{:?}", parsed);
+
+        assert!(!debug.contains("secret"));
+        assert!(debug.contains("\"server\", \"db\"") && debug.contains("\"user id\", \"sa\""));
+    }
+
+    #[test]
+    fn test_connectionstring_parse_reads_pairs() {
+        let parsed = ConnectionString::parse("Server=tcp:db,1433; User  ID = sa ;;Password=pass;").unwrap();
+
+        assert_eq!(parsed.get("server"), Some("tcp:db,1433"));
+        assert_eq!(parsed.get("user id"), Some("sa"));
+        assert_eq!(parsed.get("PASSWORD"), Some("pass"));
+        assert_eq!(parsed.pairs().len(), 3);
+    }
+
+    #[test]
+    fn test_connectionstring_parse_handles_quoting() {
+        let parsed = ConnectionString::parse("Password='a;b''c';Application Name=\"say \"\"hi\"\"\";key==name=x").unwrap();
+
+        assert_eq!(parsed.get("password"), Some("a;b'c"));
+        assert_eq!(parsed.get("application name"), Some("say \"hi\""));
+        assert_eq!(parsed.get("key=name"), Some("x"));
+    }
+
+    #[test]
+    fn test_connectionstring_parse_handles_odbc_braces() {
+        let parsed = ConnectionString::parse("DRIVER={ODBC Driver 18 for SQL Server};PWD={p;w}}d};UID=sa").unwrap();
+
+        assert_eq!(parsed.get("driver"), Some("ODBC Driver 18 for SQL Server"));
+        assert_eq!(parsed.get("pwd"), Some("p;w}d"));
+        assert_eq!(parsed.get("uid"), Some("sa"));
+    }
+
+    #[test]
+    fn test_connectionstring_parse_last_value_wins() {
+        let parsed = ConnectionString::parse("Database=one;database=two").unwrap();
+
+        assert_eq!(parsed.get("Database"), Some("two"));
+        assert_eq!(parsed.pairs().len(), 1);
+    }
+
+    #[test]
+    fn test_connectionstring_parse_rejects_malformed_input() {
+        assert!(matches!(ConnectionString::parse("Server"), Err(Error::Config(_))));
+        assert!(matches!(ConnectionString::parse("=value"), Err(Error::Config(_))));
+        assert!(matches!(ConnectionString::parse("Password='open"), Err(Error::Config(_))));
+        assert!(matches!(ConnectionString::parse("Password='a'b;"), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_quote_value_round_trips() {
+        for value in ["plain", "a;b", "it's", "say \"hi\"", "both ' and \"", " padded "] {
+            let parsed = ConnectionString::parse(&format!("Password={}", quote_value(value))).unwrap();
+            assert_eq!(parsed.get("password"), Some(value));
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt;
+use std::io;
+use crate::tds_token::ServerMessage;
+
+/**
+ * Everything that can go wrong talking to SQL Server
+ *
+ * Login and Server carry the ERROR token the server sent, so callers can look at the
+ * error number (e.g. 18456 for a bad password, 1205 for a deadlock victim) rather than
+ * the message text.
+ */
+#[derive(Debug)]
+pub enum Error {
+    Io { context: String, source: io::Error },
+    Config(String),
+    Protocol(String),
+    Tls(String),
+    Login(ServerMessage),
+    Server(ServerMessage),
+    Timeout(String),
+    Cancelled,
+    NotConnected,
+    NotLoggedIn
+}
+impl Error {
+    pub fn io(context: &str, source: io::Error) -> Error {
+        Error::Io {
+            context: String::from(context),
+            source
+        }
+    }
+
+    /**
+     * A Timeout, or a socket read / write that ran out of time.
+     */
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout(_) => true,
+            Error::Io { source, .. } => matches!(source.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock),
+            _ => false
+        }
+    }
+
+    /**
+     * The server's ERROR token, for login and server errors.
+     */
+    pub fn server_message(&self) -> Option<&ServerMessage> {
+        match self {
+            Error::Login(message) | Error::Server(message) => Some(message),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::Config(message) => write!(f, "Configuration error: {}", message),
+            Error::Protocol(message) => write!(f, "Protocol error: {}", message),
+            Error::Tls(message) => write!(f, "TLS error: {}", message),
+            Error::Login(message) => write!(f, "Login failed: {}", message.message),
+            Error::Server(message) => write!(f, "Server error {}: {}", message.number, message.message),
+            Error::Timeout(message) => write!(f, "Timed out: {}", message),
+            Error::Cancelled => write!(f, "The command was cancelled"),
+            Error::NotConnected => write!(f, "Not connected to server. Please call connect first"),
+            Error::NotLoggedIn => write!(f, "Not logged in. Please call login first")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_message(number: i32) -> ServerMessage {
+        ServerMessage {
+            number,
+            state: 1,
+            class: 14,
+            message: String::from("Login failed for user 'sa'."),
+            server: String::from("db"),
+            procedure: String::new(),
+            line: 1
+        }
+    }
+
+    #[test]
+    fn test_error_display_describes_each_kind() {
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+
+        assert_eq!(Error::io("Failed to connect", refused).to_string(), "Failed to connect: refused");
+        assert_eq!(Error::Protocol(String::from("bad token")).to_string(), "Protocol error: bad token");
+        assert_eq!(Error::Login(server_message(18456)).to_string(), "Login failed: Login failed for user 'sa'.");
+        assert_eq!(Error::Server(server_message(18456)).to_string(), "Server error 18456: Login failed for user 'sa'.");
+    }
+
+    #[test]
+    fn test_error_source_and_server_message() {
+        let error = Error::io("Failed to read packet header", io::Error::from(io::ErrorKind::UnexpectedEof));
+
+        assert!(std::error::Error::source(&error).is_some());
+        assert!(error.server_message().is_none());
+        assert_eq!(Error::Server(server_message(208)).server_message().unwrap().number, 208);
+    }
+}
@@ -1,3 +1,29 @@
+#[cfg(feature = "tokio")]
+pub mod async_connector;
+#[cfg(feature = "tokio")]
+pub mod async_row_stream;
+#[cfg(feature = "tokio")]
+pub mod async_tds_stream;
+pub mod browser;
+pub mod bulk_load;
 pub mod connection_settings;
+pub mod connection_string;
+pub mod error;
+pub mod login7;
 pub mod ocbd;
-pub mod tds_message;
\ No newline at end of file
+pub mod pool;
+pub mod query_result;
+pub mod row_stream;
+pub mod secret;
+mod session;
+pub mod smp;
+pub mod sql_value;
+pub mod tds_message;
+pub mod tds_stream;
+pub mod tds_token;
+pub mod tds_type;
+pub mod tls;
+pub mod transaction;
+
+#[cfg(test)]
+mod test_server;
\ No newline at end of file
@@ -0,0 +1,246 @@
+use zeroize::Zeroize;
+use crate::secret::Secret;
+use crate::tds_message::{ucs2_bytes, DEFAULT_PACKET_SIZE};
+
+/**
+ * LOGIN7 message
+ *
+ * Sent after PRELOGIN to authenticate with a SQL Server login. The message is a fixed
+ * 94 byte block followed by the variable length data (UCS-2 strings). The fixed block holds
+ * an offset/length pair for every string, offsets from the start of the message and lengths
+ * in characters.
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/773a62b6-ee89-4c02-9e5e-344882630aac
+ */
+const FIXED_LENGTH: usize = 94;
+
+pub struct Login7 {
+    pub tds_version: TdsVersion,
+    pub packet_size: u32,
+    pub client_prog_version: u32,
+    pub client_pid: u32,
+    pub connection_id: u32,
+    pub option_flags_1: u8,
+    pub option_flags_2: u8,
+    pub type_flags: u8,
+    pub option_flags_3: u8,
+    pub client_timezone: i32,
+    pub client_lcid: u32,
+    pub hostname: String,
+    pub username: String,
+    pub password: Secret,
+    pub app_name: String,
+    pub server_name: String,
+    pub library_name: String,
+    pub language: String,
+    pub database: String,
+    pub client_id: [u8; 6]
+}
+impl Login7 {
+    pub fn new(username: &str, password: &str, server_name: &str, database: &str) -> Login7 {
+        Login7 {
+            tds_version: TdsVersion::Tds74,
+            packet_size: DEFAULT_PACKET_SIZE,
+            client_prog_version: 0x00000001,
+            client_pid: std::process::id(),
+            connection_id: 0,
+            option_flags_1: OptionFlags1::UseDbWarning.value()
+                | OptionFlags1::InitDbFatal.value()
+                | OptionFlags1::SetLangWarning.value(),
+            option_flags_2: OptionFlags2::InitLangFatal.value() | OptionFlags2::Odbc.value(),
+            type_flags: 0x00,
+            option_flags_3: 0x00,
+            client_timezone: 0,
+            client_lcid: 0x00000409,
+            hostname: Login7::client_hostname(),
+            username: String::from(username),
+            password: Secret::new(password),
+            app_name: String::from("sql_connector"),
+            server_name: String::from(server_name),
+            library_name: String::from("sql_connector"),
+            language: String::new(),
+            database: String::from(database),
+            client_id: [0; 6]
+        }
+    }
+
+    fn client_hostname() -> String {
+        std::env::var("COMPUTERNAME")
+            .or_else(|_| std::env::var("HOSTNAME"))
+            .unwrap_or_else(|_| String::from("localhost"))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut fixed: Vec<u8> = Vec::with_capacity(FIXED_LENGTH);
+        let mut data: Vec<u8> = Vec::new();
+
+        fixed.extend_from_slice(&[0; 4]); // total length, filled in at the end
+        fixed.extend_from_slice(&self.tds_version.value().to_le_bytes());
+        fixed.extend_from_slice(&self.packet_size.to_le_bytes());
+        fixed.extend_from_slice(&self.client_prog_version.to_le_bytes());
+        fixed.extend_from_slice(&self.client_pid.to_le_bytes());
+        fixed.extend_from_slice(&self.connection_id.to_le_bytes());
+        fixed.push(self.option_flags_1);
+        fixed.push(self.option_flags_2);
+        fixed.push(self.type_flags);
+        fixed.push(self.option_flags_3);
+        fixed.extend_from_slice(&self.client_timezone.to_le_bytes());
+        fixed.extend_from_slice(&self.client_lcid.to_le_bytes());
+
+        let mut password: Vec<u8> = Login7::obfuscate_password(self.password.expose());
+
+        Login7::add_variable(&mut fixed, &mut data, &ucs2_bytes(&self.hostname));
+        Login7::add_variable(&mut fixed, &mut data, &ucs2_bytes(&self.username));
+        Login7::add_variable(&mut fixed, &mut data, &password);
+        Login7::add_variable(&mut fixed, &mut data, &ucs2_bytes(&self.app_name));
+        Login7::add_variable(&mut fixed, &mut data, &ucs2_bytes(&self.server_name));
+        Login7::add_variable(&mut fixed, &mut data, &[]); // unused / feature extension
+        Login7::add_variable(&mut fixed, &mut data, &ucs2_bytes(&self.library_name));
+        Login7::add_variable(&mut fixed, &mut data, &ucs2_bytes(&self.language));
+        Login7::add_variable(&mut fixed, &mut data, &ucs2_bytes(&self.database));
+
+        fixed.extend_from_slice(&self.client_id);
+        password.zeroize();
+
+        Login7::add_variable(&mut fixed, &mut data, &[]); // SSPI
+        Login7::add_variable(&mut fixed, &mut data, &[]); // attach db file
+        Login7::add_variable(&mut fixed, &mut data, &[]); // change password
+        fixed.extend_from_slice(&0u32.to_le_bytes()); // cbSSPILong
+
+        let length = (fixed.len() + data.len()) as u32;
+        fixed[0..4].copy_from_slice(&length.to_le_bytes());
+        fixed.extend_from_slice(&data);
+
+        fixed
+    }
+
+    /**
+     * Appends the offset/length pair to the fixed block and the value to the data block.
+     * The length is in UCS-2 characters, not bytes.
+     */
+    fn add_variable(fixed: &mut Vec<u8>, data: &mut Vec<u8>, value: &[u8]) {
+        let offset = (FIXED_LENGTH + data.len()) as u16;
+        let length = (value.len() / 2) as u16;
+
+        fixed.extend_from_slice(&offset.to_le_bytes());
+        fixed.extend_from_slice(&length.to_le_bytes());
+        data.extend_from_slice(value);
+    }
+
+    /**
+     * The password isn't encrypted, just scrambled: swap the nibbles of every byte of
+     * the UCS-2 string then XOR with 0xA5.
+     */
+    fn obfuscate_password(password: &str) -> Vec<u8> {
+        // scrambled in place so there's no plain UCS-2 copy left behind
+        let mut bytes: Vec<u8> = ucs2_bytes(password);
+        for byte in bytes.iter_mut() {
+            *byte = byte.rotate_right(4) ^ 0xa5;
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TdsVersion {
+    Tds71,
+    Tds72,
+    Tds73,
+    Tds74
+}
+impl TdsVersion {
+    pub fn value(&self) -> u32 {
+        match self {
+            TdsVersion::Tds71 => 0x71000001,
+            TdsVersion::Tds72 => 0x72090002,
+            TdsVersion::Tds73 => 0x730B0003,
+            TdsVersion::Tds74 => 0x74000004
+        }
+    }
+}
+
+pub enum OptionFlags1 {
+    UseDbWarning,
+    InitDbFatal,
+    SetLangWarning
+}
+impl OptionFlags1 {
+    pub fn value(&self) -> u8 {
+        match self {
+            OptionFlags1::UseDbWarning => 0x20,
+            OptionFlags1::InitDbFatal => 0x40,
+            OptionFlags1::SetLangWarning => 0x80
+        }
+    }
+}
+
+pub enum OptionFlags2 {
+    InitLangFatal,
+    Odbc,
+    IntegratedSecurity
+}
+impl OptionFlags2 {
+    pub fn value(&self) -> u8 {
+        match self {
+            OptionFlags2::InitLangFatal => 0x01,
+            OptionFlags2::Odbc => 0x02,
+            OptionFlags2::IntegratedSecurity => 0x80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_pair(bytes: &[u8], position: usize) -> (usize, usize) {
+        let offset = u16::from_le_bytes([bytes[position], bytes[position + 1]]) as usize;
+        let length = u16::from_le_bytes([bytes[position + 2], bytes[position + 3]]) as usize;
+        (offset, length)
+    }
+
+    #[test]
+    fn test_login7_obfuscate_password_scrambles_bytes() {
+        let password = Login7::obfuscate_password("a");
+
+        assert_eq!(password, vec![0xb3, 0xa5]);
+    }
+
+    #[test]
+    fn test_login7_tobytes_writes_fixed_block() {
+        let login = Login7::new("sa", "pass", "localhost", "sample");
+
+        let bytes = login.to_bytes();
+
+        assert_eq!(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize, bytes.len());
+        assert_eq!(&bytes[4..8], &[0x04, 0x00, 0x00, 0x74]);
+        assert_eq!(&bytes[8..12], &4096u32.to_le_bytes());
+        assert_eq!(bytes[24], 0xe0);
+        assert_eq!(bytes[25], 0x03);
+    }
+
+    #[test]
+    fn test_login7_tobytes_writes_offsets_and_strings() {
+        let mut login = Login7::new("sa", "pass", "localhost", "sample");
+        login.hostname = String::from("box");
+
+        let bytes = login.to_bytes();
+
+        let (host_offset, host_length) = read_pair(&bytes, 36);
+        assert_eq!(host_offset, FIXED_LENGTH);
+        assert_eq!(host_length, 3);
+        assert_eq!(&bytes[host_offset..host_offset + 6], &ucs2_bytes("box"));
+
+        let (user_offset, user_length) = read_pair(&bytes, 40);
+        assert_eq!(user_offset, FIXED_LENGTH + 6);
+        assert_eq!(&bytes[user_offset..user_offset + user_length * 2], &ucs2_bytes("sa"));
+
+        let (pass_offset, pass_length) = read_pair(&bytes, 44);
+        assert_eq!(pass_length, 4);
+        assert_eq!(&bytes[pass_offset..pass_offset + 8], &Login7::obfuscate_password("pass")[..]);
+
+        let (db_offset, db_length) = read_pair(&bytes, 68);
+        assert_eq!(&bytes[db_offset..db_offset + db_length * 2], &ucs2_bytes("sample"));
+        assert_eq!(db_offset + db_length * 2, bytes.len());
+    }
+}
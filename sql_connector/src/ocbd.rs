@@ -1,7 +1,21 @@
-use std::io::{Write, Read};
-use std::net::{TcpStream, Shutdown};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::bulk_load::{self, BulkLoad, BulkLoadOptions};
 use crate::connection_settings::ConnectionSettings;
-use crate::tds_message::TdsMessage;
+use crate::session::{self, Session};
+use crate::tds_message::{TdsMessage, PreLoginResponse, TransactionRequest, PacketWriter, ClientMessageType};
+use crate::tds_token::{LoginAck, ServerMessage};
+use crate::query_result::QueryResult;
+use crate::row_stream::RowStream;
+use crate::tds_stream::{TdsStream, TdsWriter};
+use crate::sql_value::SqlValue;
+use crate::tls::Encryption;
+use crate::transaction::{Transaction, IsolationLevel};
+use crate::error::{Error, Result};
+
 /**
  * OCDB Driver
  * 
@@ -10,42 +24,75 @@ use crate::tds_message::TdsMessage;
  * 
  * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/893fcc7e-8a39-4b3c-815a-773b7b982c50
  */
-
-struct Connector {
-    database: String,
-    settings: ConnectionSettings,
-    stream: Option<TcpStream>,
-    authenticated: bool
+pub struct Connector {
+    session: Session,
+    stream: Option<TdsStream>,
+    cancelled: Arc<AtomicBool>
 }
 
 impl Connector {
-    pub fn new(db_name: &str) -> Connector {
-        let settings = ConnectionSettings::from_file();
-        
+    /**
+     * The server, port, database etc. all come from the settings, build them with
+     * ConnectionSettings::builder() or load them with one of its from_* functions.
+     * Nothing happens on the network until connect.
+     */
+    pub fn new(settings: ConnectionSettings) -> Connector {
         Connector {
-            database: String::from(db_name),
-            settings,
+            session: Session::new(settings),
             stream: None,
-            authenticated: false
+            cancelled: Arc::new(AtomicBool::new(false))
         }
     }
 
-    pub fn connect(&mut self) -> Result<bool, String> {
-        let server = self.settings.get("server");
-        let port = self.settings.get("port");
+    /**
+     * Settings from the config file and SQL_API_* environment variables.
+     */
+    pub fn from_config() -> Result<Connector> {
+        Ok(Connector::new(ConnectionSettings::from_file()?))
+    }
+
+    /**
+     * Like from_config, but with one of the named profiles from the config file.
+     */
+    pub fn with_profile(profile: &str) -> Result<Connector> {
+        Ok(Connector::new(ConnectionSettings::load(Some(profile))?))
+    }
+
+    pub fn settings(&self) -> &ConnectionSettings {
+        self.session.settings()
+    }
+
+    /**
+     * Tries each of the server's addresses in turn (e.g. IPv6 and IPv4 for localhost), giving
+     * each one the connect timeout.
+     */
+    pub fn connect(&mut self) -> Result<bool> {
+        let server: &str = self.settings().server();
+        let port: u16 = session::server_port(self.settings())?;
 
-        let addr = format!("{server}:{port}");
+        let addresses: Vec<SocketAddr> = (server, port).to_socket_addrs()
+            .map_err(|e| Error::io("Failed to resolve the server address", e))?
+            .collect();
 
-        let stream = TcpStream::connect(addr);
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("No address found for {}", server));
+        for address in addresses {
+            let stream = match self.settings().connect_timeout() {
+                Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+                None => TcpStream::connect(address)
+            };
 
-        match stream {
-            Ok(_) => self.save_connection(stream.unwrap()), // Connection successful
-            Err(err) => Err(format!("Failed to connect: {}", err))
+            match stream {
+                Ok(stream) => return self.save_connection(stream), // Connection successful
+                Err(err) => last_error = err
+            }
         }
+
+        Err(Error::io("Failed to connect", last_error))
     }
 
-    fn save_connection(&mut self, stream: TcpStream) -> Result<bool, String> {
-        self.stream = Some(stream);
+    fn save_connection(&mut self, stream: TcpStream) -> Result<bool> {
+        self.stream = Some(TdsStream::new(stream));
+        self.cancelled.store(false, Ordering::SeqCst);
         Ok(true)
     } 
 
@@ -54,59 +101,664 @@ impl Connector {
     }
 
     pub fn get_stream(&mut self) -> TcpStream {
-        let stream: TcpStream = self.stream.take().expect("No active stream");
-        stream
-    } 
+        let stream: TdsStream = self.stream.take().expect("No active stream");
+        stream.into_socket()
+    }
+
+    pub fn encryption(&self) -> Encryption {
+        self.session.encryption()
+    }
+
+    /**
+     * PRELOGIN exchange. If the client and server settle on encryption the TLS handshake
+     * happens here too, so everything from LOGIN7 on goes over TLS.
+     */
+    pub fn authenticate(&mut self) -> Result<bool> {
+        let deadline: Option<Instant> = self.login_deadline();
+        self.within_login_timeout(|con| con.prelogin(deadline))?;
+
+        Ok(true)
+    }
+
+    /**
+     * Sends LOGIN7 with the user and password from the settings, doing the PRELOGIN
+     * exchange first if it hasn't happened yet. Only a LOGINACK from the server counts
+     * as being logged in.
+     *
+     * The login timeout covers PRELOGIN and TLS too. If it runs out the connection is closed.
+     */
+    pub fn login(&mut self) -> Result<bool> {
+        let deadline: Option<Instant> = self.login_deadline();
+
+        self.within_login_timeout(|con| {
+            if con.session.prelogin_response().is_none() {
+                con.prelogin(deadline)?;
+            }
+
+            let mut message: TdsMessage = con.session.login_message()?;
+            con.set_deadline(deadline)?;
+            con.write_message(&mut message)?;
+
+            // with login only encryption the server answers LOGIN7 in plain text
+            if con.encryption() == Encryption::LoginOnly {
+                con.stream.as_mut().ok_or(Error::NotConnected)?.stop_tls();
+            }
+
+            con.set_deadline(deadline)?;
+            let response: TdsMessage = con.read_message()?;
+            con.session.read_login_response(response.body())?;
 
-    pub fn authenticate(&mut self) -> Result<bool, String> {
+            // SMP starts once the login is done
+            if con.session.is_mars() {
+                let packet_size: u32 = con.session.packet_size();
+                con.stream.as_mut().ok_or(Error::NotConnected)?.start_mars(packet_size)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(true)
+    }
+
+    fn prelogin(&mut self, deadline: Option<Instant>) -> Result<()> {
         if !self.is_connected() {
-            return Err(format!("Not connected to server. Please call connect first"));
+            return Err(Error::NotConnected);
         }
-        
-        let mut message: TdsMessage = TdsMessage::new();
 
-        message.generate_prelogin();
-        message.calc_length();
+        let mut message: TdsMessage = self.session.prelogin_message();
+        self.set_deadline(deadline)?;
+        let response: TdsMessage = self.send_message(&mut message)?;
+
+        if self.session.read_prelogin(response.body())? != Encryption::None {
+            let (client_config, server_name) = self.session.tls_parameters()?;
 
-        let bytes:Vec<u8> = message.to_bytes();
-        for byte in &bytes {
-            print!("0x{:02X} ", byte);
+            self.set_deadline(deadline)?;
+            let stream: &mut TdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+            stream.start_tls(client_config, server_name)?;
         }
-        println!();
 
-        let mut stream: TcpStream = self.stream.take().ok_or("No active stream")?;
+        Ok(())
+    }
 
-        let result = stream.write_all(&bytes).map_err(|e| format!("Failed to write to steam: {}", e));
-        println!("result: {:?}", result);
+    fn login_deadline(&self) -> Option<Instant> {
+        self.settings().login_timeout().map(|timeout| Instant::now() + timeout)
+    }
 
-        let mut read_buffer = [0u8; 1024];
-        let size = stream.read(&mut read_buffer).map_err(|e| e.to_string())?;
+    /**
+     * Runs part of the login, turning a timed out read or write into a Timeout and closing
+     * the connection, as there's no telling where the server is up to.
+     */
+    fn within_login_timeout<F>(&mut self, login: F) -> Result<()>
+    where
+        F: FnOnce(&mut Connector) -> Result<()>
+    {
+        let result: Result<()> = login(self);
 
-        println!("response: {:?}, size: {size}", &read_buffer[..size]);
-        
+        match result {
+            Err(error) if error.is_timeout() => {
+                self.stream = None;
+                Err(Error::Timeout(format!("Login didn't finish within {:?}", self.settings().login_timeout().unwrap_or_default())))
+            },
+            Err(error) => Err(error),
+            Ok(()) => self.set_timeout(None)
+        }
+    }
+
+    /**
+     * Another connection to the server over the same socket, for running a statement while
+     * this one is busy, e.g. from another thread. Needs MARS to have been asked for (see
+     * ConnectionSettings::mars) and the server to have agreed.
+     *
+     * The new Connector is already logged in and joins any open transaction. Dropping it
+     * closes its session, the socket stays open until every session is gone.
+     */
+    pub fn open_session(&self) -> Result<Connector> {
+        if !self.is_authenticated() {
+            return Err(Error::NotLoggedIn);
+        }
+
+        let stream: &TdsStream = self.stream.as_ref().ok_or(Error::NotConnected)?;
+
+        Ok(Connector {
+            session: self.session.mars_session(),
+            stream: Some(stream.open_session()?),
+            cancelled: Arc::new(AtomicBool::new(false))
+        })
+    }
+
+    pub fn is_mars(&self) -> bool {
+        self.stream.as_ref().is_some_and(|stream| stream.is_mars())
+    }
+
+    pub fn login_ack(&self) -> Option<&LoginAck> {
+        self.session.login_ack()
+    }
+
+    pub fn current_database(&self) -> Option<&str> {
+        self.session.current_database()
+    }
+
+    pub fn packet_size(&self) -> u32 {
+        self.session.packet_size()
+    }
+
+    /**
+     * INFO messages the server has sent so far, e.g. "Changed database context to ...".
+     */
+    pub fn messages(&self) -> &[ServerMessage] {
+        self.session.messages()
+    }
+
+    /**
+     * Runs a batch and returns the total number of rows it affected.
+     */
+    pub fn execute(&mut self, sql: &str) -> Result<u64> {
+        Ok(self.query(sql)?.total_rows_affected())
+    }
+
+    /**
+     * Runs a batch and collects every result set it produces.
+     */
+    pub fn query(&mut self, sql: &str) -> Result<QueryResult> {
+        let mut message: TdsMessage = self.session.sql_batch_message(sql)?;
+
+        let response: TdsMessage = self.send_command(&mut message)?;
+        self.session.read_query_result(response.body())
+    }
+
+    /**
+     * Runs a parameterised statement through sp_executesql and returns the number of rows
+     * it affected. Refer to the parameters as @P1, @P2, ... in `sql`.
+     */
+    pub fn execute_params(&mut self, sql: &str, params: &[SqlValue]) -> Result<u64> {
+        Ok(self.query_params(sql, params)?.total_rows_affected())
+    }
+
+    /**
+     * Like query, but the values are sent separately from the SQL as typed parameters
+     * (@P1, @P2, ...) so they never need escaping.
+     */
+    pub fn query_params(&mut self, sql: &str, params: &[SqlValue]) -> Result<QueryResult> {
+        let mut message: TdsMessage = self.session.sp_executesql_message(sql, params)?;
+
+        let response: TdsMessage = self.send_command(&mut message)?;
+        self.session.read_query_result(response.body())
+    }
+
+    /**
+     * Runs a batch and hands its rows over as they arrive rather than collecting them, for
+     * results too big to hold. The connection is busy until the RowStream is dropped.
+     */
+    pub fn query_stream(&mut self, sql: &str) -> Result<RowStream<'_>> {
+        let mut message: TdsMessage = self.session.sql_batch_message(sql)?;
+        RowStream::start(self, &mut message)
+    }
+
+    /**
+     * query_stream with parameters, see query_params.
+     */
+    pub fn query_params_stream(&mut self, sql: &str, params: &[SqlValue]) -> Result<RowStream<'_>> {
+        let mut message: TdsMessage = self.session.sp_executesql_message(sql, params)?;
+        RowStream::start(self, &mut message)
+    }
+
+    /**
+     * Sends a request whose response a RowStream reads, handing it the stream to read from.
+     * The command timeout applies to every read until it's given back with end_stream.
+     */
+    pub(crate) fn start_stream(&mut self, message: &mut TdsMessage) -> Result<TdsStream> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            self.finish_cancel(None)?;
+        }
+
+        let timeout: Option<Duration> = self.settings().command_timeout();
+        self.set_timeout(timeout)?;
+
+        match self.write_message(message) {
+            Err(error) if error.is_timeout() => return Err(self.cancel_after_timeout(timeout.unwrap_or_default())),
+            result => result?
+        }
+
+        self.stream.take().ok_or(Error::NotConnected)
+    }
+
+    /**
+     * Takes the stream back once the whole response has been read. A cancel that came in
+     * meanwhile is finished here, true if there was one.
+     */
+    pub(crate) fn end_stream(&mut self, stream: TdsStream, attention_acknowledged: bool) -> Result<bool> {
+        self.stream = Some(stream);
+
+        if !self.cancelled.load(Ordering::SeqCst) {
+            self.set_timeout(None)?;
+            return Ok(false);
+        }
+
+        if attention_acknowledged {
+            self.cancelled.store(false, Ordering::SeqCst);
+            self.set_timeout(None)?;
+        } else {
+            self.finish_cancel(None)?;
+        }
         Ok(true)
     }
+
+    /**
+     * For a read that failed part way through a response. A timeout cancels the command
+     * like send_command does, anything else leaves the connection closed.
+     */
+    pub(crate) fn abort_stream(&mut self, stream: TdsStream, error: Error) -> Error {
+        if !error.is_timeout() {
+            return error;
+        }
+
+        self.stream = Some(stream);
+        let timeout: Duration = self.settings().command_timeout().unwrap_or_default();
+        self.cancel_after_timeout(timeout)
+    }
+
+    pub(crate) fn session_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    /**
+     * Loads rows into `table` with INSERT BULK and returns how many were copied. `table` can
+     * have a schema and database in front (e.g. `dbo.people`), each part is quoted, so use
+     * brackets for a name with a dot in it. `columns` names the destination columns the
+     * values in each row are for, in order, and each value is converted to its column's
+     * type before it's sent.
+     *
+     * The rows are sent as they come out of the iterator, so they never all need to be in
+     * memory. A row that can't be converted stops the load with Error::Config, and whatever
+     * had been sent of the current batch is thrown away by the server. Don't cancel a bulk
+     * insert with a CancelHandle while rows are still being sent.
+     */
+    pub fn bulk_insert<I>(&mut self, table: &str, columns: &[&str], rows: I, options: &BulkLoadOptions) -> Result<u64>
+    where
+        I: IntoIterator<Item = Vec<SqlValue>>
+    {
+        let names: Vec<String> = columns.iter().map(|column| bulk_load::quote_name(column)).collect();
+        let destination: QueryResult = self.query(&format!("SELECT TOP 0 {} FROM {}", names.join(", "), bulk_load::quote_table_name(table)?))?;
+        let metadata = destination.result_sets.into_iter().next().map(|result_set| result_set.columns).unwrap_or_default();
+        let bulk_load: BulkLoad = BulkLoad::new(table, metadata)?;
+
+        let batch_size: usize = options.batch_size.filter(|size| *size > 0).unwrap_or(usize::MAX);
+        let mut rows = rows.into_iter().peekable();
+        let mut copied: u64 = 0;
+
+        while rows.peek().is_some() {
+            self.execute(&bulk_load.statement(options))?;
+            copied += self.send_bulk_load(&bulk_load, rows.by_ref().take(batch_size))?;
+        }
+
+        Ok(copied)
+    }
+
+    /**
+     * Streams one batch of rows as a BULK_LOAD message, writing packets as they fill up,
+     * and reads back the number of rows copied.
+     */
+    fn send_bulk_load<I>(&mut self, bulk_load: &BulkLoad, rows: I) -> Result<u64>
+    where
+        I: Iterator<Item = Vec<SqlValue>>
+    {
+        let timeout: Option<Duration> = self.settings().command_timeout();
+        self.set_timeout(timeout)?;
+
+        let mut writer: PacketWriter = PacketWriter::new(ClientMessageType::BulkLoad, self.session.packet_size());
+        writer.write(&bulk_load.column_metadata());
+
+        for row in rows {
+            let bytes: Vec<u8> = match bulk_load.row(&row) {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    self.abandon_bulk_load(writer)?;
+                    return Err(error);
+                }
+            };
+
+            writer.write(&bytes);
+            let packets: Vec<Vec<u8>> = writer.full_packets();
+            self.write_bulk_packets(packets)?;
+        }
+
+        writer.write(&BulkLoad::done());
+        self.write_bulk_packets(writer.finish())?;
+
+        let response: TdsMessage = match self.read_message() {
+            Err(error) if error.is_timeout() => return Err(self.cancel_after_timeout(timeout.unwrap_or_default())),
+            result => result?
+        };
+        self.set_timeout(None)?;
+
+        Ok(self.session.read_query_result(response.body())?.total_rows_affected())
+    }
+
+    /**
+     * Has the server throw away a BULK_LOAD message that's been partly sent. It's still
+     * waiting for the rows after that, ATTENTION stops it.
+     */
+    fn abandon_bulk_load(&mut self, writer: PacketWriter) -> Result<()> {
+        if let Some(packet) = writer.abandon() {
+            self.write_bulk_packets(vec![packet])?;
+        }
+
+        self.send_attention()
+    }
+
+    /**
+     * Part of a message can't be taken back, so a connection that fails part way through
+     * one is closed.
+     */
+    fn write_bulk_packets(&mut self, packets: Vec<Vec<u8>>) -> Result<()> {
+        let stream: &mut TdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+
+        let result: Result<()> = packets.iter()
+            .try_for_each(|packet| stream.write_all(packet))
+            .and_then(|_| stream.flush())
+            .map_err(|e| Error::io("Failed to write to stream", e));
+
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.session.is_authenticated()
+    }
+
+    /**
+     * Whether the connection can take another request: still open and logged in, and not
+     * left part way through a packet by a failed read or write.
+     */
+    pub(crate) fn is_reusable(&self) -> bool {
+        self.is_authenticated() && self.stream.as_ref().is_some_and(|stream| stream.at_packet_boundary())
+    }
+
+    /**
+     * Starts a transaction that's rolled back when the returned guard is dropped, unless
+     * it's committed first.
+     */
+    pub fn transaction(&mut self, isolation: IsolationLevel) -> Result<Transaction<'_>> {
+        self.begin_transaction(isolation)?;
+        Ok(Transaction::new(self))
+    }
+
+    /**
+     * Starts a transaction with a TM_BEGIN_XACT request. Every request after this runs in
+     * it until commit or rollback. Prefer transaction(), which can't be left open by mistake.
+     */
+    pub fn begin_transaction(&mut self, isolation: IsolationLevel) -> Result<()> {
+        self.transaction_request(TransactionRequest::Begin(isolation))
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        self.transaction_request(TransactionRequest::Commit)
+    }
+
+    /**
+     * Rolls back the whole transaction, savepoints included.
+     */
+    pub fn rollback(&mut self) -> Result<()> {
+        self.transaction_request(TransactionRequest::Rollback(None))
+    }
+
+    /**
+     * Marks a point in the transaction that rollback_to can go back to.
+     */
+    pub fn savepoint(&mut self, name: &str) -> Result<()> {
+        self.transaction_request(TransactionRequest::Save(String::from(name)))
+    }
+
+    /**
+     * Undoes everything since the savepoint. The transaction itself stays open.
+     */
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        self.transaction_request(TransactionRequest::Rollback(Some(String::from(name))))
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.session.in_transaction()
+    }
+
+    fn transaction_request(&mut self, request: TransactionRequest) -> Result<()> {
+        let mut message: TdsMessage = self.session.transaction_message(&request)?;
+
+        let response: TdsMessage = self.send_command(&mut message)?;
+        self.session.read_query_result(response.body()).map(|_| ())
+    }
+
+    /**
+     * Has the server reset the session before the next request runs, see
+     * Session::reset_on_next_request. Used by the pool when a connection is returned.
+     */
+    pub fn reset_session(&mut self, skip_transaction: bool) {
+        self.session.reset_on_next_request(skip_transaction);
+    }
+
+    /**
+     * For cancelling a running query from another thread. Get it before starting the query,
+     * the query then fails with Error::Cancelled and the connection can be used again.
+     *
+     * The handle works for as long as this connection stays open.
+     */
+    pub fn cancel_handle(&self) -> Result<CancelHandle> {
+        let stream: &TdsStream = self.stream.as_ref().ok_or(Error::NotConnected)?;
+
+        Ok(CancelHandle {
+            writer: stream.writer()?,
+            attention: self.session.attention_message().to_packets(self.session.packet_size()).concat(),
+            cancelled: Arc::clone(&self.cancelled)
+        })
+    }
+
+    /**
+     * send_message with the command timeout. When it runs out the server is sent an
+     * ATTENTION so it stops, and everything up to its acknowledgement is thrown away, which
+     * leaves the connection ready for the next command.
+     */
+    fn send_command(&mut self, message: &mut TdsMessage) -> Result<TdsMessage> {
+        // a cancel that came in after the last command finished is still acknowledged
+        if self.cancelled.load(Ordering::SeqCst) {
+            self.finish_cancel(None)?;
+        }
+
+        let timeout: Option<Duration> = self.settings().command_timeout();
+        self.set_timeout(timeout)?;
+
+        let result: Result<TdsMessage> = self.send_message(message);
+        if self.cancelled.load(Ordering::SeqCst) {
+            let response: Option<TdsMessage> = result.ok();
+            self.finish_cancel(response)?;
+            return Err(Error::Cancelled);
+        }
+
+        match result {
+            Err(error) if error.is_timeout() => Err(self.cancel_after_timeout(timeout.unwrap_or_default())),
+            Err(error) => Err(error),
+            Ok(response) => {
+                self.set_timeout(None)?;
+                Ok(response)
+            }
+        }
+    }
+
+    fn cancel_after_timeout(&mut self, timeout: Duration) -> Error {
+        // ATTENTION can only go between packets, part way through one the connection is lost
+        if !self.stream.as_ref().is_some_and(|stream| stream.at_packet_boundary()) {
+            self.stream = None;
+            return Error::Timeout(format!("The command didn't finish within {:?}, the connection was closed as it stopped part way through a packet", timeout));
+        }
+
+        match self.send_attention() {
+            Ok(()) => Error::Timeout(format!("The command didn't finish within {:?} and was cancelled", timeout)),
+            Err(error) => {
+                self.stream = None;
+                Error::Timeout(format!("The command didn't finish within {:?}, the connection was closed as cancelling it failed: {}", timeout, error))
+            }
+        }
+    }
+
+    /**
+     * Sends ATTENTION and reads until the server acknowledges it.
+     */
+    fn send_attention(&mut self) -> Result<()> {
+        self.set_timeout(Some(session::ATTENTION_TIMEOUT))?;
+
+        let mut message: TdsMessage = self.session.attention_message();
+        self.write_message(&mut message)?;
+
+        self.read_until_attention_ack()?;
+        self.set_timeout(None)
+    }
+
+    /**
+     * After a CancelHandle has sent ATTENTION: skips whatever the server still sends up to
+     * its acknowledgement. `response` is what was read while the command ran, which may
+     * already end with it. The connection is closed if the acknowledgement doesn't come.
+     */
+    fn finish_cancel(&mut self, response: Option<TdsMessage>) -> Result<()> {
+        let acknowledged: bool = response.is_some_and(|response| session::is_attention_ack(response.body()));
+
+        let result: Result<()> = self.set_timeout(Some(session::ATTENTION_TIMEOUT))
+            .and_then(|_| if acknowledged { Ok(()) } else { self.read_until_attention_ack() })
+            .and_then(|_| self.set_timeout(None));
+
+        if result.is_err() {
+            self.stream = None;
+        }
+        self.cancelled.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn read_until_attention_ack(&mut self) -> Result<()> {
+        while !session::is_attention_ack(self.read_message()?.body()) {}
+        Ok(())
+    }
+
+    /**
+     * Read and write timeout for the socket, None to wait forever.
+     */
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let stream: &mut TdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+
+        stream.set_timeout(timeout).map_err(|e| Error::io("Failed to set the socket timeout", e))
+    }
+
+    /**
+     * Sets the socket timeout to whatever is left until `deadline`.
+     */
+    fn set_deadline(&mut self, deadline: Option<Instant>) -> Result<()> {
+        let Some(deadline) = deadline else {
+            return Ok(());
+        };
+
+        let remaining: Duration = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::Timeout(String::from("Deadline passed")));
+        }
+
+        self.set_timeout(Some(remaining))
+    }
+
+    fn send_message(&mut self, message: &mut TdsMessage) -> Result<TdsMessage> {
+        self.write_message(message)?;
+        self.read_message()
+    }
+
+    fn write_message(&mut self, message: &mut TdsMessage) -> Result<()> {
+        let packets: Vec<Vec<u8>> = message.to_packets(self.session.packet_size());
+
+        let stream: &mut TdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+
+        for packet in packets {
+            stream.write_all(&packet).map_err(|e| Error::io("Failed to write to stream", e))?;
+        }
+        stream.flush().map_err(|e| Error::io("Failed to write to stream", e))
+    }
+
+    fn read_message(&mut self) -> Result<TdsMessage> {
+        let stream: &mut TdsStream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+
+        TdsMessage::from_stream(stream)
+    }
+
+    pub fn prelogin_response(&self) -> Option<&PreLoginResponse> {
+        self.session.prelogin_response()
+    }
+}
+
+/**
+ * Cancels whatever its Connector is running, from any thread.
+ */
+pub struct CancelHandle {
+    writer: TdsWriter,
+    attention: Vec<u8>,
+    cancelled: Arc<AtomicBool>
+}
+impl CancelHandle {
+    /**
+     * Sends the server an ATTENTION. The running query stops with Error::Cancelled. If
+     * nothing is running the next command reads the acknowledgement first and runs as
+     * normal.
+     */
+    pub fn cancel(&self) -> Result<()> {
+        // set first, so the Connector knows to wait for the acknowledgement
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.writer.write_all(&self.attention)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Read;
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+    use crate::tds_message::ucs2_bytes;
+    use crate::test_server::{fake_server, fake_tls_server, server_packet, prelogin_response, login_ack_response, query_response, attention_ack_response, transaction_response, prelogin_response_with_mars, smp_packet, read_smp_packet};
+    use crate::smp::{SmpHeader, SmpFlag};
+    use crate::tls::{TlsSettings, EncryptMode};
+
+    fn fake_connector(port: &str) -> Connector {
+        let settings: ConnectionSettings = ConnectionSettings::builder()
+            .server("127.0.0.1")
+            .port(port.parse().unwrap())
+            .user("sa")
+            .password("pass")
+            .database("sample")
+            .build()
+            .unwrap();
+        Connector::new(settings)
+    }
 
     #[test]
     fn test_connector_new_creates_instance() {
-        let db_name: &str = "sample";
-        let con: Connector = Connector::new(db_name);
+        let con: Connector = fake_connector("14330");
+
+        assert_eq!(con.settings().server(), "127.0.0.1");
+        assert_eq!(con.settings().port(), 14330);
+        assert_eq!(con.settings().database(), Some("sample"));
+        assert!(!con.is_connected());
+    }
+
+    #[test]
+    fn test_connector_from_config_creates_instance() {
+        let con: Connector = Connector::from_config().unwrap();
+
+        assert_eq!(con.settings().server(), "localhost");
+    }
 
-        assert_eq!(con.database, db_name);
+    #[test]
+    fn test_connector_with_profile_fails_on_missing_profile() {
+        let result = Connector::with_profile("missing");
 
-        let server = con.settings.get("server");
-        assert_eq!(server, "localhost");
+        assert!(matches!(result, Err(Error::Config(_))));
     }
 
     #[test]
     fn test_connector_get_stream_returns_stream() {
-        let db_name: &str = "sample";
-        let mut con: Connector = Connector::new(db_name);
+        let mut con: Connector = Connector::from_config().unwrap();
 
         let _ = con.connect();
 
@@ -116,9 +768,9 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_connector_connect_establishes_connection() {
-        let db_name: &str = "sample";
-        let mut con: Connector = Connector::new(db_name);
+        let mut con: Connector = Connector::from_config().unwrap();
 
         let result = con.connect();
 
@@ -127,33 +779,28 @@ mod tests {
 
         match result {
             Ok(value) => assert_eq!(value, true),
-            Err(err) => assert_eq!(err, "")
+            Err(err) => assert_eq!(err.to_string(), "")
         };
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_connector_connect_fails_on_wrong_settings() {
-        let settings: ConnectionSettings = ConnectionSettings::new("127.0.0.1", "8080", "sa", "pass");
+        let settings: ConnectionSettings = ConnectionSettings::new("127.0.0.1", 8080, "sa", "pass");
 
-        let mut con: Connector = Connector {
-            database: String::from("sample"),
-            settings,
-            stream: None,
-            authenticated: false
-        };
+        let mut con: Connector = Connector::new(settings);
 
         let result = con.connect();
 
         match result {
             Ok(value) => assert_eq!(value, false),
-            Err(err) => assert!(err.contains("Failed to connect: No connection could be made because the target machine actively refused it. (os error 10061)"))
+            Err(err) => assert!(err.to_string().contains("Failed to connect: No connection could be made because the target machine actively refused it. (os error 10061)"))
         }
     }
 
     #[test]
     fn test_connector_is_connected_success() {
-        let db_name = "sample";
-        let mut con: Connector = Connector::new(db_name);
+        let mut con: Connector = Connector::from_config().unwrap();
         
         let _ = con.connect();
 
@@ -165,23 +812,602 @@ mod tests {
 
     #[test]
     fn test_connector_is_connected_fails() {
-        let db_name = "sample";
-        let con: Connector = Connector::new(db_name);
+        let con: Connector = Connector::from_config().unwrap();
 
         assert!(!(con.is_connected()));
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_connector_can_authenticate() {
-        let db_name = "sample";
-        let mut con: Connector = Connector::new(db_name);
+        let mut con: Connector = Connector::from_config().unwrap();
 
         let _ = con.connect();
         let result = con.authenticate();
 
         match result {
             Ok(value) => assert_eq!(value, true),
-            Err(err) => assert_eq!(err, "")
+            Err(err) => assert_eq!(err.to_string(), "")
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_connector_login_sets_authenticated() {
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response()]);
+        let mut con: Connector = fake_connector(&port);
+
+        let _ = con.connect();
+        let result = con.login();
+
+        assert!(matches!(result, Ok(true)));
+        assert!(con.is_authenticated());
+        assert!(con.prelogin_response().is_some());
+        assert_eq!(con.login_ack().unwrap().tds_version, 0x74000004);
+        assert_eq!(con.packet_size(), 8000);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[0].header().message_type(), 0x12);
+        assert_eq!(received[1].header().message_type(), 0x10);
+    }
+
+    #[test]
+    fn test_connector_login_fails_without_loginack() {
+        let done: Vec<u8> = server_packet(&[0xfd, 0x02, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let (port, server) = fake_server(vec![prelogin_response(), done]);
+        let mut con: Connector = fake_connector(&port);
+
+        let _ = con.connect();
+        let result = con.login();
+
+        assert!(result.is_err());
+        assert!(!con.is_authenticated());
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_connector_query_returns_rows() {
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), query_response()]);
+        let mut con: Connector = fake_connector(&port);
+
+        let _ = con.connect();
+        let _ = con.login();
+        let result = con.query("SELECT id, name FROM people").unwrap();
+
+        assert_eq!(result.result_sets.len(), 1);
+        assert_eq!(result.result_sets[0].column_index("name"), Some(1));
+        assert_eq!(result.rows().len(), 2);
+        assert_eq!(result.rows()[0].get(0), Some(&SqlValue::Int(1)));
+        assert_eq!(result.rows()[0].get(1).and_then(|value| value.as_str()), Some("ab"));
+        assert_eq!(result.rows()[1].get(1), Some(&SqlValue::Null));
+        assert_eq!(result.rows()[1].get(2), None);
+        assert_eq!(result.total_rows_affected(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[2].header().message_type(), 0x01);
+    }
+
+    #[test]
+    fn test_connector_execute_returns_error_from_server() {
+        let message = ucs2_bytes("Invalid object name 'nope'.");
+        let mut data: Vec<u8> = vec![0xd0, 0x00, 0x00, 0x00, 0x01, 0x10];
+        data.extend_from_slice(&(message.len() as u16 / 2).to_le_bytes());
+        data.extend_from_slice(&message);
+        data.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+
+        let mut body: Vec<u8> = vec![0xaa];
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(&data);
+        body.extend_from_slice(&[0xfd, 0x02, 0x00, 0xc1, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), server_packet(&body)]);
+        let mut con: Connector = fake_connector(&port);
+
+        let _ = con.connect();
+        let _ = con.login();
+        let result = con.execute("DELETE FROM nope");
+
+        assert!(matches!(result, Err(Error::Server(ref error)) if error.number == 208));
+        assert_eq!(result.unwrap_err().to_string(), "Server error 208: Invalid object name 'nope'.");
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_connector_query_splits_and_reassembles_packets() {
+        let response: Vec<u8> = query_response();
+        let mut split: Vec<u8> = response[..8].to_vec();
+        split[1] = 0x00;
+        split[3] = 0x10;
+        split.extend_from_slice(&response[8..16]);
+        split.extend_from_slice(&server_packet(&response[16..]));
+
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), split]);
+        let mut con: Connector = fake_connector(&port);
+        let sql: String = format!("SELECT id, name FROM people WHERE name <> '{}'", "x".repeat(5000));
+
+        let _ = con.connect();
+        let _ = con.login();
+        let result = con.query(&sql).unwrap();
+
+        assert_eq!(result.rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[2].header().packet_id(), 2);
+        assert_eq!(received[2].body().len(), 22 + sql.len() * 2);
+    }
+
+    #[test]
+    fn test_connector_execute_params_sends_rpc() {
+        let done: Vec<u8> = server_packet(&[
+            0xff, 0x10, 0x00, 0xc1, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0,
+            0x79, 0x00, 0x00, 0x00, 0x00,
+            0xfe, 0x00, 0x00, 0xe0, 0x00, 0, 0, 0, 0, 0, 0, 0, 0
+        ]);
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), done]);
+        let mut con: Connector = fake_connector(&port);
+
+        let _ = con.connect();
+        let _ = con.login();
+        let result = con.execute_params(
+            "UPDATE people SET name = @P1 WHERE id = @P2",
+            &[SqlValue::String(String::from("Robert'); DROP TABLE people;--")), SqlValue::Int(1)]
+        );
+
+        assert!(matches!(result, Ok(1)));
+
+        let received = server.join().unwrap();
+        let body = received[2].body();
+        assert_eq!(received[2].header().message_type(), 0x03);
+        assert_eq!(&body[22..26], &[0xff, 0xff, 0x0a, 0x00]);
+        let declarations = ucs2_bytes("@P1 nvarchar(4000), @P2 int");
+        assert!(body.windows(declarations.len()).any(|window| window == &declarations[..]));
+    }
+
+    fn rows_copied_response(count: u8) -> Vec<u8> {
+        server_packet(&[0xfd, 0x10, 0x00, 0x00, 0x00, count, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    #[test]
+    fn test_connector_bulk_insert_sends_batches() {
+        let responses: Vec<Vec<u8>> = vec![
+            prelogin_response(), login_ack_response(), query_response(),
+            rows_copied_response(0), rows_copied_response(2),
+            rows_copied_response(0), rows_copied_response(1)
+        ];
+        let (port, server) = fake_server(responses);
+        let mut con: Connector = fake_connector(&port);
+        let rows: Vec<Vec<SqlValue>> = vec![
+            vec![SqlValue::Int(1), SqlValue::String(String::from("ab"))],
+            vec![SqlValue::Int(2), SqlValue::Null],
+            vec![SqlValue::TinyInt(3), SqlValue::String(String::from("c"))]
+        ];
+        let mut options: BulkLoadOptions = BulkLoadOptions::new();
+        options.table_lock = true;
+        options.batch_size = Some(2);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+        let copied = con.bulk_insert("people", &["id", "name"], rows, &options).unwrap();
+
+        assert_eq!(copied, 3);
+
+        let received = server.join().unwrap();
+        assert_eq!(&received[2].body()[22..], &ucs2_bytes("SELECT TOP 0 [id], [name] FROM [people]")[..]);
+        assert_eq!(&received[3].body()[22..], &ucs2_bytes("INSERT BULK [people] ([id] int, [name] nvarchar(50)) WITH (TABLOCK)")[..]);
+        assert_eq!(received[4].header().message_type(), 0x07);
+        assert_eq!(received[4].body()[0], 0x81);
+        assert_eq!(received[4].body().iter().filter(|byte| **byte == 0xd1).count(), 2);
+        assert!(received[4].body().ends_with(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(received[6].header().message_type(), 0x07);
+    }
+
+    #[test]
+    fn test_connector_bulk_insert_abandons_load_on_bad_row() {
+        let responses: Vec<Vec<u8>> = vec![
+            prelogin_response(), login_ack_response(), query_response(),
+            rows_copied_response(0), Vec::new(), attention_ack_response(), query_response()
+        ];
+        let (port, server) = fake_server(responses);
+        let mut con: Connector = fake_connector(&port);
+        // enough good rows to fill some packets before the bad one
+        let mut rows: Vec<Vec<SqlValue>> = (0..1000).map(|id| vec![SqlValue::Int(id), SqlValue::String(String::from("name"))]).collect();
+        rows.push(vec![SqlValue::String(String::from("not an id")), SqlValue::Null]);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+        let result = con.bulk_insert("people", &["id", "name"], rows, &BulkLoadOptions::new());
+
+        assert!(matches!(result, Err(Error::Config(_))));
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[4].header().message_type(), 0x07);
+        assert_eq!(received[4].header().status(), 0x03);
+        assert_eq!(received[5].header().message_type(), 0x06);
+    }
+
+    #[test]
+    fn test_connector_query_params_fails_when_not_logged_in() {
+        let mut con: Connector = fake_connector("1433");
+
+        assert!(con.query_params("SELECT @P1", &[SqlValue::Int(1)]).is_err());
+    }
+
+    #[test]
+    fn test_connector_query_times_out_and_sends_attention() {
+        // no answer to the first query, so it times out, then the ATTENTION is acknowledged
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), Vec::new(), attention_ack_response(), query_response()];
+        let (port, server) = fake_server(responses);
+        let settings: ConnectionSettings = fake_connector(&port).settings().to_builder()
+            .command_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let mut con: Connector = Connector::new(settings);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+
+        let result = con.query("WAITFOR DELAY '01:00'");
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        assert!(con.is_connected());
+
+        // the connection is still usable afterwards
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[3].header().message_type(), 0x06);
+        assert!(received[3].body().is_empty());
+    }
+
+    #[test]
+    fn test_connector_query_timing_out_mid_packet_closes_connection() {
+        // only the start of the response arrives, then the server stalls
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), query_response()[..12].to_vec(), Vec::new()];
+        let (port, server) = fake_server(responses);
+        let settings: ConnectionSettings = fake_connector(&port).settings().to_builder()
+            .command_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let mut con: Connector = Connector::new(settings);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+
+        let result = con.query("SELECT id, name FROM people");
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        assert!(!con.is_connected());
+
+        // no ATTENTION was sent, the server just sees the connection close
+        assert!(server.join().is_err());
+    }
+
+    #[test]
+    fn test_connector_cancel_handle_cancels_running_query() {
+        // no answer to the query until the ATTENTION arrives
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), Vec::new(), attention_ack_response(), query_response()];
+        let (port, server) = fake_server(responses);
+        let mut con: Connector = fake_connector(&port);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+
+        let handle: CancelHandle = con.cancel_handle().unwrap();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.cancel().unwrap();
+        });
+
+        assert!(matches!(con.query("WAITFOR DELAY '01:00'"), Err(Error::Cancelled)));
+        canceller.join().unwrap();
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[3].header().message_type(), 0x06);
+    }
+
+    #[test]
+    fn test_connector_cancel_handle_when_idle_is_acknowledged_before_next_query() {
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), query_response(), attention_ack_response(), query_response()];
+        let (port, server) = fake_server(responses);
+        let mut con: Connector = fake_connector(&port);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        con.cancel_handle().unwrap().cancel().unwrap();
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[3].header().message_type(), 0x06);
+        assert_eq!(received[4].header().message_type(), 0x01);
+    }
+
+    #[test]
+    fn test_connector_cancel_handle_fails_when_not_connected() {
+        let con: Connector = fake_connector("1433");
+
+        assert!(matches!(con.cancel_handle(), Err(Error::NotConnected)));
+    }
+
+    #[test]
+    fn test_connector_login_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+
+        let settings: ConnectionSettings = fake_connector(&port).settings().to_builder()
+            .connect_timeout(Duration::from_secs(5))
+            .login_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let mut con: Connector = Connector::new(settings);
+
+        con.connect().unwrap();
+        let result = con.login();
+
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        assert!(!con.is_connected());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connector_transaction_sends_descriptor_and_commits() {
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), transaction_response(0x08, 0x0102), query_response(), transaction_response(0x09, 0x0102)];
+        let (port, server) = fake_server(responses);
+        let mut con: Connector = fake_connector(&port);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+
+        let mut transaction: Transaction = con.transaction(IsolationLevel::Serializable).unwrap();
+        assert!(transaction.in_transaction());
+        transaction.execute("UPDATE people SET name = 'ab'").unwrap();
+        transaction.commit().unwrap();
+        assert!(!con.in_transaction());
+
+        let received = server.join().unwrap();
+        assert_eq!(received[2].header().message_type(), 0x0e);
+        assert_eq!(&received[2].body()[22..], &[0x05, 0x00, 0x04, 0x00]);
+        // the batch and the commit carry the descriptor from the ENVCHANGE
+        assert_eq!(&received[3].body()[10..18], &0x0102u64.to_le_bytes());
+        assert_eq!(&received[4].body()[10..18], &0x0102u64.to_le_bytes());
+        assert_eq!(&received[4].body()[22..24], &[0x07, 0x00]);
+    }
+
+    #[test]
+    fn test_connector_transaction_rolls_back_on_drop() {
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response(), transaction_response(0x08, 7), transaction_response(0x0a, 7)];
+        let (port, server) = fake_server(responses);
+        let mut con: Connector = fake_connector(&port);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+
+        drop(con.transaction(IsolationLevel::Unchanged).unwrap());
+        assert!(!con.in_transaction());
+
+        let received = server.join().unwrap();
+        assert_eq!(received[3].header().message_type(), 0x0e);
+        assert_eq!(&received[3].body()[22..], &[0x08, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_connector_savepoint_rejects_bad_names() {
+        let responses: Vec<Vec<u8>> = vec![prelogin_response(), login_ack_response()];
+        let (port, server) = fake_server(responses);
+        let mut con: Connector = fake_connector(&port);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+
+        assert!(matches!(con.savepoint(""), Err(Error::Config(_))));
+        assert!(matches!(con.rollback_to(&"a".repeat(256)), Err(Error::Config(_))));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connector_open_session_runs_queries_side_by_side() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            TdsMessage::from_stream(&mut stream).unwrap();
+            stream.write_all(&prelogin_response_with_mars()).unwrap();
+            TdsMessage::from_stream(&mut stream).unwrap();
+            stream.write_all(&login_ack_response()).unwrap();
+
+            // SYN for each session, then a query from each
+            let mut received: Vec<SmpHeader> = Vec::new();
+            for _ in 0..4 {
+                received.push(read_smp_packet(&mut stream).0);
+            }
+
+            // answered the other way round, each reader has to pick out its own
+            stream.write_all(&smp_packet(SmpFlag::Data, 1, 1, 4, &query_response())).unwrap();
+            stream.write_all(&smp_packet(SmpFlag::Data, 0, 1, 4, &query_response())).unwrap();
+
+            received.push(read_smp_packet(&mut stream).0);
+            received
+        });
+
+        let settings: ConnectionSettings = fake_connector(&port).settings().to_builder()
+            .mars(true)
+            .build()
+            .unwrap();
+        let mut con: Connector = Connector::new(settings);
+        con.connect().unwrap();
+        con.login().unwrap();
+        assert!(con.is_mars());
+
+        let mut second: Connector = con.open_session().unwrap();
+        assert!(second.is_authenticated());
+        let other = thread::spawn(move || {
+            let rows: usize = second.query("SELECT id, name FROM people").unwrap().rows().len();
+            drop(second);
+            rows
+        });
+
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+        assert_eq!(other.join().unwrap(), 2);
+
+        let received = server.join().unwrap();
+        assert!(received[0].has_flag(SmpFlag::Syn));
+        assert_eq!((received[1].session_id, received[1].flags), (1, SmpFlag::Syn.value()));
+        assert!(received[2..4].iter().all(|header| header.has_flag(SmpFlag::Data) && header.sequence_number == 1));
+        assert_eq!((received[4].session_id, received[4].flags), (1, SmpFlag::Fin.value()));
+    }
+
+    #[test]
+    fn test_connector_open_session_fails_without_mars() {
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response()]);
+        let settings: ConnectionSettings = fake_connector(&port).settings().to_builder()
+            .mars(true)
+            .build()
+            .unwrap();
+        let mut con: Connector = Connector::new(settings);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+
+        assert!(!con.is_mars());
+        assert!(matches!(con.open_session(), Err(Error::Protocol(_))));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connector_query_fails_when_not_logged_in() {
+        let mut con: Connector = fake_connector("1433");
+
+        let result = con.query("SELECT 1");
+
+        assert!(result.is_err());
+    }
+
+    fn tls_connector(port: &str, tls: TlsSettings) -> Connector {
+        let settings: ConnectionSettings = ConnectionSettings::builder()
+            .server("localhost")
+            .port(port.parse().unwrap())
+            .user("sa")
+            .password("pass")
+            .tls(tls)
+            .build()
+            .unwrap();
+        Connector::new(settings)
+    }
+
+    #[test]
+    fn test_connector_login_encrypts_login_only() {
+        let (port, _, server) = fake_tls_server(0x00, false, vec![login_ack_response(), query_response()]);
+        let mut tls: TlsSettings = TlsSettings::new();
+        tls.trust_server_certificate = true;
+        let mut con: Connector = tls_connector(&port, tls);
+
+        let _ = con.connect();
+        let result = con.login();
+
+        assert!(matches!(result, Ok(true)));
+        assert_eq!(con.encryption(), Encryption::LoginOnly);
+        assert!(!con.stream.as_ref().unwrap().is_encrypted());
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1].header().message_type(), 0x10);
+        assert_eq!(received[2].header().message_type(), 0x01);
+    }
+
+    #[test]
+    fn test_connector_login_encrypts_everything_when_on() {
+        let (port, _, server) = fake_tls_server(0x01, true, vec![login_ack_response(), query_response()]);
+        let mut tls: TlsSettings = TlsSettings::new();
+        tls.encrypt = EncryptMode::On;
+        tls.trust_server_certificate = true;
+        let mut con: Connector = tls_connector(&port, tls);
+
+        let _ = con.connect();
+        let result = con.login();
+
+        assert!(matches!(result, Ok(true)));
+        assert_eq!(con.encryption(), Encryption::Full);
+        assert!(con.stream.as_ref().unwrap().is_encrypted());
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[0].body()[received[0].body().len() - 1], 0x00);
+        assert_eq!(received[2].header().message_type(), 0x01);
+    }
+
+    #[test]
+    fn test_connector_cancel_handle_works_over_tls() {
+        let (port, _, server) = fake_tls_server(0x01, true, vec![login_ack_response(), Vec::new(), attention_ack_response(), query_response()]);
+        let mut tls: TlsSettings = TlsSettings::new();
+        tls.encrypt = EncryptMode::On;
+        tls.trust_server_certificate = true;
+        let mut con: Connector = tls_connector(&port, tls);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+
+        let handle: CancelHandle = con.cancel_handle().unwrap();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.cancel().unwrap();
+        });
+
+        assert!(matches!(con.query("WAITFOR DELAY '01:00'"), Err(Error::Cancelled)));
+        canceller.join().unwrap();
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[3].header().message_type(), 0x06);
+    }
+
+    #[test]
+    fn test_connector_login_rejects_untrusted_certificate() {
+        let (port, _, server) = fake_tls_server(0x01, true, vec![login_ack_response()]);
+        let mut con: Connector = tls_connector(&port, TlsSettings::new());
+
+        let _ = con.connect();
+        let result = con.login();
+
+        assert!(matches!(result, Err(Error::Tls(ref message)) if message.contains("TLS handshake failed")));
+        assert!(!con.is_authenticated());
+        drop(con);
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_connector_login_trusts_certificate_from_ca_file() {
+        let (port, pem, server) = fake_tls_server(0x03, true, vec![login_ack_response()]);
+        let ca_file = std::env::temp_dir().join(format!("sql_connector_ca_{}.pem", port));
+        std::fs::write(&ca_file, pem).unwrap();
+
+        let mut tls: TlsSettings = TlsSettings::new();
+        tls.ca_file = Some(ca_file.clone());
+        let mut con: Connector = tls_connector(&port, tls);
+
+        let _ = con.connect();
+        let result = con.login();
+        let _ = std::fs::remove_file(&ca_file);
+
+        assert!(matches!(result, Ok(true)));
+        assert_eq!(con.encryption(), Encryption::Full);
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_connector_authenticate_fails_when_server_cannot_encrypt() {
+        let (port, server) = fake_server(vec![prelogin_response()]);
+        let mut tls: TlsSettings = TlsSettings::new();
+        tls.encrypt = EncryptMode::On;
+        let mut con: Connector = tls_connector(&port, tls);
+
+        let _ = con.connect();
+        let result = con.authenticate();
+
+        assert!(result.is_err());
+        let _ = server.join();
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::connection_settings::ConnectionSettings;
+use crate::ocbd::Connector;
+use crate::error::{Error, Result};
+
+/**
+ * Limits for a Pool. idle_timeout only closes connections while there are more than
+ * min_size open, max_lifetime closes them regardless.
+ *
+ * min_size isn't a floor: connections closed for their lifetime or because they broke
+ * aren't replaced until get() needs one.
+ */
+#[derive(Debug, Clone)]
+pub struct PoolSettings {
+    pub min_size: usize,
+    pub max_size: usize,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    pub checkout_timeout: Duration,
+    pub test_on_checkout: bool,
+    pub keep_transaction_on_reset: bool
+}
+impl Default for PoolSettings {
+    fn default() -> PoolSettings {
+        PoolSettings::new()
+    }
+}
+impl PoolSettings {
+    pub fn new() -> PoolSettings {
+        PoolSettings {
+            min_size: 0,
+            max_size: 10,
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            checkout_timeout: Duration::from_secs(30),
+            test_on_checkout: true,
+            keep_transaction_on_reset: false
+        }
+    }
+}
+
+/**
+ * Connection pool
+ *
+ * Keeps logged in Connectors around so they don't have to go through PRELOGIN / LOGIN7
+ * every time. get() hands out an idle connection (checking it still works first) or opens
+ * a new one while there are fewer than max_size, otherwise waits for one to be returned.
+ *
+ * When a connection comes back it's marked to be reset, so the server clears temp tables,
+ * SET options and any open transaction before the next request runs on it. Expired
+ * connections are closed as the pool is used, there's no background thread.
+ *
+ * Clones share the same connections.
+ */
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>
+}
+
+struct Shared {
+    settings: ConnectionSettings,
+    options: PoolSettings,
+    state: Mutex<PoolState>,
+    returned: Condvar
+}
+
+struct PoolState {
+    idle: VecDeque<IdleConnection>,
+    open: usize
+}
+
+struct IdleConnection {
+    connector: Connector,
+    created: Instant,
+    since: Instant
+}
+
+impl Pool {
+    /**
+     * Opens min_size connections straight away, so bad settings show up here.
+     */
+    pub fn new(settings: ConnectionSettings, options: PoolSettings) -> Result<Pool> {
+        if options.max_size == 0 {
+            return Err(Error::Config(String::from("The pool's max_size has to be at least 1")));
+        }
+        if options.min_size > options.max_size {
+            return Err(Error::Config(format!("The pool's min_size ({}) is bigger than its max_size ({})", options.min_size, options.max_size)));
+        }
+
+        let pool: Pool = Pool {
+            shared: Arc::new(Shared {
+                settings,
+                options,
+                state: Mutex::new(PoolState {
+                    idle: VecDeque::new(),
+                    open: 0
+                }),
+                returned: Condvar::new()
+            })
+        };
+
+        for _ in 0..pool.shared.options.min_size {
+            let connector: Connector = pool.open_connection()?;
+            let now: Instant = Instant::now();
+
+            let mut state = pool.lock();
+            state.open += 1;
+            state.idle.push_back(IdleConnection {
+                connector,
+                created: now,
+                since: now
+            });
+        }
+
+        Ok(pool)
+    }
+
+    pub fn settings(&self) -> &PoolSettings {
+        &self.shared.options
+    }
+
+    /**
+     * Connections open, whether idle or handed out.
+     */
+    pub fn size(&self) -> usize {
+        self.lock().open
+    }
+
+    pub fn idle(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    /**
+     * A logged in connection, waiting up to checkout_timeout if the pool is at max_size.
+     * The connection goes back to the pool when the PooledConnection is dropped.
+     */
+    pub fn get(&self) -> Result<PooledConnection> {
+        let options: &PoolSettings = &self.shared.options;
+        let deadline: Instant = Instant::now() + options.checkout_timeout;
+        let mut state = self.lock();
+
+        loop {
+            self.close_expired(&mut state);
+
+            // most recently returned first, so the rest can reach idle_timeout
+            if let Some(mut idle) = state.idle.pop_back() {
+                drop(state);
+
+                if !options.test_on_checkout || idle.connector.query("SELECT 1").is_ok() {
+                    return Ok(PooledConnection::new(self.clone(), idle.connector, idle.created));
+                }
+
+                state = self.lock();
+                state.open -= 1;
+                continue;
+            }
+
+            if state.open < options.max_size {
+                state.open += 1;
+                drop(state);
+
+                return match self.open_connection() {
+                    Ok(connector) => Ok(PooledConnection::new(self.clone(), connector, Instant::now())),
+                    Err(error) => {
+                        self.lock().open -= 1;
+                        self.shared.returned.notify_one();
+                        Err(error)
+                    }
+                };
+            }
+
+            let now: Instant = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout(format!("No pooled connection became free within {:?}", options.checkout_timeout)));
+            }
+
+            state = self.shared.returned.wait_timeout(state, deadline - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    fn open_connection(&self) -> Result<Connector> {
+        let mut connector: Connector = Connector::new(self.shared.settings.clone());
+        connector.connect()?;
+        connector.login()?;
+        Ok(connector)
+    }
+
+    fn close_expired(&self, state: &mut PoolState) {
+        let options: &PoolSettings = &self.shared.options;
+        let now: Instant = Instant::now();
+
+        let mut index: usize = 0;
+        while index < state.idle.len() {
+            let idle: &IdleConnection = &state.idle[index];
+            let too_old: bool = options.max_lifetime.is_some_and(|lifetime| now - idle.created >= lifetime);
+            let too_idle: bool = options.idle_timeout.is_some_and(|timeout| now - idle.since >= timeout)
+                && state.open > options.min_size;
+
+            if too_old || too_idle {
+                state.idle.remove(index);
+                state.open -= 1;
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    fn put_back(&self, mut connector: Connector, created: Instant) {
+        let options: &PoolSettings = &self.shared.options;
+        let expired: bool = options.max_lifetime.is_some_and(|lifetime| created.elapsed() >= lifetime);
+
+        let mut state = self.lock();
+        if expired || !connector.is_reusable() {
+            state.open -= 1;
+        } else {
+            connector.reset_session(options.keep_transaction_on_reset);
+            state.idle.push_back(IdleConnection {
+                connector,
+                created,
+                since: Instant::now()
+            });
+        }
+        drop(state);
+
+        self.shared.returned.notify_one();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        // the state is only counters and a queue, still usable if a holder panicked
+        self.shared.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/**
+ * A Connector borrowed from a Pool, use it like a Connector.
+ */
+pub struct PooledConnection {
+    pool: Pool,
+    connector: Option<Connector>,
+    created: Instant
+}
+impl PooledConnection {
+    fn new(pool: Pool, connector: Connector, created: Instant) -> PooledConnection {
+        PooledConnection {
+            pool,
+            connector: Some(connector),
+            created
+        }
+    }
+
+    /**
+     * Closes the connection instead of returning it, e.g. after an error that leaves it
+     * in an unknown state.
+     */
+    pub fn discard(mut self) {
+        if self.connector.take().is_some() {
+            self.pool.lock().open -= 1;
+            self.pool.shared.returned.notify_one();
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connector;
+
+    fn deref(&self) -> &Connector {
+        self.connector.as_ref().expect("connection already returned")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connector {
+        self.connector.as_mut().expect("connection already returned")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connector) = self.connector.take() {
+            self.pool.put_back(connector, self.created);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{fake_server, prelogin_response, login_ack_response, query_response};
+
+    fn settings(port: &str) -> ConnectionSettings {
+        ConnectionSettings::new("127.0.0.1", port.parse().unwrap(), "sa", "pass")
+    }
+
+    #[test]
+    fn test_pool_new_validates_sizes() {
+        let mut options: PoolSettings = PoolSettings::new();
+        options.max_size = 0;
+        assert!(matches!(Pool::new(settings("1433"), options.clone()), Err(Error::Config(_))));
+
+        options.max_size = 1;
+        options.min_size = 2;
+        assert!(matches!(Pool::new(settings("1433"), options), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_pool_get_reuses_and_resets_connection() {
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), query_response(), query_response()]);
+        let mut options: PoolSettings = PoolSettings::new();
+        options.max_size = 1;
+        let pool: Pool = Pool::new(settings(&port), options).unwrap();
+
+        let mut connection: PooledConnection = pool.get().unwrap();
+        assert_eq!(connection.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+        drop(connection);
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+
+        // the health check is the first request after the return, so it carries the reset
+        let connection: PooledConnection = pool.get().unwrap();
+        assert!(connection.is_authenticated());
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[2].header().status(), 0x01);
+        assert_eq!(received[3].header().status(), 0x09);
+    }
+
+    #[test]
+    fn test_pool_get_times_out_when_full() {
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response()]);
+        let mut options: PoolSettings = PoolSettings::new();
+        options.max_size = 1;
+        options.checkout_timeout = Duration::from_millis(50);
+        let pool: Pool = Pool::new(settings(&port), options).unwrap();
+
+        let connection: PooledConnection = pool.get().unwrap();
+        assert!(matches!(pool.get(), Err(Error::Timeout(_))));
+
+        connection.discard();
+        assert_eq!(pool.size(), 0);
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_pool_drops_connection_left_mid_packet() {
+        // the response stops part way through a packet and the server hangs up
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), query_response()[..12].to_vec()]);
+        let pool: Pool = Pool::new(settings(&port), PoolSettings::new()).unwrap();
+
+        let mut connection: PooledConnection = pool.get().unwrap();
+        assert!(connection.query("SELECT id, name FROM people").is_err());
+        assert!(connection.is_authenticated());
+        drop(connection);
+
+        assert_eq!((pool.size(), pool.idle()), (0, 0));
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_pool_closes_connections_past_max_lifetime() {
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response()]);
+        let mut options: PoolSettings = PoolSettings::new();
+        options.max_lifetime = Some(Duration::ZERO);
+        let pool: Pool = Pool::new(settings(&port), options).unwrap();
+
+        drop(pool.get().unwrap());
+
+        assert_eq!((pool.size(), pool.idle()), (0, 0));
+        let _ = server.join();
+    }
+}
@@ -0,0 +1,79 @@
+use crate::tds_token::{Column, Row};
+
+/**
+ * Everything a batch sent back: each result set in order, the row counts from the
+ * DONE tokens and the return status if a procedure ran.
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct QueryResult {
+    pub result_sets: Vec<ResultSet>,
+    pub rows_affected: Vec<u64>,
+    pub return_status: Option<i32>
+}
+impl QueryResult {
+    pub fn new() -> QueryResult {
+        QueryResult {
+            result_sets: Vec::new(),
+            rows_affected: Vec::new(),
+            return_status: None
+        }
+    }
+
+    pub fn total_rows_affected(&self) -> u64 {
+        self.rows_affected.iter().sum()
+    }
+
+    /**
+     * Rows of the first result set, which for a single SELECT is all of them.
+     */
+    pub fn rows(&self) -> &[Row] {
+        self.result_sets.first().map_or(&[], |result_set| &result_set.rows)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultSet {
+    pub columns: Vec<Column>,
+    pub rows: Vec<Row>
+}
+impl ResultSet {
+    pub fn new(columns: Vec<Column>) -> ResultSet {
+        ResultSet {
+            columns,
+            rows: Vec::new()
+        }
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tds_type::{DataType, TypeInfo};
+
+    #[test]
+    fn test_queryresult_total_rows_affected_sums_counts() {
+        let mut result = QueryResult::new();
+        result.rows_affected = vec![2, 3];
+
+        assert_eq!(result.total_rows_affected(), 5);
+        assert!(result.rows().is_empty());
+    }
+
+    #[test]
+    fn test_resultset_column_index_finds_column() {
+        let column = Column {
+            name: String::from("id"),
+            user_type: 0,
+            flags: 0,
+            type_info: TypeInfo::new(DataType::Int4)
+        };
+        let result_set = ResultSet::new(vec![column]);
+
+        assert_eq!(result_set.column_index("id"), Some(0));
+        assert_eq!(result_set.column_index("name"), None);
+    }
+}
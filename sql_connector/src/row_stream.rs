@@ -0,0 +1,591 @@
+use std::io::{self, Read};
+use crate::ocbd::Connector;
+use crate::sql_value::SqlValue;
+use crate::tds_message::{TdsMessage, PacketReader, PlpReader};
+use crate::tds_stream::TdsStream;
+use crate::tds_token::{TokenStream, TdsToken, TdsRead, Column, Row, DoneStatus, read_error};
+use crate::tds_type::PLP_NULL;
+use crate::error::{Error, Result};
+
+/**
+ * Rows of a query as they arrive
+ *
+ * Tokens are read straight off the connection as the rows are asked for, so only the row
+ * being handed over is held in memory however big the result is. Each result set's columns
+ * are known before its first row; next_result_set moves on to the next one, skipping any
+ * rows left in the current one.
+ *
+ * Rows with (max) values too big to hold can be read a value at a time with next_row_reader,
+ * which can hand those values over as a Read of their own.
+ *
+ * The Connector can't be used until the stream is dropped. Dropping it before the end reads
+ * and throws away the rest of the response, leaving the connection ready for the next query.
+ */
+pub struct RowStream<'a> {
+    connector: &'a mut Connector,
+    tokens: Option<TokenStream<PacketReader<TdsStream>>>,
+    columns: Vec<Column>,
+    next_columns: Option<Vec<Column>>,
+    in_rows: bool,
+    attention_acknowledged: bool,
+    rows_affected: Vec<u64>,
+    return_status: Option<i32>
+}
+impl<'a> RowStream<'a> {
+    /**
+     * Sends `message` and reads up to the first result set's columns.
+     */
+    pub(crate) fn start(connector: &'a mut Connector, message: &mut TdsMessage) -> Result<RowStream<'a>> {
+        let stream: TdsStream = connector.start_stream(message)?;
+
+        let mut rows: RowStream = RowStream {
+            connector,
+            tokens: Some(TokenStream::new(PacketReader::new(stream))),
+            columns: Vec::new(),
+            next_columns: None,
+            in_rows: false,
+            attention_acknowledged: false,
+            rows_affected: Vec::new(),
+            return_status: None
+        };
+
+        rows.next_result_set()?;
+        Ok(rows)
+    }
+
+    /**
+     * Columns of the current result set, empty once there are no more.
+     */
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    /**
+     * The next row of the current result set, None when it has run out.
+     */
+    pub fn next_row(&mut self) -> Result<Option<Row>> {
+        match self.next_in_result_set(false)? {
+            Some(Event::Row(row)) => Ok(Some(row)),
+            _ => Ok(None)
+        }
+    }
+
+    /**
+     * Like next_row, but nothing of the row is read until its values are asked for, one at
+     * a time. None when the result set has run out.
+     */
+    pub fn next_row_reader(&mut self) -> Result<Option<RowReader<'_, 'a>>> {
+        match self.next_in_result_set(true)? {
+            Some(Event::RowStart(nulls)) => Ok(Some(RowReader {
+                rows: self,
+                nulls,
+                position: 0
+            })),
+            _ => Ok(None)
+        }
+    }
+
+    /**
+     * Moves to the next result set, skipping whatever is left of this one. False when
+     * there isn't another.
+     */
+    pub fn next_result_set(&mut self) -> Result<bool> {
+        while self.next_row()?.is_some() {}
+
+        loop {
+            if let Some(columns) = self.next_columns.take() {
+                self.columns = columns;
+                self.in_rows = true;
+                return Ok(true);
+            }
+
+            match self.advance(false)? {
+                Some(Event::Columns(columns)) => self.next_columns = Some(columns),
+                Some(Event::Row(_)) | Some(Event::RowStart(_)) => return Err(Error::Protocol(String::from("Row received without a result set"))),
+                Some(Event::Done) => (),
+                None => {
+                    self.columns.clear();
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    /**
+     * Row counts from the DONE tokens read so far. Only complete once the stream is at the end.
+     */
+    pub fn rows_affected(&self) -> &[u64] {
+        &self.rows_affected
+    }
+
+    pub fn return_status(&self) -> Option<i32> {
+        self.return_status
+    }
+
+    /**
+     * The next row of the current result set, read whole or just started on.
+     */
+    fn next_in_result_set(&mut self, start_rows: bool) -> Result<Option<Event>> {
+        if !self.in_rows {
+            return Ok(None);
+        }
+
+        match self.advance(start_rows)? {
+            Some(event @ (Event::Row(_) | Event::RowStart(_))) => return Ok(Some(event)),
+            Some(Event::Columns(columns)) => self.next_columns = Some(columns),
+            Some(Event::Done) | None => ()
+        }
+
+        self.in_rows = false;
+        Ok(None)
+    }
+
+    /**
+     * Reads tokens until one a caller cares about: new columns, a row or the DONE ending a
+     * statement. Everything else is dealt with here. None at the end of the response, by
+     * which point the stream has gone back to the Connector.
+     *
+     * With `start_rows` a row is only started on, its values left for a RowReader.
+     *
+     * A server error is returned as soon as it's read, but the stream can carry on after
+     * it with whatever the server sent next.
+     */
+    fn advance(&mut self, start_rows: bool) -> Result<Option<Event>> {
+        loop {
+            if start_rows {
+                match self.tokens.as_mut().map(|tokens| tokens.start_row()) {
+                    Some(Ok(Some(nulls))) => return Ok(Some(Event::RowStart(nulls))),
+                    Some(Err(error)) => return Err(self.abort(error)),
+                    _ => ()
+                }
+            }
+
+            let Some(tokens) = self.tokens.as_mut() else {
+                return Ok(None);
+            };
+
+            let token: TdsToken = match tokens.next_token() {
+                Ok(Some(token)) => token,
+                Ok(None) => return self.end().map(|_| None),
+                Err(error) => return Err(self.abort(error))
+            };
+
+            match token {
+                TdsToken::ColMetadata(columns) if !columns.is_empty() => return Ok(Some(Event::Columns(columns))),
+                TdsToken::Row(row) => return Ok(Some(Event::Row(row))),
+                TdsToken::Done(done) | TdsToken::DoneProc(done) | TdsToken::DoneInProc(done) => {
+                    if let Some(count) = done.rows_affected() {
+                        self.rows_affected.push(count);
+                    }
+                    // the server stopped for a CancelHandle
+                    if done.has_status(DoneStatus::Attention) {
+                        self.attention_acknowledged = true;
+                        return Err(Error::Cancelled);
+                    }
+                    return Ok(Some(Event::Done));
+                },
+                TdsToken::ReturnStatus(status) => self.return_status = Some(status),
+                TdsToken::EnvChange(change) => self.connector.session_mut().apply_env_change(change),
+                TdsToken::Info(info) => self.connector.session_mut().add_message(info),
+                TdsToken::Error(message) => return Err(Error::Server(message)),
+                _ => ()
+            }
+        }
+    }
+
+    /**
+     * The whole response has been read, so the Connector gets its stream back. A cancel
+     * that hasn't been reported yet, as its acknowledgement comes after the response, is
+     * reported here.
+     */
+    fn end(&mut self) -> Result<()> {
+        let Some(tokens) = self.tokens.take() else {
+            return Ok(());
+        };
+
+        let stream: TdsStream = tokens.into_inner().into_inner();
+        if self.connector.end_stream(stream, self.attention_acknowledged)? && !self.attention_acknowledged {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    fn abort(&mut self, error: Error) -> Error {
+        match self.tokens.take() {
+            Some(tokens) => self.connector.abort_stream(tokens.into_inner().into_inner(), error),
+            None => error
+        }
+    }
+}
+
+impl Iterator for RowStream<'_> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Result<Row>> {
+        self.next_row().transpose()
+    }
+}
+
+impl Drop for RowStream<'_> {
+    fn drop(&mut self) {
+        // errors have nowhere to go, and a failed read leaves the stream closed anyway
+        while self.tokens.is_some() {
+            let _ = self.advance(false);
+        }
+    }
+}
+
+/**
+ * One row of a RowStream read a value at a time, in column order
+ *
+ * read_value reads the next value whole. stream_value hands a (max) value over as a Read
+ * instead, so it goes straight from the connection to wherever it's wanted, a chunk at a
+ * time. Values not asked for are skipped when the reader is dropped, without being held.
+ */
+pub struct RowReader<'r, 'a> {
+    rows: &'r mut RowStream<'a>,
+    nulls: Vec<bool>,
+    position: usize
+}
+impl RowReader<'_, '_> {
+    pub fn columns(&self) -> &[Column] {
+        &self.rows.columns
+    }
+
+    /**
+     * Index of the column the next value is from, the column count once all have been read.
+     */
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn read_value(&mut self) -> Result<SqlValue> {
+        let index: usize = self.next_column()?;
+        if self.nulls[index] {
+            return Ok(SqlValue::Null);
+        }
+
+        let rows: &mut RowStream = &mut *self.rows;
+        let Some(tokens) = rows.tokens.as_mut() else {
+            return Err(Error::NotConnected);
+        };
+
+        match SqlValue::read(&rows.columns[index].type_info, tokens.reader_mut()) {
+            Ok(value) => Ok(value),
+            Err(error) => Err(rows.abort(error))
+        }
+    }
+
+    /**
+     * The next value as a Read over its data, None when it's NULL. Only for the (max) types,
+     * which are sent in chunks. The value has to be read or dropped before the next one.
+     */
+    pub fn stream_value(&mut self) -> Result<Option<ValueStream<'_>>> {
+        if let Some(column) = self.rows.columns.get(self.position) {
+            if !column.type_info.is_plp() {
+                return Err(Error::Config(format!(
+                    "Column {} is {}, only (max) values can be streamed",
+                    column.name,
+                    column.type_info.declaration()
+                )));
+            }
+        }
+
+        let index: usize = self.next_column()?;
+        if self.nulls[index] {
+            return Ok(None);
+        }
+
+        let total_length: u64 = self.read_plp_length()?;
+        if total_length == PLP_NULL {
+            return Ok(None);
+        }
+
+        let Some(tokens) = self.rows.tokens.as_mut() else {
+            return Err(Error::NotConnected);
+        };
+
+        Ok(Some(ValueStream {
+            plp: PlpReader::new(tokens.reader_mut(), total_length)
+        }))
+    }
+
+    /**
+     * Moves past the next value without keeping it. (max) values are read through and
+     * thrown away a chunk at a time.
+     */
+    pub fn skip_value(&mut self) -> Result<()> {
+        let is_plp: bool = self.rows.columns.get(self.position).is_some_and(|column| column.type_info.is_plp());
+        if !is_plp {
+            return self.read_value().map(|_| ());
+        }
+
+        let index: usize = self.next_column()?;
+        if self.nulls[index] {
+            return Ok(());
+        }
+
+        let total_length: u64 = self.read_plp_length()?;
+        if total_length == PLP_NULL {
+            return Ok(());
+        }
+
+        let rows: &mut RowStream = &mut *self.rows;
+        let Some(tokens) = rows.tokens.as_mut() else {
+            return Err(Error::NotConnected);
+        };
+
+        match io::copy(&mut PlpReader::new(tokens.reader_mut(), total_length), &mut io::sink()) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(rows.abort(read_error("Failed to read PLP chunk", error)))
+        }
+    }
+
+    fn next_column(&mut self) -> Result<usize> {
+        if self.position >= self.nulls.len() {
+            return Err(Error::Config(format!("All {} values of the row have been read", self.nulls.len())));
+        }
+
+        self.position += 1;
+        Ok(self.position - 1)
+    }
+
+    fn read_plp_length(&mut self) -> Result<u64> {
+        let rows: &mut RowStream = &mut *self.rows;
+        let Some(tokens) = rows.tokens.as_mut() else {
+            return Err(Error::NotConnected);
+        };
+
+        match tokens.reader_mut().read_u64_le() {
+            Ok(total_length) => Ok(total_length),
+            Err(error) => Err(rows.abort(error))
+        }
+    }
+}
+
+impl Drop for RowReader<'_, '_> {
+    fn drop(&mut self) {
+        // a failed read has already closed the stream, leaving nothing to skip
+        while self.position < self.nulls.len() && self.rows.tokens.is_some() {
+            let _ = self.skip_value();
+        }
+    }
+}
+
+/**
+ * A (max) value being read off the connection. Dropping it part way reads through and
+ * throws away the rest.
+ */
+pub struct ValueStream<'r> {
+    plp: PlpReader<&'r mut PacketReader<TdsStream>>
+}
+impl ValueStream<'_> {
+    /**
+     * Length of the whole value, None when the server sent it without one.
+     */
+    pub fn total_length(&self) -> Option<u64> {
+        self.plp.total_length()
+    }
+}
+
+impl Read for ValueStream<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.plp.read(buffer)
+    }
+}
+
+impl Drop for ValueStream<'_> {
+    fn drop(&mut self) {
+        let _ = io::copy(&mut self.plp, &mut io::sink());
+    }
+}
+
+enum Event {
+    Columns(Vec<Column>),
+    Row(Row),
+    RowStart(Vec<bool>),
+    Done
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_settings::ConnectionSettings;
+    use crate::sql_value::SqlValue;
+    use crate::tds_message::ucs2_bytes;
+    use crate::test_server::{fake_server, server_packet, split_response, prelogin_response, login_ack_response, query_response};
+
+    fn logged_in_connector(port: &str) -> Connector {
+        let settings: ConnectionSettings = ConnectionSettings::builder()
+            .server("127.0.0.1")
+            .port(port.parse().unwrap())
+            .user("sa")
+            .password("pass")
+            .build()
+            .unwrap();
+        let mut con: Connector = Connector::new(settings);
+
+        con.connect().unwrap();
+        con.login().unwrap();
+        con
+    }
+
+    #[test]
+    fn test_rowstream_reads_result_sets_across_packets() {
+        let body: Vec<u8> = [&query_response()[8..], &query_response()[8..]].concat();
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), split_response(&body, 7), query_response()]);
+        let mut con: Connector = logged_in_connector(&port);
+
+        {
+            let mut rows: RowStream = con.query_stream("SELECT id, name FROM people; SELECT id, name FROM people").unwrap();
+
+            assert_eq!(rows.column_index("name"), Some(1));
+            assert_eq!(rows.next_row().unwrap().unwrap().get(0), Some(&SqlValue::Int(1)));
+            assert_eq!(rows.next_row().unwrap().unwrap().get(1), Some(&SqlValue::Null));
+            assert!(rows.next_row().unwrap().is_none());
+
+            assert!(rows.next_result_set().unwrap());
+            assert_eq!(rows.columns().len(), 2);
+            assert_eq!(rows.by_ref().collect::<Result<Vec<Row>>>().unwrap().len(), 2);
+
+            assert!(!rows.next_result_set().unwrap());
+            assert!(rows.columns().is_empty());
+            assert_eq!(rows.rows_affected(), &[2, 2]);
+        }
+
+        // the connection has its stream back
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_rowstream_drains_response_when_dropped_early() {
+        let body: Vec<u8> = [&query_response()[8..], &query_response()[8..]].concat();
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), split_response(&body, 16), query_response()]);
+        let mut con: Connector = logged_in_connector(&port);
+
+        let mut rows: RowStream = con.query_stream("SELECT id, name FROM people; SELECT id, name FROM people").unwrap();
+        assert!(rows.next().is_some());
+        drop(rows);
+
+        assert!(con.is_connected());
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_rowstream_streams_max_values_across_packets() {
+        let blob: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+
+        // id int, data varbinary(max)
+        let mut body: Vec<u8> = vec![0x81, 0x02, 0x00];
+        body.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x38, 0x02]);
+        body.extend_from_slice(&ucs2_bytes("id"));
+        body.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0xa5, 0xff, 0xff, 0x04]);
+        body.extend_from_slice(&ucs2_bytes("data"));
+        // the blob in chunks with no total length, a row with it NULL, then a small one
+        body.extend_from_slice(&[0xd1, 0x01, 0x00, 0x00, 0x00]);
+        body.extend_from_slice(&0xfffffffffffffffeu64.to_le_bytes());
+        for chunk in blob.chunks(1000) {
+            body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            body.extend_from_slice(chunk);
+        }
+        body.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        body.extend_from_slice(&[0xd2, 0x02, 0x02, 0x00, 0x00, 0x00]);
+        body.extend_from_slice(&[0xd1, 0x03, 0x00, 0x00, 0x00]);
+        body.extend_from_slice(&crate::tds_message::plp_bytes(b"xyz"));
+        body.extend_from_slice(&[0xfd, 0x10, 0x00, 0xc1, 0x00, 0x03, 0, 0, 0, 0, 0, 0, 0]);
+
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), split_response(&body, 100), query_response()]);
+        let mut con: Connector = logged_in_connector(&port);
+
+        {
+            let mut rows: RowStream = con.query_stream("SELECT id, data FROM blobs").unwrap();
+
+            let mut row: RowReader = rows.next_row_reader().unwrap().unwrap();
+            assert_eq!(row.read_value().unwrap(), SqlValue::Int(1));
+            let mut value: ValueStream = row.stream_value().unwrap().unwrap();
+            assert_eq!(value.total_length(), None);
+            let mut data: Vec<u8> = Vec::new();
+            value.read_to_end(&mut data).unwrap();
+            drop(value);
+            assert_eq!(data, blob);
+            assert_eq!(row.position(), 2);
+            assert!(matches!(row.read_value(), Err(Error::Config(_))));
+            drop(row);
+
+            let mut row: RowReader = rows.next_row_reader().unwrap().unwrap();
+            assert!(matches!(row.stream_value(), Err(Error::Config(_))));
+            assert_eq!(row.read_value().unwrap(), SqlValue::Int(2));
+            assert!(row.stream_value().unwrap().is_none());
+            drop(row);
+
+            // left unread, so skipped
+            assert!(rows.next_row_reader().unwrap().is_some());
+
+            assert!(rows.next_row_reader().unwrap().is_none());
+            assert!(!rows.next_result_set().unwrap());
+            assert_eq!(rows.rows_affected(), &[3]);
+        }
+
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_rowstream_returns_server_error_and_carries_on() {
+        let message = ucs2_bytes("Divide by zero error encountered.");
+        let mut data: Vec<u8> = vec![0xc6, 0x1f, 0x00, 0x00, 0x01, 0x10];
+        data.extend_from_slice(&(message.len() as u16 / 2).to_le_bytes());
+        data.extend_from_slice(&message);
+        data.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+
+        let mut body: Vec<u8> = query_response()[8..].to_vec();
+        let done: usize = body.len() - 13;
+        body.truncate(done);
+        body.push(0xaa);
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(&data);
+        body.extend_from_slice(&[0xfd, 0x02, 0x00, 0xc1, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), server_packet(&body)]);
+        let mut con: Connector = logged_in_connector(&port);
+
+        let results: Vec<Result<Row>> = con.query_stream("SELECT id, 1 / 0 FROM people").unwrap().collect();
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok() && results[1].is_ok());
+        assert!(matches!(&results[2], Err(Error::Server(error)) if error.number == 8134));
+        assert!(con.is_connected());
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_rowstream_ends_with_cancelled_after_cancel_handle() {
+        // rows, then the DONE acknowledging the ATTENTION in place of the final count
+        let mut body: Vec<u8> = query_response()[8..].to_vec();
+        let done: usize = body.len() - 13;
+        body.truncate(done);
+        body.extend_from_slice(&[0xfd, 0x20, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let (port, server) = fake_server(vec![prelogin_response(), login_ack_response(), server_packet(&body), Vec::new(), query_response()]);
+        let mut con: Connector = logged_in_connector(&port);
+        let handle = con.cancel_handle().unwrap();
+
+        let mut rows: RowStream = con.query_stream("SELECT id, name FROM people").unwrap();
+        handle.cancel().unwrap();
+        let results: Vec<Result<Row>> = rows.by_ref().collect();
+        drop(rows);
+
+        assert!(matches!(results.last(), Some(Err(Error::Cancelled))));
+        assert_eq!(con.query("SELECT id, name FROM people").unwrap().rows().len(), 2);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[3].header().message_type(), 0x06);
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use zeroize::Zeroize;
+use crate::error::{Error, Result};
+
+/**
+ * A password (or anything else that shouldn't leak)
+ *
+ * The memory is wiped when it's dropped and Debug prints a placeholder, so a settings
+ * struct can be logged without giving the password away. There's deliberately no Display,
+ * use expose() where the actual value is needed.
+ */
+#[derive(Clone, Default)]
+pub struct Secret(String);
+impl Secret {
+    pub fn new(value: &str) -> Secret {
+        Secret(String::from(value))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /**
+     * The first line of a file, e.g. a Docker or Kubernetes secret mount.
+     */
+    pub fn from_file(path: &Path) -> Result<Secret> {
+        let mut contents: String = fs::read_to_string(path)
+            .map_err(|e| Error::io(&format!("Failed to read password file {}", path.display()), e))?;
+
+        let secret: Secret = Secret::new(contents.lines().next().unwrap_or(""));
+        contents.zeroize();
+
+        Ok(secret)
+    }
+
+    /**
+     * The first line a command prints, e.g. `pass show db/prod` or a vault CLI. The command
+     * goes through the shell so it can have arguments and pipes.
+     */
+    pub fn from_command(command: &str) -> Result<Secret> {
+        let output = if cfg!(windows) {
+            Command::new("cmd").args(["/C", command]).output()
+        } else {
+            Command::new("sh").args(["-c", command]).output()
+        };
+        let mut output = output.map_err(|e| Error::io(&format!("Failed to run password command '{}'", command), e))?;
+
+        if !output.status.success() {
+            let message: String = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            output.stdout.zeroize();
+            return Err(Error::Config(format!("Password command '{}' failed ({}): {}", command, output.status, message)));
+        }
+
+        let secret: Secret = match std::str::from_utf8(&output.stdout) {
+            Ok(stdout) => Ok(Secret::new(stdout.lines().next().unwrap_or(""))),
+            Err(_) => Err(Error::Config(format!("Password command '{}' didn't print UTF-8", command)))
+        }?;
+        output.stdout.zeroize();
+
+        Ok(secret)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(********)")
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Secret) -> bool {
+        self.0 == other.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_secret_debug_is_redacted() {
+        let secret: Secret = Secret::new("hunter2");
+
+        assert_eq!(format!("{:?}", secret), "Secret(********)");
+        assert_eq!(secret.expose(), "hunter2");
+    }
+
+    #[test]
+    fn test_secret_from_file_reads_first_line() {
+        let path: PathBuf = env::temp_dir().join(format!("sql_connector_secret_{}", std::process::id()));
+        fs::write(&path, "file-pass\r\nignored\n").unwrap();
+
+        let secret: Secret = Secret::from_file(&path).unwrap();
+
+        assert_eq!(secret.expose(), "file-pass");
+        assert!(matches!(Secret::from_file(&path.with_extension("missing")), Err(Error::Io { .. })));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_from_command_reads_stdout() {
+        assert_eq!(Secret::from_command("echo command-pass").unwrap().expose(), "command-pass");
+        assert!(matches!(Secret::from_command("echo nope >&2; exit 3"), Err(Error::Config(ref message)) if message.contains("nope")));
+    }
+}
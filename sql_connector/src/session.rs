@@ -0,0 +1,424 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use rustls::ClientConfig;
+use rustls_pki_types::ServerName;
+use crate::connection_settings::ConnectionSettings;
+use crate::login7::Login7;
+use crate::secret::Secret;
+use std::time::Duration;
+use crate::tds_message::{TdsMessage, ClientMessageType, PreLoginConfig, PreLoginResponse, MessageStatus, MarsOptions, TransactionRequest, DEFAULT_PACKET_SIZE};
+use crate::tds_token::{TdsToken, TokenStream, LoginAck, EnvChange, ServerMessage, DoneStatus, TokenType};
+use crate::query_result::{QueryResult, ResultSet};
+use crate::tds_type::Collation;
+use crate::sql_value::SqlValue;
+use crate::tls::{self, Encryption};
+use crate::browser;
+use crate::error::{Error, Result};
+
+/**
+ * How long the server gets to acknowledge an ATTENTION before the connection is given up on.
+ */
+pub const ATTENTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// DONE token: type, status, current command and row count
+const DONE_LENGTH: usize = 13;
+
+/**
+ * Everything about a connection apart from the socket
+ *
+ * Builds the messages to send and reads the server's responses, keeping track of what the
+ * server has told us (packet size, database, transaction, ...). The blocking and async
+ * connectors only differ in how the bytes get to and from the server, so both drive one
+ * of these.
+ */
+pub struct Session {
+    settings: ConnectionSettings,
+    encryption: Encryption,
+    mars: bool,
+    authenticated: bool,
+    prelogin: Option<PreLoginResponse>,
+    login_ack: Option<LoginAck>,
+    current_database: Option<String>,
+    packet_size: u32,
+    collation: Vec<u8>,
+    // shared by every MARS session on the connection, as the transaction is
+    transaction_descriptor: Arc<AtomicU64>,
+    messages: Vec<ServerMessage>,
+    reset: Option<MessageStatus>
+}
+impl Session {
+    pub fn new(settings: ConnectionSettings) -> Session {
+        Session {
+            settings,
+            encryption: Encryption::None,
+            mars: false,
+            authenticated: false,
+            prelogin: None,
+            login_ack: None,
+            current_database: None,
+            packet_size: DEFAULT_PACKET_SIZE,
+            collation: Vec::new(),
+            transaction_descriptor: Arc::new(AtomicU64::new(0)),
+            messages: Vec::new(),
+            reset: None
+        }
+    }
+
+    pub fn settings(&self) -> &ConnectionSettings {
+        &self.settings
+    }
+
+    /**
+     * The state for another MARS session on this connection. It shares the login and the
+     * transaction descriptor, so a transaction begun or ended on one session is seen by the
+     * others, but has its own messages and reset.
+     */
+    pub fn mars_session(&self) -> Session {
+        Session {
+            settings: self.settings.clone(),
+            encryption: self.encryption,
+            mars: self.mars,
+            authenticated: self.authenticated,
+            prelogin: self.prelogin.clone(),
+            login_ack: self.login_ack.clone(),
+            current_database: self.current_database.clone(),
+            packet_size: self.packet_size,
+            collation: self.collation.clone(),
+            transaction_descriptor: Arc::clone(&self.transaction_descriptor),
+            messages: Vec::new(),
+            reset: None
+        }
+    }
+
+    pub fn prelogin_message(&self) -> TdsMessage {
+        let mut config: PreLoginConfig = PreLoginConfig::new();
+        config.encryption = self.settings.tls().encrypt.prelogin_option();
+        if self.settings.mars() {
+            config.mars = MarsOptions::MarsRequested;
+        }
+
+        let mut message: TdsMessage = TdsMessage::new();
+        message.generate_prelogin(&config);
+        message
+    }
+
+    /**
+     * Reads the PRELOGIN answer and settles on the encryption, which is returned so the
+     * connector knows whether to start TLS.
+     */
+    pub fn read_prelogin(&mut self, body: &[u8]) -> Result<Encryption> {
+        let prelogin: PreLoginResponse = PreLoginResponse::from_bytes(body)?;
+
+        self.encryption = self.settings.tls().encrypt.negotiate(prelogin.encryption)?;
+        self.mars = self.settings.mars() && prelogin.mars;
+        self.prelogin = Some(prelogin);
+
+        Ok(self.encryption)
+    }
+
+    /**
+     * The TLS config and the name the certificate has to match.
+     */
+    pub fn tls_parameters(&self) -> Result<(Arc<ClientConfig>, ServerName<'static>)> {
+        Ok((self.settings.tls().client_config()?, tls::server_name(self.settings.server())?))
+    }
+
+    pub fn login_message(&self) -> Result<TdsMessage> {
+        let password: Secret = self.settings.password()?;
+        let mut login: Login7 = Login7::new(
+            self.settings.user(),
+            password.expose(),
+            self.settings.server(),
+            self.settings.database().unwrap_or("")
+        );
+        login.packet_size = self.settings.packet_size();
+
+        if let Some(application_name) = self.settings.application_name() {
+            login.app_name = String::from(application_name);
+        }
+
+        let mut message: TdsMessage = TdsMessage::new();
+        message.generate_login7(&login);
+        Ok(message)
+    }
+
+    /**
+     * Only a LOGINACK from the server counts as being logged in.
+     */
+    pub fn read_login_response(&mut self, body: &[u8]) -> Result<()> {
+        let mut tokens = TokenStream::new(body);
+
+        while let Some(token) = tokens.next_token()? {
+            match token {
+                TdsToken::LoginAck(ack) => self.login_ack = Some(ack),
+                TdsToken::EnvChange(change) => self.apply_env_change(change),
+                TdsToken::Info(info) => self.add_message(info),
+                TdsToken::Error(error) => return Err(Error::Login(error)),
+                _ => ()
+            }
+        }
+
+        if self.login_ack.is_none() {
+            return Err(Error::Protocol(String::from("server did not acknowledge the login")));
+        }
+
+        self.authenticated = true;
+        Ok(())
+    }
+
+    /**
+     * Has the server reset the session (temp tables, SET options, open transaction, ...)
+     * before it runs the next request, as if the connection had just logged in. With
+     * `skip_transaction` an open transaction is kept.
+     *
+     * Nothing is sent until then, the flag goes on the next request's packet headers. The
+     * transaction descriptor is shared with any other MARS sessions, so it's left for the
+     * server's ENVCHANGE to clear once the transaction has actually been rolled back.
+     */
+    pub fn reset_on_next_request(&mut self, skip_transaction: bool) {
+        if skip_transaction {
+            self.reset = Some(MessageStatus::ResetConnectionSkipTran);
+        } else {
+            self.reset = Some(MessageStatus::ResetConnection);
+        }
+        self.messages.clear();
+    }
+
+    pub fn sql_batch_message(&mut self, sql: &str) -> Result<TdsMessage> {
+        if !self.authenticated {
+            return Err(Error::NotLoggedIn);
+        }
+
+        let mut message: TdsMessage = TdsMessage::new();
+        message.generate_sql_batch(sql, self.transaction_descriptor());
+        self.apply_reset(&mut message);
+        Ok(message)
+    }
+
+    pub fn sp_executesql_message(&mut self, sql: &str, params: &[SqlValue]) -> Result<TdsMessage> {
+        if !self.authenticated {
+            return Err(Error::NotLoggedIn);
+        }
+
+        let collation: Collation = Collation::from_bytes(&self.collation).unwrap_or(Collation::new(0, 0));
+
+        let mut message: TdsMessage = TdsMessage::new();
+        message.generate_sp_executesql(sql, params, collation, self.transaction_descriptor())?;
+        self.apply_reset(&mut message);
+        Ok(message)
+    }
+
+    /**
+     * The request goes with the current transaction descriptor, the server's ENVCHANGE in
+     * the response then updates it (see apply_env_change).
+     */
+    pub fn transaction_message(&mut self, request: &TransactionRequest) -> Result<TdsMessage> {
+        if !self.authenticated {
+            return Err(Error::NotLoggedIn);
+        }
+
+        if let TransactionRequest::Save(name) | TransactionRequest::Rollback(Some(name)) = request {
+            if name.is_empty() || name.encode_utf16().count() > 255 {
+                return Err(Error::Config(format!("Savepoint names need 1 to 255 characters, got '{}'", name)));
+            }
+        }
+
+        let mut message: TdsMessage = TdsMessage::new();
+        message.generate_transaction_request(request, self.transaction_descriptor());
+        self.apply_reset(&mut message);
+        Ok(message)
+    }
+
+    /**
+     * ATTENTION cancels whatever the server is running for us. It's just the header.
+     */
+    pub fn attention_message(&self) -> TdsMessage {
+        let mut message: TdsMessage = TdsMessage::new();
+        message.set_body(ClientMessageType::Attention, Vec::new());
+        message
+    }
+
+    fn apply_reset(&mut self, message: &mut TdsMessage) {
+        if let Some(status) = self.reset.take() {
+            message.update_status(status);
+        }
+    }
+
+    pub fn read_query_result(&mut self, body: &[u8]) -> Result<QueryResult> {
+        let mut tokens = TokenStream::new(body);
+        let mut result: QueryResult = QueryResult::new();
+        let mut error: Option<ServerMessage> = None;
+
+        while let Some(token) = tokens.next_token()? {
+            match token {
+                TdsToken::ColMetadata(columns) if !columns.is_empty() => {
+                    result.result_sets.push(ResultSet::new(columns));
+                },
+                TdsToken::Row(row) => {
+                    let result_set = result.result_sets.last_mut().ok_or(Error::Protocol(String::from("Row received without a result set")))?;
+                    result_set.rows.push(row);
+                },
+                TdsToken::Done(done) | TdsToken::DoneProc(done) | TdsToken::DoneInProc(done) => {
+                    if let Some(count) = done.rows_affected() {
+                        result.rows_affected.push(count);
+                    }
+                },
+                TdsToken::ReturnStatus(status) => result.return_status = Some(status),
+                TdsToken::EnvChange(change) => self.apply_env_change(change),
+                TdsToken::Info(info) => self.add_message(info),
+                // keep reading so the connection is left at the end of the response
+                TdsToken::Error(message) if error.is_none() => error = Some(message),
+                _ => ()
+            }
+        }
+
+        if let Some(error) = error {
+            return Err(Error::Server(error));
+        }
+
+        Ok(result)
+    }
+
+    pub fn apply_env_change(&mut self, change: EnvChange) {
+        match change {
+            EnvChange::Database { new, .. } => self.current_database = Some(new),
+            EnvChange::PacketSize { new, .. } => self.packet_size = new,
+            EnvChange::SqlCollation { new, .. } => self.collation = new,
+            EnvChange::BeginTransaction { descriptor } => self.set_transaction_descriptor(descriptor),
+            EnvChange::CommitTransaction { .. } | EnvChange::RollbackTransaction { .. } => self.set_transaction_descriptor(0),
+            _ => ()
+        }
+    }
+
+    pub fn encryption(&self) -> Encryption {
+        self.encryption
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /**
+     * Whether we asked for MARS and the server agreed.
+     */
+    pub fn is_mars(&self) -> bool {
+        self.mars
+    }
+
+    pub fn prelogin_response(&self) -> Option<&PreLoginResponse> {
+        self.prelogin.as_ref()
+    }
+
+    pub fn login_ack(&self) -> Option<&LoginAck> {
+        self.login_ack.as_ref()
+    }
+
+    pub fn current_database(&self) -> Option<&str> {
+        self.current_database.as_deref()
+    }
+
+    pub fn packet_size(&self) -> u32 {
+        self.packet_size
+    }
+
+    pub fn messages(&self) -> &[ServerMessage] {
+        &self.messages
+    }
+
+    pub fn add_message(&mut self, message: ServerMessage) {
+        self.messages.push(message);
+    }
+
+    /**
+     * Whether the server has told us about a transaction that hasn't ended yet.
+     */
+    pub fn in_transaction(&self) -> bool {
+        self.transaction_descriptor() != 0
+    }
+
+    fn transaction_descriptor(&self) -> u64 {
+        self.transaction_descriptor.load(Ordering::SeqCst)
+    }
+
+    fn set_transaction_descriptor(&self, descriptor: u64) {
+        self.transaction_descriptor.store(descriptor, Ordering::SeqCst);
+    }
+}
+
+/**
+ * Whether a message from the server ends with the DONE that acknowledges an ATTENTION.
+ * Only the end is looked at because after a timeout the reads can start part way into a
+ * response, where the tokens can't be parsed.
+ */
+pub fn is_attention_ack(body: &[u8]) -> bool {
+    if body.len() < DONE_LENGTH {
+        return false;
+    }
+
+    let done: &[u8] = &body[body.len() - DONE_LENGTH..];
+    let status: u16 = u16::from_le_bytes([done[1], done[2]]);
+
+    done[0] == TokenType::Done.value() && status & DoneStatus::Attention.value() != 0
+}
+
+/**
+ * The port to connect to. A named instance without a port has to be looked up with the
+ * SQL Server Browser first, which blocks for up to the connect timeout.
+ */
+pub fn server_port(settings: &ConnectionSettings) -> Result<u16> {
+    match (settings.instance(), settings.explicit_port()) {
+        (Some(instance), None) => {
+            let timeout = settings.connect_timeout().unwrap_or(browser::DEFAULT_TIMEOUT);
+            browser::instance_port(settings.server(), instance, timeout)
+        },
+        _ => Ok(settings.port())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_mars_session_shares_transaction_descriptor() {
+        let settings: ConnectionSettings = ConnectionSettings::builder().server("localhost").build().unwrap();
+        let mut first: Session = Session::new(settings);
+        let mut second: Session = first.mars_session();
+
+        first.apply_env_change(EnvChange::BeginTransaction { descriptor: 5 });
+        assert!(second.in_transaction());
+        assert_eq!(second.transaction_descriptor(), 5);
+
+        // a session opened during the transaction joins it, and ending it ends it everywhere
+        let third: Session = second.mars_session();
+        second.apply_env_change(EnvChange::CommitTransaction { descriptor: 5 });
+        assert!(!first.in_transaction());
+        assert!(!third.in_transaction());
+    }
+
+    #[test]
+    fn test_session_reset_leaves_shared_transaction_to_server() {
+        let settings: ConnectionSettings = ConnectionSettings::builder().server("localhost").build().unwrap();
+        let mut first: Session = Session::new(settings);
+        let mut second: Session = first.mars_session();
+        first.apply_env_change(EnvChange::BeginTransaction { descriptor: 5 });
+
+        second.reset_on_next_request(false);
+        assert!(first.in_transaction());
+
+        second.apply_env_change(EnvChange::RollbackTransaction { descriptor: 5 });
+        assert!(!first.in_transaction());
+    }
+
+    #[test]
+    fn test_is_attention_ack_checks_last_done() {
+        let ack: [u8; 13] = [0xfd, 0x20, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut partial: Vec<u8> = vec![0x00, 0x12, 0xd1];
+        partial.extend_from_slice(&ack);
+
+        assert!(is_attention_ack(&ack));
+        assert!(is_attention_ack(&partial));
+        assert!(!is_attention_ack(&[0xfd, 0x10, 0x00, 0xc1, 0x00, 2, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(!is_attention_ack(&ack[1..]));
+    }
+}
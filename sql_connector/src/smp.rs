@@ -0,0 +1,633 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use rustls::ClientConnection;
+use crate::tds_stream;
+use crate::error::{Error, Result};
+
+pub const SMP_HEADER_LENGTH: usize = 16;
+const SMID: u8 = 0x53;
+
+/**
+ * How many DATA packets each side starts out able to send before it has to wait for the
+ * other to acknowledge some. It's also how far we move our window on when we do.
+ */
+pub const DEFAULT_WINDOW: u32 = 4;
+
+/**
+ * Session Multiplex Protocol header
+ *
+ * With MARS every TDS packet travels inside an SMP DATA packet, whose header says which
+ * session it belongs to. Each session numbers its DATA packets, and WNDW tells the other
+ * side the highest number it's ready to receive.
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-smp/6e3ae4ce-bc01-4d57-9e0f-f3e2a1e1fe80
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmpHeader {
+    pub flags: u8,
+    pub session_id: u16,
+    pub length: u32,
+    pub sequence_number: u32,
+    pub window: u32
+}
+impl SmpHeader {
+    pub fn new(flag: SmpFlag, session_id: u16, sequence_number: u32, window: u32, payload_length: usize) -> SmpHeader {
+        SmpHeader {
+            flags: flag.value(),
+            session_id,
+            length: (SMP_HEADER_LENGTH + payload_length) as u32,
+            sequence_number,
+            window
+        }
+    }
+
+    /**
+     * A DATA packet carries one TDS packet, so a length past `packet_size` plus the header
+     * is rejected rather than trusted.
+     */
+    pub fn from_bytes(bytes: &[u8; SMP_HEADER_LENGTH], packet_size: u32) -> Result<SmpHeader> {
+        if bytes[0] != SMID {
+            return Err(Error::Protocol(format!("Expected an SMP packet, got 0x{:02x}", bytes[0])));
+        }
+
+        let header: SmpHeader = SmpHeader {
+            flags: bytes[1],
+            session_id: u16::from_le_bytes([bytes[2], bytes[3]]),
+            length: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            sequence_number: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            window: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]])
+        };
+
+        if (header.length as usize) < SMP_HEADER_LENGTH {
+            return Err(Error::Protocol(format!("SMP packet length {} is shorter than its header", header.length)));
+        }
+        if header.length as usize > SMP_HEADER_LENGTH + packet_size as usize {
+            return Err(Error::Protocol(format!("SMP packet length {} is more than the packet size {} allows", header.length, packet_size)));
+        }
+
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> [u8; SMP_HEADER_LENGTH] {
+        let mut bytes: [u8; SMP_HEADER_LENGTH] = [0; SMP_HEADER_LENGTH];
+        bytes[0] = SMID;
+        bytes[1] = self.flags;
+        bytes[2..4].copy_from_slice(&self.session_id.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.length.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.sequence_number.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.window.to_le_bytes());
+        bytes
+    }
+
+    pub fn has_flag(&self, flag: SmpFlag) -> bool {
+        self.flags & flag.value() != 0
+    }
+
+    pub fn payload_length(&self) -> usize {
+        self.length as usize - SMP_HEADER_LENGTH
+    }
+}
+
+pub enum SmpFlag {
+    Syn,
+    Ack,
+    Fin,
+    Data
+}
+impl SmpFlag {
+    pub fn value(&self) -> u8 {
+        match self {
+            SmpFlag::Syn => 0x01,
+            SmpFlag::Ack => 0x02,
+            SmpFlag::Fin => 0x04,
+            SmpFlag::Data => 0x08
+        }
+    }
+}
+
+/**
+ * One logical session on a MARS connection, used by TdsStream in place of the socket.
+ *
+ * All the sessions share the socket. Whichever session needs something from the server
+ * reads the next SMP packet, and packets for other sessions are queued until those
+ * sessions ask for them, so no thread has to sit reading in the background.
+ *
+ * The session that opened it sends FIN when it's dropped, handle() gives a copy that
+ * doesn't (e.g. for a TdsWriter).
+ *
+ * Each session has its own timeout, the socket's timeouts are only set by whichever session
+ * is reading or writing at the time.
+ */
+pub(crate) struct SmpSession {
+    mux: Arc<Multiplexer>,
+    id: u16,
+    owner: bool,
+    timeout: Option<Duration>
+}
+
+struct Multiplexer {
+    socket: TcpStream,
+    tls: Arc<Mutex<Option<ClientConnection>>>,
+    packet_size: u32,
+    // held by whichever session is reading from the socket
+    reading: Mutex<()>,
+    state: Mutex<MuxState>,
+    changed: Condvar,
+    // set when a read stopped part way through a packet, after which we're lost
+    broken: AtomicBool
+}
+
+struct MuxState {
+    channels: HashMap<u16, Channel>,
+    next_id: u16
+}
+
+struct Channel {
+    received: VecDeque<Vec<u8>>,
+    position: usize,
+    consumed: u32,
+    window: u32,
+    sent: u32,
+    peer_window: u32,
+    finished: bool
+}
+
+impl SmpSession {
+    /**
+     * Switches the connection over to SMP, opening its first session. `packet_size` is the
+     * one negotiated at login.
+     */
+    pub fn start(socket: TcpStream, tls: Arc<Mutex<Option<ClientConnection>>>, packet_size: u32) -> io::Result<SmpSession> {
+        let mux: Arc<Multiplexer> = Arc::new(Multiplexer {
+            socket,
+            tls,
+            packet_size,
+            reading: Mutex::new(()),
+            state: Mutex::new(MuxState {
+                channels: HashMap::new(),
+                next_id: 0
+            }),
+            changed: Condvar::new(),
+            broken: AtomicBool::new(false)
+        });
+
+        Multiplexer::open(&mux)
+    }
+
+    /**
+     * Another session on the same connection.
+     */
+    pub fn open(&self) -> io::Result<SmpSession> {
+        Multiplexer::open(&self.mux)
+    }
+
+    pub fn handle(&self) -> SmpSession {
+        SmpSession {
+            mux: Arc::clone(&self.mux),
+            id: self.id,
+            owner: false,
+            timeout: self.timeout
+        }
+    }
+
+    /**
+     * How long each read or write waits, None for no limit. Running out is a TimedOut error.
+     */
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /**
+     * Sends `data` as one DATA packet, first waiting for the server to open its window if
+     * it's full.
+     */
+    pub fn write(&self, data: &[u8]) -> io::Result<()> {
+        let deadline: Option<Instant> = self.deadline();
+        let mut state = self.mux.lock();
+
+        loop {
+            self.mux.check()?;
+            let channel: &mut Channel = state.channel(self.id)?;
+
+            if is_before(channel.sent, channel.peer_window) {
+                channel.sent = channel.sent.wrapping_add(1);
+
+                let header: SmpHeader = SmpHeader::new(SmpFlag::Data, self.id, channel.sent, channel.window, data.len());
+                let mut packet: Vec<u8> = header.to_bytes().to_vec();
+                packet.extend_from_slice(data);
+
+                // still holding the state so packets go out in sequence number order
+                return self.mux.send(&packet, deadline);
+            }
+
+            state = self.mux.pump(state, deadline)?;
+        }
+    }
+
+    /**
+     * Reads from the DATA this session has received, reading from the socket when there
+     * isn't any. Ok(0) once the server has closed the session.
+     */
+    pub fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let deadline: Option<Instant> = self.deadline();
+        let mut state = self.mux.lock();
+
+        loop {
+            let channel: &mut Channel = state.channel(self.id)?;
+
+            if let Some(packet) = channel.received.front() {
+                let count: usize = buffer.len().min(packet.len() - channel.position);
+                buffer[..count].copy_from_slice(&packet[channel.position..channel.position + count]);
+                channel.position += count;
+
+                if channel.position == packet.len() {
+                    channel.received.pop_front();
+                    channel.position = 0;
+                    channel.consumed = channel.consumed.wrapping_add(1);
+
+                    // move the window on before the server runs out of room, not every packet
+                    if channel.window.wrapping_sub(channel.consumed) < DEFAULT_WINDOW / 2 {
+                        channel.window = channel.consumed.wrapping_add(DEFAULT_WINDOW);
+
+                        let ack: SmpHeader = SmpHeader::new(SmpFlag::Ack, self.id, channel.sent, channel.window, 0);
+                        self.mux.send(&ack.to_bytes(), deadline)?;
+                    }
+                }
+
+                return Ok(count);
+            }
+
+            if channel.finished {
+                return Ok(0);
+            }
+
+            self.mux.check()?;
+            state = self.mux.pump(state, deadline)?;
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.mux.lock();
+
+        if let Some(channel) = state.channels.remove(&self.id) {
+            if !channel.finished {
+                let fin: SmpHeader = SmpHeader::new(SmpFlag::Fin, self.id, channel.sent, channel.window, 0);
+                let _ = self.mux.send(&fin.to_bytes(), self.deadline());
+            }
+        }
+    }
+}
+
+impl Drop for SmpSession {
+    fn drop(&mut self) {
+        if self.owner {
+            self.close();
+        }
+    }
+}
+
+impl Multiplexer {
+    fn open(mux: &Arc<Multiplexer>) -> io::Result<SmpSession> {
+        let mut state = mux.lock();
+        mux.check()?;
+
+        let mut id: u16 = state.next_id;
+        while state.channels.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        state.next_id = id.wrapping_add(1);
+
+        let channel: Channel = Channel {
+            received: VecDeque::new(),
+            position: 0,
+            consumed: 0,
+            window: DEFAULT_WINDOW,
+            sent: 0,
+            peer_window: DEFAULT_WINDOW,
+            finished: false
+        };
+        state.channels.insert(id, channel);
+
+        let syn: SmpHeader = SmpHeader::new(SmpFlag::Syn, id, 0, DEFAULT_WINDOW, 0);
+        mux.send(&syn.to_bytes(), None)?;
+
+        Ok(SmpSession {
+            mux: Arc::clone(mux),
+            id,
+            owner: true,
+            timeout: None
+        })
+    }
+
+    /**
+     * Reads one packet from the socket and hands it to its session, or if another session
+     * is already reading waits for it to finish. Either way the caller then looks again
+     * for what it was waiting on, until `deadline`.
+     */
+    fn pump<'a>(&'a self, state: MutexGuard<'a, MuxState>, deadline: Option<Instant>) -> io::Result<MutexGuard<'a, MuxState>> {
+        let timeout: Option<Duration> = remaining(deadline)?;
+
+        // tried while holding the state, so the reader can't finish and notify before we wait
+        let reading = match self.reading.try_lock() {
+            Ok(reading) => reading,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                return Ok(match timeout {
+                    Some(timeout) => self.changed.wait_timeout(state, timeout).unwrap_or_else(|poisoned| poisoned.into_inner()).0,
+                    None => self.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner())
+                });
+            }
+        };
+        drop(state);
+
+        // only the reader touches the read timeout
+        let packet: io::Result<(SmpHeader, Vec<u8>)> = self.socket.set_read_timeout(timeout)
+            .and_then(|_| self.read_packet());
+
+        let mut state = self.lock();
+        drop(reading);
+        self.changed.notify_all();
+
+        let (header, payload) = packet?;
+        state.dispatch(header, payload);
+        Ok(state)
+    }
+
+    fn read_packet(&self) -> io::Result<(SmpHeader, Vec<u8>)> {
+        let mut header: [u8; SMP_HEADER_LENGTH] = [0; SMP_HEADER_LENGTH];
+        self.read_exact(&mut header, false)?;
+
+        let header: SmpHeader = SmpHeader::from_bytes(&header, self.packet_size).map_err(|e| {
+            self.broken.store(true, Ordering::SeqCst);
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })?;
+
+        let mut payload: Vec<u8> = vec![0; header.payload_length()];
+        self.read_exact(&mut payload, true)?;
+
+        Ok((header, payload))
+    }
+
+    /**
+     * A failure before anything has been read (e.g. the socket timeout) leaves the stream
+     * where it was, once part of a packet has gone it's broken.
+     */
+    fn read_exact(&self, buffer: &mut [u8], started: bool) -> io::Result<()> {
+        let mut filled: usize = 0;
+
+        while filled < buffer.len() {
+            match tds_stream::read_raw(&self.socket, &self.tls, &mut buffer[filled..]) {
+                Ok(0) => {
+                    self.broken.store(true, Ordering::SeqCst);
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                },
+                Ok(read) => filled += read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(error) => {
+                    if started || filled > 0 {
+                        self.broken.store(true, Ordering::SeqCst);
+                    }
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Called holding the state, so only one session at a time sets the write timeout. Part
+     * of a packet going out before a failure breaks the connection.
+     */
+    fn send(&self, packet: &[u8], deadline: Option<Instant>) -> io::Result<()> {
+        self.socket.set_write_timeout(remaining(deadline)?)?;
+
+        let mut sent: usize = 0;
+        tds_stream::write_packet(&self.socket, &self.tls, packet, &mut sent).inspect_err(|_| {
+            if sent > 0 && sent < packet.len() {
+                self.broken.store(true, Ordering::SeqCst);
+            }
+        })
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The MARS connection was lost part way through a packet"));
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MuxState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MuxState {
+    fn channel(&mut self, id: u16) -> io::Result<&mut Channel> {
+        self.channels.get_mut(&id).ok_or(io::Error::new(io::ErrorKind::NotConnected, format!("MARS session {} is closed", id)))
+    }
+
+    fn dispatch(&mut self, header: SmpHeader, payload: Vec<u8>) {
+        // e.g. the server's FIN for a session we've already closed
+        let Some(channel) = self.channels.get_mut(&header.session_id) else {
+            return;
+        };
+
+        if header.has_flag(SmpFlag::Data) {
+            channel.received.push_back(payload);
+        }
+        if header.has_flag(SmpFlag::Data) || header.has_flag(SmpFlag::Ack) {
+            channel.peer_window = header.window;
+        }
+        if header.has_flag(SmpFlag::Fin) {
+            channel.finished = true;
+        }
+    }
+}
+
+/**
+ * Time left until `deadline`, TimedOut once it's passed.
+ */
+fn remaining(deadline: Option<Instant>) -> io::Result<Option<Duration>> {
+    let Some(deadline) = deadline else {
+        return Ok(None);
+    };
+
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(remaining) if !remaining.is_zero() => Ok(Some(remaining)),
+        _ => Err(io::Error::new(io::ErrorKind::TimedOut, "The MARS session timed out"))
+    }
+}
+
+// sequence numbers wrap, so compare them by distance
+fn is_before(sequence_number: u32, other: u32) -> bool {
+    (other.wrapping_sub(sequence_number) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::tds_message::DEFAULT_PACKET_SIZE;
+    use crate::test_server::{smp_packet, read_smp_packet as read_smp};
+
+    fn write_smp(stream: &mut TcpStream, flag: SmpFlag, session_id: u16, sequence_number: u32, window: u32, payload: &[u8]) {
+        stream.write_all(&smp_packet(flag, session_id, sequence_number, window, payload)).unwrap();
+    }
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_smpheader_to_bytes_round_trips() {
+        let header: SmpHeader = SmpHeader::new(SmpFlag::Data, 2, 5, 8, 10);
+        let bytes = header.to_bytes();
+
+        assert_eq!(bytes, [0x53, 0x08, 0x02, 0x00, 26, 0, 0, 0, 5, 0, 0, 0, 8, 0, 0, 0]);
+        assert_eq!(SmpHeader::from_bytes(&bytes, DEFAULT_PACKET_SIZE).unwrap(), header);
+        assert_eq!(header.payload_length(), 10);
+        assert!(header.has_flag(SmpFlag::Data));
+        assert!(!header.has_flag(SmpFlag::Ack));
+
+        let mut bad = bytes;
+        bad[0] = 0x04;
+        assert!(matches!(SmpHeader::from_bytes(&bad, DEFAULT_PACKET_SIZE), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn test_smpsession_read_rejects_packet_longer_than_packet_size() {
+        let (client, mut server) = connected_pair();
+        let session: SmpSession = SmpSession::start(client, Arc::new(Mutex::new(None)), DEFAULT_PACKET_SIZE).unwrap();
+
+        let mut header: SmpHeader = SmpHeader::new(SmpFlag::Data, 0, 1, 4, 0);
+        header.length = u32::MAX;
+        server.write_all(&header.to_bytes()).unwrap();
+
+        let mut buffer: [u8; 16] = [0; 16];
+        assert_eq!(session.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // the connection can't be followed any more
+        write_smp(&mut server, SmpFlag::Data, 0, 1, 4, b"data");
+        assert!(session.read(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_smpsession_write_waits_for_window() {
+        let (client, mut server) = connected_pair();
+        let session: SmpSession = SmpSession::start(client, Arc::new(Mutex::new(None)), DEFAULT_PACKET_SIZE).unwrap();
+
+        let peer = thread::spawn(move || {
+            let (syn, _) = read_smp(&mut server);
+            assert!(syn.has_flag(SmpFlag::Syn));
+
+            let mut sequence_numbers: Vec<u32> = Vec::new();
+            for _ in 0..DEFAULT_WINDOW {
+                sequence_numbers.push(read_smp(&mut server).0.sequence_number);
+            }
+
+            // the fifth packet only comes once the window has moved on
+            write_smp(&mut server, SmpFlag::Ack, 0, 0, DEFAULT_WINDOW + 1, &[]);
+            let (header, payload) = read_smp(&mut server);
+            sequence_numbers.push(header.sequence_number);
+
+            (sequence_numbers, payload)
+        });
+
+        for index in 0..=DEFAULT_WINDOW {
+            session.write(&[index as u8]).unwrap();
+        }
+
+        let (sequence_numbers, payload) = peer.join().unwrap();
+        assert_eq!(sequence_numbers, vec![1, 2, 3, 4, 5]);
+        assert_eq!(payload, vec![4]);
+    }
+
+    #[test]
+    fn test_smpsession_read_sorts_packets_by_session_and_acknowledges() {
+        let (client, mut server) = connected_pair();
+        let first: SmpSession = SmpSession::start(client, Arc::new(Mutex::new(None)), DEFAULT_PACKET_SIZE).unwrap();
+        let second: SmpSession = first.open().unwrap();
+
+        let peer = thread::spawn(move || {
+            read_smp(&mut server);
+            read_smp(&mut server);
+
+            write_smp(&mut server, SmpFlag::Data, 1, 1, 4, b"second");
+            for sequence_number in 1..=3 {
+                write_smp(&mut server, SmpFlag::Data, 0, sequence_number, 4, b"first");
+            }
+
+            let (ack, _) = read_smp(&mut server);
+            let (fin, _) = read_smp(&mut server);
+            (ack, fin)
+        });
+
+        let mut buffer: [u8; 16] = [0; 16];
+        for _ in 0..3 {
+            let read: usize = first.read(&mut buffer).unwrap();
+            assert_eq!(&buffer[..read], b"first");
+        }
+        let read: usize = second.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..read], b"second");
+        drop(second);
+
+        let (ack, fin) = peer.join().unwrap();
+        assert!(ack.has_flag(SmpFlag::Ack));
+        assert_eq!((ack.session_id, ack.window), (0, 3 + DEFAULT_WINDOW));
+        assert!(fin.has_flag(SmpFlag::Fin));
+        assert_eq!(fin.session_id, 1);
+    }
+
+    #[test]
+    fn test_smpsession_timeout_only_applies_to_its_session() {
+        let (client, mut server) = connected_pair();
+        let mut first: SmpSession = SmpSession::start(client, Arc::new(Mutex::new(None)), DEFAULT_PACKET_SIZE).unwrap();
+        let second: SmpSession = first.open().unwrap();
+
+        let peer = thread::spawn(move || {
+            read_smp(&mut server);
+            read_smp(&mut server);
+
+            thread::sleep(Duration::from_millis(200));
+            write_smp(&mut server, SmpFlag::Data, 1, 1, 4, b"second");
+            server
+        });
+
+        let waiting = thread::spawn(move || {
+            let mut buffer: [u8; 16] = [0; 16];
+            let read: usize = second.read(&mut buffer).unwrap();
+            buffer[..read].to_vec()
+        });
+
+        first.set_timeout(Some(Duration::from_millis(50)));
+        let mut buffer: [u8; 16] = [0; 16];
+        // WouldBlock when it was the socket's read timeout that ran out
+        let error: io::Error = first.read(&mut buffer).unwrap_err();
+        assert!(matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock));
+
+        // the session without a timeout is still waiting, and gets its data
+        assert_eq!(waiting.join().unwrap(), b"second");
+        let _server = peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_is_before_handles_wrapping() {
+        assert!(is_before(1, 4));
+        assert!(!is_before(4, 4));
+        assert!(is_before(u32::MAX, 2));
+    }
+}
@@ -0,0 +1,1200 @@
+use std::fmt;
+use std::io::Read;
+use crate::tds_type::{DataType, TypeInfo, Collation, LengthKind, PLP_NULL};
+use crate::tds_message::{ucs2_bytes, plp_bytes};
+use crate::error::{Error, Result};
+
+/**
+ * A column value decoded from its TYPE_INFO
+ *
+ * Every TDS type lands in one of these. The nullable (N) forms decode to the same variant as
+ * their fixed counterparts, e.g. INTN with length 4 is an Int, and SQL_VARIANT decodes to
+ * whatever type the server put inside it.
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/ffb02215-af07-4b50-8545-1fd29d8eb0b9
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Bit(bool),
+    TinyInt(u8),
+    SmallInt(i16),
+    Int(i32),
+    BigInt(i64),
+    Real(f32),
+    Float(f64),
+    Money(Decimal),
+    Decimal(Decimal),
+    SmallDateTime(DateTime),
+    DateTime(DateTime),
+    Date(Date),
+    Time(Time),
+    DateTime2(DateTime),
+    DateTimeOffset(DateTimeOffset),
+    Guid(Guid),
+    String(String),
+    Binary(Vec<u8>),
+    Xml(String)
+}
+impl SqlValue {
+    /**
+     * Reads one value of the given type from a ROW.
+     */
+    pub fn read<R: Read>(type_info: &TypeInfo, reader: &mut R) -> Result<SqlValue> {
+        match type_info.read_value(reader)? {
+            Some(bytes) => SqlValue::decode(type_info, &bytes),
+            None => Ok(SqlValue::Null)
+        }
+    }
+
+    /**
+     * Decodes the bytes of a non NULL value, with the length framing already taken off.
+     */
+    pub fn decode(type_info: &TypeInfo, bytes: &[u8]) -> Result<SqlValue> {
+        let value = match type_info.data_type {
+            DataType::Null => SqlValue::Null,
+            DataType::Int1 | DataType::Int2 | DataType::Int4 | DataType::Int8 | DataType::IntN => {
+                match bytes.len() {
+                    1 => SqlValue::TinyInt(bytes[0]),
+                    2 => SqlValue::SmallInt(i16::from_le_bytes(fixed(bytes)?)),
+                    4 => SqlValue::Int(i32::from_le_bytes(fixed(bytes)?)),
+                    8 => SqlValue::BigInt(i64::from_le_bytes(fixed(bytes)?)),
+                    length => return Err(invalid_length(type_info, length))
+                }
+            },
+            DataType::Bit | DataType::BitN => SqlValue::Bit(fixed::<1>(bytes)?[0] != 0),
+            DataType::Flt4 | DataType::Flt8 | DataType::FltN => {
+                match bytes.len() {
+                    4 => SqlValue::Real(f32::from_le_bytes(fixed(bytes)?)),
+                    8 => SqlValue::Float(f64::from_le_bytes(fixed(bytes)?)),
+                    length => return Err(invalid_length(type_info, length))
+                }
+            },
+            DataType::Money | DataType::Money4 | DataType::MoneyN => SqlValue::Money(decode_money(bytes)?),
+            DataType::Decimal | DataType::Numeric | DataType::DecimalN | DataType::NumericN => {
+                SqlValue::Decimal(decode_decimal(bytes, type_info.scale)?)
+            },
+            DataType::DateTime4 | DataType::DateTime | DataType::DateTimeN => {
+                match bytes.len() {
+                    4 => SqlValue::SmallDateTime(decode_small_datetime(bytes)?),
+                    8 => SqlValue::DateTime(decode_datetime(bytes)?),
+                    length => return Err(invalid_length(type_info, length))
+                }
+            },
+            DataType::DateN => SqlValue::Date(Date::from_days(read_uint(bytes)? as i64)),
+            DataType::TimeN => SqlValue::Time(decode_time(bytes, type_info.scale)?),
+            DataType::DateTime2N => {
+                let (time, date) = split_time(bytes, type_info.scale)?;
+                SqlValue::DateTime2(DateTime::new(Date::from_days(read_uint(date)? as i64), decode_time(time, type_info.scale)?))
+            },
+            DataType::DateTimeOffsetN => SqlValue::DateTimeOffset(decode_datetime_offset(bytes, type_info.scale)?),
+            DataType::Guid => SqlValue::Guid(Guid::from_bytes(fixed(bytes)?)),
+            DataType::Char | DataType::VarChar | DataType::BigChar | DataType::BigVarChar | DataType::Text => {
+                SqlValue::String(decode_varchar(bytes, type_info.collation)?)
+            },
+            DataType::NChar | DataType::NVarChar | DataType::NText => SqlValue::String(decode_ucs2(bytes)?),
+            DataType::Binary | DataType::VarBinary | DataType::BigBinary | DataType::BigVarBinary
+                | DataType::Image | DataType::Udt => SqlValue::Binary(bytes.to_vec()),
+            DataType::Xml => SqlValue::Xml(decode_ucs2(bytes.strip_prefix(&[0xff, 0xfe]).unwrap_or(bytes))?),
+            DataType::SqlVariant => decode_variant(bytes)?
+        };
+
+        Ok(value)
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == SqlValue::Null
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            SqlValue::Bit(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    /**
+     * Any of the integer types, widened.
+     */
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            SqlValue::TinyInt(value) => Some(*value as i64),
+            SqlValue::SmallInt(value) => Some(*value as i64),
+            SqlValue::Int(value) => Some(*value as i64),
+            SqlValue::BigInt(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            SqlValue::Real(value) => Some(*value as f64),
+            SqlValue::Float(value) => Some(*value),
+            SqlValue::Money(value) | SqlValue::Decimal(value) => Some(value.to_f64()),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            SqlValue::String(value) | SqlValue::Xml(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            SqlValue::Binary(value) => Some(value),
+            _ => None
+        }
+    }
+
+    /**
+     * The SQL type a parameter holding this value is declared as. NULL goes as nvarchar
+     * since that converts implicitly to most column types.
+     */
+    pub fn declaration(&self) -> String {
+        let declaration = match self {
+            SqlValue::Null => "nvarchar(1)",
+            SqlValue::Bit(_) => "bit",
+            SqlValue::TinyInt(_) => "tinyint",
+            SqlValue::SmallInt(_) => "smallint",
+            SqlValue::Int(_) => "int",
+            SqlValue::BigInt(_) => "bigint",
+            SqlValue::Real(_) => "real",
+            SqlValue::Float(_) => "float",
+            SqlValue::Money(_) => "money",
+            SqlValue::Decimal(value) => return format!("decimal({}, {})", MAX_DECIMAL_PRECISION, value.scale),
+            SqlValue::SmallDateTime(_) => "smalldatetime",
+            SqlValue::DateTime(_) => "datetime",
+            SqlValue::Date(_) => "date",
+            SqlValue::Time(_) => "time(7)",
+            SqlValue::DateTime2(_) => "datetime2(7)",
+            SqlValue::DateTimeOffset(_) => "datetimeoffset(7)",
+            SqlValue::Guid(_) => "uniqueidentifier",
+            SqlValue::String(value) if value.encode_utf16().count() > MAX_NVARCHAR_LENGTH => "nvarchar(max)",
+            SqlValue::String(_) => "nvarchar(4000)",
+            SqlValue::Binary(value) if value.len() > MAX_VARBINARY_LENGTH => "varbinary(max)",
+            SqlValue::Binary(_) => "varbinary(8000)",
+            SqlValue::Xml(_) => "xml"
+        };
+
+        String::from(declaration)
+    }
+
+    /**
+     * TYPE_INFO followed by the value, as a parameter in an RPC request. Strings use
+     * `collation`, which only matters to the server for the nvarchar's metadata. Fails for
+     * money and smalldatetime values outside what those types can hold.
+     */
+    pub fn encode(&self, collation: Collation) -> Result<Vec<u8>> {
+        let out_of_range = || Error::Config(format!("{} is out of range for {}", self, self.declaration()));
+        let mut bytes: Vec<u8> = Vec::new();
+
+        match self {
+            SqlValue::Null => {
+                bytes.push(DataType::NVarChar.value());
+                bytes.extend_from_slice(&2u16.to_le_bytes());
+                bytes.extend_from_slice(&collation.to_bytes());
+                bytes.extend_from_slice(&0xffffu16.to_le_bytes());
+            },
+            SqlValue::Bit(value) => add_bytelen(&mut bytes, DataType::BitN, &[*value as u8]),
+            SqlValue::TinyInt(value) => add_bytelen(&mut bytes, DataType::IntN, &[*value]),
+            SqlValue::SmallInt(value) => add_bytelen(&mut bytes, DataType::IntN, &value.to_le_bytes()),
+            SqlValue::Int(value) => add_bytelen(&mut bytes, DataType::IntN, &value.to_le_bytes()),
+            SqlValue::BigInt(value) => add_bytelen(&mut bytes, DataType::IntN, &value.to_le_bytes()),
+            SqlValue::Real(value) => add_bytelen(&mut bytes, DataType::FltN, &value.to_le_bytes()),
+            SqlValue::Float(value) => add_bytelen(&mut bytes, DataType::FltN, &value.to_le_bytes()),
+            SqlValue::Money(value) => {
+                let money: i64 = value.rescale(4).and_then(|money| i64::try_from(money).ok()).ok_or_else(out_of_range)?;
+                let mut data: Vec<u8> = ((money >> 32) as i32).to_le_bytes().to_vec();
+                data.extend_from_slice(&(money as u32).to_le_bytes());
+                add_bytelen(&mut bytes, DataType::MoneyN, &data);
+            },
+            SqlValue::Decimal(value) => {
+                bytes.extend_from_slice(&[DataType::DecimalN.value(), 17, MAX_DECIMAL_PRECISION, value.scale, 17]);
+                bytes.push(if value.value < 0 { 0x00 } else { 0x01 });
+                bytes.extend_from_slice(&value.value.unsigned_abs().to_le_bytes());
+            },
+            SqlValue::SmallDateTime(value) => {
+                let days: u16 = u16::try_from(value.date.to_days() - DAYS_TO_1900).map_err(|_| out_of_range())?;
+                let mut data: Vec<u8> = days.to_le_bytes().to_vec();
+                data.extend_from_slice(&((value.time.to_nanos() / (60 * NANOS_PER_SECOND)) as u16).to_le_bytes());
+                add_bytelen(&mut bytes, DataType::DateTimeN, &data);
+            },
+            SqlValue::DateTime(value) => {
+                let mut data: Vec<u8> = ((value.date.to_days() - DAYS_TO_1900) as i32).to_le_bytes().to_vec();
+                data.extend_from_slice(&((value.time.to_nanos() * 300 / NANOS_PER_SECOND) as u32).to_le_bytes());
+                add_bytelen(&mut bytes, DataType::DateTimeN, &data);
+            },
+            SqlValue::Date(value) => {
+                bytes.push(DataType::DateN.value());
+                bytes.push(3);
+                bytes.extend_from_slice(&encode_date(value));
+            },
+            SqlValue::Time(value) => {
+                bytes.extend_from_slice(&[DataType::TimeN.value(), 7, 5]);
+                bytes.extend_from_slice(&encode_time(value));
+            },
+            SqlValue::DateTime2(value) => {
+                bytes.extend_from_slice(&[DataType::DateTime2N.value(), 7, 8]);
+                bytes.extend_from_slice(&encode_time(&value.time));
+                bytes.extend_from_slice(&encode_date(&value.date));
+            },
+            SqlValue::DateTimeOffset(value) => {
+                let utc: DateTime = value.to_utc();
+
+                bytes.extend_from_slice(&[DataType::DateTimeOffsetN.value(), 7, 10]);
+                bytes.extend_from_slice(&encode_time(&utc.time));
+                bytes.extend_from_slice(&encode_date(&utc.date));
+                bytes.extend_from_slice(&value.offset_minutes.to_le_bytes());
+            },
+            SqlValue::Guid(value) => add_bytelen(&mut bytes, DataType::Guid, &value.bytes),
+            SqlValue::String(value) => {
+                let data = ucs2_bytes(value);
+                bytes.push(DataType::NVarChar.value());
+
+                if data.len() > MAX_NVARCHAR_LENGTH * 2 {
+                    bytes.extend_from_slice(&0xffffu16.to_le_bytes());
+                    bytes.extend_from_slice(&collation.to_bytes());
+                    bytes.extend_from_slice(&plp_bytes(&data));
+                } else {
+                    bytes.extend_from_slice(&((MAX_NVARCHAR_LENGTH * 2) as u16).to_le_bytes());
+                    bytes.extend_from_slice(&collation.to_bytes());
+                    bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+                    bytes.extend_from_slice(&data);
+                }
+            },
+            SqlValue::Binary(value) => {
+                bytes.push(DataType::BigVarBinary.value());
+
+                if value.len() > MAX_VARBINARY_LENGTH {
+                    bytes.extend_from_slice(&0xffffu16.to_le_bytes());
+                    bytes.extend_from_slice(&plp_bytes(value));
+                } else {
+                    bytes.extend_from_slice(&(MAX_VARBINARY_LENGTH as u16).to_le_bytes());
+                    bytes.extend_from_slice(&(value.len() as u16).to_le_bytes());
+                    bytes.extend_from_slice(value);
+                }
+            },
+            SqlValue::Xml(value) => {
+                //0x00: no schema collection
+                bytes.extend_from_slice(&[DataType::Xml.value(), 0x00]);
+                bytes.extend_from_slice(&plp_bytes(&ucs2_bytes(value)));
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    /**
+     * The value as it goes in a ROW of a column with `type_info`, converted to the column's
+     * type and framed the way that type is. Used for bulk loads, where the rows are sent in
+     * the destination table's own types rather than described by the value.
+     */
+    pub fn encode_as(&self, type_info: &TypeInfo) -> Result<Vec<u8>> {
+        let data: Option<Vec<u8>> = self.column_data(type_info)?;
+
+        if type_info.is_plp() {
+            return Ok(data.map_or(PLP_NULL.to_le_bytes().to_vec(), |data| plp_bytes(&data)));
+        }
+
+        let mut bytes: Vec<u8> = Vec::new();
+        match (type_info.data_type.length_kind(), data) {
+            (LengthKind::Fixed, None) => {
+                return Err(Error::Config(format!("NULL can't be sent as {}, the column is NOT NULL", type_info.declaration())));
+            },
+            (LengthKind::Fixed, Some(data)) => bytes = data,
+            (LengthKind::ByteLen, None) => bytes.push(0),
+            (LengthKind::ByteLen, Some(data)) => {
+                bytes.push(data.len() as u8);
+                bytes.extend_from_slice(&data);
+            },
+            (LengthKind::UShortLen, None) => bytes.extend_from_slice(&0xffffu16.to_le_bytes()),
+            (LengthKind::UShortLen, Some(data)) => {
+                if data.len() > type_info.length as usize {
+                    return Err(Error::Config(format!("Value of {} bytes is too long for {}", data.len(), type_info.declaration())));
+                }
+                bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+                bytes.extend_from_slice(&data);
+            },
+            (LengthKind::LongLen, None) => bytes.push(0),
+            (LengthKind::LongLen, Some(data)) => {
+                //text pointer and timestamp, which the server ignores, then the value
+                bytes.push(16);
+                bytes.extend_from_slice(&[0u8; 16 + 8]);
+                bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(&data);
+            },
+            (LengthKind::Plp, _) => unreachable!("PLP types are framed above")
+        }
+
+        Ok(bytes)
+    }
+
+    /**
+     * The bytes of the value converted to `type_info`'s type, without any framing.
+     * None for NULL.
+     */
+    fn column_data(&self, type_info: &TypeInfo) -> Result<Option<Vec<u8>>> {
+        if self.is_null() {
+            return Ok(None);
+        }
+
+        let length: usize = type_info.data_type.fixed_length().unwrap_or(type_info.length as usize);
+        let mismatch = || Error::Config(format!("A {} value can't be sent as {}", self.declaration(), type_info.declaration()));
+        let out_of_range = || Error::Config(format!("{} is out of range for {}", self, type_info.declaration()));
+
+        let data: Vec<u8> = match type_info.data_type {
+            DataType::Int1 | DataType::Int2 | DataType::Int4 | DataType::Int8 | DataType::IntN => {
+                let value: i64 = self.as_i64().ok_or_else(mismatch)?;
+                match length {
+                    1 => vec![u8::try_from(value).map_err(|_| out_of_range())?],
+                    2 => i16::try_from(value).map_err(|_| out_of_range())?.to_le_bytes().to_vec(),
+                    4 => i32::try_from(value).map_err(|_| out_of_range())?.to_le_bytes().to_vec(),
+                    _ => value.to_le_bytes().to_vec()
+                }
+            },
+            DataType::Bit | DataType::BitN => vec![self.as_bool().ok_or_else(mismatch)? as u8],
+            DataType::Flt4 | DataType::Flt8 | DataType::FltN => {
+                let value: f64 = self.as_f64().or(self.as_i64().map(|value| value as f64)).ok_or_else(mismatch)?;
+                if length == 4 { (value as f32).to_le_bytes().to_vec() } else { value.to_le_bytes().to_vec() }
+            },
+            DataType::Money | DataType::Money4 | DataType::MoneyN => {
+                let money: i128 = self.as_decimal().ok_or_else(mismatch)?.rescale(4).ok_or_else(out_of_range)?;
+                if length == 4 {
+                    i32::try_from(money).map_err(|_| out_of_range())?.to_le_bytes().to_vec()
+                } else {
+                    let money: i64 = i64::try_from(money).map_err(|_| out_of_range())?;
+                    let mut data: Vec<u8> = ((money >> 32) as i32).to_le_bytes().to_vec();
+                    data.extend_from_slice(&(money as u32).to_le_bytes());
+                    data
+                }
+            },
+            DataType::Decimal | DataType::Numeric | DataType::DecimalN | DataType::NumericN => {
+                let value: i128 = self.as_decimal().ok_or_else(mismatch)?.rescale(type_info.scale).ok_or_else(out_of_range)?;
+                if value.unsigned_abs() >= 10u128.pow(type_info.precision.min(MAX_DECIMAL_PRECISION) as u32) {
+                    return Err(out_of_range());
+                }
+
+                let mut data: Vec<u8> = vec![if value < 0 { 0x00 } else { 0x01 }];
+                data.extend_from_slice(&value.unsigned_abs().to_le_bytes()[..length.clamp(1, 17) - 1]);
+                data
+            },
+            DataType::DateTime4 | DataType::DateTime | DataType::DateTimeN => {
+                let value: DateTime = self.as_datetime().ok_or_else(mismatch)?;
+                let days: i64 = value.date.to_days() - DAYS_TO_1900;
+
+                if length == 4 {
+                    let days: u16 = u16::try_from(days).map_err(|_| out_of_range())?;
+                    let mut data: Vec<u8> = days.to_le_bytes().to_vec();
+                    data.extend_from_slice(&((value.time.to_nanos() / (60 * NANOS_PER_SECOND)) as u16).to_le_bytes());
+                    data
+                } else {
+                    let days: i32 = i32::try_from(days).map_err(|_| out_of_range())?;
+                    let mut data: Vec<u8> = days.to_le_bytes().to_vec();
+                    data.extend_from_slice(&((value.time.to_nanos() * 300 / NANOS_PER_SECOND) as u32).to_le_bytes());
+                    data
+                }
+            },
+            DataType::DateN => encode_date(&self.as_datetime().ok_or_else(mismatch)?.date).to_vec(),
+            DataType::TimeN => {
+                let time: Time = match self {
+                    SqlValue::Time(time) => *time,
+                    _ => self.as_datetime().ok_or_else(mismatch)?.time
+                };
+                encode_scaled_time(&time, type_info.scale)
+            },
+            DataType::DateTime2N => {
+                let value: DateTime = self.as_datetime().ok_or_else(mismatch)?;
+                let mut data: Vec<u8> = encode_scaled_time(&value.time, type_info.scale);
+                data.extend_from_slice(&encode_date(&value.date));
+                data
+            },
+            DataType::DateTimeOffsetN => {
+                let value: DateTimeOffset = match self {
+                    SqlValue::DateTimeOffset(value) => *value,
+                    _ => DateTimeOffset { datetime: self.as_datetime().ok_or_else(mismatch)?, offset_minutes: 0 }
+                };
+                let utc: DateTime = value.to_utc();
+
+                let mut data: Vec<u8> = encode_scaled_time(&utc.time, type_info.scale);
+                data.extend_from_slice(&encode_date(&utc.date));
+                data.extend_from_slice(&value.offset_minutes.to_le_bytes());
+                data
+            },
+            DataType::Guid => match self {
+                SqlValue::Guid(value) => value.bytes.to_vec(),
+                _ => return Err(mismatch())
+            },
+            DataType::NVarChar | DataType::NChar | DataType::NText => ucs2_bytes(self.as_str().ok_or_else(mismatch)?),
+            DataType::Char | DataType::VarChar | DataType::BigChar | DataType::BigVarChar | DataType::Text => {
+                let value: &str = self.as_str().ok_or_else(mismatch)?;
+                let encoding = match type_info.collation {
+                    Some(collation) => collation.encoding()?,
+                    None => encoding_rs::WINDOWS_1252
+                };
+
+                let (data, _, had_errors) = encoding.encode(value);
+                if had_errors {
+                    return Err(Error::Config(format!("'{}' can't be represented in {}", value, encoding.name())));
+                }
+                data.into_owned()
+            },
+            DataType::Binary | DataType::VarBinary | DataType::BigBinary | DataType::BigVarBinary | DataType::Image => {
+                self.as_bytes().ok_or_else(mismatch)?.to_vec()
+            },
+            DataType::Xml => ucs2_bytes(self.as_str().ok_or_else(mismatch)?),
+            DataType::Udt | DataType::SqlVariant | DataType::Null => {
+                return Err(Error::Config(format!("Values can't be sent as {}", type_info.declaration())));
+            }
+        };
+
+        Ok(Some(data))
+    }
+
+    /**
+     * Decimal, money or an integer as a Decimal.
+     */
+    fn as_decimal(&self) -> Option<Decimal> {
+        match self {
+            SqlValue::Money(value) | SqlValue::Decimal(value) => Some(*value),
+            _ => self.as_i64().map(|value| Decimal::new(value as i128, 0))
+        }
+    }
+
+    /**
+     * Any of the date and time types, a date being midnight on that day.
+     */
+    fn as_datetime(&self) -> Option<DateTime> {
+        match self {
+            SqlValue::SmallDateTime(value) | SqlValue::DateTime(value) | SqlValue::DateTime2(value) => Some(*value),
+            SqlValue::DateTimeOffset(value) => Some(value.datetime),
+            SqlValue::Date(value) => Some(DateTime::new(*value, Time::new(0, 0, 0, 0))),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for SqlValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SqlValue::Null => write!(f, "NULL"),
+            SqlValue::Bit(value) => write!(f, "{}", *value as u8),
+            SqlValue::TinyInt(value) => write!(f, "{}", value),
+            SqlValue::SmallInt(value) => write!(f, "{}", value),
+            SqlValue::Int(value) => write!(f, "{}", value),
+            SqlValue::BigInt(value) => write!(f, "{}", value),
+            SqlValue::Real(value) => write!(f, "{}", value),
+            SqlValue::Float(value) => write!(f, "{}", value),
+            SqlValue::Money(value) | SqlValue::Decimal(value) => write!(f, "{}", value),
+            SqlValue::SmallDateTime(value) | SqlValue::DateTime(value) | SqlValue::DateTime2(value) => write!(f, "{}", value),
+            SqlValue::Date(value) => write!(f, "{}", value),
+            SqlValue::Time(value) => write!(f, "{}", value),
+            SqlValue::DateTimeOffset(value) => write!(f, "{}", value),
+            SqlValue::Guid(value) => write!(f, "{}", value),
+            SqlValue::String(value) | SqlValue::Xml(value) => write!(f, "{}", value),
+            SqlValue::Binary(value) => {
+                write!(f, "0x")?;
+                value.iter().try_for_each(|byte| write!(f, "{:02X}", byte))
+            }
+        }
+    }
+}
+
+/**
+ * DECIMAL, NUMERIC and MONEY: an integer and how many of its digits are after the point.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decimal {
+    pub value: i128,
+    pub scale: u8
+}
+impl Decimal {
+    pub fn new(value: i128, scale: u8) -> Decimal {
+        Decimal {
+            value,
+            scale
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.value as f64 / 10f64.powi(self.scale as i32)
+    }
+
+    /**
+     * The value with `scale` digits after the point, truncating any extra. None if it
+     * doesn't fit in an i128.
+     */
+    pub fn rescale(&self, scale: u8) -> Option<i128> {
+        if scale >= self.scale {
+            10i128.checked_pow((scale - self.scale) as u32).and_then(|factor| self.value.checked_mul(factor))
+        } else {
+            // dividing by more than an i128 holds leaves nothing
+            Some(10i128.checked_pow((self.scale - scale) as u32).map_or(0, |factor| self.value / factor))
+        }
+    }
+}
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.value < 0 { "-" } else { "" };
+        let digits = self.value.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, whole, fraction)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Date {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8
+}
+impl Date {
+    pub fn new(year: i32, month: u8, day: u8) -> Date {
+        Date {
+            year,
+            month,
+            day
+        }
+    }
+
+    /**
+     * DATE counts days from 0001-01-01. (Howard Hinnant's civil_from_days, shifted from 1970.)
+     */
+    pub fn from_days(days: i64) -> Date {
+        let z = days - DAYS_TO_1970 + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Date::new(year as i32, month as u8, day as u8)
+    }
+
+    /**
+     * The other way round: days since 0001-01-01 (Hinnant's days_from_civil).
+     */
+    pub fn to_days(&self) -> i64 {
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let month = self.month as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+        era * 146097 + day_of_era - 719468 + DAYS_TO_1970
+    }
+}
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32
+}
+impl Time {
+    pub fn new(hour: u8, minute: u8, second: u8, nanosecond: u32) -> Time {
+        Time {
+            hour,
+            minute,
+            second,
+            nanosecond
+        }
+    }
+
+    pub fn from_nanos(nanos: u64) -> Time {
+        let seconds = nanos / NANOS_PER_SECOND;
+
+        Time::new(
+            (seconds / 3600) as u8,
+            (seconds / 60 % 60) as u8,
+            (seconds % 60) as u8,
+            (nanos % NANOS_PER_SECOND) as u32
+        )
+    }
+
+    pub fn to_nanos(&self) -> u64 {
+        (self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64) * NANOS_PER_SECOND + self.nanosecond as u64
+    }
+}
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}", self.hour, self.minute, self.second)?;
+
+        if self.nanosecond != 0 {
+            let fraction = format!("{:09}", self.nanosecond);
+            write!(f, ".{}", fraction.trim_end_matches('0'))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime {
+    pub date: Date,
+    pub time: Time
+}
+impl DateTime {
+    pub fn new(date: Date, time: Time) -> DateTime {
+        DateTime {
+            date,
+            time
+        }
+    }
+}
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.date, self.time)
+    }
+}
+
+/**
+ * The server sends DATETIMEOFFSET as UTC plus the offset, this holds the local time
+ * (what SQL Server displays) and the offset in minutes.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTimeOffset {
+    pub datetime: DateTime,
+    pub offset_minutes: i16
+}
+impl DateTimeOffset {
+    /**
+     * The UTC time it's sent as, i.e. the local time with the offset taken back off.
+     */
+    pub fn to_utc(&self) -> DateTime {
+        let local: DateTime = self.datetime;
+        let nanos: i64 = local.time.to_nanos() as i64 - self.offset_minutes as i64 * 60 * NANOS_PER_SECOND as i64;
+
+        DateTime::new(
+            Date::from_days(local.date.to_days() + nanos.div_euclid(NANOS_PER_DAY)),
+            Time::from_nanos(nanos.rem_euclid(NANOS_PER_DAY) as u64)
+        )
+    }
+}
+impl fmt::Display for DateTimeOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.offset_minutes < 0 { '-' } else { '+' };
+        let offset = self.offset_minutes.unsigned_abs();
+        write!(f, "{} {}{:02}:{:02}", self.datetime, sign, offset / 60, offset % 60)
+    }
+}
+
+/**
+ * UNIQUEIDENTIFIER. The first three groups are little endian on the wire.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Guid {
+    pub bytes: [u8; 16]
+}
+impl Guid {
+    pub fn from_bytes(bytes: [u8; 16]) -> Guid {
+        Guid {
+            bytes
+        }
+    }
+}
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.bytes;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+        )
+    }
+}
+
+const MAX_DECIMAL_PRECISION: u8 = 38;
+const MAX_NVARCHAR_LENGTH: usize = 4000;
+const MAX_VARBINARY_LENGTH: usize = 8000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const NANOS_PER_DAY: i64 = 86_400 * NANOS_PER_SECOND as i64;
+// days from 0001-01-01 to 1900-01-01 (DATETIME's epoch) and to 1970-01-01
+const DAYS_TO_1900: i64 = 693595;
+const DAYS_TO_1970: i64 = 719162;
+
+fn add_bytelen(bytes: &mut Vec<u8>, data_type: DataType, data: &[u8]) {
+    bytes.push(data_type.value());
+    bytes.push(data.len() as u8);
+    bytes.push(data.len() as u8);
+    bytes.extend_from_slice(data);
+}
+
+fn encode_date(date: &Date) -> [u8; 3] {
+    let days = (date.to_days() as u32).to_le_bytes();
+    [days[0], days[1], days[2]]
+}
+
+/**
+ * Scale 7 time: 100ns units in 5 bytes.
+ */
+fn encode_time(time: &Time) -> [u8; 5] {
+    let units = (time.to_nanos() / 100).to_le_bytes();
+    [units[0], units[1], units[2], units[3], units[4]]
+}
+
+/**
+ * TIME at a column's scale: 10^-scale second units in 3 to 5 bytes. Extra precision is truncated.
+ */
+fn encode_scaled_time(time: &Time, scale: u8) -> Vec<u8> {
+    let scale: u8 = scale.min(7);
+    let units: u64 = time.to_nanos() / 10u64.pow(9 - scale as u32);
+    units.to_le_bytes()[..time_length(scale)].to_vec()
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N]> {
+    bytes.try_into().map_err(|_| Error::Protocol(format!("Expected {} bytes but got {}", N, bytes.len())))
+}
+
+fn invalid_length(type_info: &TypeInfo, length: usize) -> Error {
+    Error::Protocol(format!("Invalid length {} for {:?}", length, type_info.data_type))
+}
+
+/**
+ * Little endian unsigned integer of up to 8 bytes, for the 3 to 5 byte date and time parts.
+ */
+fn read_uint(bytes: &[u8]) -> Result<u64> {
+    if bytes.len() > 8 {
+        return Err(Error::Protocol(format!("Expected at most 8 bytes but got {}", bytes.len())));
+    }
+
+    Ok(bytes.iter().rev().fold(0u64, |value, byte| (value << 8) | *byte as u64))
+}
+
+/**
+ * MONEY is the high 4 bytes then the low 4 bytes, SMALLMONEY a plain i32. Both in ten thousandths.
+ */
+fn decode_money(bytes: &[u8]) -> Result<Decimal> {
+    let value: i64 = match bytes.len() {
+        4 => i32::from_le_bytes(fixed(bytes)?) as i64,
+        8 => {
+            let high = i32::from_le_bytes(fixed(&bytes[0..4])?) as i64;
+            let low = u32::from_le_bytes(fixed(&bytes[4..8])?) as i64;
+            (high << 32) | low
+        },
+        length => return Err(Error::Protocol(format!("Invalid length {} for money", length)))
+    };
+
+    Ok(Decimal::new(value as i128, 4))
+}
+
+/**
+ * Sign byte (1 positive, 0 negative) then the magnitude as a little endian integer.
+ */
+fn decode_decimal(bytes: &[u8], scale: u8) -> Result<Decimal> {
+    if bytes.is_empty() || bytes.len() > 17 {
+        return Err(Error::Protocol(format!("Invalid length {} for decimal", bytes.len())));
+    }
+
+    let magnitude = bytes[1..].iter().rev().fold(0u128, |value, byte| (value << 8) | *byte as u128);
+    let value = magnitude as i128;
+
+    Ok(Decimal::new(if bytes[0] == 0 { -value } else { value }, scale))
+}
+
+/**
+ * DATETIME: days since 1900-01-01 then 1/300ths of a second since midnight.
+ */
+fn decode_datetime(bytes: &[u8]) -> Result<DateTime> {
+    let days = i32::from_le_bytes(fixed(&bytes[0..4])?) as i64;
+    let ticks = u32::from_le_bytes(fixed(&bytes[4..8])?) as u64;
+
+    Ok(DateTime::new(Date::from_days(DAYS_TO_1900 + days), Time::from_nanos(ticks * NANOS_PER_SECOND / 300)))
+}
+
+/**
+ * SMALLDATETIME: days since 1900-01-01 then minutes since midnight.
+ */
+fn decode_small_datetime(bytes: &[u8]) -> Result<DateTime> {
+    let days = u16::from_le_bytes(fixed(&bytes[0..2])?) as i64;
+    let minutes = u16::from_le_bytes(fixed(&bytes[2..4])?) as u64;
+
+    Ok(DateTime::new(Date::from_days(DAYS_TO_1900 + days), Time::from_nanos(minutes * 60 * NANOS_PER_SECOND)))
+}
+
+/**
+ * TIME is a count of 10^-scale seconds since midnight, in 3 to 5 bytes depending on the scale.
+ */
+fn decode_time(bytes: &[u8], scale: u8) -> Result<Time> {
+    if scale > 7 {
+        return Err(Error::Protocol(format!("Invalid time scale {}", scale)));
+    }
+
+    let units = read_uint(bytes)?;
+    Ok(Time::from_nanos(units * 10u64.pow(9 - scale as u32)))
+}
+
+fn time_length(scale: u8) -> usize {
+    match scale {
+        0..=2 => 3,
+        3..=4 => 4,
+        _ => 5
+    }
+}
+
+fn split_time(bytes: &[u8], scale: u8) -> Result<(&[u8], &[u8])> {
+    let time_length = time_length(scale);
+    if bytes.len() < time_length + 3 {
+        return Err(Error::Protocol(format!("Invalid length {} for a scale {} date and time", bytes.len(), scale)));
+    }
+
+    Ok((&bytes[..time_length], &bytes[time_length..time_length + 3]))
+}
+
+fn decode_datetime_offset(bytes: &[u8], scale: u8) -> Result<DateTimeOffset> {
+    let (time, date) = split_time(bytes, scale)?;
+    let offset_start = time.len() + date.len();
+    let offset_minutes = i16::from_le_bytes(fixed(bytes.get(offset_start..offset_start + 2).unwrap_or(&[]))?);
+
+    //move from UTC to the local time the offset describes
+    let days = read_uint(date)? as i64;
+    let nanos = decode_time(time, scale)?.to_nanos() as i64 + offset_minutes as i64 * 60 * NANOS_PER_SECOND as i64;
+
+    Ok(DateTimeOffset {
+        datetime: DateTime::new(
+            Date::from_days(days + nanos.div_euclid(NANOS_PER_DAY)),
+            Time::from_nanos(nanos.rem_euclid(NANOS_PER_DAY) as u64)
+        ),
+        offset_minutes
+    })
+}
+
+fn decode_ucs2(bytes: &[u8]) -> Result<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(Error::Protocol(String::from("UCS-2 value has an odd number of bytes")));
+    }
+
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+    String::from_utf16(&units).map_err(|e| Error::Protocol(format!("Invalid UCS-2 value: {}", e)))
+}
+
+/**
+ * Single byte character types are in the code page of the column's collation.
+ */
+fn decode_varchar(bytes: &[u8], collation: Option<Collation>) -> Result<String> {
+    let encoding = match collation {
+        Some(collation) => collation.encoding()?,
+        None => encoding_rs::WINDOWS_1252
+    };
+
+    let (value, _, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        return Err(Error::Protocol(format!("Invalid {} value", encoding.name())));
+    }
+
+    Ok(value.into_owned())
+}
+
+/**
+ * SQL_VARIANT: base type, how many bytes of type properties follow, the properties, then
+ * the value filling the rest. The properties are the parts of TYPE_INFO that type needs.
+ */
+fn decode_variant(bytes: &[u8]) -> Result<SqlValue> {
+    if bytes.len() < 2 {
+        return Err(Error::Protocol(String::from("SQL_VARIANT value is too short")));
+    }
+
+    let data_type = DataType::from_value(bytes[0])
+        .ok_or(Error::Protocol(format!("Unsupported data type 0x{:02X} in SQL_VARIANT", bytes[0])))?;
+    let properties_length = bytes[1] as usize;
+    let properties = bytes.get(2..2 + properties_length).ok_or(Error::Protocol(String::from("SQL_VARIANT value is too short")))?;
+    let value = &bytes[2 + properties_length..];
+
+    let mut type_info = TypeInfo::new(data_type);
+    match data_type {
+        DataType::DecimalN | DataType::NumericN => {
+            type_info.precision = fixed::<2>(properties)?[0];
+            type_info.scale = properties[1];
+        },
+        DataType::TimeN | DataType::DateTime2N | DataType::DateTimeOffsetN => {
+            type_info.scale = fixed::<1>(properties)?[0];
+        },
+        DataType::BigVarChar | DataType::BigChar | DataType::NVarChar | DataType::NChar => {
+            type_info.collation = Collation::from_bytes(properties);
+        },
+        _ => ()
+    }
+
+    SqlValue::decode(&type_info, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tds_message::ucs2_bytes;
+
+    fn decode(data_type: DataType, bytes: &[u8]) -> SqlValue {
+        SqlValue::decode(&TypeInfo::new(data_type), bytes).unwrap()
+    }
+
+    fn decode_scaled(data_type: DataType, scale: u8, bytes: &[u8]) -> SqlValue {
+        let mut type_info = TypeInfo::new(data_type);
+        type_info.scale = scale;
+        SqlValue::decode(&type_info, bytes).unwrap()
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_integers() {
+        assert_eq!(decode(DataType::Int1, &[0xff]), SqlValue::TinyInt(255));
+        assert_eq!(decode(DataType::Int2, &[0xfe, 0xff]), SqlValue::SmallInt(-2));
+        assert_eq!(decode(DataType::Int4, &[0x2a, 0, 0, 0]), SqlValue::Int(42));
+        assert_eq!(decode(DataType::IntN, &(-5i64).to_le_bytes()), SqlValue::BigInt(-5));
+        assert_eq!(decode(DataType::BitN, &[0x01]), SqlValue::Bit(true));
+        assert!(SqlValue::decode(&TypeInfo::new(DataType::IntN), &[0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_floats() {
+        assert_eq!(decode(DataType::Flt4, &1.5f32.to_le_bytes()), SqlValue::Real(1.5));
+        assert_eq!(decode(DataType::FltN, &(-2.25f64).to_le_bytes()), SqlValue::Float(-2.25));
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_money() {
+        // 123.4567 = 1234567 ten thousandths
+        let mut money: Vec<u8> = 0i32.to_le_bytes().to_vec();
+        money.extend_from_slice(&1234567u32.to_le_bytes());
+
+        assert_eq!(decode(DataType::Money, &money), SqlValue::Money(Decimal::new(1234567, 4)));
+        assert_eq!(decode(DataType::MoneyN, &(-10000i32).to_le_bytes()), SqlValue::Money(Decimal::new(-10000, 4)));
+        assert_eq!(Decimal::new(1234567, 4).to_string(), "123.4567");
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_decimal() {
+        let mut bytes: Vec<u8> = vec![0x00];
+        bytes.extend_from_slice(&12345u32.to_le_bytes());
+
+        let value = decode_scaled(DataType::DecimalN, 3, &bytes);
+
+        assert_eq!(value, SqlValue::Decimal(Decimal::new(-12345, 3)));
+        assert_eq!(Decimal::new(-12345, 3).to_string(), "-12.345");
+        assert_eq!(Decimal::new(5, 2).to_string(), "0.05");
+        assert_eq!(value.as_f64(), Some(-12.345));
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_datetime() {
+        // 2024-02-29 is 45349 days after 1900-01-01, 12:00:00.5 is 12960150 ticks
+        let mut bytes: Vec<u8> = 45349i32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&12960150u32.to_le_bytes());
+
+        let value = decode(DataType::DateTime, &bytes);
+
+        assert_eq!(value, SqlValue::DateTime(DateTime::new(Date::new(2024, 2, 29), Time::new(12, 0, 0, 500_000_000))));
+        assert_eq!(value.to_string(), "2024-02-29 12:00:00.5");
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_small_datetime() {
+        let mut bytes: Vec<u8> = 1u16.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&61u16.to_le_bytes());
+
+        assert_eq!(
+            decode(DataType::DateTimeN, &bytes),
+            SqlValue::SmallDateTime(DateTime::new(Date::new(1900, 1, 2), Time::new(1, 1, 0, 0)))
+        );
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_date_and_time() {
+        // 0001-01-01 is day 0, 2000-01-01 is day 730119
+        assert_eq!(decode(DataType::DateN, &[0, 0, 0]), SqlValue::Date(Date::new(1, 1, 1)));
+        assert_eq!(decode(DataType::DateN, &730119u32.to_le_bytes()[..3]), SqlValue::Date(Date::new(2000, 1, 1)));
+
+        // 01:02:03.1234567 at scale 7
+        let units: u64 = 37231234567;
+        assert_eq!(decode_scaled(DataType::TimeN, 7, &units.to_le_bytes()[..5]), SqlValue::Time(Time::new(1, 2, 3, 123_456_700)));
+        assert_eq!(decode_scaled(DataType::TimeN, 0, &3723u32.to_le_bytes()[..3]), SqlValue::Time(Time::new(1, 2, 3, 0)));
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_datetime2_and_offset() {
+        let mut bytes: Vec<u8> = 3723u32.to_le_bytes()[..3].to_vec();
+        bytes.extend_from_slice(&730119u32.to_le_bytes()[..3]);
+
+        assert_eq!(
+            decode_scaled(DataType::DateTime2N, 0, &bytes),
+            SqlValue::DateTime2(DateTime::new(Date::new(2000, 1, 1), Time::new(1, 2, 3, 0)))
+        );
+
+        // 01:02:03 UTC at -02:00 is the day before locally
+        bytes.extend_from_slice(&(-120i16).to_le_bytes());
+        let value = decode_scaled(DataType::DateTimeOffsetN, 0, &bytes);
+
+        assert_eq!(value.to_string(), "1999-12-31 23:02:03 -02:00");
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_guid() {
+        let bytes: [u8; 16] = [0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+
+        assert_eq!(decode(DataType::Guid, &bytes).to_string(), "00112233-4455-6677-8899-AABBCCDDEEFF");
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_strings_with_collation() {
+        let mut type_info = TypeInfo::new(DataType::BigVarChar);
+        type_info.collation = Some(Collation::new(0x00d00409, 0x34));
+        assert_eq!(SqlValue::decode(&type_info, &[0x63, 0x61, 0x66, 0xe9]).unwrap(), SqlValue::String(String::from("café")));
+
+        // Russian, so windows-1251
+        type_info.collation = Some(Collation::new(0x00d00419, 0x00));
+        assert_eq!(SqlValue::decode(&type_info, &[0xc4, 0xe0]).unwrap(), SqlValue::String(String::from("Да")));
+
+        // _UTF8 collations
+        type_info.collation = Some(Collation::new(0x04d00409, 0x00));
+        assert_eq!(SqlValue::decode(&type_info, "café".as_bytes()).unwrap(), SqlValue::String(String::from("café")));
+
+        assert_eq!(decode(DataType::NVarChar, &ucs2_bytes("hi")), SqlValue::String(String::from("hi")));
+        assert_eq!(decode(DataType::Xml, &ucs2_bytes("<a/>")).as_str(), Some("<a/>"));
+        assert_eq!(decode(DataType::BigVarBinary, &[0x01, 0x02]).as_bytes(), Some(&[0x01, 0x02][..]));
+    }
+
+    #[test]
+    fn test_sqlvalue_decode_variant() {
+        assert_eq!(decode(DataType::SqlVariant, &[0x38, 0x00, 0x07, 0x00, 0x00, 0x00]), SqlValue::Int(7));
+
+        let mut nvarchar: Vec<u8> = vec![0xe7, 0x07, 0x09, 0x04, 0xd0, 0x00, 0x34, 0x08, 0x00];
+        nvarchar.extend_from_slice(&ucs2_bytes("ok"));
+        assert_eq!(decode(DataType::SqlVariant, &nvarchar), SqlValue::String(String::from("ok")));
+
+        let decimal: Vec<u8> = vec![0x6a, 0x02, 0x05, 0x02, 0x01, 0x39, 0x30, 0x00, 0x00];
+        assert_eq!(decode(DataType::SqlVariant, &decimal), SqlValue::Decimal(Decimal::new(12345, 2)));
+    }
+
+    #[test]
+    fn test_date_to_days_round_trips() {
+        for days in [0, 1, 59, 60, 693595, 719162, 730119, 738945, 3652058] {
+            assert_eq!(Date::from_days(days).to_days(), days);
+        }
+    }
+
+    #[test]
+    fn test_sqlvalue_encode_round_trips_through_decode() {
+        let values: Vec<SqlValue> = vec![
+            SqlValue::Bit(true),
+            SqlValue::TinyInt(200),
+            SqlValue::SmallInt(-300),
+            SqlValue::Int(70000),
+            SqlValue::BigInt(-5_000_000_000),
+            SqlValue::Real(1.5),
+            SqlValue::Float(-2.25),
+            SqlValue::Money(Decimal::new(-1234567, 4)),
+            SqlValue::Decimal(Decimal::new(-123456789012345678901234567, 6)),
+            SqlValue::SmallDateTime(DateTime::new(Date::new(2024, 2, 29), Time::new(13, 45, 0, 0))),
+            SqlValue::DateTime(DateTime::new(Date::new(1899, 12, 31), Time::new(23, 59, 59, 0))),
+            SqlValue::Date(Date::new(2000, 1, 1)),
+            SqlValue::Time(Time::new(1, 2, 3, 123_456_700)),
+            SqlValue::DateTime2(DateTime::new(Date::new(1, 1, 1), Time::new(0, 0, 0, 100))),
+            SqlValue::DateTimeOffset(DateTimeOffset {
+                datetime: DateTime::new(Date::new(2000, 1, 1), Time::new(0, 30, 0, 0)),
+                offset_minutes: 90
+            }),
+            SqlValue::Guid(Guid::from_bytes([0x5a; 16])),
+            SqlValue::String(String::from("héllo")),
+            SqlValue::String("x".repeat(5000)),
+            SqlValue::Binary(vec![0x00, 0xff]),
+            SqlValue::Binary(vec![0x01; 9000]),
+            SqlValue::Xml(String::from("<a>b</a>"))
+        ];
+
+        for value in values {
+            let bytes = value.encode(Collation::new(0x00d00409, 0x34)).unwrap();
+            let mut reader: &[u8] = &bytes;
+
+            let type_info = TypeInfo::read(&mut reader).unwrap();
+            let decoded = SqlValue::read(&type_info, &mut reader).unwrap();
+
+            assert_eq!(decoded, value);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_sqlvalue_encode_null_and_declarations() {
+        let bytes = SqlValue::Null.encode(Collation::new(0, 0)).unwrap();
+        let mut reader: &[u8] = &bytes;
+        let type_info = TypeInfo::read(&mut reader).unwrap();
+
+        assert!(SqlValue::read(&type_info, &mut reader).unwrap().is_null());
+        assert_eq!(SqlValue::Decimal(Decimal::new(1, 2)).declaration(), "decimal(38, 2)");
+        assert_eq!(SqlValue::String(String::from("a")).declaration(), "nvarchar(4000)");
+        assert_eq!(SqlValue::String("a".repeat(4001)).declaration(), "nvarchar(max)");
+    }
+
+    #[test]
+    fn test_sqlvalue_encode_rejects_out_of_range_values() {
+        let collation: Collation = Collation::new(0, 0);
+
+        assert!(matches!(SqlValue::Money(Decimal::new(i64::MAX as i128, 0)).encode(collation), Err(Error::Config(_))));
+        assert!(matches!(SqlValue::SmallDateTime(DateTime::new(Date::new(1899, 12, 31), Time::new(0, 0, 0, 0))).encode(collation), Err(Error::Config(_))));
+        assert!(matches!(SqlValue::SmallDateTime(DateTime::new(Date::new(2080, 1, 1), Time::new(0, 0, 0, 0))).encode(collation), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_sqlvalue_read_null() {
+        let mut bytes: &[u8] = &[0x00];
+
+        let value = SqlValue::read(&TypeInfo::new(DataType::IntN), &mut bytes).unwrap();
+
+        assert!(value.is_null());
+    }
+
+    #[test]
+    fn test_sqlvalue_encode_as_converts_to_column_type() {
+        let mut time: TypeInfo = TypeInfo::new(DataType::TimeN);
+        time.scale = 3;
+        let mut offset: TypeInfo = TypeInfo::new(DataType::DateTimeOffsetN);
+        offset.scale = 0;
+        let local = DateTime::new(Date::new(2024, 1, 1), Time::new(1, 0, 0, 0));
+        let value = SqlValue::DateTimeOffset(DateTimeOffset { datetime: local, offset_minutes: 120 });
+
+        // 00:00:01.5 is 1500 milliseconds
+        let encoded = SqlValue::Time(Time::new(0, 0, 1, 500_000_000)).encode_as(&time).unwrap();
+        assert_eq!(encoded, vec![0x04, 0xdc, 0x05, 0x00, 0x00]);
+
+        // sent as 23:00 the day before, UTC
+        let encoded = value.encode_as(&offset).unwrap();
+        let mut reader: &[u8] = &encoded;
+        assert_eq!(SqlValue::read(&offset, &mut reader).unwrap(), value);
+
+        assert!(SqlValue::Null.encode_as(&TypeInfo::new(DataType::Int4)).is_err());
+        assert_eq!(SqlValue::Int(5).encode_as(&TypeInfo::new(DataType::Flt8)).unwrap(), 5f64.to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_sqlvalue_encode_as_rejects_decimal_overflow() {
+        let mut decimal: TypeInfo = TypeInfo::new(DataType::DecimalN);
+        decimal.length = 17;
+        decimal.precision = 38;
+        decimal.scale = 38;
+
+        assert!(matches!(SqlValue::BigInt(i64::MAX).encode_as(&decimal), Err(Error::Config(_))));
+        assert!(matches!(SqlValue::Money(Decimal::new(i128::MAX, 0)).encode_as(&TypeInfo::new(DataType::Money)), Err(Error::Config(_))));
+        assert!(matches!(SqlValue::Money(Decimal::new(i128::MAX, 0)).encode(Collation::new(0, 0)), Err(Error::Config(_))));
+
+        // scaling down past what an i128 holds just leaves 0
+        assert_eq!(Decimal::new(5, 60).rescale(0), Some(0));
+        assert_eq!(Decimal::new(1, 0).rescale(60), None);
+    }
+}
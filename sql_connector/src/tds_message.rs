@@ -1,7 +1,35 @@
+use std::io::{self, Read, Write};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::login7::Login7;
+use crate::sql_value::SqlValue;
+use crate::tds_type::{Collation, PLP_NULL, PLP_UNKNOWN_LENGTH};
+use crate::tds_token::{TdsRead, read_error};
+use crate::transaction::IsolationLevel;
+use crate::error::{Error, Result};
+
+/**
+ * Packet size used until the server sends a packet size ENVCHANGE. Also what Login7 asks for.
+ */
+pub const DEFAULT_PACKET_SIZE: u32 = 4096;
+pub const MIN_PACKET_SIZE: u32 = 512;
+pub const MAX_PACKET_SIZE: u32 = 32767;
+const HEADER_LENGTH: usize = 8;
+/**
+ * Most we set aside up front for a value from the length the server gives. Anything
+ * bigger grows as the data arrives, so a bad length can't ask for more than this.
+ */
+pub(crate) const MAX_RESERVE: usize = 1 << 20;
+
 pub struct TdsMessage {
     header: TdsHeader,
     body: Vec<u8>
 }
+impl Default for TdsMessage {
+    fn default() -> TdsMessage {
+        TdsMessage::new()
+    }
+}
 impl TdsMessage {
     pub fn new() -> TdsMessage {
         let body: Vec<u8> = Vec::new();
@@ -12,47 +40,260 @@ impl TdsMessage {
         }
     }
 
+    /**
+     * The whole message as it goes on the wire, split with the default packet size.
+     */
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buffer: Vec<u8> = Vec::new();
-        buffer.extend_from_slice(&self.header.to_byte_array());
-        buffer.extend_from_slice(&self.body);
-        buffer
+        self.to_packets(DEFAULT_PACKET_SIZE).concat()
+    }
+
+    /**
+     * Splits the message into packets of at most `packet_size` bytes (header included).
+     *
+     * Packet ids count up from 1 (wrapping at 255) and only the last packet has
+     * EndOfMessage set. Any other status bits, e.g. ResetConnection, are kept on every packet.
+     */
+    pub fn to_packets(&self, packet_size: u32) -> Vec<Vec<u8>> {
+        let packet_size = packet_size.clamp(MIN_PACKET_SIZE, MAX_PACKET_SIZE) as usize;
+        let eom: u8 = MessageStatus::EndOfMessage.value();
+
+        //an empty message still needs one packet to carry the header
+        let mut chunks: Vec<&[u8]> = self.body.chunks(packet_size - HEADER_LENGTH).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        let last = chunks.len() - 1;
+        let mut packets: Vec<Vec<u8>> = Vec::new();
+
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let mut header: TdsHeader = self.header.clone();
+            header.length = (HEADER_LENGTH + chunk.len()) as u16;
+            header.packet_id = (index as u8).wrapping_add(1);
+            header.status = if index == last { self.header.status | eom } else { self.header.status & !eom };
+
+            let mut packet: Vec<u8> = header.to_byte_array().to_vec();
+            packet.extend_from_slice(chunk);
+            packets.push(packet);
+        }
+
+        packets
     }
 
-    pub fn calc_length(&mut self) {
-        let length: u16 = 0x08 + self.body.len() as u16;
-        self.header.length = length;
+    /**
+     * Reads one message from the server, joining packets together until one arrives
+     * with EndOfMessage set. The header kept is the last packet's.
+     */
+    pub fn from_stream<R: Read>(stream: &mut R) -> Result<TdsMessage> {
+        let mut message: TdsMessage = TdsMessage::read_packet(stream)?;
+
+        while !message.header.is_end_of_message() {
+            let packet: TdsMessage = TdsMessage::read_packet(stream)?;
+            message.append_packet(packet)?;
+        }
+
+        Ok(message)
+    }
+
+    /**
+     * from_stream for tokio streams.
+     */
+    #[cfg(feature = "tokio")]
+    pub async fn from_async_stream<R: AsyncRead + Unpin>(stream: &mut R) -> Result<TdsMessage> {
+        let mut message: TdsMessage = TdsMessage::read_async_packet(stream).await?;
+
+        while !message.header.is_end_of_message() {
+            let packet: TdsMessage = TdsMessage::read_async_packet(stream).await?;
+            message.append_packet(packet)?;
+        }
+
+        Ok(message)
+    }
+
+    fn append_packet(&mut self, packet: TdsMessage) -> Result<()> {
+        if packet.header.message_type != self.header.message_type {
+            return Err(Error::Protocol(format!(
+                "Packet of type {:#04x} received in the middle of a {:#04x} message",
+                packet.header.message_type,
+                self.header.message_type
+            )));
+        }
+
+        self.body.extend_from_slice(&packet.body);
+        self.header = packet.header;
+        Ok(())
     }
 
-    pub fn generate_prelogin(&mut self) {
-        let version = SqlVersion::SqlServer2022.value();
-        let encryption = EncryptionOptions::NoEncryption.value();
-        let mars = MarsOptions::NoMars.value();
-        let fed_auth = FedAuthOptions::No.value();
+    fn read_packet<R: Read>(stream: &mut R) -> Result<TdsMessage> {
+        let mut header_bytes = [0u8; HEADER_LENGTH];
+        stream.read_exact(&mut header_bytes).map_err(|e| Error::io("Failed to read packet header", e))?;
+
+        let header = TdsHeader::from_byte_array(&header_bytes);
+        let mut body: Vec<u8> = vec![0u8; header.body_length()?];
+        stream.read_exact(&mut body).map_err(|e| Error::io("Failed to read packet body", e))?;
+
+        Ok(TdsMessage {
+            header,
+            body
+        })
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn read_async_packet<R: AsyncRead + Unpin>(stream: &mut R) -> Result<TdsMessage> {
+        let mut header_bytes = [0u8; HEADER_LENGTH];
+        stream.read_exact(&mut header_bytes).await.map_err(|e| Error::io("Failed to read packet header", e))?;
+
+        let header = TdsHeader::from_byte_array(&header_bytes);
+        let mut body: Vec<u8> = vec![0u8; header.body_length()?];
+        stream.read_exact(&mut body).await.map_err(|e| Error::io("Failed to read packet body", e))?;
+
+        Ok(TdsMessage {
+            header,
+            body
+        })
+    }
+
+    pub fn set_body(&mut self, message_type: ClientMessageType, body: Vec<u8>) {
+        self.header.update_message_type(message_type);
+        self.body = body;
+    }
+
+    pub fn header(&self) -> &TdsHeader {
+        &self.header
+    }
+
+    /**
+     * Status bits for every packet of the message, EndOfMessage is added to the last one
+     * when it's split.
+     */
+    pub fn update_status(&mut self, status: MessageStatus) {
+        self.header.update_status(status);
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn generate_prelogin(&mut self, config: &PreLoginConfig) {
         let terminator = StaticValues::Terminator.value();
 
+        let mut options: Vec<(PreLoginOptionToken, Vec<u8>)> = vec![
+            (PreLoginOptionToken::Version, config.version.value().to_vec()),
+            (PreLoginOptionToken::Encryption, vec![config.encryption.value()])
+        ];
+
+        //instance name goes over the wire as a null terminated string
+        let mut instance: Vec<u8> = config.instance.as_bytes().to_vec();
+        instance.push(0x00);
+        options.push((PreLoginOptionToken::InStopT, instance));
+
+        options.push((PreLoginOptionToken::ThreadId, config.thread_id.to_be_bytes().to_vec()));
+        options.push((PreLoginOptionToken::Mars, vec![config.mars.value()]));
+
+        if let Some(trace_id) = &config.trace_id {
+            options.push((PreLoginOptionToken::TraceId, trace_id.to_bytes()));
+        }
+
+        options.push((PreLoginOptionToken::FedAuthRequired, vec![config.fed_auth.value()]));
+
+        if let Some(nonce) = &config.nonce {
+            options.push((PreLoginOptionToken::NonceOpt, nonce.to_vec()));
+        }
+
+        //each option takes 5 bytes in the table, plus 1 for the terminator. Data follows straight after.
+        let mut offset_start = (options.len() * 5 + 1) as u16;
         let mut body: Vec<u8> = Vec::new();
+        let mut data: Vec<u8> = Vec::new();
 
-        let mut offset_start = (5)+1; 
+        for (option, value) in options {
+            let length = value.len() as u16;
+            body = TdsMessage::add_preflight(body, offset_start, length, option);
+            offset_start += length;
+            data.extend_from_slice(&value);
+        }
 
-        body = TdsMessage::add_preflight(body, offset_start, 6, PreLoginOptionToken::Version);
-        // offset_start += 6;
-        // body = TdsMessage::add_preflight(body, offset_start, 1, PreLoginOptionToken::Encryption);
-        // offset_start += 1;
-        // body = TdsMessage::add_preflight(body, offset_start, 1, PreLoginOptionToken::Mars);
-        // offset_start += 1;
-        // body = TdsMessage::add_preflight(body, offset_start, 1, PreLoginOptionToken::FedAuthRequired);
-        
         body.push(terminator);
-        
-        body.extend_from_slice(&version);
-        // body.push(encryption);
-        // body.push(mars);
-        // body.push(fed_auth);
+        body.extend_from_slice(&data);
+
+        self.header.update_message_type(ClientMessageType::PreLogin);
+        self.body = body;
+    }
+
+    pub fn generate_login7(&mut self, login: &Login7) {
+        self.header.update_message_type(ClientMessageType::Tds7Login);
+        self.body = login.to_bytes();
+    }
+
+    /**
+     * SQL_BATCH: ALL_HEADERS followed by the statement text as UCS-2.
+     */
+    pub fn generate_sql_batch(&mut self, sql: &str, transaction_descriptor: u64) {
+        let mut body: Vec<u8> = all_headers(transaction_descriptor);
+        body.extend_from_slice(&ucs2_bytes(sql));
+
+        self.header.update_message_type(ClientMessageType::SqlBatch);
+        self.body = body;
+    }
+
+    /**
+     * RPC request calling sp_executesql, which runs `sql` with the parameters bound to
+     * @P1, @P2, ... in order. The statement and the parameter declarations are themselves
+     * the first two (unnamed) parameters.
+     *
+     * Fails, leaving the message as it was, when a parameter can't be sent as its type.
+     *
+     * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/619c43b6-9495-4a58-9e49-a4950db245b3
+     */
+    pub fn generate_sp_executesql(&mut self, sql: &str, params: &[SqlValue], collation: Collation, transaction_descriptor: u64) -> Result<()> {
+        let mut body: Vec<u8> = all_headers(transaction_descriptor);
+
+        //0xffff instead of a name length means a well known procedure id follows
+        body.extend_from_slice(&0xffffu16.to_le_bytes());
+        body.extend_from_slice(&ProcId::ExecuteSql.value().to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+
+        TdsMessage::add_rpc_param(&mut body, "", &SqlValue::String(String::from(sql)), collation)?;
+
+        if !params.is_empty() {
+            let declarations: Vec<String> = params.iter()
+                .enumerate()
+                .map(|(index, param)| format!("@P{} {}", index + 1, param.declaration()))
+                .collect();
+            TdsMessage::add_rpc_param(&mut body, "", &SqlValue::String(declarations.join(", ")), collation)?;
+
+            for (index, param) in params.iter().enumerate() {
+                TdsMessage::add_rpc_param(&mut body, &format!("@P{}", index + 1), param, collation)?;
+            }
+        }
+
+        self.header.update_message_type(ClientMessageType::Rpc);
+        self.body = body;
+        Ok(())
+    }
+
+    /**
+     * Transaction manager request: ALL_HEADERS, the request type and its payload. The
+     * server answers with an ENVCHANGE when a transaction starts or ends.
+     *
+     * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/a310d5f0-2a38-43e8-8b8a-cc5bc4c1f1d0
+     */
+    pub fn generate_transaction_request(&mut self, request: &TransactionRequest, transaction_descriptor: u64) {
+        let mut body: Vec<u8> = all_headers(transaction_descriptor);
+        body.extend_from_slice(&request.request_type().to_le_bytes());
+        body.extend_from_slice(&request.payload());
 
+        self.header.update_message_type(ClientMessageType::TransactionManagerRequest);
         self.body = body;
     }
 
+    fn add_rpc_param(body: &mut Vec<u8>, name: &str, value: &SqlValue, collation: Collation) -> Result<()> {
+        body.push(name.encode_utf16().count() as u8);
+        body.extend_from_slice(&ucs2_bytes(name));
+        body.push(0x00); //status flags, 0x01 would make it an output parameter
+        body.extend_from_slice(&value.encode(collation)?);
+        Ok(())
+    }
+
     fn add_preflight(mut body: Vec<u8>, offset_start: u16, length: u16, option: PreLoginOptionToken) -> Vec<u8> {
         body.push(option.value()); //Option
         body.push((offset_start >> 8) as u8); //offset msb
@@ -64,7 +305,594 @@ impl TdsMessage {
     }
 }
 
-enum StaticValues {
+/**
+ * Splits a message into packets while its body is still being written, for messages too
+ * big to build in one go (BULK_LOAD). Full packets can be taken and sent as they fill up,
+ * the last one goes out with EndOfMessage from finish.
+ */
+pub struct PacketWriter {
+    header: TdsHeader,
+    packet_size: usize,
+    body: Vec<u8>,
+    packets_sent: usize
+}
+impl PacketWriter {
+    pub fn new(message_type: ClientMessageType, packet_size: u32) -> PacketWriter {
+        PacketWriter {
+            header: TdsHeader::new(message_type, MessageStatus::Normal),
+            packet_size: packet_size.clamp(MIN_PACKET_SIZE, MAX_PACKET_SIZE) as usize,
+            body: Vec::new(),
+            packets_sent: 0
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.body.extend_from_slice(bytes);
+    }
+
+    /**
+     * Takes the packets that are full. Anything left over waits for more to be written, so
+     * there's always something for the last packet.
+     */
+    pub fn full_packets(&mut self) -> Vec<Vec<u8>> {
+        let chunk_size: usize = self.packet_size - HEADER_LENGTH;
+        let mut packets: Vec<Vec<u8>> = Vec::new();
+
+        while self.body.len() > chunk_size {
+            let chunk: Vec<u8> = self.body.drain(..chunk_size).collect();
+            packets.push(self.packet(&chunk, MessageStatus::Normal.value()));
+        }
+
+        packets
+    }
+
+    /**
+     * The rest of the message, ending with the EndOfMessage packet.
+     */
+    pub fn finish(mut self) -> Vec<Vec<u8>> {
+        let mut packets: Vec<Vec<u8>> = self.full_packets();
+        let body: Vec<u8> = std::mem::take(&mut self.body);
+        packets.push(self.packet(&body, MessageStatus::EndOfMessage.value()));
+        packets
+    }
+
+    /**
+     * Ends a message that can't be finished. Packets already sent are thrown away by the
+     * server when it gets an empty one with Ignore and EndOfMessage set. None if nothing
+     * has been sent, as then there's nothing to end.
+     */
+    pub fn abandon(mut self) -> Option<Vec<u8>> {
+        if self.packets_sent == 0 {
+            return None;
+        }
+
+        Some(self.packet(&[], MessageStatus::Ignore.value() | MessageStatus::EndOfMessage.value()))
+    }
+
+    fn packet(&mut self, chunk: &[u8], status: u8) -> Vec<u8> {
+        let mut header: TdsHeader = self.header.clone();
+        header.length = (HEADER_LENGTH + chunk.len()) as u16;
+        header.packet_id = (self.packets_sent as u8).wrapping_add(1);
+        header.status = status;
+        self.packets_sent += 1;
+
+        let mut packet: Vec<u8> = header.to_byte_array().to_vec();
+        packet.extend_from_slice(chunk);
+        packet
+    }
+}
+
+/**
+ * Reads the body of one incoming message straight off the stream, a packet at a time, so
+ * a big response can be worked through without holding all of it. Reading stops at the
+ * end of the message's last packet.
+ */
+pub struct PacketReader<R: Read> {
+    stream: R,
+    remaining: usize,
+    last_packet: bool
+}
+impl<R: Read> PacketReader<R> {
+    pub fn new(stream: R) -> PacketReader<R> {
+        PacketReader {
+            stream,
+            remaining: 0,
+            last_packet: false
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.stream
+    }
+}
+impl<R: Read> Read for PacketReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.last_packet || buffer.is_empty() {
+                return Ok(0);
+            }
+
+            let mut header_bytes = [0u8; HEADER_LENGTH];
+            self.stream.read_exact(&mut header_bytes)?;
+
+            let header = TdsHeader::from_byte_array(&header_bytes);
+            self.remaining = header.body_length().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            self.last_packet = header.is_end_of_message();
+        }
+
+        let length: usize = buffer.len().min(self.remaining);
+        let read: usize = self.stream.read(&mut buffer[..length])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended part way through a packet"));
+        }
+
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+/**
+ * ALL_HEADERS block that starts SQL_BATCH, RPC and transaction manager requests.
+ * We only send the transaction descriptor header, which the server insists on: the
+ * descriptor of the open transaction (0 when there isn't one) and the number of
+ * outstanding requests on the connection.
+ */
+pub fn all_headers(transaction_descriptor: u64) -> Vec<u8> {
+    let header_length: u32 = 4 + 2 + 8 + 4;
+    let total_length: u32 = 4 + header_length;
+
+    let mut headers: Vec<u8> = Vec::with_capacity(total_length as usize);
+    headers.extend_from_slice(&total_length.to_le_bytes());
+    headers.extend_from_slice(&header_length.to_le_bytes());
+    headers.extend_from_slice(&AllHeadersType::TransactionDescriptor.value().to_le_bytes());
+    headers.extend_from_slice(&transaction_descriptor.to_le_bytes());
+    headers.extend_from_slice(&1u32.to_le_bytes());
+    headers
+}
+
+pub enum AllHeadersType {
+    QueryNotifications,
+    TransactionDescriptor,
+    TraceActivity
+}
+impl AllHeadersType {
+    pub fn value(&self) -> u16 {
+        match self {
+            AllHeadersType::QueryNotifications => 0x0001,
+            AllHeadersType::TransactionDescriptor => 0x0002,
+            AllHeadersType::TraceActivity => 0x0003
+        }
+    }
+}
+
+/**
+ * The transaction manager requests we send. Names are savepoints, an unnamed rollback
+ * rolls back the whole transaction. Names go out as B_VARCHAR so are limited to 255
+ * characters (SQL Server itself allows 32).
+ */
+pub enum TransactionRequest {
+    Begin(IsolationLevel),
+    Commit,
+    Rollback(Option<String>),
+    Save(String)
+}
+impl TransactionRequest {
+    pub fn request_type(&self) -> u16 {
+        match self {
+            TransactionRequest::Begin(_) => 5,
+            TransactionRequest::Commit => 7,
+            TransactionRequest::Rollback(_) => 8,
+            TransactionRequest::Save(_) => 9
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::new();
+
+        match self {
+            TransactionRequest::Begin(isolation) => {
+                payload.push(isolation.value());
+                payload.push(0x00); //no transaction name
+            },
+            TransactionRequest::Commit | TransactionRequest::Rollback(None) => {
+                payload.push(0x00); //no name
+                payload.push(0x00); //flags, 0x01 would begin a new transaction straight after
+            },
+            TransactionRequest::Rollback(Some(name)) => {
+                payload.extend_from_slice(&b_varchar(name));
+                payload.push(0x00);
+            },
+            TransactionRequest::Save(name) => payload.extend_from_slice(&b_varchar(name))
+        }
+
+        payload
+    }
+}
+
+pub(crate) fn b_varchar(value: &str) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![value.encode_utf16().count() as u8];
+    bytes.extend_from_slice(&ucs2_bytes(value));
+    bytes
+}
+
+pub(crate) fn us_varchar(value: &str) -> Vec<u8> {
+    let mut bytes: Vec<u8> = (value.encode_utf16().count() as u16).to_le_bytes().to_vec();
+    bytes.extend_from_slice(&ucs2_bytes(value));
+    bytes
+}
+
+/**
+ * Stored procedures that can be called by id in an RPC request rather than by name.
+ */
+pub enum ProcId {
+    Cursor,
+    CursorOpen,
+    CursorPrepare,
+    CursorExecute,
+    CursorPrepExec,
+    CursorUnprepare,
+    CursorFetch,
+    CursorOption,
+    CursorClose,
+    ExecuteSql,
+    Prepare,
+    Execute,
+    PrepExec,
+    PrepExecRpc,
+    Unprepare
+}
+impl ProcId {
+    pub fn value(&self) -> u16 {
+        match self {
+            ProcId::Cursor => 1,
+            ProcId::CursorOpen => 2,
+            ProcId::CursorPrepare => 3,
+            ProcId::CursorExecute => 4,
+            ProcId::CursorPrepExec => 5,
+            ProcId::CursorUnprepare => 6,
+            ProcId::CursorFetch => 7,
+            ProcId::CursorOption => 8,
+            ProcId::CursorClose => 9,
+            ProcId::ExecuteSql => 10,
+            ProcId::Prepare => 11,
+            ProcId::Execute => 12,
+            ProcId::PrepExec => 13,
+            ProcId::PrepExecRpc => 14,
+            ProcId::Unprepare => 15
+        }
+    }
+}
+
+/**
+ * PLP framing for (max) values we send: total length, the data as one chunk, then the
+ * zero length chunk that ends it.
+ */
+pub fn plp_bytes(data: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(data.len() + 16);
+    bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+
+    if !data.is_empty() {
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+    }
+
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes
+}
+
+/**
+ * A whole PLP value, None when it's NULL.
+ */
+pub fn read_plp<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let total_length = reader.read_u64_le()?;
+    if total_length == PLP_NULL {
+        return Ok(None);
+    }
+
+    let mut value: Vec<u8> = Vec::new();
+    if total_length != PLP_UNKNOWN_LENGTH {
+        value.reserve(total_length.min(MAX_RESERVE as u64) as usize);
+    }
+
+    PlpReader::new(reader, total_length)
+        .read_to_end(&mut value)
+        .map_err(|e| read_error("Failed to read PLP chunk", e))?;
+    Ok(Some(value))
+}
+
+/**
+ * Partially Length-Prefixed data
+ *
+ * varchar(max), nvarchar(max), varbinary(max) and xml values are sent as a u64 total length
+ * (or a marker for NULL, or for a length that isn't known up front) followed by chunks, each
+ * with a u32 length, ending with a zero length chunk.
+ *
+ * PlpReader reads the data a chunk at a time so the value never has to be held whole. It's
+ * made once the total length has been read and stops at the chunk that ends the value.
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/3f983fde-0509-485a-8c40-a9fa6679a828
+ */
+pub struct PlpReader<R: Read> {
+    reader: R,
+    total_length: u64,
+    chunk_remaining: usize,
+    finished: bool
+}
+impl<R: Read> PlpReader<R> {
+    pub fn new(reader: R, total_length: u64) -> PlpReader<R> {
+        PlpReader {
+            reader,
+            total_length,
+            chunk_remaining: 0,
+            finished: false
+        }
+    }
+
+    /**
+     * Length the value was sent with, None when the sender didn't know it.
+     */
+    pub fn total_length(&self) -> Option<u64> {
+        if self.total_length == PLP_UNKNOWN_LENGTH {
+            return None;
+        }
+        Some(self.total_length)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+impl<R: Read> Read for PlpReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.chunk_remaining == 0 {
+            if self.finished || buffer.is_empty() {
+                return Ok(0);
+            }
+
+            let mut length = [0u8; 4];
+            self.reader.read_exact(&mut length)?;
+            self.chunk_remaining = u32::from_le_bytes(length) as usize;
+            self.finished = self.chunk_remaining == 0;
+        }
+
+        let length: usize = buffer.len().min(self.chunk_remaining);
+        let read: usize = self.reader.read(&mut buffer[..length])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended part way through a PLP chunk"));
+        }
+
+        self.chunk_remaining -= read;
+        Ok(read)
+    }
+}
+
+/**
+ * Writes a PLP value a chunk at a time, each write becoming a chunk, so a value can be sent
+ * from a stream without being held whole. Without a total_length the value is sent as being
+ * of unknown length. finish writes the chunk that ends it.
+ */
+pub struct PlpWriter<W: Write> {
+    writer: W
+}
+impl<W: Write> PlpWriter<W> {
+    pub fn new(mut writer: W, total_length: Option<u64>) -> io::Result<PlpWriter<W>> {
+        writer.write_all(&total_length.unwrap_or(PLP_UNKNOWN_LENGTH).to_le_bytes())?;
+
+        Ok(PlpWriter {
+            writer
+        })
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&0u32.to_le_bytes())?;
+        Ok(self.writer)
+    }
+}
+impl<W: Write> Write for PlpWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the value
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let length: usize = data.len().min(u32::MAX as usize);
+        self.writer.write_all(&(length as u32).to_le_bytes())?;
+        self.writer.write_all(&data[..length])?;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/**
+ * Strings go over the wire as little endian UCS-2.
+ */
+pub fn ucs2_bytes(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect()
+}
+
+/**
+ * The server's reply to a PRELOGIN message.
+ *
+ * The reply uses the same option table layout as the request (token, offset, length),
+ * so we walk it the same way add_preflight writes it.
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/60f56408-0188-4cd5-8b90-25c6f2423868
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreLoginResponse {
+    pub version: ServerVersion,
+    pub encryption: EncryptionOptions,
+    pub instance_valid: bool,
+    pub thread_id: Option<u32>,
+    pub mars: bool,
+    pub fed_auth_required: bool,
+    pub nonce: Option<[u8; 32]>
+}
+impl PreLoginResponse {
+    pub fn from_bytes(body: &[u8]) -> Result<PreLoginResponse> {
+        let mut response = PreLoginResponse {
+            version: ServerVersion::new(0, 0, 0, 0),
+            encryption: EncryptionOptions::NoEncryption,
+            instance_valid: true,
+            thread_id: None,
+            mars: false,
+            fed_auth_required: false,
+            nonce: None
+        };
+        let mut found_version = false;
+        let mut position: usize = 0;
+
+        loop {
+            let token = *body.get(position).ok_or(Error::Protocol(String::from("PRELOGIN response ended before the terminator")))?;
+            if token == PreLoginOptionToken::Terminator.value() {
+                break;
+            }
+
+            let entry = body.get(position..position + 5).ok_or(Error::Protocol(String::from("PRELOGIN option table is truncated")))?;
+            let offset = u16::from_be_bytes([entry[1], entry[2]]) as usize;
+            let length = u16::from_be_bytes([entry[3], entry[4]]) as usize;
+            let data = body.get(offset..offset + length)
+                .ok_or(Error::Protocol(format!("PRELOGIN option 0x{:02X} points outside of the message", token)))?;
+
+            match PreLoginOptionToken::from_value(token) {
+                Some(PreLoginOptionToken::Version) => {
+                    if length < 6 {
+                        return Err(Error::Protocol(format!("PRELOGIN VERSION option is {} bytes, expected 6", length)));
+                    }
+                    response.version = ServerVersion::from_bytes(data);
+                    found_version = true;
+                },
+                Some(PreLoginOptionToken::Encryption) => {
+                    let value = *data.first().ok_or(Error::Protocol(String::from("PRELOGIN ENCRYPTION option is empty")))?;
+                    response.encryption = EncryptionOptions::from_value(value)?;
+                },
+                Some(PreLoginOptionToken::InStopT) => {
+                    // 0x00 means the server matched the instance we asked for
+                    response.instance_valid = data.first().is_none_or(|value| *value == 0x00);
+                },
+                Some(PreLoginOptionToken::ThreadId) if length >= 4 => {
+                    response.thread_id = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+                },
+                Some(PreLoginOptionToken::Mars) => {
+                    response.mars = data.first() == Some(&0x01);
+                },
+                Some(PreLoginOptionToken::FedAuthRequired) => {
+                    response.fed_auth_required = data.first() == Some(&0x01);
+                },
+                Some(PreLoginOptionToken::NonceOpt) => {
+                    let nonce: [u8; 32] = data.try_into()
+                        .map_err(|_| Error::Protocol(format!("PRELOGIN NONCEOPT option is {} bytes, expected 32", length)))?;
+                    response.nonce = Some(nonce);
+                },
+                // the server never sends a TRACEID back and unknown options are meant to be ignored
+                _ => ()
+            }
+
+            position += 5;
+        }
+
+        if !found_version {
+            return Err(Error::Protocol(String::from("PRELOGIN response is missing the VERSION option")));
+        }
+
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub build: u16,
+    pub sub_build: u16
+}
+impl ServerVersion {
+    pub fn new(major: u8, minor: u8, build: u16, sub_build: u16) -> ServerVersion {
+        ServerVersion {
+            major,
+            minor,
+            build,
+            sub_build
+        }
+    }
+
+    fn from_bytes(data: &[u8]) -> ServerVersion {
+        ServerVersion {
+            major: data[0],
+            minor: data[1],
+            build: u16::from_be_bytes([data[2], data[3]]),
+            sub_build: u16::from_be_bytes([data[4], data[5]])
+        }
+    }
+}
+
+/**
+ * Everything the client can say in a PRELOGIN message.
+ *
+ * new() gives the options we have always sent: no encryption, no MARS, no fed auth,
+ * the default instance and no trace id or nonce.
+ */
+pub struct PreLoginConfig {
+    pub version: SqlVersion,
+    pub encryption: EncryptionOptions,
+    pub instance: String,
+    pub thread_id: u32,
+    pub mars: MarsOptions,
+    pub trace_id: Option<TraceId>,
+    pub fed_auth: FedAuthOptions,
+    pub nonce: Option<[u8; 32]>
+}
+impl Default for PreLoginConfig {
+    fn default() -> PreLoginConfig {
+        PreLoginConfig::new()
+    }
+}
+impl PreLoginConfig {
+    pub fn new() -> PreLoginConfig {
+        PreLoginConfig {
+            version: SqlVersion::SqlServer2022,
+            encryption: EncryptionOptions::NoEncryption,
+            instance: String::new(),
+            thread_id: std::process::id(),
+            mars: MarsOptions::NoMars,
+            trace_id: None,
+            fed_auth: FedAuthOptions::No,
+            nonce: None
+        }
+    }
+}
+
+/**
+ * TRACEID option: connection id and activity id GUIDs followed by the activity sequence number.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceId {
+    pub connection_id: [u8; 16],
+    pub activity_id: [u8; 16],
+    pub activity_sequence: u32
+}
+impl TraceId {
+    pub fn new(connection_id: [u8; 16], activity_id: [u8; 16], activity_sequence: u32) -> TraceId {
+        TraceId {
+            connection_id,
+            activity_id,
+            activity_sequence
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(36);
+        bytes.extend_from_slice(&self.connection_id);
+        bytes.extend_from_slice(&self.activity_id);
+        bytes.extend_from_slice(&self.activity_sequence.to_le_bytes());
+        bytes
+    }
+}
+
+pub enum StaticValues {
     Terminator
 }
 impl StaticValues {
@@ -75,7 +903,7 @@ impl StaticValues {
     }
 }
 
-enum PreLoginOptionToken {
+pub enum PreLoginOptionToken {
     Version,
     Encryption,
     InStopT,
@@ -97,29 +925,46 @@ impl PreLoginOptionToken {
             PreLoginOptionToken::TraceId => 0x05,
             PreLoginOptionToken::FedAuthRequired => 0x06,
             PreLoginOptionToken::NonceOpt => 0x07,
-            PreLoginOptionToken::Terminator => 0x08,
+            PreLoginOptionToken::Terminator => 0xff,
+        }
+    }
+
+    fn from_value(value: u8) -> Option<PreLoginOptionToken> {
+        match value {
+            0x00 => Some(PreLoginOptionToken::Version),
+            0x01 => Some(PreLoginOptionToken::Encryption),
+            0x02 => Some(PreLoginOptionToken::InStopT),
+            0x03 => Some(PreLoginOptionToken::ThreadId),
+            0x04 => Some(PreLoginOptionToken::Mars),
+            0x05 => Some(PreLoginOptionToken::TraceId),
+            0x06 => Some(PreLoginOptionToken::FedAuthRequired),
+            0x07 => Some(PreLoginOptionToken::NonceOpt),
+            0xff => Some(PreLoginOptionToken::Terminator),
+            _ => None
         }
     }
 }
 
-enum SqlVersion {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SqlVersion {
     SqlServer2022
 }
 impl SqlVersion {
-    fn value(&self) -> [u8; 6] {
+    pub fn value(&self) -> [u8; 6] {
         match self {
             SqlVersion::SqlServer2022 => [0x10, 0x00, 0x7f, 0x10, 0x00, 0x00]
         }
     }
 }
-enum EncryptionOptions {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionOptions {
     NoEncryption,
     EncryptionEnabled,
     EncryptionRequested,
     EncryptionEnabledRequested
 }
 impl EncryptionOptions {
-    fn value(&self) -> u8 {
+    pub fn value(&self) -> u8 {
         match self {
             EncryptionOptions::NoEncryption => 0x00,
             EncryptionOptions::EncryptionEnabled => 0x01,
@@ -127,15 +972,26 @@ impl EncryptionOptions {
             EncryptionOptions::EncryptionEnabledRequested => 0x03
         }
     }
+
+    fn from_value(value: u8) -> Result<EncryptionOptions> {
+        match value {
+            0x00 => Ok(EncryptionOptions::NoEncryption),
+            0x01 => Ok(EncryptionOptions::EncryptionEnabled),
+            0x02 => Ok(EncryptionOptions::EncryptionRequested),
+            0x03 => Ok(EncryptionOptions::EncryptionEnabledRequested),
+            _ => Err(Error::Protocol(format!("Unknown encryption option 0x{:02X}", value)))
+        }
+    }
 }
-enum MarsOptions {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MarsOptions {
     NoMars,
     MarsRequested,
     MarsSupported,
     MarsRequestedSupportd
 }
 impl MarsOptions {
-    fn value(&self) -> u8 {
+    pub fn value(&self) -> u8 {
         match self {
             MarsOptions::NoMars => 0x00,
             MarsOptions::MarsRequested => 0x01,
@@ -144,12 +1000,13 @@ impl MarsOptions {
         }
     }
 }
-enum FedAuthOptions {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FedAuthOptions {
     Yes,
     No
 }
 impl FedAuthOptions {
-    fn value(&self) -> u8 {
+    pub fn value(&self) -> u8 {
         match self {
             FedAuthOptions::Yes => 0x01,
             FedAuthOptions::No => 0x00 
@@ -157,7 +1014,8 @@ impl FedAuthOptions {
     }
 }
 
-struct TdsHeader {
+#[derive(Debug, Clone)]
+pub struct TdsHeader {
     message_type: u8,
     status: u8,
     length: u16,
@@ -172,20 +1030,63 @@ impl TdsHeader {
             status: status.value(), 
             length: 0x200,
             spid: 0x0000,
-            packet_id: 0x00,
+            packet_id: 0x01,
             window: 0x00
         }
     }
 
-    fn update_message_type(&mut self, message_type: ClientMessageType) {
+    pub fn from_byte_array(header: &[u8; 8]) -> TdsHeader {
+        TdsHeader {
+            message_type: header[0],
+            status: header[1],
+            length: u16::from_be_bytes([header[2], header[3]]),
+            spid: u16::from_be_bytes([header[4], header[5]]),
+            packet_id: header[6],
+            window: header[7]
+        }
+    }
+
+    pub fn message_type(&self) -> u8 {
+        self.message_type
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    pub fn spid(&self) -> u16 {
+        self.spid
+    }
+
+    pub fn length(&self) -> u16 {
+        self.length
+    }
+
+    pub fn packet_id(&self) -> u8 {
+        self.packet_id
+    }
+
+    /**
+     * Bytes left to read after the header.
+     */
+    pub(crate) fn body_length(&self) -> Result<usize> {
+        (self.length as usize).checked_sub(HEADER_LENGTH)
+            .ok_or(Error::Protocol(format!("Invalid packet length {}", self.length)))
+    }
+
+    pub fn is_end_of_message(&self) -> bool {
+        self.status & MessageStatus::EndOfMessage.value() != 0
+    }
+
+    pub fn update_message_type(&mut self, message_type: ClientMessageType) {
         self.message_type = message_type.value();
     }
 
-    fn update_status(&mut self, status: MessageStatus) {
+    pub fn update_status(&mut self, status: MessageStatus) {
         self.status = status.value();
     }
 
-    fn to_byte_array(&self) -> [u8;8] {
+    pub fn to_byte_array(&self) -> [u8;8] {
         let mut header: [u8; 8] = [0; 8];
         header[0] = self.message_type;
         header[1] = self.status;
@@ -227,7 +1128,7 @@ impl ClientMessageType {
     }
 }
 
-enum MessageStatus {
+pub enum MessageStatus {
     Normal,
     EndOfMessage,
     Ignore,
@@ -345,7 +1246,51 @@ mod tests {
     fn test_tdsmessage_generate_prelogin_generates_body() {
         let mut message = TdsMessage::new();
 
-        message.generate_prelogin();
+        message.generate_prelogin(&PreLoginConfig::new());
+
+        // VERSION, ENCRYPTION, INSTOPT, THREADID, MARS, FEDAUTHREQUIRED + terminator
+        assert_eq!(message.body[30], StaticValues::Terminator.value());
+        assert_eq!(&message.body[0..5], &[0x00, 0x00, 31, 0x00, 0x06]);
+        assert_eq!(&message.body[31..37], &SqlVersion::SqlServer2022.value());
+        assert_eq!(message.body.len(), 31 + 6 + 1 + 1 + 4 + 1 + 1);
+    }
+
+    #[test]
+    fn test_tdsmessage_generate_prelogin_computes_offsets() {
+        let mut message = TdsMessage::new();
+        let mut config = PreLoginConfig::new();
+        config.encryption = EncryptionOptions::EncryptionEnabled;
+        config.instance = String::from("SQLEXPRESS");
+        config.thread_id = 0x01020304;
+        config.mars = MarsOptions::MarsRequested;
+        config.trace_id = Some(TraceId::new([0x11; 16], [0x22; 16], 7));
+        config.fed_auth = FedAuthOptions::Yes;
+        config.nonce = Some([0x33; 32]);
+
+        message.generate_prelogin(&config);
+
+        let body = &message.body;
+        let mut tokens: Vec<u8> = Vec::new();
+        let mut position = 0;
+        let mut expected_offset = 8 * 5 + 1;
+        while body[position] != 0xff {
+            let offset = u16::from_be_bytes([body[position + 1], body[position + 2]]) as usize;
+            let length = u16::from_be_bytes([body[position + 3], body[position + 4]]) as usize;
+            assert_eq!(offset, expected_offset);
+            expected_offset += length;
+            tokens.push(body[position]);
+            position += 5;
+        }
+
+        assert_eq!(tokens, vec![0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07]);
+        assert_eq!(expected_offset, body.len());
+        assert_eq!(body[41 + 6], 0x01);
+        assert_eq!(&body[41 + 7..41 + 18], b"SQLEXPRESS\0");
+        assert_eq!(&body[41 + 18..41 + 22], &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(body[41 + 22], 0x01);
+        assert_eq!(&body[41 + 55..41 + 59], &[0x07, 0x00, 0x00, 0x00]);
+        assert_eq!(body[41 + 59], 0x01);
+        assert_eq!(&body[41 + 60..], &[0x33; 32]);
     }
 
     #[test]
@@ -355,4 +1300,339 @@ mod tests {
         let bytes = message.to_bytes();
         assert_eq!(bytes.len(), 9);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_tdsmessage_generate_sql_batch_writes_headers_and_text() {
+        let mut message = TdsMessage::new();
+
+        message.generate_sql_batch("SELECT 1", 0x0102);
+
+        assert_eq!(message.header.message_type, ClientMessageType::SqlBatch.value());
+        assert_eq!(&message.body[0..4], &[22, 0, 0, 0]);
+        assert_eq!(&message.body[4..8], &[18, 0, 0, 0]);
+        assert_eq!(&message.body[8..10], &[0x02, 0x00]);
+        assert_eq!(&message.body[10..18], &[0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&message.body[18..22], &[1, 0, 0, 0]);
+        assert_eq!(&message.body[22..], &ucs2_bytes("SELECT 1")[..]);
+    }
+
+    #[test]
+    fn test_tdsmessage_generate_sp_executesql_writes_params() {
+        let mut message = TdsMessage::new();
+        let collation = Collation::new(0x00d00409, 0x34);
+
+        message.generate_sp_executesql("SELECT @P1", &[SqlValue::Int(7)], collation, 0).unwrap();
+
+        let body = &message.body[22..];
+        assert_eq!(message.header.message_type, ClientMessageType::Rpc.value());
+        assert_eq!(&body[0..6], &[0xff, 0xff, 0x0a, 0x00, 0x00, 0x00]);
+
+        //statement: no name, no flags, nvarchar(4000) with the collation
+        let statement = ucs2_bytes("SELECT @P1");
+        assert_eq!(&body[6..10], &[0x00, 0x00, 0xe7, 0x40]);
+        assert_eq!(&body[11..16], &collation.to_bytes());
+        assert_eq!(&body[16..18], &(statement.len() as u16).to_le_bytes());
+        let mut position = 18 + statement.len();
+
+        let declarations = ucs2_bytes("@P1 int");
+        position += 2 + 1 + 2 + 5 + 2;
+        assert_eq!(&body[position..position + declarations.len()], &declarations[..]);
+        position += declarations.len();
+
+        let mut param: Vec<u8> = vec![0x03];
+        param.extend_from_slice(&ucs2_bytes("@P1"));
+        param.extend_from_slice(&[0x00, 0x26, 0x04, 0x04, 0x07, 0x00, 0x00, 0x00]);
+        assert_eq!(&body[position..], &param[..]);
+    }
+
+    #[test]
+    fn test_tdsmessage_generate_sp_executesql_without_params() {
+        let mut message = TdsMessage::new();
+
+        message.generate_sp_executesql("SELECT 1", &[], Collation::new(0, 0), 0).unwrap();
+
+        assert_eq!(message.body.len(), 22 + 6 + 2 + 10 + ucs2_bytes("SELECT 1").len());
+    }
+
+    #[test]
+    fn test_tdsmessage_generate_transaction_request_writes_payload() {
+        let mut message = TdsMessage::new();
+
+        message.generate_transaction_request(&TransactionRequest::Begin(IsolationLevel::Serializable), 0);
+        assert_eq!(message.header.message_type, ClientMessageType::TransactionManagerRequest.value());
+        assert_eq!(&message.body[22..], &[0x05, 0x00, 0x04, 0x00]);
+
+        message.generate_transaction_request(&TransactionRequest::Commit, 0x0102);
+        assert_eq!(&message.body[10..18], &[0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&message.body[22..], &[0x07, 0x00, 0x00, 0x00]);
+
+        message.generate_transaction_request(&TransactionRequest::Save(String::from("sp")), 0x0102);
+        assert_eq!(&message.body[22..], &[0x09, 0x00, 0x02, b's', 0x00, b'p', 0x00]);
+
+        message.generate_transaction_request(&TransactionRequest::Rollback(Some(String::from("sp"))), 0x0102);
+        assert_eq!(&message.body[22..], &[0x08, 0x00, 0x02, b's', 0x00, b'p', 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_plp_bytes_frames_data() {
+        let mut expected: Vec<u8> = 3u64.to_le_bytes().to_vec();
+        expected.extend_from_slice(&[0x03, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00]);
+
+        assert_eq!(plp_bytes(&[0x01, 0x02, 0x03]), expected);
+        assert_eq!(plp_bytes(&[]), vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_plpreader_reads_chunks_then_stops() {
+        let mut bytes: Vec<u8> = vec![0x03, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x02, 0x00, 0x00, 0x00, 0x04, 0x05];
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0xfd]);
+        let mut reader = PlpReader::new(&bytes[..], PLP_UNKNOWN_LENGTH);
+
+        let mut first = [0u8; 2];
+        reader.read_exact(&mut first).unwrap();
+        let mut rest: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+
+        assert_eq!(reader.total_length(), None);
+        assert_eq!((first.to_vec(), rest), (vec![0x01, 0x02], vec![0x03, 0x04, 0x05]));
+        assert_eq!(reader.into_inner(), &[0xfd]);
+    }
+
+    #[test]
+    fn test_plpwriter_round_trips_through_read_plp() {
+        let mut writer = PlpWriter::new(Vec::new(), None).unwrap();
+        writer.write_all(&[0x01, 0x02]).unwrap();
+        writer.write_all(&[]).unwrap();
+        writer.write_all(&[0x03]).unwrap();
+        let bytes: Vec<u8> = writer.finish().unwrap();
+
+        assert_eq!(bytes.len(), 8 + 4 + 2 + 4 + 1 + 4);
+        assert_eq!(read_plp(&mut &bytes[..]).unwrap(), Some(vec![0x01, 0x02, 0x03]));
+        assert_eq!(read_plp(&mut &plp_bytes(&[0x07])[..]).unwrap(), Some(vec![0x07]));
+        assert_eq!(read_plp(&mut &PLP_NULL.to_le_bytes()[..]).unwrap(), None);
+        assert!(matches!(read_plp(&mut &bytes[..bytes.len() - 2]), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn test_read_plp_does_not_trust_total_length() {
+        let mut bytes: Vec<u8> = 0xfffffffffffffffdu64.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0x01, 0x00, 0x00, 0x00, 0xab, 0x00, 0x00, 0x00, 0x00]);
+
+        assert_eq!(read_plp(&mut &bytes[..]).unwrap(), Some(vec![0xab]));
+    }
+
+    #[test]
+    fn test_tdsmessage_from_stream_reads_packet() {
+        let packet: Vec<u8> = vec![0x04, 0x01, 0x00, 0x0b, 0x00, 0x00, 0x01, 0x00, 0xaa, 0xbb, 0xcc, 0xdd];
+        let mut reader: &[u8] = &packet;
+
+        let message = TdsMessage::from_stream(&mut reader).unwrap();
+
+        assert_eq!(message.header().message_type(), 0x04);
+        assert_eq!(message.header().status(), MessageStatus::EndOfMessage.value());
+        assert_eq!(message.body(), &[0xaa, 0xbb, 0xcc]);
+        assert_eq!(reader, &[0xdd]);
+    }
+
+    #[test]
+    fn test_tdsmessage_to_packets_splits_by_packet_size() {
+        let mut message = TdsMessage::new();
+        message.set_body(ClientMessageType::SqlBatch, vec![0xab; 1200]);
+
+        let packets = message.to_packets(512);
+
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets.iter().map(|packet| packet.len()).collect::<Vec<usize>>(), vec![512, 512, 200]);
+        for (index, packet) in packets.iter().enumerate() {
+            let header = TdsHeader::from_byte_array(packet[0..8].try_into().unwrap());
+            assert_eq!(header.message_type(), ClientMessageType::SqlBatch.value());
+            assert_eq!(header.packet_id(), index as u8 + 1);
+            assert_eq!(header.length() as usize, packet.len());
+            assert_eq!(header.is_end_of_message(), index == 2);
+        }
+    }
+
+    #[test]
+    fn test_tdsmessage_to_packets_keeps_other_status_bits() {
+        let mut message = TdsMessage::new();
+        message.header.update_status(MessageStatus::ResetConnection);
+        message.set_body(ClientMessageType::SqlBatch, vec![0x00; 600]);
+
+        let packets = message.to_packets(512);
+
+        assert_eq!(packets[0][1], 0x08);
+        assert_eq!(packets[1][1], 0x09);
+    }
+
+    #[test]
+    fn test_tdsmessage_to_packets_sends_empty_body() {
+        let message = TdsMessage::new();
+
+        let packets = message.to_packets(DEFAULT_PACKET_SIZE);
+
+        assert_eq!(packets, vec![vec![0x12, 0x01, 0x00, 0x08, 0x00, 0x00, 0x01, 0x00]]);
+    }
+
+    #[test]
+    fn test_packetreader_reads_body_across_packets() {
+        let mut bytes: Vec<u8> = vec![0x04, 0x00, 0x00, 0x0b, 0x00, 0x00, 0x01, 0x00, 0x01, 0x02, 0x03];
+        bytes.extend_from_slice(&[0x04, 0x01, 0x00, 0x0a, 0x00, 0x00, 0x02, 0x00, 0x04, 0x05]);
+        bytes.extend_from_slice(&[0x04, 0x01, 0x00, 0x09, 0x00, 0x00, 0x01, 0x00, 0xff]);
+        let mut reader = PacketReader::new(&bytes[..]);
+
+        let mut body: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+
+        assert_eq!(body, vec![0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert_eq!(reader.into_inner(), &[0x04, 0x01, 0x00, 0x09, 0x00, 0x00, 0x01, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn test_packetreader_fails_on_truncated_packet() {
+        let bytes: Vec<u8> = vec![0x04, 0x01, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x00, 0x01];
+        let mut reader = PacketReader::new(&bytes[..]);
+
+        let mut body: Vec<u8> = Vec::new();
+        assert!(reader.read_to_end(&mut body).is_err());
+    }
+
+    #[test]
+    fn test_packetwriter_sends_full_packets_then_finishes() {
+        let mut writer = PacketWriter::new(ClientMessageType::BulkLoad, MIN_PACKET_SIZE);
+
+        writer.write(&[0xab; 504]);
+        assert!(writer.full_packets().is_empty());
+        writer.write(&[0xcd; 600]);
+        let sent = writer.full_packets();
+        let rest = writer.finish();
+
+        assert_eq!(sent.len(), 2);
+        assert_eq!(&sent[0][..8], &[0x07, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00]);
+        assert_eq!(sent[1][6], 2);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(&rest[0][..8], &[0x07, 0x01, 0x00, 0x68, 0x00, 0x00, 0x03, 0x00]);
+    }
+
+    #[test]
+    fn test_packetwriter_abandon_sends_ignore() {
+        let mut writer = PacketWriter::new(ClientMessageType::BulkLoad, MIN_PACKET_SIZE);
+        writer.write(&[0x00; 1000]);
+        assert_eq!(writer.full_packets().len(), 1);
+
+        assert_eq!(writer.abandon(), Some(vec![0x07, 0x03, 0x00, 0x08, 0x00, 0x00, 0x02, 0x00]));
+        assert_eq!(PacketWriter::new(ClientMessageType::BulkLoad, MIN_PACKET_SIZE).abandon(), None);
+    }
+
+    #[test]
+    fn test_tdsmessage_from_stream_reassembles_packets() {
+        let mut message = TdsMessage::new();
+        message.set_body(ClientMessageType::SqlBatch, (0..1000).map(|i| i as u8).collect());
+        let bytes: Vec<u8> = message.to_packets(512).concat();
+        let mut reader: &[u8] = &bytes;
+
+        let received = TdsMessage::from_stream(&mut reader).unwrap();
+
+        assert_eq!(received.body(), message.body());
+        assert_eq!(received.header().packet_id(), 2);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_tdsmessage_from_stream_fails_on_mixed_packet_types() {
+        let packets: Vec<u8> = vec![
+            0x04, 0x00, 0x00, 0x09, 0x00, 0x00, 0x01, 0x00, 0xaa,
+            0x12, 0x01, 0x00, 0x09, 0x00, 0x00, 0x02, 0x00, 0xbb
+        ];
+        let mut reader: &[u8] = &packets;
+
+        assert!(TdsMessage::from_stream(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_tdsmessage_from_stream_fails_on_short_packet() {
+        let packet: Vec<u8> = vec![0x04, 0x01, 0x00, 0x10, 0x00, 0x00, 0x01, 0x00, 0xaa];
+        let mut reader: &[u8] = &packet;
+
+        let result = TdsMessage::from_stream(&mut reader);
+
+        assert!(result.is_err());
+    }
+
+    fn sample_prelogin_response() -> Vec<u8> {
+        vec![
+            0x00, 0x00, 0x1f, 0x00, 0x06, // VERSION
+            0x01, 0x00, 0x25, 0x00, 0x01, // ENCRYPTION
+            0x02, 0x00, 0x26, 0x00, 0x01, // INSTOPT
+            0x03, 0x00, 0x27, 0x00, 0x00, // THREADID (servers send it empty)
+            0x04, 0x00, 0x27, 0x00, 0x01, // MARS
+            0x06, 0x00, 0x28, 0x00, 0x01, // FEDAUTHREQUIRED
+            0xff,
+            0x10, 0x00, 0x07, 0xd0, 0x00, 0x01,
+            0x03,
+            0x00,
+            0x01,
+            0x00
+        ]
+    }
+
+    #[test]
+    fn test_preloginresponse_from_bytes_parses_options() {
+        let response = PreLoginResponse::from_bytes(&sample_prelogin_response()).unwrap();
+
+        assert_eq!(response.version, ServerVersion::new(16, 0, 2000, 1));
+        assert_eq!(response.encryption, EncryptionOptions::EncryptionEnabledRequested);
+        assert!(response.instance_valid);
+        assert_eq!(response.thread_id, None);
+        assert!(response.mars);
+        assert!(!response.fed_auth_required);
+        assert_eq!(response.nonce, None);
+    }
+
+    #[test]
+    fn test_preloginresponse_from_bytes_reads_thread_id_and_nonce() {
+        let mut body: Vec<u8> = vec![
+            0x00, 0x00, 0x10, 0x00, 0x06,
+            0x03, 0x00, 0x16, 0x00, 0x04,
+            0x07, 0x00, 0x1a, 0x00, 0x20,
+            0xff,
+            0x0f, 0x00, 0x10, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x12, 0x34
+        ];
+        body.extend_from_slice(&[0x5a; 32]);
+
+        let response = PreLoginResponse::from_bytes(&body).unwrap();
+
+        assert_eq!(response.version.major, 15);
+        assert_eq!(response.thread_id, Some(0x1234));
+        assert_eq!(response.nonce, Some([0x5a; 32]));
+    }
+
+    #[test]
+    fn test_preloginresponse_from_bytes_fails_without_terminator() {
+        let mut body = sample_prelogin_response();
+        body.truncate(30);
+
+        let result = PreLoginResponse::from_bytes(&body);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_preloginresponse_from_bytes_fails_on_bad_offset() {
+        let mut body = sample_prelogin_response();
+        body[2] = 0xf0;
+
+        let result = PreLoginResponse::from_bytes(&body);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_preloginresponse_from_bytes_fails_without_version() {
+        let body: Vec<u8> = vec![0x01, 0x00, 0x06, 0x00, 0x01, 0xff, 0x00];
+
+        let result = PreLoginResponse::from_bytes(&body);
+
+        assert!(result.is_err());
+    }
+}
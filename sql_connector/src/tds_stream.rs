@@ -0,0 +1,405 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use rustls::{ClientConfig, ClientConnection};
+use rustls_pki_types::ServerName;
+use crate::tds_message::{TdsMessage, TdsHeader, ClientMessageType, DEFAULT_PACKET_SIZE};
+use crate::smp::SmpSession;
+use crate::error::{Error, Result};
+
+/**
+ * The connection to the server, either plain TCP or TLS on top of it.
+ *
+ * While the TLS handshake is running its records are wrapped in PRELOGIN packets, once it
+ * finishes TLS runs directly on the socket and every TDS packet is encrypted whole.
+ *
+ * The TLS state is shared with any TdsWriters, so the lock is only held while records are
+ * being encrypted or decrypted, never while waiting on the socket.
+ *
+ * With MARS each stream is one SMP session, and several streams share the socket.
+ *
+ * The stream keeps track of whether a read or write that failed, e.g. by timing out, left
+ * it part way through a packet. Nothing else can safely be sent or read after that.
+ */
+pub struct TdsStream {
+    socket: TcpStream,
+    tls: Arc<Mutex<Option<ClientConnection>>>,
+    smp: Option<SmpSession>,
+    incoming: PacketProgress,
+    partial_write: bool
+}
+impl TdsStream {
+    pub fn new(socket: TcpStream) -> TdsStream {
+        TdsStream {
+            socket,
+            tls: Arc::new(Mutex::new(None)),
+            smp: None,
+            incoming: PacketProgress::new(),
+            partial_write: false
+        }
+    }
+
+    /**
+     * False when the last packet read only partly arrived, or a write only got part of
+     * its packet out.
+     */
+    pub fn at_packet_boundary(&self) -> bool {
+        self.incoming.at_boundary() && !self.partial_write
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        lock(&self.tls).is_some()
+    }
+
+    /**
+     * The read and write timeout. With MARS it's only this session's, otherwise the socket's.
+     */
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match &mut self.smp {
+            Some(smp) => {
+                smp.set_timeout(timeout);
+                Ok(())
+            },
+            None => self.socket.set_read_timeout(timeout).and_then(|_| self.socket.set_write_timeout(timeout))
+        }
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    pub fn into_socket(self) -> TcpStream {
+        self.socket
+    }
+
+    /**
+     * Another way to write to the connection, which can be moved to a different thread.
+     */
+    pub fn writer(&self) -> Result<TdsWriter> {
+        Ok(TdsWriter {
+            socket: self.clone_socket()?,
+            tls: Arc::clone(&self.tls),
+            smp: self.smp.as_ref().map(|smp| smp.handle())
+        })
+    }
+
+    /**
+     * Wraps everything from here on in SMP, once the server has agreed to MARS. This
+     * stream becomes the first session. SMP packets longer than `packet_size` allows are
+     * rejected.
+     */
+    pub fn start_mars(&mut self, packet_size: u32) -> Result<()> {
+        if self.smp.is_none() {
+            let smp: SmpSession = SmpSession::start(self.clone_socket()?, Arc::clone(&self.tls), packet_size)
+                .map_err(|e| Error::io("Failed to start MARS", e))?;
+            self.smp = Some(smp);
+        }
+        Ok(())
+    }
+
+    pub fn is_mars(&self) -> bool {
+        self.smp.is_some()
+    }
+
+    /**
+     * A new MARS session on the same connection. It's closed when the stream is dropped.
+     */
+    pub fn open_session(&self) -> Result<TdsStream> {
+        let smp: &SmpSession = self.smp.as_ref().ok_or(Error::Protocol(String::from("MARS isn't enabled on this connection")))?;
+
+        Ok(TdsStream {
+            socket: self.clone_socket()?,
+            tls: Arc::clone(&self.tls),
+            smp: Some(smp.open().map_err(|e| Error::io("Failed to open a MARS session", e))?),
+            incoming: PacketProgress::new(),
+            partial_write: false
+        })
+    }
+
+    fn clone_socket(&self) -> Result<TcpStream> {
+        self.socket.try_clone().map_err(|e| Error::io("Failed to clone the socket", e))
+    }
+
+    pub fn start_tls(&mut self, config: Arc<ClientConfig>, server_name: ServerName<'static>) -> Result<()> {
+        let mut connection = ClientConnection::new(config, server_name)
+            .map_err(|e| Error::Tls(format!("Failed to start TLS: {}", e)))?;
+
+        while connection.is_handshaking() {
+            if connection.wants_write() {
+                self.write_handshake(&mut connection)?;
+            } else {
+                let packet = TdsMessage::from_stream(&mut self.socket)?;
+                let mut records: &[u8] = packet.body();
+
+                while !records.is_empty() {
+                    connection.read_tls(&mut records).map_err(|e| Error::Tls(format!("TLS handshake failed: {}", e)))?;
+                    connection.process_new_packets().map_err(|e| Error::Tls(format!("TLS handshake failed: {}", e)))?;
+                }
+            }
+        }
+
+        // our last flight (e.g. Finished) is still part of the handshake so still gets wrapped
+        if connection.wants_write() {
+            self.write_handshake(&mut connection)?;
+        }
+
+        *lock(&self.tls) = Some(connection);
+        Ok(())
+    }
+
+    /**
+     * For login only encryption: TLS is dropped as soon as LOGIN7 has been sent.
+     */
+    pub fn stop_tls(&mut self) {
+        *lock(&self.tls) = None;
+    }
+
+    fn write_handshake(&mut self, connection: &mut ClientConnection) -> Result<()> {
+        let mut records: Vec<u8> = Vec::new();
+        while connection.wants_write() {
+            connection.write_tls(&mut records).map_err(|e| Error::Tls(format!("TLS handshake failed: {}", e)))?;
+        }
+
+        let mut message = TdsMessage::new();
+        message.set_body(ClientMessageType::PreLogin, records);
+
+        for packet in message.to_packets(DEFAULT_PACKET_SIZE) {
+            self.socket.write_all(&packet).map_err(|e| Error::io("Failed to write to stream", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for TdsStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read: usize = match &self.smp {
+            Some(smp) => smp.read(buffer)?,
+            None => read_raw(&self.socket, &self.tls, buffer)?
+        };
+
+        self.incoming.consume(&buffer[..read]);
+        Ok(read)
+    }
+}
+
+/**
+ * Each write is one whole packet.
+ */
+impl Write for TdsStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match &self.smp {
+            Some(smp) => smp.write(buffer)?,
+            None => {
+                let mut sent: usize = 0;
+                if let Err(error) = write_packet(&self.socket, &self.tls, buffer, &mut sent) {
+                    self.partial_write = sent > 0 && sent < buffer.len();
+                    return Err(error);
+                }
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+/**
+ * Follows the packets going through a stream by the lengths in their headers.
+ */
+pub(crate) struct PacketProgress {
+    header: [u8; 8],
+    header_read: usize,
+    body_remaining: usize
+}
+impl PacketProgress {
+    pub(crate) fn new() -> PacketProgress {
+        PacketProgress {
+            header: [0; 8],
+            header_read: 0,
+            body_remaining: 0
+        }
+    }
+
+    pub(crate) fn consume(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.body_remaining > 0 {
+                let length: usize = self.body_remaining.min(bytes.len());
+                self.body_remaining -= length;
+                bytes = &bytes[length..];
+                continue;
+            }
+
+            let length: usize = (self.header.len() - self.header_read).min(bytes.len());
+            self.header[self.header_read..self.header_read + length].copy_from_slice(&bytes[..length]);
+            self.header_read += length;
+            bytes = &bytes[length..];
+
+            if self.header_read == self.header.len() {
+                self.header_read = 0;
+                // a bad length fails the read of the packet itself
+                self.body_remaining = TdsHeader::from_byte_array(&self.header).body_length().unwrap_or(0);
+            }
+        }
+    }
+
+    pub(crate) fn at_boundary(&self) -> bool {
+        self.header_read == 0 && self.body_remaining == 0
+    }
+}
+
+/**
+ * Writes to a TdsStream's connection from somewhere else, e.g. to send ATTENTION from
+ * another thread while the stream's owner is waiting on a response. Each write_all goes
+ * out whole, it can't end up in the middle of one of the stream's own writes.
+ */
+pub struct TdsWriter {
+    socket: TcpStream,
+    tls: Arc<Mutex<Option<ClientConnection>>>,
+    smp: Option<SmpSession>
+}
+impl TdsWriter {
+    pub fn write_all(&self, buffer: &[u8]) -> Result<()> {
+        match &self.smp {
+            Some(smp) => smp.write(buffer),
+            None => write_all(&self.socket, &self.tls, buffer)
+        }.map_err(|e| Error::io("Failed to write to stream", e))
+    }
+}
+
+/**
+ * Reads from the socket, decrypting if TLS is on. The lock is only taken once there's
+ * something to decrypt.
+ */
+pub(crate) fn read_raw(mut socket: &TcpStream, tls: &Mutex<Option<ClientConnection>>, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut records: [u8; 4096] = [0; 4096];
+
+    loop {
+        {
+            let mut tls = lock(tls);
+            let Some(connection) = tls.as_mut() else {
+                drop(tls);
+                return socket.read(buffer);
+            };
+
+            match connection.reader().read(buffer) {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result
+            }
+        }
+
+        let read: usize = socket.read(&mut records)?;
+        if read == 0 {
+            return Ok(0);
+        }
+
+        let mut tls = lock(tls);
+        if let Some(connection) = tls.as_mut() {
+            let mut received: &[u8] = &records[..read];
+            while !received.is_empty() {
+                connection.read_tls(&mut received)?;
+                connection.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            // e.g. a key update the server asked for
+            write_records(connection, socket)?;
+        }
+    }
+}
+
+/**
+ * The lock is held for the whole write so writes from a TdsStream and its TdsWriters
+ * don't interleave.
+ */
+pub(crate) fn write_all(socket: &TcpStream, tls: &Mutex<Option<ClientConnection>>, buffer: &[u8]) -> io::Result<()> {
+    write_packet(socket, tls, buffer, &mut 0)
+}
+
+/**
+ * write_all, counting in `sent` how much of `buffer` is on its way. With TLS that's all of
+ * it once rustls has it, any records that don't get out go before the next write.
+ */
+pub(crate) fn write_packet(mut socket: &TcpStream, tls: &Mutex<Option<ClientConnection>>, buffer: &[u8], sent: &mut usize) -> io::Result<()> {
+    let mut tls = lock(tls);
+
+    if let Some(connection) = tls.as_mut() {
+        connection.writer().write_all(buffer)?;
+        *sent = buffer.len();
+        return write_records(connection, socket);
+    }
+
+    while *sent < buffer.len() {
+        match socket.write(&buffer[*sent..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => *sent += written,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error)
+        }
+    }
+    Ok(())
+}
+
+fn write_records(connection: &mut ClientConnection, mut socket: &TcpStream) -> io::Result<()> {
+    while connection.wants_write() {
+        connection.write_tls(&mut socket)?;
+    }
+    Ok(())
+}
+
+fn lock(tls: &Mutex<Option<ClientConnection>>) -> MutexGuard<'_, Option<ClientConnection>> {
+    // a panic part way through a write leaves the TLS state no worse than a failed write
+    tls.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::test_server::packet;
+
+    #[test]
+    fn test_tdswriter_write_all_never_lands_inside_a_stream_write() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream: TdsStream = TdsStream::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (mut server, _) = listener.accept().unwrap();
+        let writer: TdsWriter = stream.writer().unwrap();
+
+        // starts reading late so the socket fills up and the request packets only get out a
+        // bit at a time
+        let reader = thread::spawn(move || {
+            let mut types: Vec<u8> = Vec::new();
+            let mut header: [u8; 8] = [0; 8];
+
+            thread::sleep(Duration::from_millis(200));
+            while server.read_exact(&mut header).is_ok() {
+                let header: TdsHeader = TdsHeader::from_byte_array(&header);
+                let mut body: Vec<u8> = vec![0; header.body_length().unwrap()];
+                server.read_exact(&mut body).unwrap();
+                types.push(header.message_type());
+            }
+            types
+        });
+
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            for _ in 0..20 {
+                writer.write_all(&packet(0x06, &[])).unwrap();
+                thread::sleep(Duration::from_millis(2));
+            }
+        });
+
+        let request: Vec<u8> = packet(0x01, &[0x41; 32000]);
+        for _ in 0..500 {
+            stream.write_all(&request).unwrap();
+        }
+        canceller.join().unwrap();
+        drop(stream);
+
+        let types: Vec<u8> = reader.join().unwrap();
+        assert_eq!(types.iter().filter(|message_type| **message_type == 0x01).count(), 500);
+        assert_eq!(types.iter().filter(|message_type| **message_type == 0x06).count(), 20);
+    }
+}
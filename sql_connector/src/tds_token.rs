@@ -0,0 +1,892 @@
+use std::io::{self, Read};
+use crate::tds_message::{ServerVersion, MAX_RESERVE};
+use crate::tds_type::TypeInfo;
+use crate::sql_value::SqlValue;
+use crate::error::{Error, Result};
+
+/**
+ * Server to client token stream
+ *
+ * Everything the server sends back after PRELOGIN is a stream of tokens, each one starting
+ * with a single byte saying what it is. TokenStream reads them one at a time from anything
+ * that implements Read, so the same code works on a buffered packet body or a live stream.
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/7af53667-1b72-4703-8258-7984e838f746
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TdsToken {
+    LoginAck(LoginAck),
+    EnvChange(EnvChange),
+    Info(ServerMessage),
+    Error(ServerMessage),
+    Done(Done),
+    DoneProc(Done),
+    DoneInProc(Done),
+    Order(Vec<u16>),
+    ReturnStatus(i32),
+    FeatureExtAck(Vec<FeatureAck>),
+    ColMetadata(Vec<Column>),
+    Row(Row)
+}
+
+/**
+ * ROW tokens can only be read with the column types from the last COLMETADATA,
+ * so the stream keeps hold of them.
+ */
+pub struct TokenStream<R: Read> {
+    reader: R,
+    columns: Vec<Column>,
+    peeked: Option<u8>
+}
+impl<R: Read> TokenStream<R> {
+    pub fn new(reader: R) -> TokenStream<R> {
+        TokenStream {
+            reader,
+            columns: Vec::new(),
+            peeked: None
+        }
+    }
+
+    /**
+     * Reads the next token, or None once the stream has run out.
+     */
+    pub fn next_token(&mut self) -> Result<Option<TdsToken>> {
+        let Some(token_type) = self.peek_token_type()? else {
+            return Ok(None);
+        };
+        self.peeked = None;
+
+        let token = match TokenType::from_value(token_type) {
+            Some(TokenType::LoginAck) => TdsToken::LoginAck(LoginAck::read(&mut self.reader)?),
+            Some(TokenType::EnvChange) => TdsToken::EnvChange(EnvChange::read(&mut self.reader)?),
+            Some(TokenType::Info) => TdsToken::Info(ServerMessage::read(&mut self.reader)?),
+            Some(TokenType::Error) => TdsToken::Error(ServerMessage::read(&mut self.reader)?),
+            Some(TokenType::Done) => TdsToken::Done(Done::read(&mut self.reader)?),
+            Some(TokenType::DoneProc) => TdsToken::DoneProc(Done::read(&mut self.reader)?),
+            Some(TokenType::DoneInProc) => TdsToken::DoneInProc(Done::read(&mut self.reader)?),
+            Some(TokenType::Order) => {
+                let length = self.reader.read_u16_le()? as usize;
+                let mut columns: Vec<u16> = Vec::with_capacity(length / 2);
+                for _ in 0..length / 2 {
+                    columns.push(self.reader.read_u16_le()?);
+                }
+                TdsToken::Order(columns)
+            },
+            Some(TokenType::ReturnStatus) => TdsToken::ReturnStatus(self.reader.read_i32_le()?),
+            Some(TokenType::FeatureExtAck) => TdsToken::FeatureExtAck(FeatureAck::read_all(&mut self.reader)?),
+            Some(TokenType::ColMetadata) => {
+                self.columns = Column::read_all(&mut self.reader)?;
+                TdsToken::ColMetadata(self.columns.clone())
+            },
+            Some(TokenType::Row) => TdsToken::Row(Row::read(&mut self.reader, &self.columns)?),
+            Some(TokenType::NbcRow) => TdsToken::Row(Row::read_nbc(&mut self.reader, &self.columns)?),
+            None => return Err(Error::Protocol(format!("Unsupported token 0x{:02X}", token_type)))
+        };
+
+        Ok(Some(token))
+    }
+
+    /**
+     * The type byte of the next token without reading the token, None at the end of the stream.
+     */
+    pub fn peek_token_type(&mut self) -> Result<Option<u8>> {
+        if self.peeked.is_none() {
+            let mut token_type = [0u8; 1];
+            let read = self.reader.read(&mut token_type).map_err(|e| read_error("Failed to read token", e))?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.peeked = Some(token_type[0]);
+        }
+
+        Ok(self.peeked)
+    }
+
+    /**
+     * Starts on a ROW or NBCROW without reading its values, for rows with values too big to
+     * hold. Gives back which columns are NULL with no value in the row; the others are then
+     * read in column order from reader_mut. None, with nothing read, when the next token
+     * isn't a row.
+     */
+    pub fn start_row(&mut self) -> Result<Option<Vec<bool>>> {
+        let nbc: bool = match self.peek_token_type()?.and_then(TokenType::from_value) {
+            Some(TokenType::Row) => false,
+            Some(TokenType::NbcRow) => true,
+            _ => return Ok(None)
+        };
+
+        if self.columns.is_empty() {
+            return Err(Error::Protocol(String::from("Row received before COLMETADATA")));
+        }
+        self.peeked = None;
+
+        if nbc {
+            Ok(Some(Row::read_null_bitmap(&mut self.reader, self.columns.len())?))
+        } else {
+            Ok(Some(vec![false; self.columns.len()]))
+        }
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /**
+     * The reader under the tokens, for reading the values of a row begun with start_row.
+     */
+    pub fn reader_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/**
+ * Reads every token in a buffered message body.
+ */
+pub fn parse_tokens(body: &[u8]) -> Result<Vec<TdsToken>> {
+    let mut stream = TokenStream::new(body);
+    let mut tokens: Vec<TdsToken> = Vec::new();
+
+    while let Some(token) = stream.next_token()? {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginAck {
+    pub interface: u8,
+    pub tds_version: u32,
+    pub program_name: String,
+    pub version: ServerVersion
+}
+impl LoginAck {
+    fn read<R: Read>(reader: &mut R) -> Result<LoginAck> {
+        let length = reader.read_u16_le()? as usize;
+        let data = reader.read_bytes(length)?;
+        let mut data: &[u8] = &data;
+
+        let interface = data.read_u8()?;
+        // the one place TDS uses big endian inside a token
+        let tds_version = u32::from_be_bytes(data.read_fixed::<4>()?);
+        let program_name = data.read_b_varchar()?;
+        let version = data.read_fixed::<4>()?;
+
+        Ok(LoginAck {
+            interface,
+            tds_version,
+            program_name,
+            version: ServerVersion::new(version[0], version[1], u16::from_be_bytes([version[2], version[3]]), 0)
+        })
+    }
+}
+
+/**
+ * INFO and ERROR tokens share a layout.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerMessage {
+    pub number: i32,
+    pub state: u8,
+    pub class: u8,
+    pub message: String,
+    pub server: String,
+    pub procedure: String,
+    pub line: i32
+}
+impl ServerMessage {
+    fn read<R: Read>(reader: &mut R) -> Result<ServerMessage> {
+        let length = reader.read_u16_le()? as usize;
+        let data = reader.read_bytes(length)?;
+        let mut data: &[u8] = &data;
+
+        Ok(ServerMessage {
+            number: data.read_i32_le()?,
+            state: data.read_u8()?,
+            class: data.read_u8()?,
+            message: data.read_us_varchar()?,
+            server: data.read_b_varchar()?,
+            procedure: data.read_b_varchar()?,
+            line: data.read_i32_le()?
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvChange {
+    Database { new: String, old: String },
+    Language { new: String, old: String },
+    CharacterSet { new: String, old: String },
+    PacketSize { new: u32, old: u32 },
+    SqlCollation { new: Vec<u8>, old: Vec<u8> },
+    BeginTransaction { descriptor: u64 },
+    CommitTransaction { descriptor: u64 },
+    RollbackTransaction { descriptor: u64 },
+    ResetConnectionAck,
+    Routing { port: u16, server: String },
+    Other { change_type: u8, data: Vec<u8> }
+}
+impl EnvChange {
+    fn read<R: Read>(reader: &mut R) -> Result<EnvChange> {
+        let length = reader.read_u16_le()? as usize;
+        let data = reader.read_bytes(length)?;
+        let mut data: &[u8] = &data;
+
+        let change_type = data.read_u8()?;
+
+        let change = match EnvChangeType::from_value(change_type) {
+            Some(EnvChangeType::Database) => EnvChange::Database {
+                new: data.read_b_varchar()?,
+                old: data.read_b_varchar()?
+            },
+            Some(EnvChangeType::Language) => EnvChange::Language {
+                new: data.read_b_varchar()?,
+                old: data.read_b_varchar()?
+            },
+            Some(EnvChangeType::CharacterSet) => EnvChange::CharacterSet {
+                new: data.read_b_varchar()?,
+                old: data.read_b_varchar()?
+            },
+            Some(EnvChangeType::PacketSize) => {
+                // sent as text, e.g. "4096"
+                let new = data.read_b_varchar()?;
+                let old = data.read_b_varchar()?;
+                EnvChange::PacketSize {
+                    new: new.parse().map_err(|_| Error::Protocol(format!("Invalid packet size '{}'", new)))?,
+                    old: old.parse().unwrap_or(0)
+                }
+            },
+            Some(EnvChangeType::SqlCollation) => EnvChange::SqlCollation {
+                new: data.read_b_varbyte()?,
+                old: data.read_b_varbyte()?
+            },
+            Some(EnvChangeType::BeginTransaction) => EnvChange::BeginTransaction {
+                descriptor: EnvChange::read_descriptor(data.read_b_varbyte()?)?
+            },
+            Some(EnvChangeType::CommitTransaction) => {
+                let _ = data.read_b_varbyte()?;
+                EnvChange::CommitTransaction {
+                    descriptor: EnvChange::read_descriptor(data.read_b_varbyte()?)?
+                }
+            },
+            Some(EnvChangeType::RollbackTransaction) => {
+                let _ = data.read_b_varbyte()?;
+                EnvChange::RollbackTransaction {
+                    descriptor: EnvChange::read_descriptor(data.read_b_varbyte()?)?
+                }
+            },
+            Some(EnvChangeType::ResetConnectionAck) => EnvChange::ResetConnectionAck,
+            Some(EnvChangeType::Routing) => {
+                // routing data length, protocol (always TCP), port, server name
+                let _ = data.read_u16_le()?;
+                let _ = data.read_u8()?;
+                let port = data.read_u16_le()?;
+                let server = data.read_us_varchar()?;
+                EnvChange::Routing {
+                    port,
+                    server
+                }
+            },
+            None => EnvChange::Other {
+                change_type,
+                data: data.to_vec()
+            }
+        };
+
+        Ok(change)
+    }
+
+    fn read_descriptor(value: Vec<u8>) -> Result<u64> {
+        if value.is_empty() {
+            return Ok(0);
+        }
+
+        let descriptor: [u8; 8] = value.try_into()
+            .map_err(|_| Error::Protocol(String::from("Transaction descriptor should be 8 bytes")))?;
+        Ok(u64::from_le_bytes(descriptor))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Done {
+    pub status: u16,
+    pub current_command: u16,
+    pub row_count: u64
+}
+impl Done {
+    fn read<R: Read>(reader: &mut R) -> Result<Done> {
+        Ok(Done {
+            status: reader.read_u16_le()?,
+            current_command: reader.read_u16_le()?,
+            row_count: reader.read_u64_le()?
+        })
+    }
+
+    pub fn has_status(&self, status: DoneStatus) -> bool {
+        self.status & status.value() != 0
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.has_status(DoneStatus::More)
+    }
+
+    /**
+     * row_count only means something when the server says so.
+     */
+    pub fn rows_affected(&self) -> Option<u64> {
+        if self.has_status(DoneStatus::Count) {
+            Some(self.row_count)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub user_type: u32,
+    pub flags: u16,
+    pub type_info: TypeInfo
+}
+impl Column {
+    /**
+     * COLMETADATA: column count, then for each column its user type, flags, TYPE_INFO
+     * and name. A count of 0xffff means "no metadata".
+     */
+    fn read_all<R: Read>(reader: &mut R) -> Result<Vec<Column>> {
+        let count = reader.read_u16_le()?;
+        if count == 0xffff {
+            return Ok(Vec::new());
+        }
+
+        let mut columns: Vec<Column> = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let user_type = reader.read_u32_le()?;
+            let flags = reader.read_u16_le()?;
+            let type_info = TypeInfo::read(reader)?;
+            let name = reader.read_b_varchar()?;
+
+            columns.push(Column {
+                name,
+                user_type,
+                flags,
+                type_info
+            });
+        }
+
+        Ok(columns)
+    }
+
+    pub fn is_nullable(&self) -> bool {
+        self.flags & 0x0001 != 0
+    }
+}
+
+/**
+ * One row of decoded column values, SqlValue::Null where the column was NULL.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub values: Vec<SqlValue>
+}
+impl Row {
+    fn read<R: Read>(reader: &mut R, columns: &[Column]) -> Result<Row> {
+        if columns.is_empty() {
+            return Err(Error::Protocol(String::from("ROW token received before COLMETADATA")));
+        }
+
+        let mut values: Vec<SqlValue> = Vec::with_capacity(columns.len());
+        for column in columns {
+            values.push(SqlValue::read(&column.type_info, reader)?);
+        }
+
+        Ok(Row {
+            values
+        })
+    }
+
+    /**
+     * NBCROW leads with a bitmap of which columns are NULL and leaves those out of the row.
+     */
+    fn read_nbc<R: Read>(reader: &mut R, columns: &[Column]) -> Result<Row> {
+        if columns.is_empty() {
+            return Err(Error::Protocol(String::from("NBCROW token received before COLMETADATA")));
+        }
+
+        let nulls: Vec<bool> = Row::read_null_bitmap(reader, columns.len())?;
+
+        let mut values: Vec<SqlValue> = Vec::with_capacity(columns.len());
+        for (column, null) in columns.iter().zip(nulls) {
+            if null {
+                values.push(SqlValue::Null);
+            } else {
+                values.push(SqlValue::read(&column.type_info, reader)?);
+            }
+        }
+
+        Ok(Row {
+            values
+        })
+    }
+
+    /**
+     * The bitmap NBCROW leads with, a bit per column set where the column is NULL.
+     */
+    fn read_null_bitmap<R: Read>(reader: &mut R, column_count: usize) -> Result<Vec<bool>> {
+        let bitmap = reader.read_bytes(column_count.div_ceil(8))?;
+
+        Ok((0..column_count).map(|index| bitmap[index / 8] & (1 << (index % 8)) != 0).collect())
+    }
+
+    pub fn get(&self, index: usize) -> Option<&SqlValue> {
+        self.values.get(index)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureAck {
+    pub feature_id: u8,
+    pub data: Vec<u8>
+}
+impl FeatureAck {
+    fn read_all<R: Read>(reader: &mut R) -> Result<Vec<FeatureAck>> {
+        let mut features: Vec<FeatureAck> = Vec::new();
+
+        loop {
+            let feature_id = reader.read_u8()?;
+            if feature_id == 0xff {
+                break;
+            }
+
+            let length = reader.read_u32_le()? as usize;
+            features.push(FeatureAck {
+                feature_id,
+                data: reader.read_bytes(length)?
+            });
+        }
+
+        Ok(features)
+    }
+}
+
+pub enum TokenType {
+    LoginAck,
+    EnvChange,
+    Info,
+    Error,
+    Done,
+    DoneProc,
+    DoneInProc,
+    Order,
+    ReturnStatus,
+    FeatureExtAck,
+    ColMetadata,
+    Row,
+    NbcRow
+}
+impl TokenType {
+    pub fn value(&self) -> u8 {
+        match self {
+            TokenType::LoginAck => 0xad,
+            TokenType::EnvChange => 0xe3,
+            TokenType::Info => 0xab,
+            TokenType::Error => 0xaa,
+            TokenType::Done => 0xfd,
+            TokenType::DoneProc => 0xfe,
+            TokenType::DoneInProc => 0xff,
+            TokenType::Order => 0xa9,
+            TokenType::ReturnStatus => 0x79,
+            TokenType::FeatureExtAck => 0xae,
+            TokenType::ColMetadata => 0x81,
+            TokenType::Row => 0xd1,
+            TokenType::NbcRow => 0xd2
+        }
+    }
+
+    fn from_value(value: u8) -> Option<TokenType> {
+        match value {
+            0xad => Some(TokenType::LoginAck),
+            0xe3 => Some(TokenType::EnvChange),
+            0xab => Some(TokenType::Info),
+            0xaa => Some(TokenType::Error),
+            0xfd => Some(TokenType::Done),
+            0xfe => Some(TokenType::DoneProc),
+            0xff => Some(TokenType::DoneInProc),
+            0xa9 => Some(TokenType::Order),
+            0x79 => Some(TokenType::ReturnStatus),
+            0xae => Some(TokenType::FeatureExtAck),
+            0x81 => Some(TokenType::ColMetadata),
+            0xd1 => Some(TokenType::Row),
+            0xd2 => Some(TokenType::NbcRow),
+            _ => None
+        }
+    }
+}
+
+pub enum EnvChangeType {
+    Database,
+    Language,
+    CharacterSet,
+    PacketSize,
+    SqlCollation,
+    BeginTransaction,
+    CommitTransaction,
+    RollbackTransaction,
+    ResetConnectionAck,
+    Routing
+}
+impl EnvChangeType {
+    pub fn value(&self) -> u8 {
+        match self {
+            EnvChangeType::Database => 0x01,
+            EnvChangeType::Language => 0x02,
+            EnvChangeType::CharacterSet => 0x03,
+            EnvChangeType::PacketSize => 0x04,
+            EnvChangeType::SqlCollation => 0x07,
+            EnvChangeType::BeginTransaction => 0x08,
+            EnvChangeType::CommitTransaction => 0x09,
+            EnvChangeType::RollbackTransaction => 0x0a,
+            EnvChangeType::ResetConnectionAck => 0x12,
+            EnvChangeType::Routing => 0x14
+        }
+    }
+
+    fn from_value(value: u8) -> Option<EnvChangeType> {
+        match value {
+            0x01 => Some(EnvChangeType::Database),
+            0x02 => Some(EnvChangeType::Language),
+            0x03 => Some(EnvChangeType::CharacterSet),
+            0x04 => Some(EnvChangeType::PacketSize),
+            0x07 => Some(EnvChangeType::SqlCollation),
+            0x08 => Some(EnvChangeType::BeginTransaction),
+            0x09 => Some(EnvChangeType::CommitTransaction),
+            0x0a => Some(EnvChangeType::RollbackTransaction),
+            0x12 => Some(EnvChangeType::ResetConnectionAck),
+            0x14 => Some(EnvChangeType::Routing),
+            _ => None
+        }
+    }
+}
+
+pub enum DoneStatus {
+    Final,
+    More,
+    Error,
+    InTransaction,
+    Count,
+    Attention,
+    ServerError
+}
+impl DoneStatus {
+    pub fn value(&self) -> u16 {
+        match self {
+            DoneStatus::Final => 0x0000,
+            DoneStatus::More => 0x0001,
+            DoneStatus::Error => 0x0002,
+            DoneStatus::InTransaction => 0x0004,
+            DoneStatus::Count => 0x0010,
+            DoneStatus::Attention => 0x0020,
+            DoneStatus::ServerError => 0x0100
+        }
+    }
+}
+
+/**
+ * Running out of data part way through a token is a protocol error. A read that timed out
+ * on a live stream stays an IO error though, so it can be told apart.
+ */
+pub(crate) fn read_error(context: &str, error: io::Error) -> Error {
+    match error.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::io(context, error),
+        _ => Error::Protocol(format!("{}: {}", context, error))
+    }
+}
+
+/**
+ * Little endian helpers plus the TDS string types on top of Read.
+ *
+ * B_VARCHAR has a one byte length and US_VARCHAR a two byte length, both counted in
+ * UCS-2 characters. B_VARBYTE has a one byte length counted in bytes.
+ */
+pub trait TdsRead: Read {
+    fn read_fixed<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buffer = [0u8; N];
+        self.read_exact(&mut buffer).map_err(|e| read_error("Failed to read token data", e))?;
+        Ok(buffer)
+    }
+
+    /**
+     * The buffer grows as the data arrives, so a bad length runs out of data rather than
+     * allocating whatever it says.
+     */
+    fn read_bytes(&mut self, length: usize) -> Result<Vec<u8>> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(length.min(MAX_RESERVE));
+        Read::take(self, length as u64).read_to_end(&mut buffer).map_err(|e| read_error("Failed to read token data", e))?;
+
+        if buffer.len() < length {
+            return Err(read_error("Failed to read token data", io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(buffer)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_fixed::<1>()?[0])
+    }
+
+    fn read_u16_le(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.read_fixed()?))
+    }
+
+    fn read_u32_le(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_fixed()?))
+    }
+
+    fn read_i32_le(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.read_fixed()?))
+    }
+
+    fn read_u64_le(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_fixed()?))
+    }
+
+    fn read_ucs2(&mut self, characters: usize) -> Result<String> {
+        let bytes = self.read_bytes(characters * 2)?;
+        let units: Vec<u16> = bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect();
+        String::from_utf16(&units).map_err(|e| Error::Protocol(format!("Invalid UCS-2 string: {}", e)))
+    }
+
+    fn read_b_varchar(&mut self) -> Result<String> {
+        let length = self.read_u8()? as usize;
+        self.read_ucs2(length)
+    }
+
+    fn read_us_varchar(&mut self) -> Result<String> {
+        let length = self.read_u16_le()? as usize;
+        self.read_ucs2(length)
+    }
+
+    fn read_b_varbyte(&mut self) -> Result<Vec<u8>> {
+        let length = self.read_u8()? as usize;
+        self.read_bytes(length)
+    }
+}
+impl<R: Read + ?Sized> TdsRead for R {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tds_message::ucs2_bytes;
+
+    #[test]
+    fn test_tdsread_read_bytes_does_not_trust_length() {
+        let mut bytes: &[u8] = &[0xab, 0xcd];
+
+        assert!(matches!(bytes.read_bytes(usize::MAX), Err(Error::Protocol(_))));
+        assert_eq!((&[0xab, 0xcd][..]).read_bytes(2).unwrap(), vec![0xab, 0xcd]);
+    }
+
+    fn with_length(token: u8, data: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![token];
+        bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn b_varchar(value: &str) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![value.encode_utf16().count() as u8];
+        bytes.extend_from_slice(&ucs2_bytes(value));
+        bytes
+    }
+
+    fn server_message(number: i32, message: &str) -> Vec<u8> {
+        let mut data: Vec<u8> = number.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0x01, 0x0e]);
+        data.extend_from_slice(&(message.encode_utf16().count() as u16).to_le_bytes());
+        data.extend_from_slice(&ucs2_bytes(message));
+        data.extend_from_slice(&b_varchar("sqlbox"));
+        data.extend_from_slice(&b_varchar("proc"));
+        data.extend_from_slice(&7i32.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_reads_loginack() {
+        let mut data: Vec<u8> = vec![0x01, 0x74, 0x00, 0x00, 0x04];
+        data.extend_from_slice(&b_varchar("Microsoft SQL Server"));
+        data.extend_from_slice(&[0x10, 0x00, 0x07, 0xd0]);
+
+        let tokens = parse_tokens(&with_length(0xad, &data)).unwrap();
+
+        assert_eq!(tokens, vec![TdsToken::LoginAck(LoginAck {
+            interface: 1,
+            tds_version: 0x74000004,
+            program_name: String::from("Microsoft SQL Server"),
+            version: ServerVersion::new(16, 0, 2000, 0)
+        })]);
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_reads_envchange() {
+        let mut database: Vec<u8> = vec![0x01];
+        database.extend_from_slice(&b_varchar("sample"));
+        database.extend_from_slice(&b_varchar("master"));
+
+        let mut packet_size: Vec<u8> = vec![0x04];
+        packet_size.extend_from_slice(&b_varchar("8000"));
+        packet_size.extend_from_slice(&b_varchar("4096"));
+
+        let collation: Vec<u8> = vec![0x07, 0x05, 0x09, 0x04, 0xd0, 0x00, 0x34, 0x00];
+
+        let begin: Vec<u8> = vec![0x08, 0x08, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        let mut bytes = with_length(0xe3, &database);
+        bytes.extend_from_slice(&with_length(0xe3, &packet_size));
+        bytes.extend_from_slice(&with_length(0xe3, &collation));
+        bytes.extend_from_slice(&with_length(0xe3, &begin));
+        bytes.extend_from_slice(&with_length(0xe3, &[0x13, 0x00, 0x00]));
+
+        let tokens = parse_tokens(&bytes).unwrap();
+
+        assert_eq!(tokens[0], TdsToken::EnvChange(EnvChange::Database {
+            new: String::from("sample"),
+            old: String::from("master")
+        }));
+        assert_eq!(tokens[1], TdsToken::EnvChange(EnvChange::PacketSize { new: 8000, old: 4096 }));
+        assert_eq!(tokens[2], TdsToken::EnvChange(EnvChange::SqlCollation {
+            new: vec![0x09, 0x04, 0xd0, 0x00, 0x34],
+            old: Vec::new()
+        }));
+        assert_eq!(tokens[3], TdsToken::EnvChange(EnvChange::BeginTransaction { descriptor: 1 }));
+        assert_eq!(tokens[4], TdsToken::EnvChange(EnvChange::Other { change_type: 0x13, data: vec![0x00, 0x00] }));
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_reads_info_and_error() {
+        let mut bytes = with_length(0xab, &server_message(5701, "Changed database context to 'sample'."));
+        bytes.extend_from_slice(&with_length(0xaa, &server_message(208, "Invalid object name 'nope'.")));
+
+        let tokens = parse_tokens(&bytes).unwrap();
+
+        match &tokens[0] {
+            TdsToken::Info(info) => {
+                assert_eq!(info.number, 5701);
+                assert_eq!(info.message, "Changed database context to 'sample'.");
+            },
+            other => panic!("expected INFO, got {:?}", other)
+        }
+        match &tokens[1] {
+            TdsToken::Error(error) => {
+                assert_eq!(error.number, 208);
+                assert_eq!(error.state, 1);
+                assert_eq!(error.class, 14);
+                assert_eq!(error.server, "sqlbox");
+                assert_eq!(error.procedure, "proc");
+                assert_eq!(error.line, 7);
+            },
+            other => panic!("expected ERROR, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_reads_done_variants() {
+        let mut bytes: Vec<u8> = vec![0xff, 0x11, 0x00, 0xc1, 0x00, 0x03, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend_from_slice(&[0x79, 0x00, 0x00, 0x00, 0x00]);
+        bytes.extend_from_slice(&[0xfe, 0x00, 0x00, 0xe0, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&[0xfd, 0x20, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let tokens = parse_tokens(&bytes).unwrap();
+
+        match tokens[0] {
+            TdsToken::DoneInProc(done) => {
+                assert!(done.has_more());
+                assert_eq!(done.rows_affected(), Some(3));
+            },
+            ref other => panic!("expected DONEINPROC, got {:?}", other)
+        }
+        assert_eq!(tokens[1], TdsToken::ReturnStatus(0));
+        match tokens[2] {
+            TdsToken::DoneProc(done) => assert_eq!(done.rows_affected(), None),
+            ref other => panic!("expected DONEPROC, got {:?}", other)
+        }
+        match tokens[3] {
+            TdsToken::Done(done) => assert!(done.has_status(DoneStatus::Attention)),
+            ref other => panic!("expected DONE, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_reads_order_and_featureextack() {
+        let mut bytes: Vec<u8> = vec![0xa9, 0x04, 0x00, 0x01, 0x00, 0x03, 0x00];
+        bytes.extend_from_slice(&[0xae, 0x0a, 0x01, 0x00, 0x00, 0x00, 0x01, 0xff]);
+
+        let tokens = parse_tokens(&bytes).unwrap();
+
+        assert_eq!(tokens[0], TdsToken::Order(vec![1, 3]));
+        assert_eq!(tokens[1], TdsToken::FeatureExtAck(vec![FeatureAck { feature_id: 0x0a, data: vec![0x01] }]));
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_fails_on_unknown_token() {
+        let result = parse_tokens(&[0x42, 0x00]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_fails_on_truncated_token() {
+        let result = parse_tokens(&[0xfd, 0x00, 0x00]);
+
+        assert!(result.is_err());
+    }
+
+    fn two_column_metadata() -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![0x81, 0x02, 0x00];
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x38]);
+        bytes.extend_from_slice(&b_varchar("id"));
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0xe7, 0x64, 0x00, 0x09, 0x04, 0xd0, 0x00, 0x34]);
+        bytes.extend_from_slice(&b_varchar("name"));
+        bytes
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_reads_colmetadata_and_rows() {
+        let mut bytes = two_column_metadata();
+        bytes.extend_from_slice(&[0xd1, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00]);
+        bytes.extend_from_slice(&ucs2_bytes("ab"));
+        bytes.extend_from_slice(&[0xd2, 0x02, 0x02, 0x00, 0x00, 0x00]);
+
+        let tokens = parse_tokens(&bytes).unwrap();
+
+        match &tokens[0] {
+            TdsToken::ColMetadata(columns) => {
+                assert_eq!(columns.len(), 2);
+                assert_eq!(columns[0].name, "id");
+                assert!(!columns[0].is_nullable());
+                assert_eq!(columns[1].name, "name");
+                assert!(columns[1].is_nullable());
+            },
+            other => panic!("expected COLMETADATA, got {:?}", other)
+        }
+        assert_eq!(tokens[1], TdsToken::Row(Row { values: vec![SqlValue::Int(1), SqlValue::String(String::from("ab"))] }));
+        assert_eq!(tokens[2], TdsToken::Row(Row { values: vec![SqlValue::Int(2), SqlValue::Null] }));
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_reads_empty_colmetadata() {
+        let tokens = parse_tokens(&[0x81, 0xff, 0xff]).unwrap();
+
+        assert_eq!(tokens, vec![TdsToken::ColMetadata(Vec::new())]);
+    }
+
+    #[test]
+    fn test_tokenstream_next_token_fails_on_row_without_metadata() {
+        let result = parse_tokens(&[0xd1, 0x01, 0x00, 0x00, 0x00]);
+
+        assert!(result.is_err());
+    }
+}
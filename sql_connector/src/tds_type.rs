@@ -0,0 +1,751 @@
+use std::io::Read;
+use crate::tds_token::TdsRead;
+use crate::tds_message::{b_varchar, us_varchar, read_plp};
+use crate::error::{Error, Result};
+
+/**
+ * TYPE_INFO
+ *
+ * Every column in COLMETADATA describes its type with a TYPE_INFO. How the type's values
+ * are framed in a ROW depends on which family the type belongs to:
+ *
+ * - fixed length: no length on the wire at all
+ * - BYTELEN / USHORTLEN / LONGLEN: a 1, 2 or 4 byte length ahead of each value
+ * - PLP: the (max) types, sent as a total length followed by chunks
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/cbe9c510-eae6-4b1f-9893-a098944d430a
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeInfo {
+    pub data_type: DataType,
+    pub length: u32,
+    pub precision: u8,
+    pub scale: u8,
+    pub collation: Option<Collation>,
+    pub table_name: Vec<String>,
+    pub xml_schema: Option<XmlSchema>,
+    pub udt: Option<UdtInfo>
+}
+impl TypeInfo {
+    pub fn new(data_type: DataType) -> TypeInfo {
+        TypeInfo {
+            length: data_type.fixed_length().unwrap_or(0) as u32,
+            data_type,
+            precision: 0,
+            scale: 0,
+            collation: None,
+            table_name: Vec::new(),
+            xml_schema: None,
+            udt: None
+        }
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<TypeInfo> {
+        let type_byte = reader.read_u8()?;
+        let data_type = DataType::from_value(type_byte)
+            .ok_or(Error::Protocol(format!("Unsupported data type 0x{:02X}", type_byte)))?;
+        let mut info = TypeInfo::new(data_type);
+
+        match data_type.length_kind() {
+            LengthKind::Fixed => (),
+            LengthKind::ByteLen => {
+                match data_type {
+                    // these carry the scale instead of a length
+                    DataType::DateN => (),
+                    DataType::TimeN | DataType::DateTime2N | DataType::DateTimeOffsetN => {
+                        info.scale = reader.read_u8()?;
+                    },
+                    _ => {
+                        info.length = reader.read_u8()? as u32;
+                    }
+                }
+
+                if data_type.has_precision() {
+                    info.precision = reader.read_u8()?;
+                    info.scale = reader.read_u8()?;
+                }
+            },
+            LengthKind::UShortLen => {
+                info.length = reader.read_u16_le()? as u32;
+                if data_type.has_collation() {
+                    info.collation = Some(Collation::read(reader)?);
+                }
+            },
+            LengthKind::LongLen => {
+                info.length = reader.read_u32_le()?;
+                if data_type.has_collation() {
+                    info.collation = Some(Collation::read(reader)?);
+                }
+
+                if data_type != DataType::SqlVariant {
+                    let parts = reader.read_u8()?;
+                    for _ in 0..parts {
+                        info.table_name.push(reader.read_us_varchar()?);
+                    }
+                }
+            },
+            LengthKind::Plp => {
+                match data_type {
+                    DataType::Xml => {
+                        if reader.read_u8()? == 0x01 {
+                            info.xml_schema = Some(XmlSchema {
+                                database: reader.read_b_varchar()?,
+                                owning_schema: reader.read_b_varchar()?,
+                                collection: reader.read_us_varchar()?
+                            });
+                        }
+                    },
+                    _ => {
+                        info.length = reader.read_u16_le()? as u32;
+                        info.udt = Some(UdtInfo {
+                            database: reader.read_b_varchar()?,
+                            schema: reader.read_b_varchar()?,
+                            type_name: reader.read_b_varchar()?,
+                            assembly_name: reader.read_us_varchar()?
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(info)
+    }
+
+    /**
+     * TYPE_INFO as it's written in a COLMETADATA we send, the reverse of read.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![self.data_type.value()];
+
+        match self.data_type.length_kind() {
+            LengthKind::Fixed => (),
+            LengthKind::ByteLen => {
+                match self.data_type {
+                    DataType::DateN => (),
+                    DataType::TimeN | DataType::DateTime2N | DataType::DateTimeOffsetN => bytes.push(self.scale),
+                    _ => bytes.push(self.length as u8)
+                }
+
+                if self.data_type.has_precision() {
+                    bytes.push(self.precision);
+                    bytes.push(self.scale);
+                }
+            },
+            LengthKind::UShortLen => {
+                bytes.extend_from_slice(&(self.length as u16).to_le_bytes());
+                if let Some(collation) = self.collation {
+                    bytes.extend_from_slice(&collation.to_bytes());
+                }
+            },
+            LengthKind::LongLen => {
+                bytes.extend_from_slice(&self.length.to_le_bytes());
+                if let Some(collation) = self.collation {
+                    bytes.extend_from_slice(&collation.to_bytes());
+                }
+
+                if self.data_type != DataType::SqlVariant {
+                    bytes.push(self.table_name.len() as u8);
+                    for part in &self.table_name {
+                        bytes.extend_from_slice(&us_varchar(part));
+                    }
+                }
+            },
+            LengthKind::Plp => {
+                match (&self.xml_schema, &self.udt) {
+                    (Some(schema), _) => {
+                        bytes.push(0x01);
+                        bytes.extend_from_slice(&b_varchar(&schema.database));
+                        bytes.extend_from_slice(&b_varchar(&schema.owning_schema));
+                        bytes.extend_from_slice(&us_varchar(&schema.collection));
+                    },
+                    (None, Some(udt)) => {
+                        bytes.extend_from_slice(&(self.length as u16).to_le_bytes());
+                        bytes.extend_from_slice(&b_varchar(&udt.database));
+                        bytes.extend_from_slice(&b_varchar(&udt.schema));
+                        bytes.extend_from_slice(&b_varchar(&udt.type_name));
+                        bytes.extend_from_slice(&us_varchar(&udt.assembly_name));
+                    },
+                    (None, None) => bytes.push(0x00)
+                }
+            }
+        }
+
+        bytes
+    }
+
+    /**
+     * The type as it's written in T-SQL, e.g. nvarchar(50) or decimal(18, 4). The nullable
+     * types go by their length, so an INTN of length 2 is a smallint.
+     */
+    pub fn declaration(&self) -> String {
+        let max_or = |length: u32| if length == 0xffff { String::from("max") } else { length.to_string() };
+
+        match self.data_type {
+            DataType::Int1 => String::from("tinyint"),
+            DataType::Int2 => String::from("smallint"),
+            DataType::Int4 => String::from("int"),
+            DataType::Int8 => String::from("bigint"),
+            DataType::IntN => String::from(match self.length {
+                1 => "tinyint",
+                2 => "smallint",
+                4 => "int",
+                _ => "bigint"
+            }),
+            DataType::Bit | DataType::BitN => String::from("bit"),
+            DataType::Flt4 => String::from("real"),
+            DataType::Flt8 => String::from("float"),
+            DataType::FltN => String::from(if self.length == 4 { "real" } else { "float" }),
+            DataType::Money4 => String::from("smallmoney"),
+            DataType::Money => String::from("money"),
+            DataType::MoneyN => String::from(if self.length == 4 { "smallmoney" } else { "money" }),
+            DataType::DateTime4 => String::from("smalldatetime"),
+            DataType::DateTime => String::from("datetime"),
+            DataType::DateTimeN => String::from(if self.length == 4 { "smalldatetime" } else { "datetime" }),
+            DataType::Decimal | DataType::DecimalN => format!("decimal({}, {})", self.precision, self.scale),
+            DataType::Numeric | DataType::NumericN => format!("numeric({}, {})", self.precision, self.scale),
+            DataType::DateN => String::from("date"),
+            DataType::TimeN => format!("time({})", self.scale),
+            DataType::DateTime2N => format!("datetime2({})", self.scale),
+            DataType::DateTimeOffsetN => format!("datetimeoffset({})", self.scale),
+            DataType::Guid => String::from("uniqueidentifier"),
+            DataType::Char | DataType::BigChar => format!("char({})", self.length),
+            DataType::VarChar | DataType::BigVarChar => format!("varchar({})", max_or(self.length)),
+            DataType::Binary | DataType::BigBinary => format!("binary({})", self.length),
+            DataType::VarBinary | DataType::BigVarBinary => format!("varbinary({})", max_or(self.length)),
+            DataType::NChar => format!("nchar({})", self.length / 2),
+            DataType::NVarChar if self.length == 0xffff => String::from("nvarchar(max)"),
+            DataType::NVarChar => format!("nvarchar({})", self.length / 2),
+            DataType::Xml => String::from("xml"),
+            DataType::Udt => self.udt.as_ref().map_or(String::from("varbinary(max)"), |udt| udt.type_name.clone()),
+            DataType::Text => String::from("text"),
+            DataType::NText => String::from("ntext"),
+            DataType::Image => String::from("image"),
+            DataType::Null | DataType::SqlVariant => String::from("sql_variant")
+        }
+    }
+
+    /**
+     * USHORTLEN types declared as (max) switch to PLP framing.
+     */
+    pub fn is_plp(&self) -> bool {
+        self.data_type.length_kind() == LengthKind::Plp
+            || (self.data_type.length_kind() == LengthKind::UShortLen && self.length == 0xffff)
+    }
+
+    /**
+     * Reads one value of this type from a ROW, giving back the bytes without the framing.
+     * None means the value was NULL.
+     */
+    pub fn read_value<R: Read>(&self, reader: &mut R) -> Result<Option<Vec<u8>>> {
+        if self.is_plp() {
+            return read_plp(reader);
+        }
+
+        match self.data_type.length_kind() {
+            LengthKind::Fixed => {
+                let length = self.data_type.fixed_length().unwrap_or(0);
+                Ok(Some(reader.read_bytes(length)?))
+            },
+            LengthKind::ByteLen => {
+                let length = reader.read_u8()? as usize;
+                if length == 0 {
+                    return Ok(None);
+                }
+                Ok(Some(reader.read_bytes(length)?))
+            },
+            LengthKind::UShortLen => {
+                let length = reader.read_u16_le()?;
+                if length == 0xffff {
+                    return Ok(None);
+                }
+                Ok(Some(reader.read_bytes(length as usize)?))
+            },
+            LengthKind::LongLen => {
+                if self.data_type == DataType::SqlVariant {
+                    let length = reader.read_u32_le()? as usize;
+                    if length == 0 {
+                        return Ok(None);
+                    }
+                    return Ok(Some(reader.read_bytes(length)?));
+                }
+
+                // text pointer, then timestamp, then the value itself
+                let pointer_length = reader.read_u8()? as usize;
+                if pointer_length == 0 {
+                    return Ok(None);
+                }
+                let _ = reader.read_bytes(pointer_length)?;
+                let _ = reader.read_bytes(8)?;
+                let length = reader.read_u32_le()? as usize;
+                Ok(Some(reader.read_bytes(length)?))
+            },
+            LengthKind::Plp => read_plp(reader)
+        }
+    }
+}
+
+pub const PLP_NULL: u64 = 0xffffffffffffffff;
+pub const PLP_UNKNOWN_LENGTH: u64 = 0xfffffffffffffffe;
+const COLLATION_UTF8: u32 = 0x04000000;
+
+/**
+ * Collation sent with character types: LCID plus comparison flags packed into 4 bytes,
+ * then a sort id that is only used by SQL collations.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Collation {
+    pub info: u32,
+    pub sort_id: u8
+}
+impl Collation {
+    pub fn new(info: u32, sort_id: u8) -> Collation {
+        Collation {
+            info,
+            sort_id
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Collation> {
+        if bytes.len() < 5 {
+            return None;
+        }
+
+        Some(Collation {
+            info: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            sort_id: bytes[4]
+        })
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<Collation> {
+        let bytes = reader.read_fixed::<5>()?;
+        Ok(Collation::from_bytes(&bytes).unwrap())
+    }
+
+    pub fn lcid(&self) -> u32 {
+        self.info & 0x000fffff
+    }
+
+    pub fn is_utf8(&self) -> bool {
+        self.info & COLLATION_UTF8 != 0
+    }
+
+    /**
+     * Windows code page for char/varchar/text data. SQL collations (non zero sort id) decide
+     * it from the sort id, Windows collations from the LCID.
+     */
+    pub fn code_page(&self) -> u16 {
+        if self.is_utf8() {
+            return 65001;
+        }
+
+        if self.sort_id != 0 {
+            return match self.sort_id {
+                30..=34 => 437,
+                40..=49 | 55..=61 => 850,
+                80..=96 => 1250,
+                104..=108 => 1251,
+                112..=124 => 1253,
+                128..=136 => 1254,
+                137..=143 => 1255,
+                144..=151 => 1256,
+                152..=160 => 1257,
+                192 | 200 => 932,
+                193 => 949,
+                194 | 196 => 950,
+                198 => 936,
+                _ => 1252
+            };
+        }
+
+        let lcid = self.lcid();
+        match lcid & 0x3ff {
+            0x05 | 0x0e | 0x15 | 0x18 | 0x1a | 0x1b | 0x1c | 0x24 => if lcid == 0x0c1a { 1251 } else { 1250 },
+            0x02 | 0x19 | 0x22 | 0x23 | 0x2f | 0x3f | 0x40 | 0x44 | 0x50 => 1251,
+            0x08 => 1253,
+            0x1f | 0x2c | 0x43 => 1254,
+            0x0d => 1255,
+            0x01 | 0x20 | 0x29 | 0x8c => 1256,
+            0x25..=0x27 => 1257,
+            0x2a => 1258,
+            0x1e => 874,
+            0x11 => 932,
+            0x12 => 949,
+            0x04 => if lcid == 0x0804 || lcid == 0x1004 { 936 } else { 950 },
+            _ => 1252
+        }
+    }
+
+    pub fn encoding(&self) -> Result<&'static encoding_rs::Encoding> {
+        match self.code_page() {
+            874 => Ok(encoding_rs::WINDOWS_874),
+            932 => Ok(encoding_rs::SHIFT_JIS),
+            936 => Ok(encoding_rs::GBK),
+            949 => Ok(encoding_rs::EUC_KR),
+            950 => Ok(encoding_rs::BIG5),
+            1250 => Ok(encoding_rs::WINDOWS_1250),
+            1251 => Ok(encoding_rs::WINDOWS_1251),
+            1252 => Ok(encoding_rs::WINDOWS_1252),
+            1253 => Ok(encoding_rs::WINDOWS_1253),
+            1254 => Ok(encoding_rs::WINDOWS_1254),
+            1255 => Ok(encoding_rs::WINDOWS_1255),
+            1256 => Ok(encoding_rs::WINDOWS_1256),
+            1257 => Ok(encoding_rs::WINDOWS_1257),
+            1258 => Ok(encoding_rs::WINDOWS_1258),
+            65001 => Ok(encoding_rs::UTF_8),
+            code_page => Err(Error::Protocol(format!("Unsupported code page {}", code_page)))
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 5] {
+        let info = self.info.to_le_bytes();
+        [info[0], info[1], info[2], info[3], self.sort_id]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct XmlSchema {
+    pub database: String,
+    pub owning_schema: String,
+    pub collection: String
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdtInfo {
+    pub database: String,
+    pub schema: String,
+    pub type_name: String,
+    pub assembly_name: String
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LengthKind {
+    Fixed,
+    ByteLen,
+    UShortLen,
+    LongLen,
+    Plp
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataType {
+    Null,
+    Int1,
+    Bit,
+    Int2,
+    Int4,
+    DateTime4,
+    Flt4,
+    Money,
+    DateTime,
+    Flt8,
+    Money4,
+    Int8,
+    Guid,
+    IntN,
+    Decimal,
+    Numeric,
+    BitN,
+    DecimalN,
+    NumericN,
+    FltN,
+    MoneyN,
+    DateTimeN,
+    DateN,
+    TimeN,
+    DateTime2N,
+    DateTimeOffsetN,
+    Char,
+    VarChar,
+    Binary,
+    VarBinary,
+    BigVarBinary,
+    BigVarChar,
+    BigBinary,
+    BigChar,
+    NVarChar,
+    NChar,
+    Xml,
+    Udt,
+    Text,
+    Image,
+    NText,
+    SqlVariant
+}
+impl DataType {
+    pub fn value(&self) -> u8 {
+        match self {
+            DataType::Null => 0x1f,
+            DataType::Int1 => 0x30,
+            DataType::Bit => 0x32,
+            DataType::Int2 => 0x34,
+            DataType::Int4 => 0x38,
+            DataType::DateTime4 => 0x3a,
+            DataType::Flt4 => 0x3b,
+            DataType::Money => 0x3c,
+            DataType::DateTime => 0x3d,
+            DataType::Flt8 => 0x3e,
+            DataType::Money4 => 0x7a,
+            DataType::Int8 => 0x7f,
+            DataType::Guid => 0x24,
+            DataType::IntN => 0x26,
+            DataType::Decimal => 0x37,
+            DataType::Numeric => 0x3f,
+            DataType::BitN => 0x68,
+            DataType::DecimalN => 0x6a,
+            DataType::NumericN => 0x6c,
+            DataType::FltN => 0x6d,
+            DataType::MoneyN => 0x6e,
+            DataType::DateTimeN => 0x6f,
+            DataType::DateN => 0x28,
+            DataType::TimeN => 0x29,
+            DataType::DateTime2N => 0x2a,
+            DataType::DateTimeOffsetN => 0x2b,
+            DataType::Char => 0x2f,
+            DataType::VarChar => 0x27,
+            DataType::Binary => 0x2d,
+            DataType::VarBinary => 0x25,
+            DataType::BigVarBinary => 0xa5,
+            DataType::BigVarChar => 0xa7,
+            DataType::BigBinary => 0xad,
+            DataType::BigChar => 0xaf,
+            DataType::NVarChar => 0xe7,
+            DataType::NChar => 0xef,
+            DataType::Xml => 0xf1,
+            DataType::Udt => 0xf0,
+            DataType::Text => 0x23,
+            DataType::Image => 0x22,
+            DataType::NText => 0x63,
+            DataType::SqlVariant => 0x62
+        }
+    }
+
+    pub fn from_value(value: u8) -> Option<DataType> {
+        match value {
+            0x1f => Some(DataType::Null),
+            0x30 => Some(DataType::Int1),
+            0x32 => Some(DataType::Bit),
+            0x34 => Some(DataType::Int2),
+            0x38 => Some(DataType::Int4),
+            0x3a => Some(DataType::DateTime4),
+            0x3b => Some(DataType::Flt4),
+            0x3c => Some(DataType::Money),
+            0x3d => Some(DataType::DateTime),
+            0x3e => Some(DataType::Flt8),
+            0x7a => Some(DataType::Money4),
+            0x7f => Some(DataType::Int8),
+            0x24 => Some(DataType::Guid),
+            0x26 => Some(DataType::IntN),
+            0x37 => Some(DataType::Decimal),
+            0x3f => Some(DataType::Numeric),
+            0x68 => Some(DataType::BitN),
+            0x6a => Some(DataType::DecimalN),
+            0x6c => Some(DataType::NumericN),
+            0x6d => Some(DataType::FltN),
+            0x6e => Some(DataType::MoneyN),
+            0x6f => Some(DataType::DateTimeN),
+            0x28 => Some(DataType::DateN),
+            0x29 => Some(DataType::TimeN),
+            0x2a => Some(DataType::DateTime2N),
+            0x2b => Some(DataType::DateTimeOffsetN),
+            0x2f => Some(DataType::Char),
+            0x27 => Some(DataType::VarChar),
+            0x2d => Some(DataType::Binary),
+            0x25 => Some(DataType::VarBinary),
+            0xa5 => Some(DataType::BigVarBinary),
+            0xa7 => Some(DataType::BigVarChar),
+            0xad => Some(DataType::BigBinary),
+            0xaf => Some(DataType::BigChar),
+            0xe7 => Some(DataType::NVarChar),
+            0xef => Some(DataType::NChar),
+            0xf1 => Some(DataType::Xml),
+            0xf0 => Some(DataType::Udt),
+            0x23 => Some(DataType::Text),
+            0x22 => Some(DataType::Image),
+            0x63 => Some(DataType::NText),
+            0x62 => Some(DataType::SqlVariant),
+            _ => None
+        }
+    }
+
+    pub fn fixed_length(&self) -> Option<usize> {
+        match self {
+            DataType::Null => Some(0),
+            DataType::Int1 | DataType::Bit => Some(1),
+            DataType::Int2 => Some(2),
+            DataType::Int4 | DataType::DateTime4 | DataType::Flt4 | DataType::Money4 => Some(4),
+            DataType::Money | DataType::DateTime | DataType::Flt8 | DataType::Int8 => Some(8),
+            _ => None
+        }
+    }
+
+    pub fn length_kind(&self) -> LengthKind {
+        match self {
+            DataType::Null | DataType::Int1 | DataType::Bit | DataType::Int2 | DataType::Int4
+                | DataType::DateTime4 | DataType::Flt4 | DataType::Money | DataType::DateTime
+                | DataType::Flt8 | DataType::Money4 | DataType::Int8 => LengthKind::Fixed,
+            DataType::BigVarBinary | DataType::BigVarChar | DataType::BigBinary | DataType::BigChar
+                | DataType::NVarChar | DataType::NChar => LengthKind::UShortLen,
+            DataType::Text | DataType::Image | DataType::NText | DataType::SqlVariant => LengthKind::LongLen,
+            DataType::Xml | DataType::Udt => LengthKind::Plp,
+            _ => LengthKind::ByteLen
+        }
+    }
+
+    pub fn has_collation(&self) -> bool {
+        matches!(self, DataType::BigVarChar | DataType::BigChar | DataType::NVarChar
+            | DataType::NChar | DataType::Text | DataType::NText)
+    }
+
+    pub fn has_precision(&self) -> bool {
+        matches!(self, DataType::Decimal | DataType::Numeric | DataType::DecimalN | DataType::NumericN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datatype_from_value_round_trips() {
+        for value in 0..=255u8 {
+            if let Some(data_type) = DataType::from_value(value) {
+                assert_eq!(data_type.value(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_typeinfo_read_fixed_length_type() {
+        let mut bytes: &[u8] = &[0x38, 0x2a, 0x00, 0x00, 0x00];
+
+        let info = TypeInfo::read(&mut bytes).unwrap();
+        let value = info.read_value(&mut bytes).unwrap();
+
+        assert_eq!(info.data_type, DataType::Int4);
+        assert_eq!(info.length, 4);
+        assert_eq!(value, Some(vec![0x2a, 0x00, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn test_typeinfo_read_bytelen_type_with_null() {
+        let mut bytes: &[u8] = &[0x26, 0x08, 0x00, 0x02, 0x01, 0x00];
+
+        let info = TypeInfo::read(&mut bytes).unwrap();
+
+        assert_eq!(info.data_type, DataType::IntN);
+        assert_eq!(info.length, 8);
+        assert_eq!(info.read_value(&mut bytes).unwrap(), None);
+        assert_eq!(info.read_value(&mut bytes).unwrap(), Some(vec![0x01, 0x00]));
+    }
+
+    #[test]
+    fn test_typeinfo_read_decimal_and_time() {
+        let mut bytes: &[u8] = &[0x6a, 0x11, 0x12, 0x04, 0x2a, 0x07, 0x28];
+
+        let decimal = TypeInfo::read(&mut bytes).unwrap();
+        let datetime2 = TypeInfo::read(&mut bytes).unwrap();
+        let date = TypeInfo::read(&mut bytes).unwrap();
+
+        assert_eq!(decimal.length, 17);
+        assert_eq!(decimal.precision, 18);
+        assert_eq!(decimal.scale, 4);
+        assert_eq!(datetime2.data_type, DataType::DateTime2N);
+        assert_eq!(datetime2.scale, 7);
+        assert_eq!(date.data_type, DataType::DateN);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_typeinfo_read_nvarchar_with_collation() {
+        let mut bytes: &[u8] = &[0xe7, 0x64, 0x00, 0x09, 0x04, 0xd0, 0x00, 0x34, 0x04, 0x00, 0x68, 0x00, 0x69, 0x00, 0xff, 0xff];
+
+        let info = TypeInfo::read(&mut bytes).unwrap();
+
+        assert_eq!(info.data_type, DataType::NVarChar);
+        assert_eq!(info.length, 100);
+        assert_eq!(info.collation.unwrap().lcid(), 0x0409);
+        assert_eq!(info.collation.unwrap().sort_id, 0x34);
+        assert!(!info.is_plp());
+        assert_eq!(info.read_value(&mut bytes).unwrap(), Some(vec![0x68, 0x00, 0x69, 0x00]));
+        assert_eq!(info.read_value(&mut bytes).unwrap(), None);
+    }
+
+    #[test]
+    fn test_typeinfo_read_max_type_as_plp() {
+        let mut bytes: Vec<u8> = vec![0xa5, 0xff, 0xff];
+        bytes.extend_from_slice(&3u64.to_le_bytes());
+        bytes.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x01, 0x02]);
+        bytes.extend_from_slice(&[0x01, 0x00, 0x00, 0x00, 0x03]);
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        bytes.extend_from_slice(&PLP_NULL.to_le_bytes());
+        let mut reader: &[u8] = &bytes;
+
+        let info = TypeInfo::read(&mut reader).unwrap();
+
+        assert!(info.is_plp());
+        assert_eq!(info.read_value(&mut reader).unwrap(), Some(vec![0x01, 0x02, 0x03]));
+        assert_eq!(info.read_value(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_typeinfo_read_text_with_table_name() {
+        let mut bytes: Vec<u8> = vec![0x23, 0xff, 0xff, 0xff, 0x7f, 0x09, 0x04, 0xd0, 0x00, 0x34, 0x01, 0x01, 0x00, 0x74, 0x00];
+        bytes.push(0x10);
+        bytes.extend_from_slice(&[0xaa; 16]);
+        bytes.extend_from_slice(&[0xbb; 8]);
+        bytes.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x68, 0x69]);
+        bytes.push(0x00);
+        let mut reader: &[u8] = &bytes;
+
+        let info = TypeInfo::read(&mut reader).unwrap();
+
+        assert_eq!(info.data_type, DataType::Text);
+        assert_eq!(info.table_name, vec![String::from("t")]);
+        assert_eq!(info.read_value(&mut reader).unwrap(), Some(vec![0x68, 0x69]));
+        assert_eq!(info.read_value(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_typeinfo_to_bytes_round_trips() {
+        let types: [&[u8]; 6] = [
+            &[0x38],
+            &[0x6a, 0x11, 0x12, 0x04],
+            &[0x2a, 0x03],
+            &[0xe7, 0x64, 0x00, 0x09, 0x04, 0xd0, 0x00, 0x34],
+            &[0x23, 0xff, 0xff, 0xff, 0x7f, 0x09, 0x04, 0xd0, 0x00, 0x34, 0x01, 0x01, 0x00, 0x74, 0x00],
+            &[0xf1, 0x00]
+        ];
+
+        for bytes in types {
+            let mut reader: &[u8] = bytes;
+            assert_eq!(TypeInfo::read(&mut reader).unwrap().to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_typeinfo_declaration_uses_length_and_scale() {
+        let mut bytes: &[u8] = &[0x26, 0x02, 0x29, 0x03, 0xe7, 0xff, 0xff, 0x09, 0x04, 0xd0, 0x00, 0x34, 0x6a, 0x11, 0x12, 0x04];
+
+        let declarations: Vec<String> = (0..4).map(|_| TypeInfo::read(&mut bytes).unwrap().declaration()).collect();
+
+        assert_eq!(declarations, vec!["smallint", "time(3)", "nvarchar(max)", "decimal(18, 4)"]);
+    }
+
+    #[test]
+    fn test_collation_code_page_from_lcid_and_sort_id() {
+        assert_eq!(Collation::new(0x00d00409, 0x34).code_page(), 1252);
+        assert_eq!(Collation::new(0x00d00409, 0x1e).code_page(), 437);
+        assert_eq!(Collation::new(0x00d00419, 0x00).code_page(), 1251);
+        assert_eq!(Collation::new(0x00d00411, 0x00).code_page(), 932);
+        assert_eq!(Collation::new(0x00d00804, 0x00).code_page(), 936);
+        assert_eq!(Collation::new(0x04d00409, 0x00).code_page(), 65001);
+        assert!(Collation::new(0x00d00409, 0x1e).encoding().is_err());
+    }
+
+    #[test]
+    fn test_typeinfo_read_fails_on_unknown_type() {
+        let mut bytes: &[u8] = &[0x01];
+
+        assert!(TypeInfo::read(&mut bytes).is_err());
+    }
+}
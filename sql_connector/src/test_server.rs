@@ -0,0 +1,253 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use rustls::{ServerConfig, ServerConnection};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use crate::tds_message::{TdsMessage, ucs2_bytes, MAX_PACKET_SIZE};
+use crate::smp::{SmpHeader, SmpFlag, SMP_HEADER_LENGTH};
+
+/**
+ * Stand-in SQL Server for the tests
+ *
+ * Answers every packet it receives with the next canned response and hands back what it
+ * was sent, so the connector can be tested without a real server running.
+ */
+pub fn fake_server(responses: Vec<Vec<u8>>) -> (String, thread::JoinHandle<Vec<TdsMessage>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received: Vec<TdsMessage> = Vec::new();
+
+        for response in responses {
+            received.push(TdsMessage::from_stream(&mut stream).unwrap());
+            stream.write_all(&response).unwrap();
+        }
+
+        received
+    });
+
+    (port, handle)
+}
+
+pub fn packet(message_type: u8, body: &[u8]) -> Vec<u8> {
+    let length = (body.len() + 8) as u16;
+    let mut packet: Vec<u8> = vec![message_type, 0x01, (length >> 8) as u8, (length & 0xff) as u8, 0x00, 0x00, 0x01, 0x00];
+    packet.extend_from_slice(body);
+    packet
+}
+
+pub fn server_packet(body: &[u8]) -> Vec<u8> {
+    packet(0x04, body)
+}
+
+/**
+ * A response body split into packets of `size` bytes, so tokens straddle packet boundaries.
+ */
+pub fn split_response(body: &[u8], size: usize) -> Vec<u8> {
+    let chunks: Vec<&[u8]> = body.chunks(size).collect();
+    let last: usize = chunks.len() - 1;
+
+    chunks.iter().enumerate().flat_map(|(index, chunk)| {
+        let mut packet: Vec<u8> = server_packet(chunk);
+        packet[1] = if index == last { 0x01 } else { 0x00 };
+        packet
+    }).collect()
+}
+
+/**
+ * PRELOGIN answer with the given ENCRYPTION byte. Defaults to 0x02 (ENCRYPT_NOT_SUP)
+ * in prelogin_response so the plain tests don't start TLS.
+ */
+pub fn prelogin_response_with(encryption: u8) -> Vec<u8> {
+    server_packet(&[
+        0x00, 0x00, 0x0b, 0x00, 0x06,
+        0x01, 0x00, 0x11, 0x00, 0x01,
+        0xff,
+        0x10, 0x00, 0x07, 0xd0, 0x00, 0x00,
+        encryption
+    ])
+}
+
+pub fn prelogin_response() -> Vec<u8> {
+    prelogin_response_with(0x02)
+}
+
+/**
+ * PRELOGIN answer agreeing to MARS, without encryption.
+ */
+pub fn prelogin_response_with_mars() -> Vec<u8> {
+    server_packet(&[
+        0x00, 0x00, 0x10, 0x00, 0x06,
+        0x01, 0x00, 0x16, 0x00, 0x01,
+        0x04, 0x00, 0x17, 0x00, 0x01,
+        0xff,
+        0x10, 0x00, 0x07, 0xd0, 0x00, 0x00,
+        0x02,
+        0x01
+    ])
+}
+
+pub fn smp_packet(flag: SmpFlag, session_id: u16, sequence_number: u32, window: u32, payload: &[u8]) -> Vec<u8> {
+    let mut packet: Vec<u8> = SmpHeader::new(flag, session_id, sequence_number, window, payload.len()).to_bytes().to_vec();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub fn read_smp_packet(stream: &mut TcpStream) -> (SmpHeader, Vec<u8>) {
+    let mut header: [u8; SMP_HEADER_LENGTH] = [0; SMP_HEADER_LENGTH];
+    stream.read_exact(&mut header).unwrap();
+    let header: SmpHeader = SmpHeader::from_bytes(&header, MAX_PACKET_SIZE).unwrap();
+
+    let mut payload: Vec<u8> = vec![0; header.payload_length()];
+    stream.read_exact(&mut payload).unwrap();
+    (header, payload)
+}
+
+pub fn login_ack_response() -> Vec<u8> {
+    let mut body: Vec<u8> = vec![0xe3, 0x0b, 0x00, 0x04, 0x04];
+    body.extend_from_slice(&ucs2_bytes("8000"));
+    body.push(0x00);
+    body.extend_from_slice(&[0xad, 0x0e, 0x00, 0x01, 0x74, 0x00, 0x00, 0x04, 0x02]);
+    body.extend_from_slice(&ucs2_bytes("ab"));
+    body.extend_from_slice(&[0x10, 0x00, 0x07, 0xd0]);
+    body.extend_from_slice(&[0xfd, 0x00, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+    server_packet(&body)
+}
+
+/**
+ * Two rows of (id INT, name NVARCHAR(50)), the second name NULL, then DONE with a count of 2.
+ */
+pub fn query_response() -> Vec<u8> {
+    let mut body: Vec<u8> = vec![0x81, 0x02, 0x00];
+    body.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x38, 0x02]);
+    body.extend_from_slice(&ucs2_bytes("id"));
+    body.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0xe7, 0x64, 0x00, 0x09, 0x04, 0xd0, 0x00, 0x34, 0x04]);
+    body.extend_from_slice(&ucs2_bytes("name"));
+    body.extend_from_slice(&[0xd1, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00]);
+    body.extend_from_slice(&ucs2_bytes("ab"));
+    body.extend_from_slice(&[0xd2, 0x02, 0x02, 0x00, 0x00, 0x00]);
+    body.extend_from_slice(&[0xfd, 0x10, 0x00, 0xc1, 0x00, 0x02, 0, 0, 0, 0, 0, 0, 0]);
+    server_packet(&body)
+}
+
+/**
+ * DONE with the attention bit, the server's answer to ATTENTION.
+ */
+pub fn attention_ack_response() -> Vec<u8> {
+    server_packet(&[0xfd, 0x20, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0])
+}
+
+/**
+ * ENVCHANGE for a transaction starting (0x08), committing (0x09) or rolling back (0x0a),
+ * then DONE. The descriptor is the new value when starting and the old one otherwise.
+ */
+pub fn transaction_response(change_type: u8, descriptor: u64) -> Vec<u8> {
+    let mut change: Vec<u8> = vec![change_type];
+    if change_type == 0x08 {
+        change.push(0x08);
+        change.extend_from_slice(&descriptor.to_le_bytes());
+        change.push(0x00);
+    } else {
+        change.extend_from_slice(&[0x00, 0x08]);
+        change.extend_from_slice(&descriptor.to_le_bytes());
+    }
+
+    let mut body: Vec<u8> = vec![0xe3];
+    body.extend_from_slice(&(change.len() as u16).to_le_bytes());
+    body.extend_from_slice(&change);
+    body.extend_from_slice(&[0xfd, 0x00, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+    server_packet(&body)
+}
+
+/**
+ * Self signed certificate for "localhost", as (PEM, DER certificate, DER key).
+ */
+pub fn self_signed_certificate() -> (String, CertificateDer<'static>, PrivateKeyDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()));
+
+    (certified.cert.pem(), certified.cert.der().clone(), key)
+}
+
+/**
+ * Like fake_server but does the TLS handshake inside PRELOGIN packets after answering
+ * PRELOGIN with `encryption`. LOGIN7 is read over TLS; with `full` set everything after it
+ * stays encrypted, otherwise TLS is dropped like SQL Server does for login only encryption.
+ */
+pub fn fake_tls_server(encryption: u8, full: bool, responses: Vec<Vec<u8>>) -> (String, String, thread::JoinHandle<Vec<TdsMessage>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let (pem, certificate, key) = self_signed_certificate();
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS12])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate], key)
+        .unwrap();
+
+    let handle = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut received: Vec<TdsMessage> = Vec::new();
+
+        received.push(TdsMessage::from_stream(&mut socket).unwrap());
+        socket.write_all(&prelogin_response_with(encryption)).unwrap();
+
+        let mut connection = ServerConnection::new(Arc::new(config)).unwrap();
+        if tls_handshake(&mut socket, &mut connection).is_err() {
+            return received;
+        }
+
+        let mut tls = rustls::Stream::new(&mut connection, &mut socket);
+        received.push(TdsMessage::from_stream(&mut tls).unwrap());
+
+        let mut responses = responses.into_iter();
+        let login_response = responses.next().unwrap();
+
+        if full {
+            tls.write_all(&login_response).unwrap();
+            for response in responses {
+                received.push(TdsMessage::from_stream(&mut tls).unwrap());
+                tls.write_all(&response).unwrap();
+            }
+        } else {
+            socket.write_all(&login_response).unwrap();
+            for response in responses {
+                received.push(TdsMessage::from_stream(&mut socket).unwrap());
+                socket.write_all(&response).unwrap();
+            }
+        }
+
+        received
+    });
+
+    (port, pem, handle)
+}
+
+fn tls_handshake(socket: &mut TcpStream, connection: &mut ServerConnection) -> Result<(), String> {
+    loop {
+        if connection.wants_write() {
+            let mut records: Vec<u8> = Vec::new();
+            while connection.wants_write() {
+                connection.write_tls(&mut records).unwrap();
+            }
+            socket.write_all(&packet(0x12, &records)).map_err(|e| e.to_string())?;
+            continue;
+        }
+
+        if !connection.is_handshaking() {
+            return Ok(());
+        }
+
+        let message = TdsMessage::from_stream(socket).map_err(|e| e.to_string())?;
+        let mut records: &[u8] = message.body();
+        while !records.is_empty() {
+            connection.read_tls(&mut records).map_err(|e| e.to_string())?;
+            connection.process_new_packets().map_err(|e| e.to_string())?;
+        }
+    }
+}
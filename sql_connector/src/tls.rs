@@ -0,0 +1,313 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme, CertificateError};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls_pki_types::pem::PemObject;
+use crate::tds_message::EncryptionOptions;
+use crate::error::{Error, Result};
+
+/**
+ * TLS for TDS
+ *
+ * SQL Server doesn't speak TLS straight away. The client says in PRELOGIN whether it wants
+ * encryption, the server answers with its own setting and between them they decide on one of:
+ *
+ * - no encryption at all
+ * - login only: TLS is set up, LOGIN7 goes through it, then both sides drop back to plain TCP
+ * - everything encrypted
+ *
+ * The TLS handshake itself travels inside PRELOGIN packets (see TdsStream).
+ *
+ * https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-tds/60f56408-0188-4cd5-8b90-25c6f2423868
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsSettings {
+    pub encrypt: EncryptMode,
+    pub trust_server_certificate: bool,
+    pub ca_file: Option<PathBuf>,
+    pub verify_hostname: bool
+}
+impl Default for TlsSettings {
+    fn default() -> TlsSettings {
+        TlsSettings::new()
+    }
+}
+impl TlsSettings {
+    pub fn new() -> TlsSettings {
+        TlsSettings {
+            encrypt: EncryptMode::Off,
+            trust_server_certificate: false,
+            ca_file: None,
+            verify_hostname: true
+        }
+    }
+
+    /**
+     * Builds the rustls config for these settings. Certificates are checked against the
+     * CA file if there is one, the bundled webpki roots if not, unless we've been told to
+     * trust whatever the server sends.
+     */
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(format!("Failed to set up TLS: {}", e)))?;
+
+        let verifier: Arc<dyn ServerCertVerifier> = if self.trust_server_certificate {
+            Arc::new(TrustAnyCertificate {
+                provider
+            })
+        } else {
+            let roots = Arc::new(self.root_store()?);
+            let webpki = WebPkiServerVerifier::builder_with_provider(roots, provider)
+                .build()
+                .map_err(|e| Error::Tls(format!("Failed to set up certificate verification: {}", e)))?;
+
+            if self.verify_hostname {
+                webpki
+            } else {
+                Arc::new(IgnoreHostname {
+                    inner: webpki
+                })
+            }
+        };
+
+        let config = builder
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+
+        Ok(Arc::new(config))
+    }
+
+    fn root_store(&self) -> Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+
+        match &self.ca_file {
+            Some(path) => {
+                let certificates = CertificateDer::pem_file_iter(path)
+                    .map_err(|e| Error::Tls(format!("Failed to read CA file {}: {}", path.display(), e)))?;
+
+                for certificate in certificates {
+                    let certificate = certificate.map_err(|e| Error::Tls(format!("Invalid certificate in {}: {}", path.display(), e)))?;
+                    roots.add(certificate).map_err(|e| Error::Tls(format!("Invalid certificate in {}: {}", path.display(), e)))?;
+                }
+            },
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned())
+        }
+
+        Ok(roots)
+    }
+}
+
+pub fn server_name(host: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(host.to_string()).map_err(|e| Error::Tls(format!("Invalid server name '{}': {}", host, e)))
+}
+
+/**
+ * What the client asks for in PRELOGIN.
+ *
+ * Off still encrypts the login if the server can, On insists on encrypting everything and
+ * NotSupported never sets up TLS (and fails if the server requires it).
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptMode {
+    Off,
+    On,
+    NotSupported
+}
+impl EncryptMode {
+    pub fn prelogin_option(&self) -> EncryptionOptions {
+        match self {
+            EncryptMode::Off => EncryptionOptions::NoEncryption,
+            EncryptMode::On => EncryptionOptions::EncryptionEnabled,
+            EncryptMode::NotSupported => EncryptionOptions::EncryptionRequested
+        }
+    }
+
+    pub fn from_value(value: &str) -> Result<EncryptMode> {
+        match value.to_lowercase().as_str() {
+            "off" | "false" | "no" | "optional" => Ok(EncryptMode::Off),
+            "on" | "true" | "yes" | "mandatory" => Ok(EncryptMode::On),
+            "not_supported" | "none" => Ok(EncryptMode::NotSupported),
+            _ => Err(Error::Config(format!("Invalid encrypt value '{}'", value)))
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match self {
+            EncryptMode::Off => "off",
+            EncryptMode::On => "on",
+            EncryptMode::NotSupported => "not_supported"
+        }
+    }
+
+    /**
+     * Works out what we ended up with given the server's PRELOGIN answer.
+     * (EncryptionRequested is ENCRYPT_NOT_SUP and EncryptionEnabledRequested is ENCRYPT_REQ
+     * in the spec.)
+     */
+    pub fn negotiate(&self, server: EncryptionOptions) -> Result<Encryption> {
+        match (self, server) {
+            (EncryptMode::NotSupported, EncryptionOptions::EncryptionEnabledRequested) => {
+                Err(Error::Tls(String::from("Server requires encryption but encryption is turned off")))
+            },
+            (EncryptMode::NotSupported, _) => Ok(Encryption::None),
+            (EncryptMode::On, EncryptionOptions::EncryptionRequested) => {
+                Err(Error::Tls(String::from("Encryption was requested but the server does not support it")))
+            },
+            (EncryptMode::On, _) => Ok(Encryption::Full),
+            (EncryptMode::Off, EncryptionOptions::NoEncryption) => Ok(Encryption::LoginOnly),
+            (EncryptMode::Off, EncryptionOptions::EncryptionRequested) => Ok(Encryption::None),
+            (EncryptMode::Off, _) => Ok(Encryption::Full)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encryption {
+    None,
+    LoginOnly,
+    Full
+}
+
+/**
+ * TrustServerCertificate=true: any certificate is accepted, but the handshake signatures
+ * are still checked so the server has to hold the key for the certificate it sent.
+ */
+#[derive(Debug)]
+struct TrustAnyCertificate {
+    provider: Arc<CryptoProvider>
+}
+impl ServerCertVerifier for TrustAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/**
+ * Checks the certificate chain as normal but lets a name mismatch through.
+ */
+#[derive(Debug)]
+struct IgnoreHostname {
+    inner: Arc<WebPkiServerVerifier>
+}
+impl ServerCertVerifier for IgnoreHostname {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName))
+                | Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForNameContext { .. })) => {
+                Ok(ServerCertVerified::assertion())
+            },
+            result => result
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryptmode_negotiate_follows_spec_table() {
+        assert_eq!(EncryptMode::Off.negotiate(EncryptionOptions::NoEncryption).unwrap(), Encryption::LoginOnly);
+        assert_eq!(EncryptMode::Off.negotiate(EncryptionOptions::EncryptionEnabled).unwrap(), Encryption::Full);
+        assert_eq!(EncryptMode::Off.negotiate(EncryptionOptions::EncryptionRequested).unwrap(), Encryption::None);
+        assert_eq!(EncryptMode::Off.negotiate(EncryptionOptions::EncryptionEnabledRequested).unwrap(), Encryption::Full);
+        assert_eq!(EncryptMode::On.negotiate(EncryptionOptions::EncryptionEnabled).unwrap(), Encryption::Full);
+        assert!(EncryptMode::On.negotiate(EncryptionOptions::EncryptionRequested).is_err());
+        assert_eq!(EncryptMode::NotSupported.negotiate(EncryptionOptions::NoEncryption).unwrap(), Encryption::None);
+        assert!(EncryptMode::NotSupported.negotiate(EncryptionOptions::EncryptionEnabledRequested).is_err());
+    }
+
+    #[test]
+    fn test_encryptmode_from_value_parses_aliases() {
+        assert_eq!(EncryptMode::from_value("true").unwrap(), EncryptMode::On);
+        assert_eq!(EncryptMode::from_value("Off").unwrap(), EncryptMode::Off);
+        assert_eq!(EncryptMode::from_value("not_supported").unwrap(), EncryptMode::NotSupported);
+        assert!(EncryptMode::from_value("sometimes").is_err());
+    }
+
+    #[test]
+    fn test_tlssettings_client_config_builds_for_each_mode() {
+        let mut settings = TlsSettings::new();
+        assert!(settings.client_config().is_ok());
+
+        settings.trust_server_certificate = true;
+        assert!(settings.client_config().is_ok());
+
+        settings.trust_server_certificate = false;
+        settings.verify_hostname = false;
+        assert!(settings.client_config().is_ok());
+    }
+
+    #[test]
+    fn test_tlssettings_client_config_fails_on_missing_ca_file() {
+        let mut settings = TlsSettings::new();
+        settings.ca_file = Some(PathBuf::from("does/not/exist.pem"));
+
+        assert!(settings.client_config().is_err());
+    }
+}
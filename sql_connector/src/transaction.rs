@@ -0,0 +1,82 @@
+use std::ops::{Deref, DerefMut};
+use crate::ocbd::Connector;
+use crate::error::Result;
+
+/**
+ * Isolation level for a new transaction, as SET TRANSACTION ISOLATION LEVEL would set it.
+ * Unchanged keeps whatever the session is using.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    Unchanged,
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+    Snapshot
+}
+impl IsolationLevel {
+    pub fn value(&self) -> u8 {
+        match self {
+            IsolationLevel::Unchanged => 0x00,
+            IsolationLevel::ReadUncommitted => 0x01,
+            IsolationLevel::ReadCommitted => 0x02,
+            IsolationLevel::RepeatableRead => 0x03,
+            IsolationLevel::Serializable => 0x04,
+            IsolationLevel::Snapshot => 0x05
+        }
+    }
+}
+
+/**
+ * Transaction that rolls back unless it's committed
+ *
+ * From Connector::transaction. Use it like the Connector to run statements inside the
+ * transaction. Dropping it without calling commit rolls the transaction back, so an early
+ * return or `?` can't leave it open on the connection.
+ */
+pub struct Transaction<'a> {
+    connector: &'a mut Connector,
+    finished: bool
+}
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(connector: &'a mut Connector) -> Transaction<'a> {
+        Transaction {
+            connector,
+            finished: false
+        }
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.finished = true;
+        self.connector.commit()
+    }
+
+    pub fn rollback(mut self) -> Result<()> {
+        self.finished = true;
+        self.connector.rollback()
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = Connector;
+
+    fn deref(&self) -> &Connector {
+        self.connector
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut Connector {
+        self.connector
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        // nothing to report an error to, and a closed connection has lost the transaction anyway
+        if !self.finished && self.connector.in_transaction() {
+            let _ = self.connector.rollback();
+        }
+    }
+}